@@ -2,7 +2,7 @@
 /// https://github.com/SecretFoundation/SNIPs/blob/master/SNIP-20.md
 use std::convert::TryInto;
 use cosmwasm_std::{
-    to_binary, from_binary, Api, Binary, Env, Extern,
+    to_binary, from_binary, Api, Binary, CanonicalAddr, Env, Extern,
     HandleResponse, HumanAddr, InitResponse, Querier, QueryResult, ReadonlyStorage, StdError,
     StdResult, Storage, Uint128,
 };
@@ -10,17 +10,25 @@ use cosmwasm_std::{
 use crate::msg::QueryWithPermit;
 use crate::msg::{
     space_pad, HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg, ReceiveMsg,
+    ContractStatusLevel, status_level_to_u8,
     ResponseStatus::Success, TreasuryHandleMsg, StakingHandleMsg,
     TreasuryQueryMsg, BondCalculatorQueryMsg,
-    ValueOfResponse, MarkdownResponse,
-    BondParameter,
+    ValueOfResponse, MarkdownResponse, TotalReservesResponse,
+    BondParameter, Note, MarketInfo,
     RESPONSE_BLOCK_SIZE
 };
 use crate::rand::sha_256;
 use crate::state::{
     read_viewing_key, 
     write_viewing_key, Config, Constants, ReadonlyConfig,
-    Adjust, Terms, Contract, Bond, BondInfo, ReadonlyBondInfo
+    Adjust, Controller, Terms, TermChange, Contract, Principle, Market, PurchaseLimits, PurchaseUsage, window_start,
+    Twap, Observation, Allowlist, AllowedDepositor,
+    Bond, BondInfo, ReadonlyBondInfo,
+    BondApproval, read_bond_approval, write_bond_approval, remove_bond_approval
+};
+use crate::transaction_history::{
+    get_bond_transfers, store_bond_transfer, get_bond_txs, store_bond_tx, BondAction, ReservePayout,
+    get_term_changes, store_term_change,
 };
 use secret_toolkit::snip20;
 use crate::viewing_key::{ViewingKey, VIEWING_KEY_SIZE};
@@ -29,6 +37,9 @@ use secret_toolkit::utils::{Query};
 
 pub const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
 pub const COMMON_VIEWING_KEY : &str = "ALL_ORGANISATION_INFO_SHOULD_BE_PUBLIC";
+pub const DEFAULT_TERMS_TIMELOCK: u64 = 14_400; // a day, in blocks of about 6 seconds
+pub const MAX_OBSERVATIONS: u64 = 32; // markdown observations kept per TWAP market
+pub const MAX_CONTROL_STEPS: u64 = 1_000; // controller buffers an idle market catches up with at once
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
@@ -50,14 +61,6 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     let admin = msg.admin.unwrap_or(env.message.sender);
     let prng_seed_hashed = sha_256(&msg.prng_seed.0);
 
-
-    let principle_decimals = snip20::token_info_query(
-        &deps.querier,
-        RESPONSE_BLOCK_SIZE,
-        msg.principle.token.code_hash.clone(),
-        msg.principle.token.address.clone(),
-    )?.decimals;
-
     let ohm_decimals = snip20::token_info_query(
         &deps.querier,
         RESPONSE_BLOCK_SIZE,
@@ -69,34 +72,21 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     config.set_constants(&Constants {
         ohm: msg.ohm.clone(),
         ohm_decimals: ohm_decimals,
-        principle: msg.principle.clone(),
-        principle_decimals: principle_decimals,
         treasury: msg.treasury.clone(),
         dao: msg.dao.clone(),
-        bond_calculator: msg.bond_calculator.clone(),
         staking: None,
-        terms: None,
-        adjustment: None,
         name: msg.name,
         symbol: msg.symbol,
         admin: admin,
         prng_seed: prng_seed_hashed.to_vec(),
         contract_address: env.contract.address,
 
+        terms_timelock: msg.terms_timelock.unwrap_or(DEFAULT_TERMS_TIMELOCK),
     })?;
-
-    let messages = vec![
-        snip20::register_receive_msg(
-            env.contract_code_hash.clone(),
-            None,
-            RESPONSE_BLOCK_SIZE,
-            msg.principle.token.code_hash.clone(),
-            msg.principle.token.address.clone(),
-        )?
-    ];
+    config.set_contract_status(ContractStatusLevel::NormalRun);
 
     Ok(InitResponse {
-        messages,
+        messages: vec![],
         log: vec![],
     })
 }
@@ -107,6 +97,42 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     env: Env,
     msg: HandleMsg,
 ) -> StdResult<HandleResponse> {
+    let contract_status = ReadonlyConfig::from_storage(&deps.storage).contract_status();
+
+    match contract_status {
+        ContractStatusLevel::StopAll => {
+            let response = match msg {
+                HandleMsg::SetContractStatus { level, .. } => set_contract_status(deps, env, level),
+                HandleMsg::RecoverLostToken{token} => recover_lost_token(deps,env,token),
+                _ => Err(StdError::generic_err(
+                    "This contract is stopped and this action is not allowed",
+                )),
+            };
+            return pad_response(response);
+        }
+        ContractStatusLevel::StopAllButRedeems => match msg {
+            // deposits go through Receive
+            HandleMsg::Receive { .. }
+            | HandleMsg::DepositNative { .. }
+            | HandleMsg::InitializeBondTerms { .. }
+            | HandleMsg::SetBondTerm { .. }
+            | HandleMsg::ApplyBondTerms { .. }
+            | HandleMsg::SetAdjustment { .. }
+            | HandleMsg::SetController { .. }
+            | HandleMsg::RemoveController { .. }
+            | HandleMsg::SetPurchaseLimits { .. }
+            | HandleMsg::SetTwap { .. }
+            | HandleMsg::UpdateTwap { .. }
+            | HandleMsg::SetAllowlist { .. }
+            | HandleMsg::SetAllowedDepositor { .. } => {
+                return pad_response(Err(StdError::generic_err(
+                    "Deposits and market changes are stopped, only redeems are allowed",
+                )));
+            }
+            _ => {}
+        },
+        ContractStatusLevel::NormalRun => {} // If it's a normal run just continue
+    }
 
     let response = match msg {
 
@@ -115,25 +141,66 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
                     from, amount, msg,..
                 } => receive(deps, env, from, amount.u128(), msg),
 
+        // Markets
+        HandleMsg::AddMarket{principle, bond_calculator, inverse} =>
+            add_market(deps,env,principle,bond_calculator,inverse.unwrap_or_default()),
+        HandleMsg::CloseMarket{market_id} => close_market(deps,env,market_id),
+
         HandleMsg::InitializeBondTerms{
+            market_id,
             control_variable,vesting_term,minimum_price, maximum_price,
-            max_payout, fee, max_debt, initial_debt
+            max_payout, fee, max_debt, initial_debt, capacity, time_based
         } => initialize_bond_terms(
-            deps,env,
+            deps,env,market_id,
             control_variable,vesting_term,minimum_price, maximum_price,
-            max_payout, fee, max_debt, initial_debt
+            max_payout, fee, max_debt, initial_debt, capacity, time_based
         ),
 
-        HandleMsg::SetBondTerm{parameter,input} => set_bond_terms(deps,env, parameter, input.u128()),
+        HandleMsg::SetBondTerm{market_id,parameter,input} => set_bond_terms(deps,env,market_id, parameter, input.u128()),
+        HandleMsg::ApplyBondTerms{market_id} => apply_bond_terms(deps,env,market_id),
 
-        HandleMsg::SetAdjustment{addition,increment, target, buffer} =>
-            set_adjustment(deps,env,addition,increment.u128(), target.u128(), buffer),
+        HandleMsg::SetAdjustment{market_id,addition,increment, target, buffer} =>
+            set_adjustment(deps,env,market_id,addition,increment.u128(), target.u128(), buffer),
+        HandleMsg::SetController{
+            market_id, target_debt_ratio, target_sold, step, min_control_variable, max_control_variable, buffer
+        } => set_controller(
+            deps,env,market_id,
+            target_debt_ratio, target_sold, step, min_control_variable, max_control_variable, buffer
+        ),
+        HandleMsg::RemoveController{market_id} => remove_controller(deps,env,market_id),
+        HandleMsg::SetPurchaseLimits{market_id,limits} => set_purchase_limits(deps,env,market_id,limits),
+        HandleMsg::SetTwap{market_id,twap} => set_twap(deps,env,market_id,twap),
+        HandleMsg::UpdateTwap{market_id} => update_twap(deps,env,market_id),
+        HandleMsg::SetAllowlist{market_id,allowlist} => set_allowlist(deps,env,market_id,allowlist),
+        HandleMsg::SetAllowedDepositor{market_id,address,cap} => set_allowed_depositor(deps,env,market_id,address,cap),
+        HandleMsg::RemoveAllowedDepositor{market_id,address} => remove_allowed_depositor(deps,env,market_id,address),
         HandleMsg::SetStaking{staking} => set_staking(deps,env,staking),
-        HandleMsg::Redeem{recipient,stake} => redeem(deps,env,recipient,stake),
+        HandleMsg::SetNativeWrapper{token} => set_native_wrapper(deps,env,token),
+        HandleMsg::DepositNative{
+            market_id, max_price, depositor, referrer, permit, min_payout, deadline_height, deadline_time
+        } => deposit_native(deps,env,ReceiveMsg::Deposit{
+            market_id, max_price, depositor, referrer, permit, min_payout, deadline_height, deadline_time
+        }),
+        HandleMsg::Redeem{recipient,stake,note_ids} => redeem(deps,env,recipient,stake,note_ids),
         HandleMsg::RecoverLostToken{token} => recover_lost_token(deps,env,token),
 
+        // Front-end referrals
+        HandleMsg::SetReferrer{address, share} => set_referrer(deps,env,address,share),
+        HandleMsg::ClaimReferralRewards{..} => claim_referral_rewards(deps,env),
+
+        // Bond notes
+        HandleMsg::TransferBond{note_id, recipient, ..} => transfer_bond(deps,env,note_id,recipient),
+        HandleMsg::TransferBondFrom{owner, note_id, recipient, ..} =>
+            transfer_bond_from(deps,env,owner,note_id,recipient),
+        HandleMsg::ApproveBond{spender, note_id, expiration, ..} =>
+            approve_bond(deps,env,spender,note_id,expiration),
+        HandleMsg::RevokeBondApproval{note_id, ..} => revoke_bond_approval(deps,env,note_id),
+        HandleMsg::SetBenefactor{benefactor, accepted, ..} => set_benefactor(deps,env,benefactor,accepted),
+
         // Other
         HandleMsg::ChangeAdmin { address, .. } => change_admin(deps, env, address),
+        HandleMsg::SetContractStatus { level, .. } => set_contract_status(deps, env, level),
+        HandleMsg::SetPauseGuardian { guardian, .. } => set_pause_guardian(deps, env, guardian),
         HandleMsg::RevokePermit { permit_name, .. } => revoke_permit(deps, env, permit_name),
 
         //ViewingKeys
@@ -148,15 +215,39 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
 pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
     match msg {
         QueryMsg::ContractInfo {} => query_contract_info(&deps.storage),
-        QueryMsg::MaxPayout{} => query_max_payout(deps),
-        QueryMsg::PayoutFor{block_height, value} => query_payout_for(deps,block_height, value.u128()),
-        QueryMsg::BondPrice{block_height} => query_bond_price(deps,block_height),
-        QueryMsg::BondPriceInUsd{block_height} => query_bond_price_in_usd(deps,block_height),
-        QueryMsg::DebtRatio{block_height} => query_debt_ratio(deps,block_height),
-        QueryMsg::StandardizedDebtRatio{block_height} => query_standardized_debt_ratio(deps,block_height),
-        QueryMsg::CurrentDebt{block_height} => query_current_debt(deps,block_height),
-        QueryMsg::DebtDecay{block_height} => query_debt_decay(deps,block_height),
-        QueryMsg::BondTerms{} => query_bond_terms(deps),
+        QueryMsg::ContractStatus {} => query_contract_status(&deps.storage),
+        QueryMsg::Market{market_id, block_height, block_time} => query_market(deps,market_id,block_height,block_time),
+        QueryMsg::QuoteDeposit{market_id, amount, block_height, block_time} =>
+            query_quote_deposit(deps,market_id,amount.u128(),block_height,block_time),
+        QueryMsg::PendingTerms{market_id, block_height} => query_pending_terms(deps,market_id,block_height),
+        QueryMsg::TermsHistory{market_id, page, page_size} =>
+            query_terms_history(deps,market_id,page.unwrap_or(0),page_size),
+        QueryMsg::Referrer{address} => query_referrer(deps,address),
+        QueryMsg::Markets{block_height, block_time} => query_markets(deps,block_height,block_time),
+        QueryMsg::MaxPayout{market_id, block_height, block_time} =>
+            query_max_payout(deps,market_id,block_height,block_time),
+        QueryMsg::RemainingCapacity{market_id, address, block_height, block_time} =>
+            query_remaining_capacity(deps,market_id,address,block_height,block_time),
+        QueryMsg::PayoutFor{market_id, block_height, block_time, value} =>
+            query_payout_for(deps,market_id,block_height,block_time, value.u128()),
+        QueryMsg::BondPrice{market_id, block_height, block_time} =>
+            query_bond_price(deps,market_id,block_height,block_time),
+        QueryMsg::BondPriceInUsd{market_id, block_height, block_time} =>
+            query_bond_price_in_usd(deps,market_id,block_height,block_time),
+        QueryMsg::Twap{market_id, block_height, block_time} =>
+            query_twap(deps,market_id,block_height,block_time),
+        QueryMsg::Controller{market_id, block_height, block_time} =>
+            query_controller(deps,market_id,block_height,block_time),
+        QueryMsg::DebtRatio{market_id, block_height, block_time} =>
+            query_debt_ratio(deps,market_id,block_height,block_time),
+        QueryMsg::StandardizedDebtRatio{market_id, block_height, block_time} =>
+            query_standardized_debt_ratio(deps,market_id,block_height,block_time),
+        QueryMsg::CurrentDebt{market_id, block_height, block_time} =>
+            query_current_debt(deps,market_id,block_height,block_time),
+        QueryMsg::DebtDecay{market_id, block_height, block_time} =>
+            query_debt_decay(deps,market_id,block_height,block_time),
+        QueryMsg::BondTerms{market_id, block_height, block_time} =>
+            query_bond_terms(deps,market_id,block_height,block_time),
 
         QueryMsg::TokenInfo {} => query_token_info(deps),
 
@@ -165,6 +256,16 @@ pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryM
     }
 }
 
+/// Clock of a market or note at the queried block, time based ones need the block time
+fn query_clock(time_based: bool, block_height: u64, block_time: Option<u64>) -> StdResult<u64> {
+    if !time_based {
+        return Ok(block_height);
+    }
+    block_time.ok_or_else(||{
+        StdError::generic_err("This bond vests in seconds, the query needs a block_time")
+    })
+}
+
 fn pad_response(response: StdResult<HandleResponse>) -> StdResult<HandleResponse> {
     response.map(|mut response| {
         response.data = response.data.map(|mut data| {
@@ -189,25 +290,119 @@ fn query_token_info<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+pub fn add_market<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    principle: Principle,
+    bond_calculator: Option<Contract>,
+    inverse: bool,
+) -> StdResult<HandleResponse> {
+    check_if_admin(&Config::from_storage(&mut deps.storage),&env.message.sender)?;
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    if bond_calculator.is_some() && principle.pair.is_none() {
+        return Err(StdError::generic_err("A market priced by a bond calculator needs a pair"));
+    }
+    if bond_calculator.is_some() && inverse {
+        return Err(StdError::generic_err("Inverse markets pay a reserve token, they can't use a bond calculator"));
+    }
+    // Inverse markets receive OHM instead of their principle
+    let received_token = if inverse { config.constants()?.ohm } else { principle.token.clone() };
+
+    let principle_decimals = snip20::token_info_query(
+        &deps.querier,
+        RESPONSE_BLOCK_SIZE,
+        principle.token.code_hash.clone(),
+        principle.token.address.clone(),
+    )?.decimals;
+
+    let mut config = Config::from_storage(&mut deps.storage);
+    let market_id = config.market_count();
+    config.set_market(&Market {
+        id: market_id,
+        principle: principle.clone(),
+        principle_decimals,
+        bond_calculator,
+        terms: None,
+        pending_terms: vec![],
+        adjustment: None,
+        controller: None,
+        capacity: None,
+        sold: Uint128(0),
+        total_debt: Uint128(0),
+        last_decay: env.block.height,
+        closed: false,
+        time_based: false,
+        limits: None,
+        window_usage: PurchaseUsage::default(),
+        twap: None,
+        inverse,
+        allowlist: None,
+    })?;
+    config.set_market_count(market_id + 1);
+
+    let messages = vec![
+        snip20::register_receive_msg(
+            env.contract_code_hash.clone(),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            received_token.code_hash,
+            received_token.address,
+        )?
+    ];
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::AddMarket{ status: Success, market_id })?),
+    })
+}
+
+pub fn close_market<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    let mut market = config.market(market_id)?;
+    market.closed = true;
+    config.set_market(&market)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CloseMarket{ status: Success })?),
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn initialize_bond_terms<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    control_variable: Uint128, 
+    market_id: u64,
+    control_variable: Uint128,
     vesting_term: u64,
     minimum_price: Uint128,
     maximum_price: Uint128,
     max_payout: Uint128,
     fee: Uint128,
-    max_debt: Uint128, 
-    initial_debt: Uint128
+    max_debt: Uint128,
+    initial_debt: Uint128,
+    capacity: Option<Uint128>,
+    time_based: Option<bool>,
 ) -> StdResult<HandleResponse> {
     let mut config = Config::from_storage(&mut deps.storage);
     check_if_admin(&config,&env.message.sender)?;
-    let mut consts = config.constants()?;
-    if consts.terms != None {
+    let mut market = config.market(market_id)?;
+    if market.terms != None {
         return Err(StdError::generic_err("Bonds must be initialized from 0"));
     }
-    consts.terms = Some(Terms{
+    market.time_based = time_based.unwrap_or_default();
+    validate_term(&market, &BondParameter::Vesting, vesting_term.into())?;
+    validate_term(&market, &BondParameter::Payout, max_payout.u128())?;
+    validate_term(&market, &BondParameter::Fee, fee.u128())?;
+    validate_term(&market, &BondParameter::Debt, max_debt.u128())?;
+    market.terms = Some(Terms{
         control_variable,
         vesting_term,
         minimum_price,
@@ -216,65 +411,173 @@ pub fn initialize_bond_terms<S: Storage, A: Api, Q: Querier>(
         fee,
         max_debt,
     });
-    config.set_constants(&consts)?;
+    market.capacity = capacity;
+    market.total_debt = initial_debt;
+    market.last_decay = market.clock(env.block.height, env.block.time);
+    config.set_market(&market)?;
 
-    config.set_total_debt(initial_debt.u128());
-    config.set_last_decay(env.block.height);
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
         data: Some(to_binary(&HandleAnswer::InitializeBondTerms{ status: Success })?),
     })
 }
-  
-   
-pub fn set_bond_terms<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
-    parameter: BondParameter,
+
+
+/// Checks a new value of the bond terms is in the range allowed for its parameter
+fn validate_term(
+    market: &Market,
+    parameter: &BondParameter,
     input: u128,
-) -> StdResult<HandleResponse> {
-    let mut config = Config::from_storage(&mut deps.storage);
-    check_if_admin(&config,&env.message.sender)?;
-    let mut consts = config.constants()?;
-    let mut terms = consts.terms.unwrap();
+) -> StdResult<()> {
     match parameter{
         BondParameter::Vesting => {
-            if input < 21600 {
+            // 36 hours, in blocks of about 6 seconds or in seconds. Inverse bonds can pay instantly
+            let minimum_vesting = if market.inverse { 0 } else if market.time_based { 129_600 } else { 21_600 };
+            if input < minimum_vesting {
                 return Err(StdError::generic_err("Vesting must be longer than 36 hours" ));
             }
-            terms.vesting_term = input.try_into().unwrap();
+            let maximum_vesting = if market.time_based { 5_184_000 } else { 864_000 };
+            if input > maximum_vesting {
+                return Err(StdError::generic_err("Vesting must be shorter than 60 days" ));
+            }
         },
         BondParameter::Payout => {
+            if input == 0 {
+                return Err(StdError::generic_err("max payout can't be zero" ));
+            }
             if input > 1000 {
                 return Err(StdError::generic_err("max payout should not be higher then 1% of the OHM total supply" ));
             }
-            terms.max_payout = Uint128(input);
         },
         BondParameter::Fee => {
             if input > 10000 {
                 return Err(StdError::generic_err("Fee should not be higher than 100%" ));
             }
-            terms.fee = Uint128(input);
         },
         BondParameter::Debt => {
-            terms.max_debt = Uint128(input);
-            
+            if input == 0 {
+                return Err(StdError::generic_err("max debt can't be zero" ));
+            }
         },
     }
-    consts.terms = Some(terms);
-    config.set_constants(&consts)?;
+    Ok(())
+}
+
+/// Applies the term changes whose activation height is reached, and returns them
+fn apply_pending_terms(
+    market: &mut Market,
+    block_height: u64,
+) -> StdResult<Vec<TermChange>> {
+    if market.pending_terms.iter().all(|change| change.activation > block_height) {
+        return Ok(vec![]);
+    }
+    let (due, pending): (Vec<TermChange>, Vec<TermChange>) = market.pending_terms
+        .drain(..)
+        .partition(|change| change.activation <= block_height);
+    market.pending_terms = pending;
+
+    let mut terms = market.terms()?.clone();
+    for change in &due {
+        let input = change.input.u128();
+        match change.parameter{
+            BondParameter::Vesting => terms.vesting_term = input.try_into().map_err(|_| {
+                StdError::generic_err("The vesting term is above the u64 capacity")
+            })?,
+            BondParameter::Payout => terms.max_payout = Uint128(input),
+            BondParameter::Fee => terms.fee = Uint128(input),
+            BondParameter::Debt => terms.max_debt = Uint128(input),
+        }
+    }
+    market.terms = Some(terms);
+    Ok(due)
+}
+
+/// Changes of the bond terms are queued for `terms_timelock` blocks,
+/// so that bond buyers see them before they apply
+pub fn set_bond_terms<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    parameter: BondParameter,
+    input: u128,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    let mut market = config.market(market_id)?;
+    market.terms()?;
+    validate_term(&market, &parameter, input)?;
+
+    // a new change of the same parameter replaces the pending one
+    let activation = env.block.height.checked_add(config.constants()?.terms_timelock)
+        .ok_or_else(|| StdError::generic_err("The terms timelock would end after the last block"))?;
+    market.pending_terms.retain(|change| change.parameter != parameter);
+    market.pending_terms.push(TermChange{
+        parameter,
+        input: Uint128(input),
+        activation,
+    });
+    config.set_market(&market)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetBondTerms{ status: Success, activation })?),
+    })
+}
+
+pub fn apply_bond_terms<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    let mut market = config.market(market_id)?;
+    let applied_terms = apply_pending_terms(&mut market, env.block.height)?;
+    if applied_terms.is_empty() {
+        return Err(StdError::generic_err("No bond terms change to apply"));
+    }
+    config.set_market(&market)?;
+    for change in applied_terms {
+        store_term_change(&mut deps.storage, market_id, change, &env.block)?;
+    }
 
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::SetBondTerms{ status: Success })?),
+        data: Some(to_binary(&HandleAnswer::ApplyBondTerms{ status: Success })?),
+    })
+}
+
+fn query_pending_terms<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    block_height: u64,
+) -> QueryResult {
+    let mut market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    apply_pending_terms(&mut market, block_height)?;
+    to_binary(&QueryAnswer::PendingTerms {
+        changes: market.pending_terms,
+    })
+}
+
+fn query_terms_history<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    page: u32,
+    page_size: u32,
+) -> QueryResult {
+    let (changes, total) = get_term_changes(&deps.storage, market_id, page, page_size)?;
+    to_binary(&QueryAnswer::TermsHistory {
+        changes,
+        total: Some(total),
     })
 }
 
 pub fn set_adjustment<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    market_id: u64,
     addition: bool,
     increment: u128,
     target: u128,
@@ -282,18 +585,19 @@ pub fn set_adjustment<S: Storage, A: Api, Q: Querier>(
 ) -> StdResult<HandleResponse> {
     let mut config = Config::from_storage(&mut deps.storage);
     check_if_admin(&config,&env.message.sender)?;
-    let mut consts = config.constants()?;
-    if increment > consts.terms.clone().unwrap().control_variable.u128()*25/1000{
+    let mut market = config.market(market_id)?;
+    if increment > market.terms()?.control_variable.u128()*25/1000{
         return Err(StdError::generic_err("Increment too large"));
     }
-    consts.adjustment = Some(Adjust{
+    market.adjustment = Some(Adjust{
         add: addition,
         rate: Uint128(increment),
         target: Uint128(target),
         buffer: buffer,
-        last_block: env.block.height,
+        last_block: market.clock(env.block.height, env.block.time),
     });
-    config.set_constants(&consts)?;
+    market.controller = None;
+    config.set_market(&market)?;
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
@@ -301,208 +605,991 @@ pub fn set_adjustment<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-pub fn set_staking<S: Storage, A: Api, Q: Querier>(
+#[allow(clippy::too_many_arguments)]
+pub fn set_controller<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    staking: Contract,
+    market_id: u64,
+    target_debt_ratio: Option<Uint128>,
+    target_sold: Option<Uint128>,
+    step: Uint128,
+    min_control_variable: Uint128,
+    max_control_variable: Uint128,
+    buffer: u64,
 ) -> StdResult<HandleResponse> {
     let mut config = Config::from_storage(&mut deps.storage);
     check_if_admin(&config,&env.message.sender)?;
-    let mut consts = config.constants()?;
-    consts.staking = Some(staking);
+    let mut market = config.market(market_id)?;
+    market.terms()?;
+    if target_debt_ratio.is_none() && target_sold.is_none() {
+        return Err(StdError::generic_err("The controller needs a target debt ratio or a target of payout sold"));
+    }
+    if target_debt_ratio == Some(Uint128(0)) || target_sold == Some(Uint128(0)) {
+        return Err(StdError::generic_err("The controller targets can't be zero"));
+    }
+    if step.u128() >= 10_000 {
+        return Err(StdError::generic_err("The controller step must be less than 100%"));
+    }
+    if min_control_variable > max_control_variable {
+        return Err(StdError::generic_err("The minimum control variable is above the maximum"));
+    }
+    if buffer == 0 {
+        return Err(StdError::generic_err("The controller buffer can't be empty"));
+    }
+    market.controller = Some(Controller{
+        target_debt_ratio,
+        target_sold,
+        step,
+        min_control_variable,
+        max_control_variable,
+        buffer,
+        last_adjustment: market.clock(env.block.height, env.block.time),
+        sold_at_last_adjustment: market.sold,
+    });
+    market.adjustment = None;
+    config.set_market(&market)?;
 
-    config.set_constants(&consts)?;
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::SetStaking{ status: Success })?),
+        data: Some(to_binary(&HandleAnswer::SetController{ status: Success })?),
     })
 }
 
-/* register receive after receiving principle from someone */
-/* amount is the amount of principle received */
-/// We query its value in terms of OHM (value)
-/// Then we see what the payout will be 
-// Payout = (value in terms of OHM)/(bond_price in terms of OHM)*100
-
-pub fn deposit<S: Storage, A: Api, Q: Querier>(
+pub fn remove_controller<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    token: HumanAddr,
-    amount: u128,
-    max_price: u128,
-    depositor: HumanAddr,
+    market_id: u64,
 ) -> StdResult<HandleResponse> {
-    decay_debt(deps,env.block.height)?;
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    let consts = config.constants()?;
-    let terms = consts.terms.ok_or_else(||{
-        StdError::generic_err("The bond terms were not initialized")
-    })?;
-    let block_height = env.block.height;
-    if token != consts.principle.token.address{
-        return Err(StdError::generic_err(format!("This bond is only for the token: {}",token)));
-    }
-    if config.total_debt() > terms.max_debt.u128(){
-        return Err(StdError::generic_err("Max capacity reached"));
-    }
-    let price_in_usd = bond_price_in_usd(deps,env.block.height)?;
-    let native_price = _bond_price(deps,&env)?;
-
-    if max_price < native_price{
-         return Err(StdError::generic_err("Slippage limit: more than max price"));
-    }
-
-    let value_of_query_msg = TreasuryQueryMsg::ValueOf{token: consts.principle.token.address.clone(), amount:Uint128(amount)};
-    let value_of_response: ValueOfResponse = value_of_query_msg.query(
-        &deps.querier,
-        consts.treasury.code_hash.clone(),
-        consts.treasury.address.clone(),
-    )?;
-    let value = value_of_response.value_of.value.u128();
-
-    let payout = payout_for(deps,env.block.height,value)?;
-
-    if payout < 10_000_000{
-         return Err(StdError::generic_err("Bond too small"));
-    }
-
-    if payout > max_payout(deps)?{
-         return Err(StdError::generic_err("Bond too large"));
-    }
-
-    // profits are calculated
-    let fee = payout.checked_mul( terms.fee.u128() ).ok_or_else(||{
-        StdError::generic_err("The fee is too high, sorry, check your privilege")
-    })?/10_000;
-
-    let profit = value.checked_sub( payout ).ok_or_else(||{
-        StdError::generic_err("No profit, too much payout")
-    })?.checked_sub(fee).ok_or_else(||{
-        StdError::generic_err("No profit, too much fee")
-    })?;
-
-    let mut messages = vec![];
-    /*
-        principle is already transferred, so we deposit it in the treasury
-        We return payout OHM to the Dao
-    */
-
-    messages.push(
-        snip20::send_msg(
-            consts.treasury.address,
-            Uint128(amount),
-            Some(to_binary(
-                &TreasuryHandleMsg::Deposit{
-                    profit:Uint128(profit)
-                }
-            )?),
-            None,
-            RESPONSE_BLOCK_SIZE,
-            consts.principle.token.code_hash,
-            consts.principle.token.address
-        )?
-    );
-
-    if fee != 0  { // fee is transferred to dao 
-        messages.push(snip20::transfer_msg(
-            consts.dao,
-            Uint128(fee),
-            None,
-            RESPONSE_BLOCK_SIZE,
-            consts.ohm.code_hash,
-            consts.ohm.address
-        )?);
-    }
-    
-
     let mut config = Config::from_storage(&mut deps.storage);
-    // total debt is increased
-    config.set_total_debt(config.total_debt().checked_add(value).ok_or_else(||{
-        StdError::generic_err("Too much bond debt")
-    })?); 
-
-    let canon_depositor = deps.api.canonical_address(&depositor)?;
-    let mut bonds = BondInfo::from_storage(&mut deps.storage);
-    bonds.set_bond(&canon_depositor,Bond{
-        payout : Uint128(bonds.bond(&canon_depositor).payout.u128()
-        .checked_add(payout).ok_or_else(||{
-            StdError::generic_err("Too much bond debt")
-        })?), 
-        vesting: terms.vesting_term,
-        last_block: block_height,
-        price_paid: Uint128(price_in_usd)
-    })?; 
-        
-    adjust(deps,env)?; // control variable is adjusted
+    check_if_admin(&config,&env.message.sender)?;
+    let mut market = config.market(market_id)?;
+    market.controller = None;
+    config.set_market(&market)?;
 
     Ok(HandleResponse {
-        messages: messages,
+        messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::Deposit{ status: Success, payout: Uint128(payout) })?),
+        data: Some(to_binary(&HandleAnswer::RemoveController{ status: Success })?),
     })
 }
 
-pub fn redeem<S: Storage, A: Api, Q: Querier>(
+pub fn set_purchase_limits<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    recipient:  HumanAddr,
-    stake: bool,
+    market_id: u64,
+    limits: Option<PurchaseLimits>,
 ) -> StdResult<HandleResponse> {
-    let bonds = ReadonlyBondInfo::from_storage(& deps.storage);
-    let canon_recipient = deps.api.canonical_address(&recipient)?;
-    let info = bonds.bond(&canon_recipient);
-    let block_height = env.block.height;
-    let percent_vested = percent_vested_for(deps,block_height,recipient.clone())?;
-    let stake_or_send_return;
-    let payout;
-    let bond_to_set;
-    let mut messages = vec![];
-    if  percent_vested >= 10_000  { // if fully vested
-        payout = info.payout;
-        bond_to_set = Bond::default();
-        stake_or_send_return = stake_or_send(deps, recipient, stake, info.payout.u128() )?; // pay user everything due
-        messages.extend(stake_or_send_return.messages);
-    } else { // if unfinished
-        // calculate payout vested
-        payout = Uint128(info.payout.u128()
-            .checked_mul(percent_vested)
-            .ok_or_else(||{
-                StdError::generic_err("Multiplication through the roof")
-            })?
-            .checked_div(10_000)
-            .ok_or_else(||{
-                StdError::generic_err("Not possible to divide this thing")
-            })?
-        );
-        bond_to_set = Bond{
-            payout: (info.payout - payout)?,
-            vesting: info.vesting.checked_sub(
-                block_height.checked_sub(
-                    info.last_block
-                ).ok_or_else(||{
-                    StdError::generic_err("Block height before the bond last block, weird flex here")
-                })?
-            ).ok_or_else(||{
-                StdError::generic_err("No bond contracted")
-            })?, 
-            last_block: block_height,
-            price_paid: info.price_paid
-        };
-        stake_or_send_return = stake_or_send(deps,recipient, stake, payout.u128() )?;
-        messages.extend(stake_or_send_return.messages);
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    let mut market = config.market(market_id)?;
+    if let Some(limits) = &limits {
+        if limits.max_payout_per_window.is_some() && limits.window == 0 {
+            return Err(StdError::generic_err("The purchase window must be at least one block"));
+        }
     }
-    //Update user info
-    let mut bonds = BondInfo::from_storage(&mut deps.storage);
-    bonds.set_bond(&canon_recipient, bond_to_set)?; 
+    market.limits = limits;
+    market.window_usage = PurchaseUsage::default();
+    config.set_market(&market)?;
 
     Ok(HandleResponse {
-        messages: messages,
+        messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::Redeem{ status: Success, payout: payout})?),
+        data: Some(to_binary(&HandleAnswer::SetPurchaseLimits{ status: Success })?),
     })
 }
-pub fn stake_or_send<S: Storage, A: Api, Q: Querier>(
+
+pub fn set_allowlist<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    allowlist: Option<Allowlist>,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    let mut market = config.market(market_id)?;
+    market.allowlist = allowlist;
+    config.set_market(&market)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetAllowlist{ status: Success })?),
+    })
+}
+
+pub fn set_allowed_depositor<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    address: HumanAddr,
+    cap: Option<Uint128>,
+) -> StdResult<HandleResponse> {
+    let canon_address = deps.api.canonical_address(&address)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    config.market(market_id)?;
+
+    // what the counterparty already bought still counts in its new cap
+    let bought = config.allowed_depositor(market_id, &canon_address)
+        .map(|allowed| allowed.bought)
+        .unwrap_or(Uint128(0));
+    config.set_allowed_depositor(market_id, &canon_address, &AllowedDepositor {
+        allowed: true,
+        cap,
+        bought,
+    })?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetAllowedDepositor{ status: Success })?),
+    })
+}
+
+pub fn remove_allowed_depositor<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    address: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let canon_address = deps.api.canonical_address(&address)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    config.market(market_id)?;
+
+    let bought = config.allowed_depositor(market_id, &canon_address)
+        .map(|allowed| allowed.bought)
+        .unwrap_or(Uint128(0));
+    config.set_allowed_depositor(market_id, &canon_address, &AllowedDepositor {
+        allowed: false,
+        cap: None,
+        bought,
+    })?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RemoveAllowedDepositor{ status: Success })?),
+    })
+}
+
+pub fn set_twap<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    twap: Option<Twap>,
+) -> StdResult<HandleResponse> {
+    check_if_admin(&Config::from_storage(&mut deps.storage),&env.message.sender)?;
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let mut market = config.market(market_id)?;
+    let now = market.clock(env.block.height, env.block.time);
+
+    // The TWAP starts over from the current spot markdown
+    let mut observations = vec![];
+    if let Some(twap) = &twap {
+        if twap.window == 0 {
+            return Err(StdError::generic_err("The TWAP window can't be empty"));
+        }
+        let spot = spot_markdown(deps,&market)?;
+        record_observation(&mut observations, twap.window, now, spot)?;
+    }
+    market.twap = twap;
+
+    let mut config = Config::from_storage(&mut deps.storage);
+    config.set_market(&market)?;
+    config.set_observations(market_id, &observations)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetTwap{ status: Success })?),
+    })
+}
+
+/// Anyone can record the markdown of the pair, once per `observation_spacing` of the TWAP window.
+/// A spot moved within the block can't drag the TWAP further than the deviation allowed to deposits
+pub fn update_twap<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let market = config.market(market_id)?;
+    let twap = market.twap.clone().ok_or_else(||{
+        StdError::generic_err("This market is not priced with a TWAP")
+    })?;
+    let now = market.clock(env.block.height, env.block.time);
+    let mut observations = config.observations(market_id);
+    let mut spot = spot_markdown(deps,&market)?;
+    if let Ok(average) = twap_markdown(&observations, twap.window, now) {
+        let max_move = average.saturating_mul(twap.max_deviation.u128()) / 10_000;
+        spot = spot.max(average.saturating_sub(max_move)).min(average.saturating_add(max_move));
+    }
+    record_observation(&mut observations, twap.window, now, spot)?;
+
+    let mut config = Config::from_storage(&mut deps.storage);
+    config.set_observations(market_id, &observations)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::UpdateTwap{ status: Success })?),
+    })
+}
+
+pub fn set_staking<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    staking: Contract,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    let mut consts = config.constants()?;
+    consts.staking = Some(staking);
+
+    config.set_constants(&consts)?;
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetStaking{ status: Success })?),
+    })
+}
+
+pub fn set_native_wrapper<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    config.set_native_wrapper(&token)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetNativeWrapper{ status: Success })?),
+    })
+}
+
+/* register receive after receiving principle from someone */
+/* amount is the amount of principle received */
+/// We query its value in terms of OHM (value)
+/// Then we see what the payout will be
+// Payout = (value in terms of OHM)/(bond_price in terms of OHM)*100
+
+#[allow(clippy::too_many_arguments)]
+pub fn deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    token: HumanAddr,
+    amount: u128,
+    max_price: u128,
+    min_payout: u128,
+    depositor: HumanAddr,
+    referrer: Option<HumanAddr>,
+    permit: Option<Permit>,
+) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let consts = config.constants()?;
+    let mut market = config.market(market_id)?;
+    let now = market.clock(env.block.height, env.block.time);
+    if market.closed {
+        return Err(StdError::generic_err(format!("The bond market {} is closed", market_id)));
+    }
+    if token != market.principle.token.address{
+        return Err(StdError::generic_err(format!("This bond is only for the token: {}",market.principle.token.address)));
+    }
+    decay_debt(&mut market, now)?;
+    let applied_terms = apply_pending_terms(&mut market, env.block.height)?;
+    // the buffers elapsed without sales move the control variable before the deposit is priced
+    control(deps, &mut market, now)?;
+
+    // LP markets priced on a TWAP refuse deposits while the pair is too far from its average.
+    // The spot isn't recorded here, it could have been moved in the block of the deposit
+    if let Some(twap) = &market.twap {
+        let observations = config.observations(market_id);
+        check_markdown_deviation(spot_markdown(deps,&market)?, twap_markdown(&observations, twap.window, now)?, twap.max_deviation.u128())?;
+    }
+
+    let terms = market.terms()?.clone();
+    if market.total_debt.u128() > terms.max_debt.u128(){
+        return Err(StdError::generic_err("Max capacity reached"));
+    }
+    let price_in_usd = bond_price_in_usd(deps,&market,now)?;
+    let native_price = _bond_price(deps,&mut market,now)?;
+
+    if max_price < native_price{
+         return Err(StdError::generic_err("Slippage limit: more than max price"));
+    }
+
+    let quote = quote_deposit(deps,&market,now,Some(env.block.time),amount)?;
+    let value = quote.value;
+    let payout = quote.payout;
+
+    if payout < min_payout{
+         return Err(StdError::generic_err("Slippage limit: less than min payout"));
+    }
+
+    if payout < 10_000_000{
+         return Err(StdError::generic_err("Bond too small"));
+    }
+
+    if payout > max_payout(deps,&market)?{
+         return Err(StdError::generic_err("Bond too large"));
+    }
+
+    if let Some(remaining_capacity) = market.remaining_capacity(){
+        if payout > remaining_capacity {
+            return Err(StdError::generic_err("Market capacity reached"));
+        }
+    }
+    let allowed = authorize_depositor(deps, &market, &depositor, permit, payout)?;
+
+    // profits are calculated
+    let fee = quote.fee;
+    let canon_referrer = referrer.map(|referrer| deps.api.canonical_address(&referrer)).transpose()?;
+    let referral = referral_fee(&config, canon_referrer.as_ref(), fee);
+
+    let profit = value.checked_sub( payout ).ok_or_else(||{
+        StdError::generic_err("No profit, too much payout")
+    })?.checked_sub(fee).ok_or_else(||{
+        StdError::generic_err("No profit, too much fee")
+    })?;
+
+    let mut messages = vec![];
+    /*
+        principle is already transferred, so we deposit it in the treasury
+        We return payout OHM to the Dao
+    */
+
+    messages.push(
+        snip20::send_msg(
+            consts.treasury.address,
+            Uint128(amount),
+            Some(to_binary(
+                &TreasuryHandleMsg::Deposit{
+                    profit:Uint128(profit)
+                }
+            )?),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            market.principle.token.code_hash.clone(),
+            market.principle.token.address.clone()
+        )?
+    );
+
+    if fee > referral  { // fee is transferred to dao, minus the referral share
+        messages.push(snip20::transfer_msg(
+            consts.dao,
+            Uint128(fee - referral),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            consts.ohm.code_hash,
+            consts.ohm.address
+        )?);
+    }
+
+    // total debt is increased
+    market.total_debt = Uint128(market.total_debt.u128().checked_add(value).ok_or_else(||{
+        StdError::generic_err("Too much bond debt")
+    })?);
+    market.sold = Uint128(market.sold.u128().checked_add(payout).ok_or_else(||{
+        StdError::generic_err("Too much payout sold")
+    })?);
+
+    adjust(&mut market, now)?; // control variable is adjusted
+
+    let canon_depositor = deps.api.canonical_address(&depositor)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    spend_purchase_limits(&mut config, &mut market, &canon_depositor, payout, now)?;
+    config.set_market(&market)?;
+    credit_referral(&mut config, canon_referrer.as_ref(), referral)?;
+    if let Some(allowed) = allowed {
+        config.set_allowed_depositor(market_id, &canon_depositor, &allowed)?;
+    }
+
+    // each deposit is its own note, so it doesn't delay the vesting of the previous ones
+    let note_id = config.bond_count() + 1;
+    config.set_bond_count(note_id);
+
+    let mut bonds = BondInfo::from_storage(&mut deps.storage);
+    bonds.add_bond(&canon_depositor,Bond{
+        id: note_id,
+        market_id,
+        payout : Uint128(payout),
+        vesting: terms.vesting_term,
+        last_block: now,
+        price_paid: Uint128(price_in_usd),
+        time_based: market.time_based,
+        payout_token: None,
+    })?;
+    store_bond_tx(&mut deps.storage, &canon_depositor, BondAction::Deposit {
+        market_id,
+        note_id,
+        token,
+        amount: Uint128(amount),
+        value: Uint128(value),
+        payout: Uint128(payout),
+        price: Uint128(native_price),
+        fee: Uint128(fee),
+    }, &env.block)?;
+    for change in applied_terms {
+        store_term_change(&mut deps.storage, market_id, change, &env.block)?;
+    }
+
+    Ok(HandleResponse {
+        messages: messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Deposit{ status: Success, payout: Uint128(payout), note_id })?),
+    })
+}
+
+/// Inverse bonds: the bond depository sends the OHM of the depositor to the treasury `Withdraw`,
+/// which burns it and sends back the reserves it is worth. The treasury checks the ReserveDepositor
+/// role of the account sending the OHM, the bond depository
+#[allow(clippy::too_many_arguments)]
+pub fn inverse_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    market_id: u64,
+    token: HumanAddr,
+    amount: u128,
+    max_price: u128,
+    min_payout: u128,
+    depositor: HumanAddr,
+    referrer: Option<HumanAddr>,
+    permit: Option<Permit>,
+) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let consts = config.constants()?;
+    let mut market = config.market(market_id)?;
+    let now = market.clock(env.block.height, env.block.time);
+    if market.closed {
+        return Err(StdError::generic_err(format!("The bond market {} is closed", market_id)));
+    }
+    if token != consts.ohm.address{
+        return Err(StdError::generic_err(format!("This inverse bond is only for the token: {}",consts.ohm.address)));
+    }
+    decay_debt(&mut market, now)?;
+    let applied_terms = apply_pending_terms(&mut market, env.block.height)?;
+    // the buffers elapsed without sales move the control variable before the deposit is priced
+    control(deps, &mut market, now)?;
+
+    let terms = market.terms()?.clone();
+    if market.total_debt.u128() > terms.max_debt.u128(){
+        return Err(StdError::generic_err("Max capacity reached"));
+    }
+    let price_in_usd = bond_price_in_usd(deps,&market,now)?;
+    let native_price = _bond_price(deps,&mut market,now)?;
+
+    if max_price < native_price{
+         return Err(StdError::generic_err("Slippage limit: more than max price"));
+    }
+
+    // value of the reserves paid, in OHM
+    let quote = quote_deposit(deps,&market,now,Some(env.block.time),amount)?;
+    let value = quote.value;
+    let reserve_amount = quote.payout;
+
+    if reserve_amount < min_payout{
+         return Err(StdError::generic_err("Slippage limit: less than min payout"));
+    }
+
+    if value < 10_000_000{
+         return Err(StdError::generic_err("Bond too small"));
+    }
+
+    if value > max_payout(deps,&market)?{
+         return Err(StdError::generic_err("Bond too large"));
+    }
+
+    if let Some(remaining_capacity) = market.remaining_capacity(){
+        if value > remaining_capacity {
+            return Err(StdError::generic_err("Market capacity reached"));
+        }
+    }
+    let allowed = authorize_depositor(deps, &market, &depositor, permit, value)?;
+
+    // the fee is taken on the OHM sent, everything else is burnt
+    let fee = quote.fee;
+    let canon_referrer = referrer.map(|referrer| deps.api.canonical_address(&referrer)).transpose()?;
+    let referral = referral_fee(&config, canon_referrer.as_ref(), fee);
+    let burnt = amount - fee;
+    if value > burnt {
+        return Err(StdError::generic_err("Inverse bonds can't pay more than the backing of the OHM sold"));
+    }
+
+    let total_reserves_response: TotalReservesResponse = TreasuryQueryMsg::TotalReserves{}.query(
+        &deps.querier,
+        consts.treasury.code_hash.clone(),
+        consts.treasury.address.clone(),
+    )?;
+    if value > total_reserves_response.total_reserves.excess_reserves.u128() {
+        return Err(StdError::generic_err("Not enough excess reserves in the treasury"));
+    }
+
+    // The treasury only accepts OHM matching its value of the reserves withdrawn, which is the quoted value
+    let mut messages = vec![
+        snip20::send_msg(
+            consts.treasury.address.clone(),
+            Uint128(value),
+            Some(to_binary(
+                &TreasuryHandleMsg::Withdraw{
+                    token: market.principle.token.address.clone(),
+                    amount: Uint128(reserve_amount),
+                }
+            )?),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            consts.ohm.code_hash.clone(),
+            consts.ohm.address.clone()
+        )?
+    ];
+
+    if burnt > value { // what the treasury doesn't burn raises the backing of OHM
+        messages.push(snip20::burn_msg(
+            Uint128(burnt - value),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            consts.ohm.code_hash.clone(),
+            consts.ohm.address.clone()
+        )?);
+    }
+
+    if fee > referral  { // fee is transferred to dao, minus the referral share
+        messages.push(snip20::transfer_msg(
+            consts.dao,
+            Uint128(fee - referral),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            consts.ohm.code_hash,
+            consts.ohm.address
+        )?);
+    }
+
+    // total debt is increased
+    market.total_debt = Uint128(market.total_debt.u128().checked_add(value).ok_or_else(||{
+        StdError::generic_err("Too much bond debt")
+    })?);
+    market.sold = Uint128(market.sold.u128().checked_add(value).ok_or_else(||{
+        StdError::generic_err("Too much payout sold")
+    })?);
+
+    adjust(&mut market, now)?; // control variable is adjusted
+
+    let canon_depositor = deps.api.canonical_address(&depositor)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    spend_purchase_limits(&mut config, &mut market, &canon_depositor, value, now)?;
+    config.set_market(&market)?;
+    credit_referral(&mut config, canon_referrer.as_ref(), referral)?;
+    if let Some(allowed) = allowed {
+        config.set_allowed_depositor(market_id, &canon_depositor, &allowed)?;
+    }
+
+    // The reserves are withdrawn before this message is executed
+    let mut note_id = 0;
+    if terms.vesting_term == 0 {
+        messages.push(snip20::transfer_msg(
+            depositor,
+            Uint128(reserve_amount),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            market.principle.token.code_hash.clone(),
+            market.principle.token.address.clone()
+        )?);
+    } else {
+        note_id = config.bond_count() + 1;
+        config.set_bond_count(note_id);
+
+        let mut bonds = BondInfo::from_storage(&mut deps.storage);
+        bonds.add_bond(&canon_depositor,Bond{
+            id: note_id,
+            market_id,
+            payout : Uint128(reserve_amount),
+            vesting: terms.vesting_term,
+            last_block: now,
+            price_paid: Uint128(price_in_usd),
+            time_based: market.time_based,
+            payout_token: Some(market.principle.token.clone()),
+        })?;
+    }
+    store_bond_tx(&mut deps.storage, &canon_depositor, BondAction::Deposit {
+        market_id,
+        note_id,
+        token,
+        amount: Uint128(amount),
+        value: Uint128(value),
+        payout: Uint128(reserve_amount),
+        price: Uint128(native_price),
+        fee: Uint128(fee),
+    }, &env.block)?;
+    for change in applied_terms {
+        store_term_change(&mut deps.storage, market_id, change, &env.block)?;
+    }
+
+    Ok(HandleResponse {
+        messages: messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Deposit{ status: Success, payout: Uint128(reserve_amount), note_id })?),
+    })
+}
+
+struct DepositQuote {
+    price: u128,
+    value: u128,
+    payout: u128,
+    fee: u128,
+}
+
+/// Pricing of a deposit of `amount`, the same for the deposits and the QuoteDeposit query.
+/// Inverse bonds pay reserves worth `value` for the OHM deposited.
+/// The treasury values the principle and the reserves with the oracle prices that are fresh at `block_time`
+fn quote_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &Market,
+    now: u64,
+    block_time: Option<u64>,
+    amount: u128,
+) -> StdResult<DepositQuote> {
+    let terms = market.terms()?;
+    let price = bond_price(deps,market,now)?;
+    let block_time = block_time.ok_or_else(||{
+        StdError::generic_err("The treasury values the deposit at a block time, the query needs a block_time")
+    })?;
+
+    if market.inverse {
+        // Reserves the treasury values at the OHM bought back, their value is then what the treasury burns
+        let bought_back = payout_for(deps,market,now,amount)?;
+        let unit = 10_u128.pow(market.principle_decimals.into());
+        let unit_value = treasury_value_of(deps,market,unit,block_time)?;
+        if unit_value == 0 {
+            return Err(StdError::generic_err("The treasury doesn't value these reserves"));
+        }
+        let payout = bought_back.checked_mul(unit).ok_or_else(||{
+            StdError::generic_err("Too much reserves")
+        })? / unit_value;
+        let value = treasury_value_of(deps,market,payout,block_time)?;
+        let fee = amount.checked_mul( terms.fee.u128() ).ok_or_else(||{
+            StdError::generic_err("The fee is too high, sorry, check your privilege")
+        })?/10_000;
+        return Ok(DepositQuote { price, value, payout, fee });
+    }
+
+    let value = treasury_value_of(deps,market,amount,block_time)?;
+
+    let payout = payout_for(deps,market,now,value)?;
+    let fee = payout.checked_mul( terms.fee.u128() ).ok_or_else(||{
+        StdError::generic_err("The fee is too high, sorry, check your privilege")
+    })?/10_000;
+    Ok(DepositQuote { price, value, payout, fee })
+}
+
+/// OHM value of `amount` of the market principle, as the treasury values its deposits and withdrawals
+fn treasury_value_of<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &Market,
+    amount: u128,
+    block_time: u64,
+) -> StdResult<u128> {
+    let treasury = ReadonlyConfig::from_storage(&deps.storage).constants()?.treasury;
+    let value_of_query_msg = TreasuryQueryMsg::ValueOf{
+        token: market.principle.token.address.clone(),
+        amount: Uint128(amount),
+        block_time,
+    };
+    let value_of_response: ValueOfResponse = value_of_query_msg.query(
+        &deps.querier,
+        treasury.code_hash,
+        treasury.address,
+    )?;
+    Ok(value_of_response.value_of.value.u128())
+}
+
+/// Copy of the market as a deposit at `block_height` would price it, with the term changes
+/// that are due and the controller buffers elapsed. Returns it with the market clock
+fn query_market_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    mut market: Market,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> StdResult<(Market, u64)> {
+    let now = query_clock(market.time_based, block_height, block_time)?;
+    apply_pending_terms(&mut market, block_height)?;
+    control(deps, &mut market, now)?;
+    Ok((market, now))
+}
+
+fn query_quote_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    amount: u128,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
+    let quote = quote_deposit(deps,&market,now,block_time,amount)?;
+    to_binary(&QueryAnswer::QuoteDeposit {
+        price: Uint128(quote.price),
+        value: Uint128(quote.value),
+        payout: Uint128(quote.payout),
+        fee: Uint128(quote.fee),
+    })
+}
+
+/// Name of the permit an allowlist issuer signs to let `depositor` bond on a private market
+pub fn allowlist_permit_name(market_id: u64, depositor: &HumanAddr) -> String {
+    format!("bond_market_{}_{}", market_id, depositor)
+}
+
+/// Private markets only sell to their allowlist, or to the depositors holding a permit
+/// from the market issuer. Returns the allowlist entry of the depositor after the purchase
+fn authorize_depositor<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &Market,
+    depositor: &HumanAddr,
+    permit: Option<Permit>,
+    payout: u128,
+) -> StdResult<Option<AllowedDepositor>> {
+    let allowlist = if let Some(allowlist) = &market.allowlist {
+        allowlist
+    } else {
+        return Ok(None);
+    };
+    let not_allowed = ||{
+        StdError::generic_err(format!("The bond market {} is private and {} is not on its allowlist", market.id, depositor))
+    };
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let canon_depositor = deps.api.canonical_address(depositor)?;
+
+    let mut allowed = match config.allowed_depositor(market.id, &canon_depositor) {
+        Some(allowed) => allowed,
+        None => {
+            let issuer = allowlist.issuer.as_ref().ok_or_else(not_allowed)?;
+            let permit = permit.ok_or_else(not_allowed)?;
+            let signer = validate(deps, PREFIX_REVOKED_PERMITS, &permit, config.constants()?.contract_address)?;
+            if &signer != issuer || permit.params.permit_name != allowlist_permit_name(market.id, depositor) {
+                return Err(not_allowed());
+            }
+            AllowedDepositor {
+                allowed: true,
+                cap: allowlist.permit_cap,
+                bought: Uint128(0),
+            }
+        }
+    };
+    if !allowed.allowed {
+        return Err(not_allowed());
+    }
+
+    let bought = allowed.bought.u128().checked_add(payout).ok_or_else(||{
+        StdError::generic_err("Too much payout bought on this private market")
+    })?;
+    if let Some(cap) = allowed.cap {
+        if bought > cap.u128() {
+            return Err(StdError::generic_err("Maximum payout for this address on this private market reached"));
+        }
+    }
+    allowed.bought = Uint128(bought);
+    Ok(Some(allowed))
+}
+
+/// Share of the fee owed to the front-end that routed the bond, nothing for unknown front-ends
+fn referral_fee<S: ReadonlyStorage>(
+    config: &ReadonlyConfig<S>,
+    referrer: Option<&CanonicalAddr>,
+    fee: u128,
+) -> u128 {
+    referrer
+        .and_then(|referrer| config.referral_share(referrer))
+        .map(|share| fee * share.u128() / 10_000)
+        .unwrap_or(0)
+}
+
+/// The referral share stays in the contract until the front-end claims it
+fn credit_referral<S: Storage>(
+    config: &mut Config<S>,
+    referrer: Option<&CanonicalAddr>,
+    referral: u128,
+) -> StdResult<()> {
+    if let Some(referrer) = referrer {
+        if referral != 0 {
+            let rewards = config.referral_rewards(referrer).checked_add(referral).ok_or_else(||{
+                StdError::generic_err("Too much referral rewards")
+            })?;
+            config.set_referral_rewards(referrer, rewards)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the purchase limits of the market and records the payout bought by the depositor
+fn spend_purchase_limits<S: Storage>(
+    config: &mut Config<S>,
+    market: &mut Market,
+    depositor: &CanonicalAddr,
+    payout: u128,
+    now: u64,
+) -> StdResult<()> {
+    let limits = if let Some(limits) = market.limits.clone(){
+        limits
+    }else{
+        return Ok(());
+    };
+    if let Some(max_payout_per_window) = limits.max_payout_per_window {
+        let start = window_start(now, limits.window);
+        let used = market.window_usage.used_in(start).checked_add(payout).ok_or_else(||{
+            StdError::generic_err("Too much payout in this block range")
+        })?;
+        if used > max_payout_per_window.u128() {
+            return Err(StdError::generic_err("Maximum payout for this block range reached, try again later"));
+        }
+        market.window_usage = PurchaseUsage{
+            used: Uint128(used),
+            window_start: start,
+        };
+    }
+    if let Some(max_payout_per_address) = limits.max_payout_per_address {
+        let start = window_start(now, market.terms()?.vesting_term);
+        let used = config.purchase_usage(market.id, depositor).used_in(start).checked_add(payout).ok_or_else(||{
+            StdError::generic_err("Too much payout for this address")
+        })?;
+        if used > max_payout_per_address.u128() {
+            return Err(StdError::generic_err("Maximum payout for this address reached, try again later"));
+        }
+        config.set_purchase_usage(market.id, depositor, &PurchaseUsage{
+            used: Uint128(used),
+            window_start: start,
+        })?;
+    }
+    Ok(())
+}
+
+pub fn redeem<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient:  HumanAddr,
+    stake: bool,
+    note_ids: Option<Vec<u64>>,
+) -> StdResult<HandleResponse> {
+    let canon_recipient = deps.api.canonical_address(&recipient)?;
+    let mut bonds = ReadonlyBondInfo::from_storage(& deps.storage).bonds(&canon_recipient);
+
+    if let Some(note_ids) = &note_ids {
+        for id in note_ids {
+            if !bonds.iter().any(|note| note.id == *id) {
+                return Err(StdError::generic_err(format!("No bond note with id {}", id)));
+            }
+        }
+    }
+    if bonds.is_empty() {
+        return Err(StdError::generic_err("No bond contracted"));
+    }
+
+    let mut payout: u128 = 0;
+    let mut reserve_payouts: Vec<(Contract, u128)> = vec![]; // inverse bonds
+    let mut vested_notes = vec![];
+    for info in bonds.iter_mut() {
+        if let Some(note_ids) = &note_ids {
+            if !note_ids.contains(&info.id) {
+                continue;
+            }
+        }
+        let now = info.clock(env.block.height, env.block.time);
+        let percent_vested = note_percent_vested(info, now)?;
+        let note_payout;
+        if  percent_vested >= 10_000  { // if fully vested
+            note_payout = info.payout;
+            vested_notes.push(info.id);
+        } else { // if unfinished
+            // calculate payout vested
+            note_payout = Uint128(info.payout.u128()
+                .checked_mul(percent_vested)
+                .ok_or_else(||{
+                    StdError::generic_err("Multiplication through the roof")
+                })?
+                .checked_div(10_000)
+                .ok_or_else(||{
+                    StdError::generic_err("Not possible to divide this thing")
+                })?
+            );
+            *info = Bond{
+                id: info.id,
+                market_id: info.market_id,
+                payout: (info.payout - note_payout)?,
+                vesting: info.vesting.checked_sub(
+                    now.checked_sub(
+                        info.last_block
+                    ).ok_or_else(||{
+                        StdError::generic_err("Block height before the bond last block, weird flex here")
+                    })?
+                ).ok_or_else(||{
+                    StdError::generic_err("No bond contracted")
+                })?, 
+                last_block: now,
+                price_paid: info.price_paid,
+                time_based: info.time_based,
+                payout_token: info.payout_token.clone(),
+            };
+        }
+        match &info.payout_token {
+            None => {
+                payout = payout.checked_add(note_payout.u128()).ok_or_else(||{
+                    StdError::generic_err("Too much bond payout")
+                })?;
+            }
+            Some(token) => match reserve_payouts.iter_mut().find(|(reserve, _)| reserve == token) {
+                Some((_, total)) => {
+                    *total = total.checked_add(note_payout.u128()).ok_or_else(||{
+                        StdError::generic_err("Too much bond payout")
+                    })?;
+                }
+                None => reserve_payouts.push((token.clone(), note_payout.u128())),
+            },
+        }
+    }
+    // Fully vested notes are dropped
+    bonds.retain(|note| !vested_notes.contains(&note.id));
+
+    let mut messages = vec![];
+    if payout != 0 || reserve_payouts.is_empty() {
+        let stake_or_send_return = stake_or_send(deps, recipient.clone(), stake, payout )?; // pay user everything due
+        messages = stake_or_send_return.messages;
+    }
+    let mut reserves = vec![];
+    for (token, amount) in reserve_payouts {
+        reserves.push(ReservePayout {
+            token: token.address.clone(),
+            amount: Uint128(amount),
+        });
+        messages.push(snip20::transfer_msg(
+            recipient.clone(),
+            Uint128(amount),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash,
+            token.address
+        )?);
+    }
+
+    //Update user info
+    let mut bond_info = BondInfo::from_storage(&mut deps.storage);
+    bond_info.set_bonds(&canon_recipient, bonds)?; 
+    store_bond_tx(&mut deps.storage, &canon_recipient, BondAction::Redeem {
+        payout: Uint128(payout),
+        stake,
+        reserves,
+    }, &env.block)?;
+
+    Ok(HandleResponse {
+        messages: messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Redeem{ status: Success, payout: Uint128(payout)})?),
+    })
+}
+pub fn stake_or_send<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     recipient:  HumanAddr,
     stake: bool,
@@ -543,16 +1630,15 @@ pub fn stake_or_send<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-pub fn adjust<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
+pub fn adjust(
+    market: &mut Market,
+    now: u64,
 ) -> StdResult<()> {
-    let mut consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
-    if let Some(mut adjustment) = consts.adjustment{
-        let mut terms = consts.terms.unwrap();
+    if let Some(mut adjustment) = market.adjustment.clone(){
+        let mut terms = market.terms()?.clone();
         let block_can_adjust = adjustment.last_block + adjustment.buffer;
 
-        if adjustment.rate.u128() != 0 && env.block.height >= block_can_adjust{
+        if adjustment.rate.u128() != 0 && now >= block_can_adjust{
             if  adjustment.add {
                 terms.control_variable = terms.control_variable + adjustment.rate;
                 if terms.control_variable >= adjustment.target {
@@ -566,37 +1652,120 @@ pub fn adjust<S: Storage, A: Api, Q: Querier>(
                     terms.control_variable = adjustment.target;
                 }
             }
-            adjustment.last_block = env.block.height;
-            consts.terms = Some(terms);
-            consts.adjustment = Some(adjustment);
-            let mut config = Config::from_storage(&mut deps.storage);
-            config.set_constants(&consts)?;
+            adjustment.last_block = now;
+            market.terms = Some(terms);
+            market.adjustment = Some(adjustment);
         }
     }
-    
+
     Ok(())
 }
-pub fn decay_debt<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    block_height: u64
+/// Relative distance from `target`, in hundreths of a %
+fn deviation(observed: u128, target: u128) -> i128 {
+    if observed >= target {
+        ((observed - target).saturating_mul(10_000) / target).min(i128::MAX as u128) as i128
+    } else {
+        -(((target - observed).saturating_mul(10_000) / target) as i128)
+    }
+}
+
+/// Raises the control variable when the market sells too much, lowers it otherwise.
+/// Applied lazily: every buffer elapsed since the last adjustment moves the control variable,
+/// the buffers without any deposit count as buffers without sales
+pub fn control<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &mut Market,
+    now: u64,
 ) -> StdResult<()> {
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    let new_debt = config.total_debt().checked_sub(debt_decay(deps,block_height)?).ok_or_else(||{
+    let mut controller = if let Some(controller) = market.controller.clone() {
+        controller
+    } else {
+        return Ok(());
+    };
+    let buffers = now.saturating_sub(controller.last_adjustment) / controller.buffer;
+    if buffers == 0 {
+        return Ok(());
+    }
+
+    let ratio_change = if let Some(target_debt_ratio) = controller.target_debt_ratio {
+        deviation(standardized_debt_ratio(deps, market, now)?, target_debt_ratio.u128())
+    } else {
+        0
+    };
+    // the sales since the last adjustment all happened in the first buffer elapsed
+    let mut sold = market.sold.u128().saturating_sub(controller.sold_at_last_adjustment.u128());
+    let step = controller.step.u128() as i128;
+
+    let mut terms = market.terms()?.clone();
+    for adjustment in 0..buffers.min(MAX_CONTROL_STEPS) {
+        let mut change = ratio_change;
+        if let Some(target_sold) = controller.target_sold {
+            change = change.saturating_add(deviation(sold, target_sold.u128()));
+        }
+        sold = 0;
+        let change = change.max(-step).min(step);
+
+        let control_variable = (terms.control_variable.u128()
+            .checked_mul((10_000 + change) as u128)
+            .ok_or_else(||{
+                StdError::generic_err("Control Variable too high")
+            })? / 10_000)
+            .max(controller.min_control_variable.u128())
+            .min(controller.max_control_variable.u128());
+        // past the first buffer every adjustment is the same, they stop changing anything at a bound
+        if adjustment > 0 && control_variable == terms.control_variable.u128() {
+            break;
+        }
+        terms.control_variable = Uint128(control_variable);
+    }
+
+    controller.last_adjustment += buffers * controller.buffer;
+    controller.sold_at_last_adjustment = market.sold;
+    market.terms = Some(terms);
+    market.controller = Some(controller);
+    Ok(())
+}
+
+fn query_controller<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
+    let controller = market.controller.clone().ok_or_else(||{
+        StdError::generic_err("This market has no controller")
+    })?;
+    to_binary(&QueryAnswer::Controller {
+        control_variable: market.terms()?.control_variable,
+        standardized_debt_ratio: Uint128(standardized_debt_ratio(deps,&market,now)?),
+        sold_since_adjustment: Uint128(market.sold.u128().saturating_sub(controller.sold_at_last_adjustment.u128())),
+        next_adjustment: controller.last_adjustment + controller.buffer,
+        controller,
+    })
+}
+
+pub fn decay_debt(
+    market: &mut Market,
+    now: u64
+) -> StdResult<()> {
+    let new_debt = market.total_debt.u128().checked_sub(debt_decay(market,now)?).ok_or_else(||{
             StdError::generic_err("not enough debt to decay")
         }
     )?;
-    let mut config = Config::from_storage(&mut deps.storage);
-    config.set_total_debt(new_debt);
-    config.set_last_decay(block_height);
+    market.total_debt = Uint128(new_debt);
+    market.last_decay = now;
     Ok(())
 }
 
 pub fn max_payout<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market: &Market,
 ) -> StdResult<u128> {
     let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
     ohm_total_supply(&deps.querier,&consts.ohm)?
-    .checked_mul(consts.terms.unwrap().max_payout.u128())
+    .checked_mul(market.terms()?.max_payout.u128())
     .ok_or_else(||{
             StdError::generic_err("too much payout")
         }
@@ -610,9 +1779,14 @@ pub fn max_payout<S: Storage, A: Api, Q: Querier>(
 
 fn query_max_payout<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
+    block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, _) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::MaxPayout {
-        payout: Uint128(max_payout(deps)?),
+        payout: Uint128(max_payout(deps,&market)?),
     })
 }
 
@@ -621,7 +1795,8 @@ fn query_max_payout<S: Storage, A: Api, Q: Querier>(
 
 pub fn payout_for<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    block_height: u64,
+    market: &Market,
+    now: u64,
     value: u128
 ) -> StdResult<u128> {
     value
@@ -630,21 +1805,25 @@ pub fn payout_for<S: Storage, A: Api, Q: Querier>(
             StdError::generic_err("Unaccessible Error")
         }
     )?
-    .checked_div(bond_price(deps,block_height)?)
+    .checked_div(bond_price(deps,market,now)?)
     .ok_or_else(||{
             StdError::generic_err("too much payout")
         }
     )
-    
+
 }
 
 fn query_payout_for<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
     value: u128
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::PayoutFor {
-        payout: Uint128(payout_for(deps,block_height,value)?),
+        payout: Uint128(payout_for(deps,&market,now,value)?),
     })
 }
 
@@ -654,11 +1833,12 @@ fn query_payout_for<S: Storage, A: Api, Q: Querier>(
 
 pub fn bond_price<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    block_height: u64
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
-    let terms = ReadonlyConfig::from_storage(&deps.storage).constants()?.terms.unwrap();
+    let terms = market.terms()?;
     let mut price = terms.control_variable.u128()
-    .checked_mul(debt_ratio(deps, block_height)?)
+    .checked_mul(debt_ratio(deps, market, now)?)
     .ok_or_else(||{
             StdError::generic_err("Control Variable too high")
         }
@@ -684,10 +1864,14 @@ pub fn bond_price<S: Storage, A: Api, Q: Querier>(
 
 fn query_bond_price<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::BondPrice {
-        price: Uint128(bond_price(deps,block_height)?),
+        price: Uint128(bond_price(deps,&market,now)?),
     })
 }
 /**
@@ -695,14 +1879,13 @@ fn query_bond_price<S: Storage, A: Api, Q: Querier>(
  *  @return price_ uint
  */
  pub fn _bond_price<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: &Env,
+    deps: &Extern<S, A, Q>,
+    market: &mut Market,
+    now: u64,
 ) -> StdResult<u128> {
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    let mut consts = config.constants()?;
-    let mut terms = consts.terms.unwrap();
+    let mut terms = market.terms()?.clone();
     let mut price = terms.control_variable.u128()
-    .checked_mul(debt_ratio(deps, env.block.height)?)
+    .checked_mul(debt_ratio(deps, market, now)?)
     .ok_or_else(||{
             StdError::generic_err("too much payout")
         }
@@ -721,9 +1904,7 @@ fn query_bond_price<S: Storage, A: Api, Q: Querier>(
         price = terms.minimum_price.u128()
     }else if terms.minimum_price.u128() != 0{
         terms.minimum_price = Uint128(0);
-        let mut config = Config::from_storage(&mut deps.storage);
-        consts.terms = Some(terms);
-        config.set_constants(&consts)?;
+        market.terms = Some(terms);
     }
 
     Ok(price)
@@ -731,23 +1912,14 @@ fn query_bond_price<S: Storage, A: Api, Q: Querier>(
 
 pub fn bond_price_in_usd<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    block_height: u64
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
 
-    let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
-    let bond_calculator = consts.bond_calculator;
     let price;
-    if let Some(bond_calculator) = bond_calculator{
-        // Query the bondcalculator for the bound price
-        let markdown_query_msg = BondCalculatorQueryMsg::Markdown{pair:consts.principle.pair.unwrap()};
-        let markdown_response: MarkdownResponse = markdown_query_msg.query(
-            &deps.querier,
-            bond_calculator.code_hash.clone(),
-            bond_calculator.address.clone(),
-        )?;
-
-        price = bond_price(deps,block_height)?
-        .checked_mul(markdown_response.markdown.value.u128())
+    if market.bond_calculator.is_some(){
+        price = bond_price(deps,market,now)?
+        .checked_mul(markdown(deps,market,now)?)
         .ok_or_else(||{
                 StdError::generic_err("BondPrice too high")
             }
@@ -760,8 +1932,8 @@ pub fn bond_price_in_usd<S: Storage, A: Api, Q: Querier>(
     }else{
 
         // BondPrice in USD is bond_price*10^principle_decimals/100
-        let decimals = consts.principle_decimals;
-        price = bond_price(deps,block_height)?
+        let decimals = market.principle_decimals;
+        price = bond_price(deps,market,now)?
         .checked_mul(10_u128.pow(decimals.into()))
         .ok_or_else(||{
                 StdError::generic_err("BondPrice too high")
@@ -778,20 +1950,167 @@ pub fn bond_price_in_usd<S: Storage, A: Api, Q: Querier>(
 
 fn query_bond_price_in_usd<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::BondPriceInUsd {
-        price: Uint128(bond_price_in_usd(deps,block_height)?),
+        price: Uint128(bond_price_in_usd(deps,&market,now)?),
+    })
+}
+
+/// Spot markdown of the market pair, as given by the bond calculator
+fn spot_markdown<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &Market,
+) -> StdResult<u128> {
+    let bond_calculator = market.bond_calculator.as_ref().ok_or_else(||{
+        StdError::generic_err("This market is not priced by a bond calculator")
+    })?;
+    // Query the bondcalculator for the bound price
+    let markdown_query_msg = BondCalculatorQueryMsg::Markdown{pair:market.principle.pair.clone().unwrap()};
+    let markdown_response: MarkdownResponse = markdown_query_msg.query(
+        &deps.querier,
+        bond_calculator.code_hash.clone(),
+        bond_calculator.address.clone(),
+    )?;
+    Ok(markdown_response.markdown.value.u128())
+}
+
+/// Markdown used to price the market, time weighted when the market has a TWAP
+pub fn markdown<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market: &Market,
+    now: u64
+) -> StdResult<u128> {
+    match &market.twap {
+        Some(twap) => {
+            let observations = ReadonlyConfig::from_storage(&deps.storage).observations(market.id);
+            twap_markdown(&observations, twap.window, now)
+        }
+        None => spot_markdown(deps, market),
+    }
+}
+
+/// Average of the markdown over the `window` before `now`.
+/// The observations need to cover the whole window, or the TWAP would be easy to move
+fn twap_markdown(
+    observations: &[Observation],
+    window: u64,
+    now: u64
+) -> StdResult<u128> {
+    let not_enough_observations = ||{
+        StdError::generic_err("Not enough markdown observations to cover the TWAP window, the TWAP needs to be updated")
+    };
+    let last = observations.last().ok_or_else(not_enough_observations)?;
+    let window_start = now.saturating_sub(window);
+    let first = observations
+        .iter()
+        .rev()
+        .find(|observation| observation.time <= window_start)
+        .ok_or_else(not_enough_observations)?;
+    let elapsed = now.checked_sub(first.time).filter(|elapsed| *elapsed > 0).ok_or_else(not_enough_observations)?;
+
+    let cumulative = cumulative_markdown(last, now)?;
+    Ok((cumulative - first.cumulative.u128()) / u128::from(elapsed))
+}
+
+fn cumulative_markdown(
+    last: &Observation,
+    now: u64
+) -> StdResult<u128> {
+    let elapsed = now.checked_sub(last.time).ok_or_else(||{
+        StdError::generic_err("Markdown observation in the future")
+    })?;
+    last.markdown.u128()
+    .checked_mul(elapsed.into())
+    .and_then(|accumulated| accumulated.checked_add(last.cumulative.u128()))
+    .ok_or_else(||{
+            StdError::generic_err("Markdown accumulator overflow")
+        }
+    )
+}
+
+/// Minimum time between two observations, so that `MAX_OBSERVATIONS` always cover the window
+/// with one observation before its start
+fn observation_spacing(window: u64) -> u64 {
+    ((window + MAX_OBSERVATIONS - 3) / (MAX_OBSERVATIONS - 2)).max(1)
+}
+
+/// Records the markdown, at most once per `observation_spacing`, and forgets the
+/// observations that are not needed anymore to compute the TWAP
+fn record_observation(
+    observations: &mut Vec<Observation>,
+    window: u64,
+    now: u64,
+    markdown: u128
+) -> StdResult<()> {
+    let spacing = observation_spacing(window);
+    let cumulative = match observations.last() {
+        Some(last) if now < last.time.saturating_add(spacing) => {
+            return Err(StdError::generic_err(format!(
+                "The TWAP was updated less than {} blocks (or seconds) ago",
+                spacing
+            )));
+        }
+        Some(last) => cumulative_markdown(last, now)?,
+        None => 0,
+    };
+    observations.push(Observation{
+        time: now,
+        cumulative: Uint128(cumulative),
+        markdown: Uint128(markdown),
+    });
+
+    let window_start = now.saturating_sub(window);
+    if let Some(first) = observations.iter().rposition(|observation| observation.time <= window_start) {
+        observations.drain(..first);
+    }
+    let excess = observations.len().saturating_sub(MAX_OBSERVATIONS as usize);
+    observations.drain(..excess);
+    Ok(())
+}
+
+fn check_markdown_deviation(
+    spot: u128,
+    twap: u128,
+    max_deviation: u128
+) -> StdResult<()> {
+    let deviation = if spot > twap { spot - twap } else { twap - spot };
+    if deviation.saturating_mul(10_000) > twap.saturating_mul(max_deviation) {
+        return Err(StdError::generic_err("The pair price moved too far from its TWAP, try again later"));
+    }
+    Ok(())
+}
+
+fn query_twap<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let now = query_clock(market.time_based, block_height, block_time)?;
+    let twap = market.twap.as_ref().ok_or_else(||{
+        StdError::generic_err("This market is not priced with a TWAP")
+    })?;
+    let observations = ReadonlyConfig::from_storage(&deps.storage).observations(market_id);
+    to_binary(&QueryAnswer::Twap {
+        spot: Uint128(spot_markdown(deps,&market)?),
+        twap: Uint128(twap_markdown(&observations, twap.window, now)?),
     })
 }
 
 pub fn debt_ratio<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    block_height: u64
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
     let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
     let supply = ohm_total_supply(&deps.querier,&consts.ohm)?;
-    current_debt(deps,block_height)?
+    current_debt(market,now)?
     .checked_mul(10_u128.pow(9_u32))
     .ok_or_else(||{
             StdError::generic_err("BondPrice too high")
@@ -806,31 +2125,26 @@ pub fn debt_ratio<S: Storage, A: Api, Q: Querier>(
 
 fn query_debt_ratio<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::DebtRatio {
-        ratio: Uint128(debt_ratio(deps,block_height)?),
+        ratio: Uint128(debt_ratio(deps,&market,now)?),
     })
 }
 
 pub fn standardized_debt_ratio<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    block_height: u64
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
-    let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
-    let bond_calculator = consts.bond_calculator;
     let ratio;
-    if let Some(bond_calculator) = bond_calculator{
-        // Query the bondcalculator for the bound price
-        let markdown_query_msg = BondCalculatorQueryMsg::Markdown{pair:consts.principle.pair.unwrap()};
-        let markdown_response: MarkdownResponse = markdown_query_msg.query(
-            &deps.querier,
-            bond_calculator.code_hash.clone(),
-            bond_calculator.address.clone(),
-        )?;
-
-        ratio = debt_ratio(deps, block_height)?
-        .checked_mul(markdown_response.markdown.value.u128())
+    if market.bond_calculator.is_some(){
+        ratio = debt_ratio(deps, market, now)?
+        .checked_mul(markdown(deps,market,now)?)
         .ok_or_else(||{
                 StdError::generic_err("ratio too high")
             }
@@ -841,7 +2155,7 @@ pub fn standardized_debt_ratio<S: Storage, A: Api, Q: Querier>(
             }
         )?;
     }else{
-        ratio = debt_ratio(deps, block_height)?;
+        ratio = debt_ratio(deps, market, now)?;
     }
     Ok(ratio)
 }
@@ -849,20 +2163,23 @@ pub fn standardized_debt_ratio<S: Storage, A: Api, Q: Querier>(
 
 fn query_standardized_debt_ratio<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::StandardizedDebtRatio {
-        ratio: Uint128(standardized_debt_ratio(deps,block_height)?),
+        ratio: Uint128(standardized_debt_ratio(deps,&market,now)?),
     })
 }
 
-pub fn current_debt<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    block_height: u64
+pub fn current_debt(
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    config.total_debt()
-    .checked_sub(debt_decay(deps, block_height)?)
+    market.total_debt.u128()
+    .checked_sub(debt_decay(market, now)?)
     .ok_or_else(||{
             StdError::generic_err("Debt less than zero")
         }
@@ -871,32 +2188,36 @@ pub fn current_debt<S: Storage, A: Api, Q: Querier>(
 
 fn query_current_debt<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::CurrentDebt {
-        debt: Uint128(current_debt(deps,block_height)?),
+        debt: Uint128(current_debt(&market,now)?),
     })
 }
 
-pub fn debt_decay<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    block_height: u64
+pub fn debt_decay(
+    market: &Market,
+    now: u64
 ) -> StdResult<u128> {
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    let blocks_since_last = block_height
-    .checked_sub(config.last_decay())
+    let elapsed = now
+    .checked_sub(market.last_decay)
     .ok_or_else(||{
             StdError::generic_err("You can't query the debt decay for a past block !")
         }
     )?;
-    let total_debt = config.total_debt();
+    let total_debt = market.total_debt.u128();
     let mut decay = total_debt
-    .checked_mul(blocks_since_last.into())
+    .checked_mul(elapsed.into())
     .ok_or_else(||{
             StdError::generic_err("too much blocks since last decay. The contract is down...")
         }
     )?
-    .checked_div(config.constants()?.terms.unwrap().vesting_term.into())
+    // inverse bonds paid instantly have no vesting, their debt decays at the next block
+    .checked_div(market.terms()?.vesting_term.max(1).into())
     .ok_or_else(||{
             StdError::generic_err("Vesting term is zero. The contract is not initialized correctly")
         }
@@ -909,33 +2230,121 @@ pub fn debt_decay<S: Storage, A: Api, Q: Querier>(
 
 fn query_debt_decay<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, now) = query_market_at(deps, market, block_height, block_time)?;
     to_binary(&QueryAnswer::DebtDecay {
-        decay: Uint128(debt_decay(deps,block_height)?),
+        decay: Uint128(debt_decay(&market,now)?),
     })
 }
 
-fn query_bond_terms<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>
+fn query_remaining_capacity<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    address: HumanAddr,
+    block_height: u64,
+    block_time: Option<u64>,
 ) -> QueryResult {
     let config = ReadonlyConfig::from_storage(&deps.storage);
-    to_binary(&config.constants()?.terms)
+    let (market, now) = query_market_at(deps, config.market(market_id)?, block_height, block_time)?;
+    let canon_address = deps.api.canonical_address(&address)?;
+
+    let mut address_capacity = None;
+    let mut window_capacity = None;
+    if let Some(limits) = &market.limits {
+        if let Some(max_payout_per_address) = limits.max_payout_per_address {
+            let start = window_start(now, market.terms()?.vesting_term);
+            let used = config.purchase_usage(market_id, &canon_address).used_in(start);
+            address_capacity = Some(Uint128(max_payout_per_address.u128().saturating_sub(used)));
+        }
+        if let Some(max_payout_per_window) = limits.max_payout_per_window {
+            let used = market.window_usage.used_in(window_start(now, limits.window));
+            window_capacity = Some(Uint128(max_payout_per_window.u128().saturating_sub(used)));
+        }
+    }
+
+    let mut allowlist_capacity = None;
+    if let Some(allowlist) = &market.allowlist {
+        allowlist_capacity = match config.allowed_depositor(market_id, &canon_address) {
+            Some(allowed) if allowed.allowed => allowed.cap.map(|cap| Uint128(cap.u128().saturating_sub(allowed.bought.u128()))),
+            Some(_) => Some(Uint128(0)),
+            // depositors with a permit from the issuer
+            None if allowlist.issuer.is_some() => allowlist.permit_cap,
+            None => Some(Uint128(0)),
+        };
+    }
+
+    to_binary(&QueryAnswer::RemainingCapacity {
+        address: address_capacity,
+        window: window_capacity,
+        market: market.remaining_capacity().map(Uint128),
+        allowlist: allowlist_capacity,
+    })
 }
 
-pub fn percent_vested_for<S: Storage, A: Api, Q: Querier>(
+fn query_bond_terms<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    market_id: u64,
     block_height: u64,
-    depositor: HumanAddr
+    block_time: Option<u64>,
+) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, _) = query_market_at(deps, market, block_height, block_time)?;
+    to_binary(&market.terms)
+}
+
+fn query_market<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    market_id: u64,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> QueryResult {
+    let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id)?;
+    let (market, _) = query_market_at(deps, market, block_height, block_time)?;
+    to_binary(&QueryAnswer::Market { market })
+}
+
+/// Lists the markets that can currently be bonded with their price and remaining capacity
+fn query_markets<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_height: u64,
+    block_time: Option<u64>,
+) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let mut markets = vec![];
+    for market in config.markets()?.into_iter().filter(|market| market.is_live()) {
+        let (market, now) = query_market_at(deps, market, block_height, block_time)?;
+        markets.push(MarketInfo {
+            id: market.id,
+            price: Uint128(bond_price(deps,&market,now)?),
+            price_in_usd: Uint128(bond_price_in_usd(deps,&market,now)?),
+            current_debt: Uint128(current_debt(&market,now)?),
+            remaining_capacity: market.remaining_capacity().map(Uint128),
+            inverse: market.inverse,
+            private: market.allowlist.is_some(),
+            terms: market.terms()?.clone(),
+            principle: market.principle,
+        });
+    }
+    to_binary(&QueryAnswer::Markets { markets })
+}
+
+pub fn note_percent_vested(
+    bond: &Bond,
+    now: u64,
 ) -> StdResult<u128> {
-    let canon_depositor = deps.api.canonical_address(&depositor)?;
-    let bond = ReadonlyBondInfo::from_storage(&deps.storage).bond(&canon_depositor);
-    let blocks_since_last = block_height - bond.last_block;
+    let elapsed = now.checked_sub(bond.last_block).ok_or_else(||{
+            StdError::generic_err("Query before the bond last interaction")
+        }
+    )?;
     let vesting = bond.vesting;
     let percent_vested: u128;
 
     if vesting > 0 {
-        percent_vested = blocks_since_last
+        percent_vested = elapsed
         .checked_mul(10_000)
         .ok_or_else(||{
                 StdError::generic_err("too much blocks since last decay. The contract is down...")
@@ -952,13 +2361,43 @@ pub fn percent_vested_for<S: Storage, A: Api, Q: Querier>(
     Ok(percent_vested)
 }
 
+pub fn note_pending_payout(
+    bond: &Bond,
+    now: u64,
+) -> StdResult<u128> {
+    let percent_vested = note_percent_vested(bond, now)?;
+    if percent_vested >= 10000{
+        Ok(bond.payout.u128())
+    } else {
+        Ok(bond.payout.u128() * percent_vested / 10_000)
+    }
+}
+
+pub fn percent_vested_for<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_height: u64,
+    block_time: Option<u64>,
+    depositor: HumanAddr,
+    note_id: u64,
+) -> StdResult<u128> {
+    let canon_depositor = deps.api.canonical_address(&depositor)?;
+    let bond = ReadonlyBondInfo::from_storage(&deps.storage)
+        .bond(&canon_depositor, note_id)
+        .ok_or_else(||{
+            StdError::generic_err(format!("No bond note with id {}", note_id))
+        })?;
+    note_percent_vested(&bond, query_clock(bond.time_based, block_height, block_time)?)
+}
+
 fn query_percent_vested_for<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     block_height: u64,
-    depositor: HumanAddr
+    block_time: Option<u64>,
+    depositor: HumanAddr,
+    note_id: u64,
 ) -> QueryResult {
     to_binary(&QueryAnswer::PercentVestedFor {
-        percent: Uint128(percent_vested_for(deps,block_height,depositor)?),
+        percent: Uint128(percent_vested_for(deps,block_height,block_time,depositor,note_id)?),
     })
 }
 
@@ -966,19 +2405,20 @@ fn query_percent_vested_for<S: Storage, A: Api, Q: Querier>(
 pub fn query_pending_payout_for<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     block_height: u64,
+    block_time: Option<u64>,
     depositor: HumanAddr
 ) -> QueryResult {
-    let percent_vested = percent_vested_for(deps,block_height,depositor.clone())?;
     let canon_depositor = deps.api.canonical_address(&depositor)?;
-    let payout = ReadonlyBondInfo::from_storage(&deps.storage).bond(&canon_depositor).payout;
-    let pending_payout;
-    if percent_vested >= 10000{
-        pending_payout = payout;
-    } else {
-        pending_payout = Uint128(payout.u128() * percent_vested / 10_000);
+    let bonds = ReadonlyBondInfo::from_storage(&deps.storage).bonds(&canon_depositor);
+    let mut pending_payout: u128 = 0;
+    // OHM vested so far, inverse bonds pay reserves
+    for bond in bonds.iter().filter(|bond| bond.payout_token.is_none()) {
+        let now = query_clock(bond.time_based, block_height, block_time)?;
+        pending_payout = pending_payout.checked_add(note_pending_payout(bond, now)?)
+            .ok_or_else(|| StdError::generic_err("Pending payout overflow"))?;
     }
     to_binary(&QueryAnswer::PendingPayoutFor {
-        payout: pending_payout,
+        payout: Uint128(pending_payout),
     })
 }
 
@@ -987,8 +2427,9 @@ pub fn recover_lost_token<S: Storage, A: Api, Q: Querier>(
     env: Env,
     token: Contract
 ) -> StdResult<HandleResponse>{
-    let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
-    if token == consts.ohm || token == consts.ohm{
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let consts = config.constants()?;
+    if token == consts.ohm || config.markets()?.iter().any(|market| market.principle.token == token){
         return Err(StdError::generic_err("You can only recover token that are not the treasury token or the principle token",
         ));
     }
@@ -1018,19 +2459,331 @@ pub fn recover_lost_token<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+pub fn set_referrer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address: HumanAddr,
+    share: Option<Uint128>,
+) -> StdResult<HandleResponse> {
+    let canon_address = deps.api.canonical_address(&address)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    if let Some(share) = share {
+        if share.u128() > 10_000 {
+            return Err(StdError::generic_err("The referral share can't be more than the whole fee"));
+        }
+    }
+    config.set_referral_share(&canon_address, share)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetReferrer{ status: Success })?),
+    })
+}
+
+pub fn claim_referral_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let canon_sender = deps.api.canonical_address(&env.message.sender)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    let rewards = config.referral_rewards(&canon_sender);
+    if rewards == 0 {
+        return Err(StdError::generic_err("No referral rewards to claim"));
+    }
+    config.set_referral_rewards(&canon_sender, 0)?;
+    let ohm = config.constants()?.ohm;
+
+    let messages = vec![
+        snip20::transfer_msg(
+            env.message.sender,
+            Uint128(rewards),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            ohm.code_hash,
+            ohm.address
+        )?
+    ];
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ClaimReferralRewards{ status: Success, amount: Uint128(rewards) })?),
+    })
+}
+
+fn query_referrer<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: HumanAddr,
+) -> QueryResult {
+    let canon_address = deps.api.canonical_address(&address)?;
+    to_binary(&QueryAnswer::Referrer {
+        share: ReadonlyConfig::from_storage(&deps.storage).referral_share(&canon_address),
+    })
+}
+
+pub fn query_referral_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+) -> StdResult<Binary> {
+    let address = deps.api.canonical_address(account)?;
+    to_binary(&QueryAnswer::ReferralRewards {
+        rewards: Uint128(ReadonlyConfig::from_storage(&deps.storage).referral_rewards(&address)),
+    })
+}
+
+fn transfer_bond_impl<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    owner: &CanonicalAddr,
+    sender: &CanonicalAddr,
+    recipient: &CanonicalAddr,
+    note_id: u64,
+) -> StdResult<()> {
+    // Like bonds on behalf of another address, notes can only be given to addresses accepting their owner
+    if recipient != owner {
+        check_benefactor(deps, &deps.api.human_address(recipient)?, &deps.api.human_address(owner)?)?;
+    }
+    let mut bonds = BondInfo::from_storage(&mut deps.storage);
+    let note = bonds.bond(owner, note_id).ok_or_else(||{
+        StdError::generic_err(format!("No bond note with id {}", note_id))
+    })?;
+    // The note keeps its id, vesting schedule and price
+    bonds.remove_bond(owner, note_id)?;
+    bonds.add_bond(recipient, note.clone())?;
+
+    remove_bond_approval(&mut deps.storage, owner, note_id);
+    store_bond_transfer(
+        &mut deps.storage,
+        note_id,
+        owner,
+        sender,
+        recipient,
+        note.payout.u128(),
+        &env.block,
+    )
+}
+
+pub fn transfer_bond<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    note_id: u64,
+    recipient: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let canon_sender = deps.api.canonical_address(&env.message.sender)?;
+    let canon_recipient = deps.api.canonical_address(&recipient)?;
+    transfer_bond_impl(deps, &env, &canon_sender, &canon_sender, &canon_recipient, note_id)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::TransferBond { status: Success })?),
+    })
+}
+
+pub fn transfer_bond_from<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    owner: HumanAddr,
+    note_id: u64,
+    recipient: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let canon_owner = deps.api.canonical_address(&owner)?;
+    let canon_sender = deps.api.canonical_address(&env.message.sender)?;
+    let canon_recipient = deps.api.canonical_address(&recipient)?;
+
+    let approval = read_bond_approval(&deps.storage, &canon_owner, note_id);
+    match approval {
+        Some(approval) if approval.spender == canon_sender => {
+            if approval.is_expired_at(env.block.height) {
+                return Err(StdError::generic_err(format!(
+                    "The approval for bond note {} has expired",
+                    note_id
+                )));
+            }
+        }
+        _ => {
+            return Err(StdError::generic_err(format!(
+                "You are not approved to transfer bond note {}",
+                note_id
+            )));
+        }
+    }
+    transfer_bond_impl(deps, &env, &canon_owner, &canon_sender, &canon_recipient, note_id)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::TransferBondFrom { status: Success })?),
+    })
+}
+
+pub fn approve_bond<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    spender: HumanAddr,
+    note_id: u64,
+    expiration: Option<u64>,
+) -> StdResult<HandleResponse> {
+    let canon_owner = deps.api.canonical_address(&env.message.sender)?;
+    if ReadonlyBondInfo::from_storage(&deps.storage).bond(&canon_owner, note_id).is_none() {
+        return Err(StdError::generic_err(format!("No bond note with id {}", note_id)));
+    }
+    let approval = BondApproval {
+        spender: deps.api.canonical_address(&spender)?,
+        expiration,
+    };
+    write_bond_approval(&mut deps.storage, &canon_owner, note_id, &approval)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ApproveBond { status: Success })?),
+    })
+}
+
+pub fn revoke_bond_approval<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    note_id: u64,
+) -> StdResult<HandleResponse> {
+    let canon_owner = deps.api.canonical_address(&env.message.sender)?;
+    remove_bond_approval(&mut deps.storage, &canon_owner, note_id);
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RevokeBondApproval { status: Success })?),
+    })
+}
+
 pub fn receive<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     from: HumanAddr,
     amount: u128,
-    msg: Binary,
+    msg: Option<Binary>,
 ) -> StdResult<HandleResponse> {
 
+    let msg = match msg {
+        Some(msg) => msg,
+        // reserves withdrawn from the treasury to pay inverse bonds
+        None if from == ReadonlyConfig::from_storage(&deps.storage).constants()?.treasury.address => {
+            return Ok(HandleResponse {
+                messages: vec![],
+                log: vec![],
+                data: None,
+            });
+        }
+        None => return Err(StdError::generic_err("Tokens can only be sent with a receive message")),
+    };
     let msg: ReceiveMsg = from_binary(&msg)?;
     let token = env.message.sender.clone();
+    try_deposit(deps, env, from, token, amount, msg)
+}
+
+/// Deposits of `amount` of `token` sent by `from`, through a SNIP-20 send or wrapped from uscrt
+fn try_deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    token: HumanAddr,
+    amount: u128,
+    msg: ReceiveMsg,
+) -> StdResult<HandleResponse> {
     match msg {
-        ReceiveMsg::Deposit {max_price, depositor } => deposit(deps, env, token, amount, max_price.u128(), depositor.unwrap_or(from)),
+        ReceiveMsg::Deposit {
+            market_id, max_price, depositor, referrer, permit, min_payout, deadline_height, deadline_time
+        } => {
+            if deadline_height.map_or(false, |deadline| env.block.height > deadline)
+                || deadline_time.map_or(false, |deadline| env.block.time > deadline) {
+                return Err(StdError::generic_err("The deposit deadline has passed"));
+            }
+            let min_payout = min_payout.map_or(0, |min_payout| min_payout.u128());
+            let depositor = match depositor {
+                Some(depositor) if depositor != from => {
+                    check_benefactor(deps, &depositor, &from)?;
+                    depositor
+                }
+                _ => from,
+            };
+            if ReadonlyConfig::from_storage(&deps.storage).market(market_id)?.inverse {
+                inverse_deposit(deps, env, market_id, token, amount, max_price.u128(), min_payout, depositor, referrer, permit)
+            } else {
+                deposit(deps, env, market_id, token, amount, max_price.u128(), min_payout, depositor, referrer, permit)
+            }
+        }
+    }
+}
+
+/// The uscrt sent with the message is wrapped through the native wrapper, then bonded like a deposit
+/// of the wrapper. The bond depository stays the reserve depositor of the treasury for these reserves
+pub fn deposit_native<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: ReceiveMsg,
+) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let native_wrapper = config.native_wrapper().ok_or_else(||{
+        StdError::generic_err("Native deposits are not enabled")
+    })?;
+    let ReceiveMsg::Deposit{ market_id, .. } = &msg;
+    let market = config.market(*market_id)?;
+    if market.inverse || market.principle.token.address != native_wrapper {
+        return Err(StdError::generic_err("This bond doesn't accept uscrt"));
+    }
+
+    let amount = match env.message.sent_funds.as_slice(){
+        [coin] if coin.denom == "uscrt" && coin.amount.u128() != 0 => coin.amount,
+        _ => return Err(StdError::generic_err("Only uscrt can be deposited")),
+    };
+    let wrap_msg = snip20::deposit_msg(
+        amount,
+        None,
+        RESPONSE_BLOCK_SIZE,
+        market.principle.token.code_hash,
+        market.principle.token.address,
+    )?;
+
+    let from = env.message.sender.clone();
+    let mut response = try_deposit(deps, env, from, native_wrapper, amount.u128(), msg)?;
+    // The wrapped tokens have to be received before they are sent to the treasury
+    response.messages.insert(0, wrap_msg);
+    Ok(response)
+}
+
+/// Bonding for someone else fills their purchase limits and private market caps
+/// and adds notes to their account, so it needs their approval
+fn check_benefactor<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    depositor: &HumanAddr,
+    benefactor: &HumanAddr,
+) -> StdResult<()> {
+    let canon_depositor = deps.api.canonical_address(depositor)?;
+    let canon_benefactor = deps.api.canonical_address(benefactor)?;
+    if !ReadonlyConfig::from_storage(&deps.storage).is_benefactor(&canon_depositor, &canon_benefactor) {
+        return Err(StdError::generic_err(format!("{} didn't accept bonds from {}", depositor, benefactor)));
     }
+    Ok(())
+}
+
+pub fn set_benefactor<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    benefactor: HumanAddr,
+    accepted: bool,
+) -> StdResult<HandleResponse> {
+    let canon_sender = deps.api.canonical_address(&env.message.sender)?;
+    let canon_benefactor = deps.api.canonical_address(&benefactor)?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    config.set_benefactor(&canon_sender, &canon_benefactor, accepted)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetBenefactor { status: Success })?),
+    })
 }
 
 fn ohm_total_supply<Q: Querier>(
@@ -1062,7 +2815,10 @@ fn permit_queries<S: Storage, A: Api, Q: Querier>(
     // Permit validated! We can now execute the query.
     match query {
        
-        QueryWithPermit::BondInfo{} => {
+        QueryWithPermit::BondInfo{
+            block_height,
+            block_time
+        } => {
             if !permit.check_permission(&Permission::Balance) {
                 return Err(StdError::generic_err(format!(
                     "No permission to query rate, got permissions {:?}",
@@ -1070,10 +2826,12 @@ fn permit_queries<S: Storage, A: Api, Q: Querier>(
                 )));
             }
 
-            query_bond_info(deps, account)
+            query_bond_info(deps, block_height, block_time, account)
         }
         QueryWithPermit::PercentVestedFor{
-            block_height
+            note_id,
+            block_height,
+            block_time
         } => {
             if !permit.check_permission(&Permission::Balance) {
                 return Err(StdError::generic_err(format!(
@@ -1082,10 +2840,11 @@ fn permit_queries<S: Storage, A: Api, Q: Querier>(
                 )));
             }
 
-            query_percent_vested_for(deps, block_height, account)
+            query_percent_vested_for(deps, block_height, block_time, account, note_id)
         }
         QueryWithPermit::PendingPayoutFor{
-            block_height
+            block_height,
+            block_time
         } => {
             if !permit.check_permission(&Permission::Balance) {
                 return Err(StdError::generic_err(format!(
@@ -1094,7 +2853,43 @@ fn permit_queries<S: Storage, A: Api, Q: Querier>(
                 )));
             }
 
-            query_pending_payout_for(deps, block_height, account)
+            query_pending_payout_for(deps, block_height, block_time, account)
+        }
+        QueryWithPermit::TransferHistory{
+            page,
+            page_size
+        } => {
+            if !permit.check_permission(&Permission::History) {
+                return Err(StdError::generic_err(format!(
+                    "No permission to query history, got permissions {:?}",
+                    permit.params.permissions
+                )));
+            }
+
+            query_transfers(deps, &account, page.unwrap_or(0), page_size)
+        }
+        QueryWithPermit::BondHistory{
+            page,
+            page_size
+        } => {
+            if !permit.check_permission(&Permission::History) {
+                return Err(StdError::generic_err(format!(
+                    "No permission to query history, got permissions {:?}",
+                    permit.params.permissions
+                )));
+            }
+
+            query_bond_history(deps, &account, page.unwrap_or(0), page_size)
+        }
+        QueryWithPermit::ReferralRewards{} => {
+            if !permit.check_permission(&Permission::Balance) {
+                return Err(StdError::generic_err(format!(
+                    "No permission to query balance, got permissions {:?}",
+                    permit.params.permissions
+                )));
+            }
+
+            query_referral_rewards(deps, &account)
         }
     }
 }
@@ -1117,11 +2912,17 @@ pub fn viewing_keys_queries<S: Storage, A: Api, Q: Querier>(
         } else if key.check_viewing_key(expected_key.unwrap().as_slice()) {
             return match msg {
                 QueryMsg::Balance { address, .. } => query_balance(deps, &address),
-                QueryMsg::BondInfo { address, .. } => query_bond_info(deps, address),
-                QueryMsg::PercentVestedFor { address, block_height, .. } 
-                    => query_percent_vested_for(deps, block_height, address),
-                QueryMsg::PendingPayoutFor { address, block_height, .. } 
-                    => query_pending_payout_for(deps, block_height, address),
+                QueryMsg::BondInfo { address, block_height, block_time, .. }
+                    => query_bond_info(deps, block_height, block_time, address),
+                QueryMsg::PercentVestedFor { address, note_id, block_height, block_time, .. } 
+                    => query_percent_vested_for(deps, block_height, block_time, address, note_id),
+                QueryMsg::PendingPayoutFor { address, block_height, block_time, .. } 
+                    => query_pending_payout_for(deps, block_height, block_time, address),
+                QueryMsg::TransferHistory { address, page, page_size, .. }
+                    => query_transfers(deps, &address, page.unwrap_or(0), page_size),
+                QueryMsg::BondHistory { address, page, page_size, .. }
+                    => query_bond_history(deps, &address, page.unwrap_or(0), page_size),
+                QueryMsg::ReferralRewards { address, .. } => query_referral_rewards(deps, &address),
                 _ => Err(StdError::generic_err(
                         "This query type does not require authentication",
                     )),
@@ -1141,17 +2942,13 @@ fn query_contract_info<S: ReadonlyStorage>(storage: &S) -> QueryResult {
 
     to_binary(&QueryAnswer::ContractInfo { 
         ohm: constants.ohm,
-        principle: constants.principle,
         treasury: constants.treasury,
         dao: constants.dao,
-        bond_calculator: constants.bond_calculator,
         staking: constants.staking,
-        terms: constants.terms,
-        adjustment: constants.adjustment,
 
         admin: constants.admin,
-        total_debt: Uint128(config.total_debt()),
-        last_decay: config.last_decay(),
+        market_count: config.market_count(),
+        terms_timelock: constants.terms_timelock,
     })
 }
 
@@ -1160,8 +2957,9 @@ pub fn query_balance<S: Storage, A: Api, Q: Querier>(
     account: &HumanAddr,
 ) -> StdResult<Binary> {
     let address = deps.api.canonical_address(account)?;
-    let bond = ReadonlyBondInfo::from_storage(&deps.storage).bond(&address);
-    let amount = bond.payout;
+    let bonds = ReadonlyBondInfo::from_storage(&deps.storage).bonds(&address);
+    // OHM still owed, inverse bonds pay reserves
+    let amount = Uint128(bonds.iter().filter(|bond| bond.payout_token.is_none()).map(|bond| bond.payout.u128()).sum());
     let response = QueryAnswer::Balance { amount };
     to_binary(&response)
 }
@@ -1169,20 +2967,68 @@ pub fn query_balance<S: Storage, A: Api, Q: Querier>(
 
 fn query_bond_info<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
+    block_height: u64,
+    block_time: Option<u64>,
     recipient: HumanAddr
 ) -> QueryResult {
     let canon_recipient = deps.api.canonical_address(&recipient)?;
-    let bond = ReadonlyBondInfo::from_storage(&deps.storage).bond(&canon_recipient);
+    let bonds = ReadonlyBondInfo::from_storage(&deps.storage).bonds(&canon_recipient);
+
+    let notes: StdResult<Vec<Note>> = bonds
+        .into_iter()
+        .map(|bond| {
+            let now = query_clock(bond.time_based, block_height, block_time)?;
+            Ok(Note {
+                id: bond.id,
+                market_id: bond.market_id,
+                time_based: bond.time_based,
+                percent_vested: Uint128(note_percent_vested(&bond, now)?),
+                pending_payout: Uint128(note_pending_payout(&bond, now)?),
+                payout: bond.payout,
+                vesting: bond.vesting,
+                last_block: bond.last_block,
+                price_paid: bond.price_paid,
+                payout_token: bond.payout_token,
+            })
+        })
+        .collect();
+
+    to_binary(&QueryAnswer::BondInfo { notes: notes? })
+}
 
-    to_binary(&QueryAnswer::Bond {
-        payout: bond.payout, 
-        vesting: bond.vesting,
-        last_block: bond.last_block,
-        price_paid: bond.price_paid,
-    })
+
+
+pub fn query_bond_history<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<Binary> {
+    let address = deps.api.canonical_address(account)?;
+    let (txs, total) = get_bond_txs(&deps.storage, &address, page, page_size)?;
+
+    let result = QueryAnswer::BondHistory {
+        txs,
+        total: Some(total),
+    };
+    to_binary(&result)
 }
 
+pub fn query_transfers<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<Binary> {
+    let address = deps.api.canonical_address(account)?;
+    let (txs, total) = get_bond_transfers(&deps.api, &deps.storage, &address, page, page_size)?;
 
+    let result = QueryAnswer::TransferHistory {
+        txs,
+        total: Some(total),
+    };
+    to_binary(&result)
+}
 
 fn change_admin<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
@@ -1217,46 +3063,106 @@ pub fn try_set_key<S: Storage, A: Api, Q: Querier>(
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::SetViewingKey { status: Success })?),
+        data: Some(to_binary(&HandleAnswer::SetViewingKey { status: Success })?),
+    })
+}
+
+pub fn try_create_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    entropy: String,
+) -> StdResult<HandleResponse> {
+    let constants = ReadonlyConfig::from_storage(&deps.storage).constants()?;
+    let prng_seed = constants.prng_seed;
+
+    let key = ViewingKey::new(&env, &prng_seed, (&entropy).as_ref());
+
+    let message_sender = deps.api.canonical_address(&env.message.sender)?;
+    write_viewing_key(&mut deps.storage, &message_sender, &key);
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CreateViewingKey { key })?),
+    })
+}
+
+fn revoke_permit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    permit_name: String,
+) -> StdResult<HandleResponse> {
+    RevokedPermits::revoke_permit(
+        &mut deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        &env.message.sender,
+        &permit_name,
+    );
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RevokePermit { status: Success })?),
     })
 }
 
-pub fn try_create_key<S: Storage, A: Api, Q: Querier>(
+fn set_contract_status<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    entropy: String,
+    status_level: ContractStatusLevel,
 ) -> StdResult<HandleResponse> {
-    let constants = ReadonlyConfig::from_storage(&deps.storage).constants()?;
-    let prng_seed = constants.prng_seed;
+    let mut config = Config::from_storage(&mut deps.storage);
 
-    let key = ViewingKey::new(&env, &prng_seed, (&entropy).as_ref());
+    if !is_admin(&config, &env.message.sender)? {
+        if config.pause_guardian().as_ref() != Some(&env.message.sender) {
+            return Err(StdError::generic_err(
+                "Only the admin and the pause guardian can change the contract status",
+            ));
+        }
+        if status_level_to_u8(status_level.clone()) <= status_level_to_u8(config.contract_status()) {
+            return Err(StdError::generic_err(
+                "The pause guardian can only stop the contract further",
+            ));
+        }
+    }
 
-    let message_sender = deps.api.canonical_address(&env.message.sender)?;
-    write_viewing_key(&mut deps.storage, &message_sender, &key);
+    config.set_contract_status(status_level);
 
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::CreateViewingKey { key })?),
+        data: Some(to_binary(&HandleAnswer::SetContractStatus {
+            status: Success,
+        })?),
     })
 }
 
-fn revoke_permit<S: Storage, A: Api, Q: Querier>(
+fn set_pause_guardian<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    permit_name: String,
+    guardian: Option<HumanAddr>,
 ) -> StdResult<HandleResponse> {
-    RevokedPermits::revoke_permit(
-        &mut deps.storage,
-        PREFIX_REVOKED_PERMITS,
-        &env.message.sender,
-        &permit_name,
-    );
+    let mut config = Config::from_storage(&mut deps.storage);
+
+    check_if_admin(&config, &env.message.sender)?;
+
+    config.set_pause_guardian(&guardian)?;
 
     Ok(HandleResponse {
         messages: vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::RevokePermit { status: Success })?),
+        data: Some(to_binary(&HandleAnswer::SetPauseGuardian {
+            status: Success,
+        })?),
+    })
+}
+
+fn query_contract_status<S: ReadonlyStorage>(storage: &S) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::ContractStatus {
+        status: config.contract_status(),
+        pause_guardian: config.pause_guardian(),
     })
 }
 
@@ -1289,4 +3195,1486 @@ fn is_valid_symbol(symbol: &str) -> bool {
     let len_is_valid = (3..=6).contains(&len);
 
     len_is_valid && symbol.bytes().all(|byte| (b'A'..=b'Z').contains(&byte))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::ResponseStatus;
+    use cosmwasm_std::testing::*;
+    use cosmwasm_std::{from_slice, Coin, CosmosMsg, Empty, QuerierResult, QueryRequest, WasmMsg, WasmQuery};
+    use serde::{Deserialize, Serialize};
+    use std::any::Any;
+    use std::collections::HashMap;
+
+    const OHM_SUPPLY: u128 = 1_000_000_000_000_000; // a million OHM
+
+    // Answers the queries the bond depository sends to the tokens, the treasury and the bond calculator
+    struct TestQuerier {
+        // Decimals and total supply of every token
+        tokens: HashMap<HumanAddr, (u8, u128)>,
+        // OHM value of a whole token, for every principle
+        token_value: u128,
+        excess_reserves: u128,
+        // Markdown of the LP pair, moved to simulate trades
+        markdown: u128,
+    }
+
+    impl TestQuerier {
+        fn new() -> Self {
+            let tokens = vec![
+                (HumanAddr("ohm".to_string()), (9, OHM_SUPPLY)),
+                (HumanAddr("sUST".to_string()), (6, 0)),
+                (HumanAddr("SSCRT".to_string()), (6, 0)),
+                (HumanAddr("sust-LP".to_string()), (9, 0)),
+            ];
+            TestQuerier {
+                tokens: tokens.into_iter().collect(),
+                token_value: 1_000_000_000,
+                excess_reserves: OHM_SUPPLY,
+                markdown: 1_000_000_000,
+            }
+        }
+
+        fn answer(&self, contract: &HumanAddr, query: TestQuery) -> StdResult<Binary> {
+            match query {
+                TestQuery::TokenInfo {} => {
+                    let (decimals, total_supply) = self.tokens.get(contract)
+                        .ok_or_else(|| StdError::generic_err("Unknown token"))?;
+                    to_binary(&TokenInfoAnswer {
+                        token_info: TokenInfoAnswerInner {
+                            name: contract.to_string(),
+                            symbol: "TKN".to_string(),
+                            decimals: *decimals,
+                            total_supply: Some(Uint128(*total_supply)),
+                        },
+                    })
+                }
+                TestQuery::ValueOf { token, amount, .. } => {
+                    let (decimals, _) = self.tokens.get(&token)
+                        .ok_or_else(|| StdError::generic_err("Unknown token"))?;
+                    to_binary(&ValueOfResponse {
+                        value_of: crate::msg::ValueOf {
+                            value: Uint128(amount.u128() * self.token_value / 10_u128.pow((*decimals).into())),
+                        },
+                    })
+                }
+                TestQuery::TotalReserves {} => to_binary(&TotalReservesResponse {
+                    total_reserves: crate::msg::TotalReserves {
+                        total_reserves: Uint128(self.excess_reserves),
+                        excess_reserves: Uint128(self.excess_reserves),
+                        total_debt: Uint128(0),
+                    },
+                }),
+                TestQuery::Markdown { .. } => to_binary(&MarkdownResponse {
+                    markdown: crate::msg::Markdown {
+                        value: Uint128(self.markdown),
+                    },
+                }),
+            }
+        }
+    }
+
+    impl Querier for TestQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            let request: QueryRequest<Empty> = from_slice(bin_request).unwrap();
+            let (contract, msg) = match request {
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg, .. }) => (contract_addr, msg),
+                _ => return Ok(Err(StdError::generic_err("Only smart queries are supported"))),
+            };
+            Ok(from_binary(&msg).and_then(|query| self.answer(&contract, query)))
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum TestQuery {
+        TokenInfo {},
+        ValueOf { token: HumanAddr, amount: Uint128 },
+        TotalReserves {},
+        Markdown {},
+    }
+
+    #[derive(Serialize)]
+    struct TokenInfoAnswer {
+        token_info: TokenInfoAnswerInner,
+    }
+
+    #[derive(Serialize)]
+    struct TokenInfoAnswerInner {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        total_supply: Option<Uint128>,
+    }
+
+    type TestDeps = Extern<MockStorage, MockApi, TestQuerier>;
+
+    // Helper functions
+
+    fn contract(address: &str) -> Contract {
+        Contract{address:HumanAddr(address.to_string()),code_hash:"Complicated_hash".to_string()}
+    }
+
+    fn init_helper() -> (StdResult<InitResponse>, TestDeps) {
+        let mut deps = Extern {
+            storage: MockStorage::default(),
+            api: MockApi::new(20),
+            querier: TestQuerier::new(),
+        };
+        let init_msg = InitMsg {
+            name: "bond depository".to_string(),
+            symbol: "BOND".to_string(),
+            ohm: contract("ohm"),
+            treasury: contract("treasury"),
+            dao: HumanAddr("dao".to_string()),
+            admin: None,
+            prng_seed: Binary::from("lolz fun yay".as_bytes()),
+            terms_timelock: Some(100),
+        };
+
+        (init(&mut deps, mock_env("admin", &[]), init_msg), deps)
+    }
+
+    fn env_at(sender: &str, height: u64, time: u64) -> Env {
+        let mut env = mock_env(sender, &[]);
+        env.block.height = height;
+        env.block.time = time;
+        env
+    }
+
+    // Adds a market of `principle` and returns its id, LP markets are priced by the bond calculator
+    fn add_market_helper(deps: &mut TestDeps, principle: &str, inverse: bool) -> u64 {
+        let lp = principle.ends_with("-LP");
+        let handle_msg = HandleMsg::AddMarket {
+            principle: Principle {
+                token: contract(principle),
+                pair: if lp { Some(contract("sust-pair")) } else { None },
+            },
+            bond_calculator: if lp { Some(contract("calculator")) } else { None },
+            inverse: Some(inverse),
+        };
+        let handle_result = handle(deps, mock_env("admin", &[]), handle_msg).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::AddMarket { market_id, .. } => market_id,
+            _ => panic!("Unexpected result from handle"),
+        }
+    }
+
+    // Terms priced at their 200 minimum price at first, one OHM of value buys half an OHM
+    fn initialize_terms_msg(market_id: u64, vesting_term: u64, time_based: bool) -> HandleMsg {
+        HandleMsg::InitializeBondTerms {
+            market_id,
+            control_variable: Uint128(1_000),
+            vesting_term,
+            minimum_price: Uint128(200),
+            maximum_price: Uint128(1_000_000),
+            max_payout: Uint128(1_000),
+            fee: Uint128(100),
+            max_debt: Uint128(OHM_SUPPLY),
+            initial_debt: Uint128(0),
+            capacity: None,
+            time_based: Some(time_based),
+        }
+    }
+
+    fn market_helper(deps: &mut TestDeps, principle: &str) -> u64 {
+        let market_id = add_market_helper(deps, principle, false);
+        let handle_result = handle(deps, mock_env("admin", &[]), initialize_terms_msg(market_id, 21_600, false));
+        assert!(ensure_success(handle_result.unwrap()));
+        market_id
+    }
+
+    fn deposit_msg(market_id: u64) -> ReceiveMsg {
+        ReceiveMsg::Deposit {
+            market_id,
+            max_price: Uint128(1_000_000),
+            depositor: None,
+            referrer: None,
+            permit: None,
+            min_payout: None,
+            deadline_height: None,
+            deadline_time: None,
+        }
+    }
+
+    // `env.message.sender` is the token that was sent
+    fn receive_at(
+        deps: &mut TestDeps,
+        env: Env,
+        from: &str,
+        amount: u128,
+        msg: ReceiveMsg,
+    ) -> StdResult<HandleResponse> {
+        let handle_msg = HandleMsg::Receive {
+            sender: HumanAddr(from.to_string()),
+            from: HumanAddr(from.to_string()),
+            amount: Uint128(amount),
+            msg: Some(to_binary(&msg).unwrap()),
+        };
+        handle(deps, env, handle_msg)
+    }
+
+    fn deposit_helper(
+        deps: &mut TestDeps,
+        token: &str,
+        from: &str,
+        amount: u128,
+        market_id: u64,
+    ) -> StdResult<HandleResponse> {
+        receive_at(deps, mock_env(token, &[]), from, amount, deposit_msg(market_id))
+    }
+
+    fn notes(deps: &TestDeps, owner: &str) -> Vec<Bond> {
+        let owner = deps.api.canonical_address(&HumanAddr(owner.to_string())).unwrap();
+        ReadonlyBondInfo::from_storage(&deps.storage).bonds(&owner)
+    }
+
+    fn viewing_key_helper(deps: &mut TestDeps, account: &str) -> String {
+        let handle_msg = HandleMsg::CreateViewingKey {
+            entropy: "42".to_string(),
+            padding: None,
+        };
+        match from_binary(&handle(deps, mock_env(account, &[]), handle_msg).unwrap().data.unwrap()).unwrap() {
+            HandleAnswer::CreateViewingKey { key } => key.0,
+            _ => panic!("Unexpected result from handle"),
+        }
+    }
+
+    fn extract_error_msg<T: Any>(error: StdResult<T>) -> String {
+        match error {
+            Err(err) => match err {
+                StdError::GenericErr { msg, .. } => msg,
+                _ => panic!("Unexpected result from init"),
+            },
+            Ok(_) => "Very nice".to_string()
+        }
+    }
+
+    fn ensure_success(handle_result: HandleResponse) -> bool {
+        let handle_result: HandleAnswer = from_binary(&handle_result.data.unwrap()).unwrap();
+
+        match handle_result {
+            HandleAnswer::AddMarket { status, .. }
+            | HandleAnswer::CloseMarket { status }
+            | HandleAnswer::InitializeBondTerms { status }
+            | HandleAnswer::SetBondTerms { status, .. }
+            | HandleAnswer::ApplyBondTerms { status }
+            | HandleAnswer::SetAdjustment { status }
+            | HandleAnswer::SetController { status }
+            | HandleAnswer::RemoveController { status }
+            | HandleAnswer::SetPurchaseLimits { status }
+            | HandleAnswer::SetTwap { status }
+            | HandleAnswer::SetAllowlist { status }
+            | HandleAnswer::SetAllowedDepositor { status }
+            | HandleAnswer::RemoveAllowedDepositor { status }
+            | HandleAnswer::UpdateTwap { status }
+            | HandleAnswer::SetStaking { status }
+            | HandleAnswer::SetNativeWrapper { status }
+            | HandleAnswer::Deposit { status, .. }
+            | HandleAnswer::Redeem { status, .. }
+            | HandleAnswer::StakeOrSend { status }
+            | HandleAnswer::RecoverLostToken { status }
+            | HandleAnswer::SetReferrer { status }
+            | HandleAnswer::ClaimReferralRewards { status, .. }
+            | HandleAnswer::TransferBond { status }
+            | HandleAnswer::TransferBondFrom { status }
+            | HandleAnswer::ApproveBond { status }
+            | HandleAnswer::RevokeBondApproval { status }
+            | HandleAnswer::SetBenefactor { status }
+            | HandleAnswer::AddRecipient { status }
+            | HandleAnswer::RemoveRecipient { status }
+            | HandleAnswer::SetViewingKey { status }
+            | HandleAnswer::ChangeAdmin { status }
+            | HandleAnswer::SetContractStatus { status }
+            | HandleAnswer::SetPauseGuardian { status }
+            | HandleAnswer::RevokePermit { status } => {
+                matches!(status, ResponseStatus::Success)
+            }
+            HandleAnswer::CreateViewingKey { .. } => true,
+        }
+    }
+
+    // Init tests
+
+    #[test]
+    fn test_init_sanity() {
+        let (init_result, deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+
+        let constants = ReadonlyConfig::from_storage(&deps.storage).constants().unwrap();
+        assert_eq!(constants.ohm_decimals, 9);
+        assert_eq!(constants.admin, HumanAddr("admin".to_string()));
+        assert_eq!(constants.terms_timelock, 100);
+    }
+
+    // Handle tests
+
+    #[test]
+    fn test_deposit_native(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let sscrt_market = market_helper(&mut deps, "SSCRT");
+        let sust_market = market_helper(&mut deps, "sUST");
+        let deposit_msg = |market_id| HandleMsg::DepositNative {
+            market_id,
+            max_price: Uint128(1_000_000),
+            depositor: None,
+            referrer: None,
+            permit: None,
+            min_payout: None,
+            deadline_height: None,
+            deadline_time: None,
+        };
+        let uscrt = |amount: u128, denom: &str| vec![Coin{ denom: denom.to_string(), amount: Uint128(amount) }];
+
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(100_000_000, "uscrt")), deposit_msg(sscrt_market));
+        assert_eq!(extract_error_msg(handle_result), "Native deposits are not enabled");
+        let set_wrapper_msg = HandleMsg::SetNativeWrapper { token: Some(HumanAddr("SSCRT".to_string())) };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), set_wrapper_msg.clone());
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), set_wrapper_msg).unwrap()));
+
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(100_000_000, "uscrt")), deposit_msg(sust_market));
+        assert_eq!(extract_error_msg(handle_result), "This bond doesn't accept uscrt");
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(100_000_000, "uatom")), deposit_msg(sscrt_market));
+        assert_eq!(extract_error_msg(handle_result), "Only uscrt can be deposited");
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), deposit_msg(sscrt_market));
+        assert_eq!(extract_error_msg(handle_result), "Only uscrt can be deposited");
+
+        // 100 SCRT worth 100 OHM buy 50 OHM
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(100_000_000, "uscrt")), deposit_msg(sscrt_market)).unwrap();
+        match &handle_result.messages[0] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, send, .. }) => {
+                assert_eq!(*contract_addr, HumanAddr("SSCRT".to_string()));
+                assert_eq!(*send, uscrt(100_000_000, "uscrt"));
+            },
+            _ => panic!("Unexpected message"),
+        }
+        // then sent to the treasury by the bond depository
+        match &handle_result.messages[1] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, send, .. }) => {
+                assert_eq!(*contract_addr, HumanAddr("SSCRT".to_string()));
+                assert!(send.is_empty());
+            },
+            _ => panic!("Unexpected message"),
+        }
+        assert!(ensure_success(handle_result));
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!(alice_notes.len(), 1);
+        assert_eq!(alice_notes[0].payout, Uint128(50_000_000_000));
+    }
+
+    #[test]
+    fn test_bond_notes(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids: None,
+        });
+        assert_eq!(extract_error_msg(handle_result), "No bond contracted");
+
+        // The second bond doesn't delay the vesting of the first one
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let handle_result = receive_at(&mut deps, env_at("sUST", start + 10_800, time), "alice", 100_000_000, deposit_msg(market_id));
+        assert!(ensure_success(handle_result.unwrap()));
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!(alice_notes.iter().map(|note| (note.id, note.last_block, note.vesting)).collect::<Vec<_>>(), vec![
+            (1, start, 21_600),
+            (2, start + 10_800, 21_600),
+        ]);
+
+        let query_msg = QueryMsg::BondInfo {
+            address: HumanAddr("alice".to_string()),
+            block_height: start + 21_600,
+            block_time: None,
+            key: viewing_key_helper(&mut deps, "alice"),
+        };
+        match from_binary(&query(&deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::BondInfo { notes } => {
+                assert_eq!(notes.iter().map(|note| note.pending_payout).collect::<Vec<_>>(), vec![
+                    Uint128(50_000_000_000),
+                    Uint128(25_000_000_000),
+                ]);
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+
+        let redeem_msg = |note_ids| HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids,
+        };
+        let handle_result = handle(&mut deps, env_at("bob", start + 21_600, time), redeem_msg(Some(vec![3])));
+        assert_eq!(extract_error_msg(handle_result), "No bond note with id 3");
+
+        // The fully vested note is paid and dropped, the other one is untouched
+        let handle_result = handle(&mut deps, env_at("bob", start + 21_600, time), redeem_msg(Some(vec![1]))).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::Redeem { payout, .. } => assert_eq!(payout, Uint128(50_000_000_000)),
+            _ => panic!("Unexpected result from handle"),
+        }
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!(alice_notes.len(), 1);
+        assert_eq!((alice_notes[0].id, alice_notes[0].payout), (2, Uint128(50_000_000_000)));
+
+        let handle_result = handle(&mut deps, env_at("bob", start + 21_600, time), redeem_msg(None)).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::Redeem { payout, .. } => assert_eq!(payout, Uint128(25_000_000_000)),
+            _ => panic!("Unexpected result from handle"),
+        }
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!((alice_notes[0].payout, alice_notes[0].last_block, alice_notes[0].vesting), (Uint128(25_000_000_000), start + 21_600, 10_800));
+    }
+
+    #[test]
+    fn test_transfer_bond(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let accept_msg = |benefactor: &str, accepted| HandleMsg::SetBenefactor {
+            benefactor: HumanAddr(benefactor.to_string()),
+            accepted,
+            padding: None,
+        };
+
+        let transfer_msg = |recipient: &str| HandleMsg::TransferBond {
+            note_id: 1,
+            recipient: HumanAddr(recipient.to_string()),
+            padding: None,
+        };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), transfer_msg("bob"));
+        assert_eq!(extract_error_msg(handle_result), "No bond note with id 1");
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), transfer_msg("bob"));
+        assert_eq!(extract_error_msg(handle_result), "bob didn't accept bonds from alice");
+        assert!(ensure_success(handle(&mut deps, mock_env("bob", &[]), accept_msg("alice", true)).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), transfer_msg("bob")).unwrap()));
+        assert!(notes(&deps, "alice").is_empty());
+        let bob_notes = notes(&deps, "bob");
+        assert_eq!((bob_notes[0].id, bob_notes[0].payout, bob_notes[0].last_block), (1, Uint128(50_000_000_000), start));
+
+        // Spenders can move the note until their approval expires
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), HandleMsg::ApproveBond {
+            spender: HumanAddr("carol".to_string()),
+            note_id: 2,
+            expiration: None,
+            padding: None,
+        });
+        assert_eq!(extract_error_msg(handle_result), "No bond note with id 2");
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), HandleMsg::ApproveBond {
+            spender: HumanAddr("carol".to_string()),
+            note_id: 1,
+            expiration: Some(start + 10),
+            padding: None,
+        });
+        assert!(ensure_success(handle_result.unwrap()));
+        let transfer_from_msg = HandleMsg::TransferBondFrom {
+            owner: HumanAddr("bob".to_string()),
+            note_id: 1,
+            recipient: HumanAddr("alice".to_string()),
+            padding: None,
+        };
+        let handle_result = handle(&mut deps, mock_env("dave", &[]), transfer_from_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "You are not approved to transfer bond note 1");
+        let handle_result = handle(&mut deps, env_at("carol", start + 11, time), transfer_from_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "The approval for bond note 1 has expired");
+        // The recipient accepts the owner of the note, not the spender
+        let handle_result = handle(&mut deps, mock_env("carol", &[]), transfer_from_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from bob");
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), accept_msg("carol", true)).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("carol", &[]), transfer_from_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from bob");
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), accept_msg("bob", true)).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("carol", &[]), transfer_from_msg.clone()).unwrap()));
+        assert_eq!(notes(&deps, "alice")[0].id, 1);
+        // The approval was for bob's note
+        let handle_result = handle(&mut deps, mock_env("carol", &[]), transfer_from_msg);
+        assert_eq!(extract_error_msg(handle_result), "You are not approved to transfer bond note 1");
+
+        let key = viewing_key_helper(&mut deps, "bob");
+        let history_query = |page, page_size| QueryMsg::TransferHistory {
+            address: HumanAddr("bob".to_string()),
+            key: key.clone(),
+            page: Some(page),
+            page_size,
+        };
+        match from_binary(&query(&deps, history_query(0, 10)).unwrap()).unwrap() {
+            QueryAnswer::TransferHistory { txs, total } => {
+                assert_eq!(total, Some(2));
+                assert_eq!(txs.iter().map(|tx| (tx.from.0.as_str(), tx.sender.0.as_str(), tx.receiver.0.as_str())).collect::<Vec<_>>(), vec![
+                    ("bob", "carol", "alice"),
+                    ("alice", "alice", "bob"),
+                ]);
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+        match from_binary(&query(&deps, history_query(u32::MAX, u32::MAX)).unwrap()).unwrap() {
+            QueryAnswer::TransferHistory { txs, total } => {
+                assert!(txs.is_empty());
+                assert_eq!(total, Some(2));
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+    }
+
+    #[test]
+    fn test_markets(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let start = mock_env("admin", &[]).block.height;
+        let add_market_msg = |bond_calculator: Option<Contract>| HandleMsg::AddMarket {
+            principle: Principle { token: contract("sust-LP"), pair: None },
+            bond_calculator,
+            inverse: None,
+        };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), add_market_msg(None));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), add_market_msg(Some(contract("calculator"))));
+        assert_eq!(extract_error_msg(handle_result), "A market priced by a bond calculator needs a pair");
+
+        let sust_market = market_helper(&mut deps, "sUST");
+        let sscrt_market = add_market_helper(&mut deps, "SSCRT", false);
+        let mut terms_msg = initialize_terms_msg(sscrt_market, 21_600, false);
+        if let HandleMsg::InitializeBondTerms { capacity, .. } = &mut terms_msg {
+            *capacity = Some(Uint128(50_000_000_000));
+        }
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), terms_msg.clone()).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), terms_msg);
+        assert_eq!(extract_error_msg(handle_result), "Bonds must be initialized from 0");
+        let pending_market = add_market_helper(&mut deps, "SSCRT", false);
+
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, sscrt_market);
+        assert_eq!(extract_error_msg(handle_result), "This bond is only for the token: SSCRT");
+        let handle_result = deposit_helper(&mut deps, "SSCRT", "alice", 100_000_000, pending_market);
+        assert_eq!(extract_error_msg(handle_result), "The bond terms of market 2 were not initialized");
+
+        // Each market has its own debt and capacity
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, sust_market).unwrap()));
+        assert!(ensure_success(deposit_helper(&mut deps, "SSCRT", "alice", 100_000_000, sscrt_market).unwrap()));
+        let handle_result = deposit_helper(&mut deps, "SSCRT", "alice", 100_000_000, sscrt_market);
+        assert_eq!(extract_error_msg(handle_result), "Market capacity reached");
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!(config.market(sust_market).unwrap().total_debt, Uint128(100_000_000_000));
+        assert_eq!(config.market(sscrt_market).unwrap().sold, Uint128(50_000_000_000));
+
+        // Only the markets with terms and capacity left are listed
+        let markets_query = QueryMsg::Markets { block_height: start, block_time: None };
+        match from_binary(&query(&deps, markets_query.clone()).unwrap()).unwrap() {
+            QueryAnswer::Markets { markets } => {
+                assert_eq!(markets.iter().map(|market| (market.id, market.price)).collect::<Vec<_>>(), vec![(sust_market, Uint128(200))]);
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+
+        let close_msg = HandleMsg::CloseMarket { market_id: sust_market };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), close_msg.clone());
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), close_msg).unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, sust_market);
+        assert_eq!(extract_error_msg(handle_result), "The bond market 0 is closed");
+        match from_binary(&query(&deps, markets_query).unwrap()).unwrap() {
+            QueryAnswer::Markets { markets } => assert!(markets.is_empty()),
+            _ => panic!("Unexpected result from query"),
+        }
+    }
+
+    #[test]
+    fn test_purchase_limits(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let limits_msg = |window| HandleMsg::SetPurchaseLimits {
+            market_id,
+            limits: Some(PurchaseLimits {
+                max_payout_per_address: Some(Uint128(80_000_000_000)),
+                max_payout_per_window: Some(Uint128(120_000_000_000)),
+                window,
+            }),
+        };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), limits_msg(100));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), limits_msg(0));
+        assert_eq!(extract_error_msg(handle_result), "The purchase window must be at least one block");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), limits_msg(100)).unwrap()));
+
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "Maximum payout for this address reached, try again later");
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "bob", 100_000_000, market_id).unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "carol", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "Maximum payout for this block range reached, try again later");
+
+        let capacity_query = QueryMsg::RemainingCapacity {
+            market_id,
+            address: HumanAddr("alice".to_string()),
+            block_height: start,
+            block_time: None,
+        };
+        match from_binary(&query(&deps, capacity_query).unwrap()).unwrap() {
+            QueryAnswer::RemainingCapacity { address, window, market, allowlist } => {
+                assert_eq!(address, Some(Uint128(30_000_000_000)));
+                assert_eq!(window, Some(Uint128(20_000_000_000)));
+                assert_eq!((market, allowlist), (None, None));
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // The block range limit starts over with the next window, the address limit lasts a vesting term
+        let next_window = start - start % 100 + 100;
+        let handle_result = receive_at(&mut deps, env_at("sUST", next_window, time), "carol", 100_000_000, deposit_msg(market_id));
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = receive_at(&mut deps, env_at("sUST", next_window, time), "alice", 100_000_000, deposit_msg(market_id));
+        assert_eq!(extract_error_msg(handle_result), "Maximum payout for this address reached, try again later");
+    }
+
+    #[test]
+    fn test_time_based_vesting(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = add_market_helper(&mut deps, "sUST", false);
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+
+        // 36 hours are 21_600 blocks but 129_600 seconds
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), initialize_terms_msg(market_id, 21_600, true));
+        assert_eq!(extract_error_msg(handle_result), "Vesting must be longer than 36 hours");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), initialize_terms_msg(market_id, 129_600, true));
+        assert!(ensure_success(handle_result.unwrap()));
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!((alice_notes[0].last_block, alice_notes[0].vesting, alice_notes[0].time_based), (time, 129_600, true));
+
+        let key = viewing_key_helper(&mut deps, "alice");
+        let vested_query = |block_time| QueryMsg::PercentVestedFor {
+            address: HumanAddr("alice".to_string()),
+            note_id: 1,
+            block_height: start,
+            block_time,
+            key: key.clone(),
+        };
+        let query_result = query(&deps, vested_query(None));
+        assert_eq!(extract_error_msg(query_result), "This bond vests in seconds, the query needs a block_time");
+        match from_binary(&query(&deps, vested_query(Some(time + 64_800))).unwrap()).unwrap() {
+            QueryAnswer::PercentVestedFor { percent } => assert_eq!(percent, Uint128(5_000)),
+            _ => panic!("Unexpected result from query"),
+        }
+        // The debt decays in seconds too
+        let debt_query = QueryMsg::CurrentDebt { market_id, block_height: start, block_time: Some(time + 64_800) };
+        match from_binary(&query(&deps, debt_query).unwrap()).unwrap() {
+            QueryAnswer::CurrentDebt { debt } => assert_eq!(debt, Uint128(50_000_000_000)),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // Vesting doesn't depend on the blocks produced
+        let handle_result = handle(&mut deps, env_at("alice", start + 1_000_000, time + 64_800), HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids: None,
+        }).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::Redeem { payout, .. } => assert_eq!(payout, Uint128(25_000_000_000)),
+            _ => panic!("Unexpected result from handle"),
+        }
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!((alice_notes[0].last_block, alice_notes[0].vesting), (time + 64_800, 64_800));
+    }
+
+    #[test]
+    fn test_twap(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sust-LP");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let twap_msg = |window| HandleMsg::SetTwap {
+            market_id,
+            twap: Some(Twap { window, max_deviation: Uint128(500) }),
+        };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), twap_msg(0));
+        assert_eq!(extract_error_msg(handle_result), "The TWAP window can't be empty");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), twap_msg(1_000)).unwrap()));
+
+        let handle_result = deposit_helper(&mut deps, "sust-LP", "alice", 100_000_000_000, market_id);
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "Not enough markdown observations to cover the TWAP window, the TWAP needs to be updated"
+        );
+        // Observations are spaced by a 30th of the window
+        let update_msg = HandleMsg::UpdateTwap { market_id };
+        let handle_result = handle(&mut deps, env_at("bob", start + 33, time), update_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "The TWAP was updated less than 34 blocks (or seconds) ago");
+        assert!(ensure_success(handle(&mut deps, env_at("bob", start + 1_000, time), update_msg.clone()).unwrap()));
+
+        // A spot moved in the block is refused, and only moves the TWAP by the max deviation
+        deps.querier.markdown = 1_200_000_000;
+        let handle_result = receive_at(&mut deps, env_at("sust-LP", start + 1_000, time), "alice", 100_000_000_000, deposit_msg(market_id));
+        assert_eq!(extract_error_msg(handle_result), "The pair price moved too far from its TWAP, try again later");
+        assert!(ensure_success(handle(&mut deps, env_at("bob", start + 1_034, time), update_msg.clone()).unwrap()));
+        let observations = ReadonlyConfig::from_storage(&deps.storage).observations(market_id);
+        assert_eq!(observations.last().unwrap().markdown, Uint128(1_050_000_000));
+
+        deps.querier.markdown = 1_000_000_000;
+        let handle_result = receive_at(&mut deps, env_at("sust-LP", start + 1_100, time), "alice", 100_000_000_000, deposit_msg(market_id));
+        assert!(ensure_success(handle_result.unwrap()));
+        // The deposit doesn't record the spot
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).observations(market_id), observations);
+
+        // The observations are capped, and still cover the window
+        for update in 1..=100 {
+            assert!(ensure_success(handle(&mut deps, env_at("bob", start + 1_034 + update * 34, time), update_msg.clone()).unwrap()));
+        }
+        let now = start + 1_034 + 100 * 34;
+        assert!(ReadonlyConfig::from_storage(&deps.storage).observations(market_id).len() <= MAX_OBSERVATIONS as usize);
+        let twap_query = QueryMsg::Twap { market_id, block_height: now, block_time: None };
+        match from_binary(&query(&deps, twap_query).unwrap()).unwrap() {
+            QueryAnswer::Twap { spot, twap } => assert_eq!((spot, twap), (Uint128(1_000_000_000), Uint128(1_000_000_000))),
+            _ => panic!("Unexpected result from query"),
+        }
+    }
+
+    #[test]
+    fn test_inverse_bonds(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::AddMarket {
+            principle: Principle { token: contract("sust-LP"), pair: Some(contract("sust-pair")) },
+            bond_calculator: Some(contract("calculator")),
+            inverse: Some(true),
+        });
+        assert_eq!(extract_error_msg(handle_result), "Inverse markets pay a reserve token, they can't use a bond calculator");
+        let instant_market = add_market_helper(&mut deps, "sUST", true);
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), initialize_terms_msg(instant_market, 0, false)).unwrap()));
+        let vesting_market = add_market_helper(&mut deps, "sUST", true);
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), initialize_terms_msg(vesting_market, 21_600, false)).unwrap()));
+        // A sUST is worth 2 OHM
+        deps.querier.token_value = 2_000_000_000;
+
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, instant_market);
+        assert_eq!(extract_error_msg(handle_result), "This inverse bond is only for the token: ohm");
+        deps.querier.excess_reserves = 10_000_000_000;
+        let handle_result = deposit_helper(&mut deps, "ohm", "alice", 100_000_000_000, instant_market);
+        assert_eq!(extract_error_msg(handle_result), "Not enough excess reserves in the treasury");
+        deps.querier.excess_reserves = OHM_SUPPLY;
+
+        // Quoted with the treasury valuation of the reserves paid
+        let quote_query = |block_time| QueryMsg::QuoteDeposit {
+            market_id: instant_market,
+            amount: Uint128(100_000_000_000),
+            block_height: start,
+            block_time,
+        };
+        let query_result = query(&deps, quote_query(None));
+        assert_eq!(extract_error_msg(query_result), "The treasury values the deposit at a block time, the query needs a block_time");
+        match from_binary(&query(&deps, quote_query(Some(time))).unwrap()).unwrap() {
+            QueryAnswer::QuoteDeposit { payout, value, fee, .. } => {
+                assert_eq!((payout, value, fee), (Uint128(25_000_000), Uint128(50_000_000_000), Uint128(1_000_000_000)));
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // The treasury burns the value of the reserves, the rest of the OHM is burnt here but the fee
+        let handle_result = deposit_helper(&mut deps, "ohm", "alice", 100_000_000_000, instant_market).unwrap();
+        let messages: Vec<(HumanAddr, String)> = handle_result.messages.iter().map(|message| match message {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => (contract_addr.clone(), String::from_utf8(msg.0.clone()).unwrap()),
+            _ => panic!("Unexpected message"),
+        }).collect();
+        assert_eq!(messages.iter().map(|(token, _)| token.0.as_str()).collect::<Vec<_>>(), vec!["ohm", "ohm", "ohm", "sUST"]);
+        assert!(messages[0].1.contains("\"amount\":\"50000000000\""));
+        let withdraw_msg: TreasuryHandleMsg = from_binary(&Binary::from_base64(
+            messages[0].1.split("\"msg\":\"").nth(1).unwrap().split('"').next().unwrap()
+        ).unwrap()).unwrap();
+        assert_eq!(withdraw_msg, TreasuryHandleMsg::Withdraw {
+            token: HumanAddr("sUST".to_string()),
+            amount: Uint128(25_000_000),
+        });
+        assert!(messages[1].1.contains("\"burn\":{\"amount\":\"49000000000\""));
+        assert!(messages[3].1.contains("\"recipient\":\"alice\",\"amount\":\"25000000\""));
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::Deposit { payout, note_id, .. } => assert_eq!((payout, note_id), (Uint128(25_000_000), 0)),
+            _ => panic!("Unexpected result from handle"),
+        }
+        assert!(notes(&deps, "alice").is_empty());
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).market(instant_market).unwrap().sold, Uint128(50_000_000_000));
+
+        // The reserves withdrawn by the treasury come back without a message
+        let handle_result = handle(&mut deps, mock_env("sUST", &[]), HandleMsg::Receive {
+            sender: HumanAddr("treasury".to_string()),
+            from: HumanAddr("treasury".to_string()),
+            amount: Uint128(25_000_000),
+            msg: None,
+        });
+        assert!(handle_result.is_ok());
+        let handle_result = handle(&mut deps, mock_env("sUST", &[]), HandleMsg::Receive {
+            sender: HumanAddr("alice".to_string()),
+            from: HumanAddr("alice".to_string()),
+            amount: Uint128(25_000_000),
+            msg: None,
+        });
+        assert_eq!(extract_error_msg(handle_result), "Tokens can only be sent with a receive message");
+
+        // Vesting inverse bonds pay the reserves when redeemed
+        assert!(ensure_success(deposit_helper(&mut deps, "ohm", "alice", 100_000_000_000, vesting_market).unwrap()));
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!((alice_notes[0].payout, alice_notes[0].payout_token.clone()), (Uint128(25_000_000), Some(contract("sUST"))));
+        // The pending payout is only OHM, the vested sUST isn't counted in it
+        let pending_payout = |deps: &TestDeps| {
+            match from_binary(&query_pending_payout_for(deps, start + 21_600, Some(time), HumanAddr("alice".to_string())).unwrap()).unwrap() {
+                QueryAnswer::PendingPayoutFor { payout } => payout,
+                _ => panic!("Unexpected result from query"),
+            }
+        };
+        assert_eq!(pending_payout(&deps), Uint128(0));
+        let ohm_market = market_helper(&mut deps, "sUST");
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, ohm_market).unwrap()));
+        let ohm_note = notes(&deps, "alice").into_iter().find(|note| note.payout_token.is_none()).unwrap();
+        assert!(ohm_note.payout.u128() > 0);
+        assert_eq!(pending_payout(&deps), ohm_note.payout);
+        let handle_result = handle(&mut deps, env_at("alice", start + 21_600, time), HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids: Some(vec![alice_notes[0].id]),
+        }).unwrap();
+        assert_eq!(handle_result.messages.len(), 1);
+        match &handle_result.messages[0] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(*contract_addr, HumanAddr("sUST".to_string()));
+                assert!(String::from_utf8(msg.0.clone()).unwrap().contains("\"amount\":\"25000000\""));
+            },
+            _ => panic!("Unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_referrals(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let referrer_msg = |share| HandleMsg::SetReferrer {
+            address: HumanAddr("frontend".to_string()),
+            share,
+        };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), referrer_msg(Some(Uint128(2_000))));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), referrer_msg(Some(Uint128(10_001))));
+        assert_eq!(extract_error_msg(handle_result), "The referral share can't be more than the whole fee");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), referrer_msg(Some(Uint128(2_000)))).unwrap()));
+
+        let referred_msg = |referrer: &str| ReceiveMsg::Deposit {
+            market_id,
+            max_price: Uint128(1_000_000),
+            depositor: None,
+            referrer: Some(HumanAddr(referrer.to_string())),
+            permit: None,
+            min_payout: None,
+            deadline_height: None,
+            deadline_time: None,
+        };
+        // Unknown front-ends get nothing, the DAO gets the whole 0.5 OHM fee
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, referred_msg("unknown")).unwrap();
+        match &handle_result.messages[1] {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => {
+                assert!(String::from_utf8(msg.0.clone()).unwrap().contains("\"recipient\":\"dao\",\"amount\":\"500000000\""));
+            },
+            _ => panic!("Unexpected message"),
+        }
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, referred_msg("frontend")).unwrap();
+        match &handle_result.messages[1] {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => {
+                assert!(String::from_utf8(msg.0.clone()).unwrap().contains("\"recipient\":\"dao\",\"amount\":\"400000000\""));
+            },
+            _ => panic!("Unexpected message"),
+        }
+
+        let key = viewing_key_helper(&mut deps, "frontend");
+        let rewards_query = QueryMsg::ReferralRewards { address: HumanAddr("frontend".to_string()), key };
+        match from_binary(&query(&deps, rewards_query.clone()).unwrap()).unwrap() {
+            QueryAnswer::ReferralRewards { rewards } => assert_eq!(rewards, Uint128(100_000_000)),
+            _ => panic!("Unexpected result from query"),
+        }
+        let claim_msg = HandleMsg::ClaimReferralRewards { padding: None };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), claim_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "No referral rewards to claim");
+        let handle_result = handle(&mut deps, mock_env("frontend", &[]), claim_msg.clone()).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimReferralRewards { amount, .. } => assert_eq!(amount, Uint128(100_000_000)),
+            _ => panic!("Unexpected result from handle"),
+        }
+        match from_binary(&query(&deps, rewards_query).unwrap()).unwrap() {
+            QueryAnswer::ReferralRewards { rewards } => assert_eq!(rewards, Uint128(0)),
+            _ => panic!("Unexpected result from query"),
+        }
+        let handle_result = handle(&mut deps, mock_env("frontend", &[]), claim_msg);
+        assert_eq!(extract_error_msg(handle_result), "No referral rewards to claim");
+    }
+
+    #[test]
+    fn test_allowlist(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let allowlist_msg = |allowlist| HandleMsg::SetAllowlist { market_id, allowlist };
+        let allowlist = Allowlist {
+            issuer: Some(HumanAddr("issuer".to_string())),
+            permit_cap: Some(Uint128(100_000_000_000)),
+        };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), allowlist_msg(Some(allowlist.clone())));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), allowlist_msg(Some(allowlist))).unwrap()));
+
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "The bond market 0 is private and alice is not on its allowlist");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::SetAllowedDepositor {
+            market_id,
+            address: HumanAddr("alice".to_string()),
+            cap: Some(Uint128(80_000_000_000)),
+        });
+        assert!(ensure_success(handle_result.unwrap()));
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "Maximum payout for this address on this private market reached");
+        let capacity_query = |address: &str| QueryMsg::RemainingCapacity {
+            market_id,
+            address: HumanAddr(address.to_string()),
+            block_height: start,
+            block_time: None,
+        };
+        match from_binary(&query(&deps, capacity_query("alice")).unwrap()).unwrap() {
+            QueryAnswer::RemainingCapacity { allowlist, .. } => assert_eq!(allowlist, Some(Uint128(30_000_000_000))),
+            _ => panic!("Unexpected result from query"),
+        }
+        // Depositors without an entry can use a permit of the issuer, capped by the permit cap
+        match from_binary(&query(&deps, capacity_query("bob")).unwrap()).unwrap() {
+            QueryAnswer::RemainingCapacity { allowlist, .. } => assert_eq!(allowlist, Some(Uint128(100_000_000_000))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // Removed counterparties can't come back with a permit
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::RemoveAllowedDepositor {
+            market_id,
+            address: HumanAddr("alice".to_string()),
+        });
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "The bond market 0 is private and alice is not on its allowlist");
+        match from_binary(&query(&deps, capacity_query("alice")).unwrap()).unwrap() {
+            QueryAnswer::RemainingCapacity { allowlist, .. } => assert_eq!(allowlist, Some(Uint128(0))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // Permits are only valid for this contract
+        let permit = Permit {
+            params: secret_toolkit::permit::PermitParams {
+                allowed_tokens: vec![HumanAddr("other-bonds".to_string())],
+                permit_name: allowlist_permit_name(market_id, &HumanAddr("bob".to_string())),
+                chain_id: "secret-4".to_string(),
+                permissions: vec![],
+            },
+            signature: secret_toolkit::permit::PermitSignature {
+                pub_key: secret_toolkit::permit::PubKey {
+                    r#type: "tendermint/PubKeySecp256k1".to_string(),
+                    value: Binary::from(vec![2; 33]),
+                },
+                signature: Binary::from(vec![1; 64]),
+            },
+        };
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "bob", 100_000_000, ReceiveMsg::Deposit {
+            market_id,
+            max_price: Uint128(1_000_000),
+            depositor: None,
+            referrer: None,
+            permit: Some(permit),
+            min_payout: None,
+            deadline_height: None,
+            deadline_time: None,
+        });
+        assert!(extract_error_msg(handle_result).starts_with("Permit doesn't apply to token \"cosmos2contract\""));
+
+        // Public again
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), allowlist_msg(None)).unwrap()));
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "bob", 100_000_000, market_id).unwrap()));
+    }
+
+    #[test]
+    fn test_bond_history(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let key = viewing_key_helper(&mut deps, "alice");
+        let history_query = |key: &str, page, page_size| QueryMsg::BondHistory {
+            address: HumanAddr("alice".to_string()),
+            key: key.to_string(),
+            page,
+            page_size,
+        };
+        match from_binary(&query(&deps, history_query(&key, None, 10)).unwrap()).unwrap() {
+            QueryAnswer::BondHistory { txs, total } => assert_eq!((txs, total), (vec![], Some(0))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let handle_result = handle(&mut deps, env_at("alice", start + 21_600, time), HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids: None,
+        });
+        assert!(ensure_success(handle_result.unwrap()));
+
+        // The latest transaction comes first
+        match from_binary(&query(&deps, history_query(&key, None, 10)).unwrap()).unwrap() {
+            QueryAnswer::BondHistory { txs, total } => {
+                assert_eq!(total, Some(2));
+                assert_eq!(txs.iter().map(|tx| (tx.id, tx.block_height)).collect::<Vec<_>>(), vec![
+                    (2, start + 21_600),
+                    (1, start),
+                ]);
+                assert_eq!(txs[0].action, BondAction::Redeem {
+                    payout: Uint128(50_000_000_000),
+                    stake: false,
+                    reserves: vec![],
+                });
+                match &txs[1].action {
+                    BondAction::Deposit { market_id: deposit_market, note_id, token, amount, value, payout, fee, .. } => {
+                        assert_eq!((*deposit_market, *note_id), (market_id, 1));
+                        assert_eq!(token, &HumanAddr("sUST".to_string()));
+                        assert_eq!(
+                            (*amount, *value, *payout, *fee),
+                            (Uint128(100_000_000), Uint128(100_000_000_000), Uint128(50_000_000_000), Uint128(500_000_000))
+                        );
+                    },
+                    _ => panic!("Expected a deposit"),
+                }
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+        match from_binary(&query(&deps, history_query(&key, Some(1), 1)).unwrap()).unwrap() {
+            QueryAnswer::BondHistory { txs, .. } => assert_eq!(txs.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![1]),
+            _ => panic!("Unexpected result from query"),
+        }
+        // Pages past the end are empty, even when their offset doesn't fit in a u32
+        match from_binary(&query(&deps, history_query(&key, Some(u32::MAX), 10)).unwrap()).unwrap() {
+            QueryAnswer::BondHistory { txs, total } => assert_eq!((txs, total), (vec![], Some(2))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        match from_binary(&query(&deps, history_query("wrong key", None, 10)).unwrap()).unwrap() {
+            QueryAnswer::ViewingKeyError { msg } => assert_eq!(msg, "Wrong viewing key for this address or viewing key not set"),
+            _ => panic!("Unexpected result from query"),
+        }
+    }
+
+    #[test]
+    fn test_benefactors(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let accept_msg = |accepted| HandleMsg::SetBenefactor {
+            benefactor: HumanAddr("bob".to_string()),
+            accepted,
+            padding: None,
+        };
+        let on_behalf_msg = |depositor: &str| ReceiveMsg::Deposit {
+            market_id,
+            max_price: Uint128(1_000_000),
+            depositor: Some(HumanAddr(depositor.to_string())),
+            referrer: None,
+            permit: None,
+            min_payout: None,
+            deadline_height: None,
+            deadline_time: None,
+        };
+
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "bob", 100_000_000, on_behalf_msg("alice"));
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from bob");
+        // Bonding on behalf of oneself needs no acceptance
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "bob", 100_000_000, on_behalf_msg("bob"));
+        assert!(ensure_success(handle_result.unwrap()));
+
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), accept_msg(true)).unwrap()));
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "bob", 100_000_000, on_behalf_msg("alice"));
+        assert!(ensure_success(handle_result.unwrap()));
+        let alice_notes = notes(&deps, "alice");
+        assert_eq!((alice_notes[0].id, alice_notes[0].payout), (2, Uint128(50_000_000_000)));
+        // Accepting bob doesn't accept the other addresses
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "carol", 100_000_000, on_behalf_msg("alice"));
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from carol");
+
+        // The acceptance also covers the notes transferred by bob, until alice withdraws it
+        let transfer_msg = HandleMsg::TransferBond {
+            note_id: 1,
+            recipient: HumanAddr("alice".to_string()),
+            padding: None,
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), accept_msg(false)).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), transfer_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from bob");
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "bob", 100_000_000, on_behalf_msg("alice"));
+        assert_eq!(extract_error_msg(handle_result), "alice didn't accept bonds from bob");
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), accept_msg(true)).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("bob", &[]), transfer_msg).unwrap()));
+        assert_eq!(notes(&deps, "alice").iter().map(|note| note.id).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(notes(&deps, "bob").is_empty());
+    }
+
+    #[test]
+    fn test_controller(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let controller_msg = |target_debt_ratio, target_sold, step, min_control_variable, buffer| HandleMsg::SetController {
+            market_id,
+            target_debt_ratio,
+            target_sold,
+            step: Uint128(step),
+            min_control_variable: Uint128(min_control_variable),
+            max_control_variable: Uint128(2_000),
+            buffer,
+        };
+        let controller_query = |block_height| QueryMsg::Controller {
+            market_id,
+            block_height,
+            block_time: None,
+        };
+        let query_result = query(&deps, controller_query(start));
+        assert_eq!(extract_error_msg(query_result), "This market has no controller");
+
+        let target_sold = Some(Uint128(10_000_000_000));
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), controller_msg(None, target_sold, 1_000, 500, 100));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        for (msg, error) in [
+            (controller_msg(None, None, 1_000, 500, 100), "The controller needs a target debt ratio or a target of payout sold"),
+            (controller_msg(Some(Uint128(0)), target_sold, 1_000, 500, 100), "The controller targets can't be zero"),
+            (controller_msg(None, target_sold, 10_000, 500, 100), "The controller step must be less than 100%"),
+            (controller_msg(None, target_sold, 1_000, 3_000, 100), "The minimum control variable is above the maximum"),
+            (controller_msg(None, target_sold, 1_000, 500, 0), "The controller buffer can't be empty"),
+        ] {
+            assert_eq!(extract_error_msg(handle(&mut deps, mock_env("admin", &[]), msg)), error);
+        }
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), controller_msg(None, target_sold, 1_000, 500, 100));
+        assert!(ensure_success(handle_result.unwrap()));
+
+        let check_controller = |deps: &TestDeps, block_height, control_variable, sold_since_adjustment, next_adjustment| {
+            match from_binary(&query(deps, controller_query(block_height)).unwrap()).unwrap() {
+                QueryAnswer::Controller { control_variable: cv, sold_since_adjustment: sold, next_adjustment: next, .. } => assert_eq!(
+                    (cv, sold, next),
+                    (Uint128(control_variable), Uint128(sold_since_adjustment), next_adjustment)
+                ),
+                _ => panic!("Unexpected result from query"),
+            }
+        };
+        check_controller(&deps, start + 99, 1_000, 0, start + 100);
+        // Every buffer elapsed without sales lowers the control variable, with or without deposits
+        check_controller(&deps, start + 250, 810, 0, start + 300);
+
+        // The deposit is priced with the lazy adjustments, and its sales count in the next buffer
+        let handle_result = receive_at(&mut deps, env_at("sUST", start + 250, time), "alice", 100_000_000, deposit_msg(market_id));
+        assert!(ensure_success(handle_result.unwrap()));
+        let market = ReadonlyConfig::from_storage(&deps.storage).market(market_id).unwrap();
+        assert_eq!(market.terms().unwrap().control_variable, Uint128(810));
+        check_controller(&deps, start + 250, 810, 50_000_000_000, start + 300);
+        check_controller(&deps, start + 300, 891, 0, start + 400);
+        check_controller(&deps, start + 400, 801, 0, start + 500);
+        // The control variable stays within its bounds
+        check_controller(&deps, start + 2_000, 500, 0, start + 2_100);
+
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), HandleMsg::RemoveController { market_id }).unwrap()));
+        let query_result = query(&deps, controller_query(start + 2_000));
+        assert_eq!(extract_error_msg(query_result), "This market has no controller");
+    }
+
+    #[test]
+    fn test_terms_timelock(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let term_msg = |parameter, input| HandleMsg::SetBondTerm { market_id, parameter, input: Uint128(input) };
+
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), term_msg(BondParameter::Payout, 500));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        for (msg, error) in [
+            (term_msg(BondParameter::Vesting, 100), "Vesting must be longer than 36 hours"),
+            (term_msg(BondParameter::Payout, 0), "max payout can't be zero"),
+            (term_msg(BondParameter::Fee, 10_001), "Fee should not be higher than 100%"),
+            (term_msg(BondParameter::Debt, 0), "max debt can't be zero"),
+        ] {
+            assert_eq!(extract_error_msg(handle(&mut deps, mock_env("admin", &[]), msg)), error);
+        }
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), term_msg(BondParameter::Payout, 500)).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::SetBondTerms { activation, .. } => assert_eq!(activation, start + 100),
+            _ => panic!("Unexpected result from handle"),
+        }
+        // A new change of the same parameter replaces the pending one
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), term_msg(BondParameter::Fee, 200)).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), term_msg(BondParameter::Fee, 300)).unwrap()));
+        let pending_query = |block_height| QueryMsg::PendingTerms { market_id, block_height };
+        match from_binary(&query(&deps, pending_query(start + 99)).unwrap()).unwrap() {
+            QueryAnswer::PendingTerms { changes } => assert_eq!(changes, vec![
+                TermChange { parameter: BondParameter::Payout, input: Uint128(500), activation: start + 100 },
+                TermChange { parameter: BondParameter::Fee, input: Uint128(300), activation: start + 100 },
+            ]),
+            _ => panic!("Unexpected result from query"),
+        }
+        let handle_result = handle(&mut deps, env_at("alice", start + 99, time), HandleMsg::ApplyBondTerms { market_id });
+        assert_eq!(extract_error_msg(handle_result), "No bond terms change to apply");
+
+        // The reads see the changes that are due before anyone applies them
+        let terms_at = |deps: &TestDeps, block_height| {
+            let terms: Option<Terms> = from_binary(&query(deps, QueryMsg::BondTerms { market_id, block_height, block_time: None }).unwrap()).unwrap();
+            let terms = terms.unwrap();
+            (terms.max_payout.u128(), terms.fee.u128())
+        };
+        let max_payout_at = |deps: &TestDeps, block_height| {
+            match from_binary(&query(deps, QueryMsg::MaxPayout { market_id, block_height, block_time: None }).unwrap()).unwrap() {
+                QueryAnswer::MaxPayout { payout } => payout.u128(),
+                _ => panic!("Unexpected result from query"),
+            }
+        };
+        assert_eq!(terms_at(&deps, start + 99), (1_000, 100));
+        assert_eq!(max_payout_at(&deps, start + 99), 10_000_000_000_000);
+        assert_eq!(terms_at(&deps, start + 100), (500, 300));
+        assert_eq!(max_payout_at(&deps, start + 100), 5_000_000_000_000);
+        match from_binary(&query(&deps, pending_query(start + 100)).unwrap()).unwrap() {
+            QueryAnswer::PendingTerms { changes } => assert!(changes.is_empty()),
+            _ => panic!("Unexpected result from query"),
+        }
+        match from_binary(&query(&deps, QueryMsg::Markets { block_height: start + 100, block_time: None }).unwrap()).unwrap() {
+            QueryAnswer::Markets { markets } => assert_eq!(markets[0].terms.fee, Uint128(300)),
+            _ => panic!("Unexpected result from query"),
+        }
+        match from_binary(&query(&deps, QueryMsg::Market { market_id, block_height: start + 100, block_time: None }).unwrap()).unwrap() {
+            QueryAnswer::Market { market } => assert_eq!(market.terms.unwrap().max_payout, Uint128(500)),
+            _ => panic!("Unexpected result from query"),
+        }
+        let stored = ReadonlyConfig::from_storage(&deps.storage).market(market_id).unwrap();
+        assert_eq!(stored.terms.unwrap().fee, Uint128(100));
+
+        let quote_query = QueryMsg::QuoteDeposit {
+            market_id,
+            amount: Uint128(100_000_000),
+            block_height: start + 100,
+            block_time: Some(time),
+        };
+        match from_binary(&query(&deps, quote_query).unwrap()).unwrap() {
+            QueryAnswer::QuoteDeposit { payout, fee, .. } => assert_eq!((payout, fee), (Uint128(50_000_000_000), Uint128(1_500_000_000))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // The deposits apply them and record them in the history
+        let handle_result = receive_at(&mut deps, env_at("sUST", start + 100, time), "alice", 100_000_000, deposit_msg(market_id));
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = handle(&mut deps, env_at("alice", start + 100, time), HandleMsg::ApplyBondTerms { market_id });
+        assert_eq!(extract_error_msg(handle_result), "No bond terms change to apply");
+        let history_query = |page| QueryMsg::TermsHistory { market_id, page: Some(page), page_size: 10 };
+        match from_binary(&query(&deps, history_query(0)).unwrap()).unwrap() {
+            QueryAnswer::TermsHistory { changes, total } => {
+                assert_eq!(total, Some(2));
+                assert_eq!(changes.iter().map(|change| (change.input.u128(), change.block_height)).collect::<Vec<_>>(), vec![
+                    (300, start + 100),
+                    (500, start + 100),
+                ]);
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+        match from_binary(&query(&deps, history_query(u32::MAX)).unwrap()).unwrap() {
+            QueryAnswer::TermsHistory { changes, total } => assert_eq!((changes, total), (vec![], Some(2))),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // Heights and vesting terms past the u64 capacity are errors, not panics
+        let handle_result = handle(&mut deps, env_at("admin", u64::MAX - 99, time), term_msg(BondParameter::Payout, 500));
+        assert_eq!(extract_error_msg(handle_result), "The terms timelock would end after the last block");
+        assert!(ensure_success(handle(&mut deps, env_at("admin", u64::MAX - 100, time), term_msg(BondParameter::Payout, 500)).unwrap()));
+        let mut config = Config::from_storage(&mut deps.storage);
+        let mut market = config.market(market_id).unwrap();
+        market.pending_terms = vec![TermChange { parameter: BondParameter::Vesting, input: Uint128(u64::MAX as u128 + 1), activation: start + 200 }];
+        config.set_market(&market).unwrap();
+        let handle_result = handle(&mut deps, env_at("alice", start + 200, time), HandleMsg::ApplyBondTerms { market_id });
+        assert_eq!(extract_error_msg(handle_result), "The vesting term is above the u64 capacity");
+    }
+
+    #[test]
+    fn test_contract_status(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        let status_msg = |level| HandleMsg::SetContractStatus { level, padding: None };
+        let guardian_msg = |guardian: Option<&str>| HandleMsg::SetPauseGuardian {
+            guardian: guardian.map(|guardian| HumanAddr(guardian.to_string())),
+            padding: None,
+        };
+        let redeem_msg = HandleMsg::Redeem {
+            recipient: HumanAddr("alice".to_string()),
+            stake: false,
+            note_ids: None,
+        };
+
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), guardian_msg(Some("guardian")));
+        assert_eq!(
+            extract_error_msg(handle_result),
+            "This is an admin command. Admin commands can only be run from admin address"
+        );
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::StopAll));
+        assert_eq!(extract_error_msg(handle_result), "Only the admin and the pause guardian can change the contract status");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), guardian_msg(Some("guardian"))).unwrap()));
+        match from_binary(&query(&deps, QueryMsg::ContractStatus {}).unwrap()).unwrap() {
+            QueryAnswer::ContractStatus { status, pause_guardian } => {
+                assert_eq!(status, ContractStatusLevel::NormalRun);
+                assert_eq!(pause_guardian, Some(HumanAddr("guardian".to_string())));
+            },
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // Deposits and terms changes stop, the notes can still be redeemed
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::StopAllButRedeems));
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id);
+        assert_eq!(extract_error_msg(handle_result), "Deposits and market changes are stopped, only redeems are allowed");
+        for msg in [
+            initialize_terms_msg(market_id, 21_600, false),
+            HandleMsg::SetBondTerm { market_id, parameter: BondParameter::Fee, input: Uint128(200) },
+            HandleMsg::ApplyBondTerms { market_id },
+            HandleMsg::SetAdjustment { market_id, addition: true, increment: Uint128(1), target: Uint128(200), buffer: 1 },
+            HandleMsg::SetController {
+                market_id,
+                target_debt_ratio: None,
+                target_sold: None,
+                step: Uint128(1),
+                min_control_variable: Uint128(1),
+                max_control_variable: Uint128(1_000),
+                buffer: 1,
+            },
+            HandleMsg::RemoveController { market_id },
+            HandleMsg::SetPurchaseLimits { market_id, limits: None },
+            HandleMsg::SetTwap { market_id, twap: None },
+            HandleMsg::UpdateTwap { market_id },
+            HandleMsg::SetAllowlist { market_id, allowlist: None },
+            HandleMsg::SetAllowedDepositor { market_id, address: HumanAddr("bob".to_string()), cap: None },
+        ] {
+            let handle_result = handle(&mut deps, mock_env("admin", &[]), msg);
+            assert_eq!(extract_error_msg(handle_result), "Deposits and market changes are stopped, only redeems are allowed");
+        }
+        let handle_result = handle(&mut deps, env_at("alice", start + 10_800, time), redeem_msg.clone());
+        assert!(ensure_success(handle_result.unwrap()));
+
+        // The guardian can't restart the contract, nor keep its status
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::NormalRun));
+        assert_eq!(extract_error_msg(handle_result), "The pause guardian can only stop the contract further");
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::StopAllButRedeems));
+        assert_eq!(extract_error_msg(handle_result), "The pause guardian can only stop the contract further");
+        assert!(ensure_success(handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::StopAll)).unwrap()));
+        let handle_result = handle(&mut deps, env_at("alice", start + 21_600, time), redeem_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "This contract is stopped and this action is not allowed");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), guardian_msg(None));
+        assert_eq!(extract_error_msg(handle_result), "This contract is stopped and this action is not allowed");
+
+        // Only the admin restarts it
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), status_msg(ContractStatusLevel::NormalRun)).unwrap()));
+        assert!(ensure_success(handle(&mut deps, env_at("alice", start + 21_600, time), redeem_msg).unwrap()));
+        assert!(ensure_success(deposit_helper(&mut deps, "sUST", "alice", 100_000_000, market_id).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), guardian_msg(None)).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("guardian", &[]), status_msg(ContractStatusLevel::StopAll));
+        assert_eq!(extract_error_msg(handle_result), "Only the admin and the pause guardian can change the contract status");
+    }
+
+    #[test]
+    fn test_slippage_and_deadlines(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let market_id = market_helper(&mut deps, "sUST");
+        let start = mock_env("admin", &[]).block.height;
+        let time = mock_env("admin", &[]).block.time;
+        let quote_query = |market_id, block_time| QueryMsg::QuoteDeposit {
+            market_id,
+            amount: Uint128(100_000_000),
+            block_height: start,
+            block_time,
+        };
+        let query_result = query(&deps, quote_query(market_id, None));
+        assert_eq!(extract_error_msg(query_result), "The treasury values the deposit at a block time, the query needs a block_time");
+        let query_result = query(&deps, quote_query(99, Some(time)));
+        assert_eq!(extract_error_msg(query_result), "No bond market with id 99");
+        match from_binary(&query(&deps, quote_query(market_id, Some(time))).unwrap()).unwrap() {
+            QueryAnswer::QuoteDeposit { price, value, payout, fee } => assert_eq!(
+                (price, value, payout, fee),
+                (Uint128(200), Uint128(100_000_000_000), Uint128(50_000_000_000), Uint128(500_000_000))
+            ),
+            _ => panic!("Unexpected result from query"),
+        }
+
+        // The quote is what the deposit pays, the limits are checked against it
+        let limited_msg = |max_price, min_payout: Option<u128>, deadline_height, deadline_time| ReceiveMsg::Deposit {
+            market_id,
+            max_price: Uint128(max_price),
+            depositor: None,
+            referrer: None,
+            permit: None,
+            min_payout: min_payout.map(Uint128),
+            deadline_height,
+            deadline_time,
+        };
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, limited_msg(199, None, None, None));
+        assert_eq!(extract_error_msg(handle_result), "Slippage limit: more than max price");
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, limited_msg(200, Some(50_000_000_001), None, None));
+        assert_eq!(extract_error_msg(handle_result), "Slippage limit: less than min payout");
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, limited_msg(200, Some(50_000_000_000), None, None)).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::Deposit { payout, .. } => assert_eq!(payout, Uint128(50_000_000_000)),
+            _ => panic!("Unexpected result from handle"),
+        }
+
+        // Deposits are accepted up to their deadline included
+        for (deadline_height, deadline_time) in [(Some(start - 1), None), (None, Some(time - 1)), (Some(start), Some(time - 1))] {
+            let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, limited_msg(200, None, deadline_height, deadline_time));
+            assert_eq!(extract_error_msg(handle_result), "The deposit deadline has passed");
+        }
+        let handle_result = receive_at(&mut deps, mock_env("sUST", &[]), "alice", 100_000_000, limited_msg(200, None, Some(start), Some(time)));
+        assert!(ensure_success(handle_result.unwrap()));
+        assert_eq!(notes(&deps, "alice").len(), 2);
+    }
+}
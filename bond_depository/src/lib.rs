@@ -3,6 +3,7 @@ pub mod contract;
 pub mod msg;
 mod rand;
 pub mod state;
+mod transaction_history;
 mod utils;
 mod viewing_key;
 
@@ -3,7 +3,8 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::{Contract, Principle, Adjust, Terms};
+use crate::state::{Contract, Principle, Market, PurchaseLimits, Terms, Twap, Allowlist, Controller, TermChange};
+use crate::transaction_history::{BondTransfer, BondTx, AppliedTermChange};
 use crate::viewing_key::ViewingKey;
 use cosmwasm_std::{Binary, HumanAddr, StdError, StdResult, Uint128};
 use secret_toolkit::permit::Permit;
@@ -18,15 +19,15 @@ pub struct InitMsg {
     pub name: String,
     pub symbol: String,
     pub ohm: Contract,
-    pub principle: Principle,
     pub treasury: Contract,
     pub dao: HumanAddr,
-    pub bond_calculator: Option<Contract>,
     pub admin: Option<HumanAddr>,
     pub prng_seed: Binary,
+    // Blocks between a change of the bond terms and its activation, a day by default
+    pub terms_timelock: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum BondParameter { Vesting, Payout, Fee, Debt } 
 
@@ -34,8 +35,19 @@ pub enum BondParameter { Vesting, Payout, Fee, Debt }
 #[serde(rename_all = "snake_case")]
 pub enum ReceiveMsg{
     Deposit{
+        market_id: u64,
         max_price: Uint128,
+        // Bond on behalf of another address, which has to accept the sender with SetBenefactor
         depositor: Option<HumanAddr>,
+        // Approved front-end routing the bond, paid a share of the fee
+        referrer: Option<HumanAddr>,
+        // Signed by the issuer of a private market to authorize the depositor
+        permit: Option<Permit>,
+        // OHM, or reserves for inverse bonds
+        min_payout: Option<Uint128>,
+        // The deposit is refused after these
+        deadline_height: Option<u64>,
+        deadline_time: Option<u64>,
     },
 }
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
@@ -45,9 +57,20 @@ pub enum HandleMsg {
         sender: HumanAddr,
         from: HumanAddr,
         amount: Uint128,
-        msg: Binary,
+        // None when the treasury pays out the reserves of an inverse bond
+        msg: Option<Binary>,
+    },
+    AddMarket{
+        principle: Principle,
+        bond_calculator: Option<Contract>,
+        // OHM is sold to the market for principle taken from the treasury
+        inverse: Option<bool>,
+    },
+    CloseMarket{
+        market_id: u64,
     },
     InitializeBondTerms{
+        market_id: u64,
         control_variable: Uint128, 
         vesting_term: u64,
         minimum_price: Uint128,
@@ -56,31 +79,141 @@ pub enum HandleMsg {
         fee: Uint128,
         max_debt: Uint128, 
         initial_debt: Uint128, 
+        capacity: Option<Uint128>,
+        // vesting, decay and adjustments in seconds instead of blocks
+        time_based: Option<bool>,
     },
     SetBondTerm{
+        market_id: u64,
         parameter: BondParameter,
         input: Uint128,
     },
+    // Applies the term changes whose activation height is reached, deposits do it too
+    ApplyBondTerms{
+        market_id: u64,
+    },
     SetAdjustment{
+        market_id: u64,
         addition: bool,
         increment: Uint128,
         target: Uint128,
         buffer: u64,
     },
+    SetController{
+        market_id: u64,
+        target_debt_ratio: Option<Uint128>,
+        target_sold: Option<Uint128>,
+        step: Uint128,
+        min_control_variable: Uint128,
+        max_control_variable: Uint128,
+        buffer: u64,
+    },
+    RemoveController{
+        market_id: u64,
+    },
+    SetPurchaseLimits{
+        market_id: u64,
+        limits: Option<PurchaseLimits>,
+    },
+    SetTwap{
+        market_id: u64,
+        twap: Option<Twap>,
+    },
+    SetAllowlist{
+        market_id: u64,
+        // The market is public if None
+        allowlist: Option<Allowlist>,
+    },
+    SetAllowedDepositor{
+        market_id: u64,
+        address: HumanAddr,
+        cap: Option<Uint128>,
+    },
+    RemoveAllowedDepositor{
+        market_id: u64,
+        address: HumanAddr,
+    },
+    // Records the markdown of the pair, allowed once per 30th of the TWAP window
+    UpdateTwap{
+        market_id: u64,
+    },
     SetStaking{
         staking: Contract,
     },
+    // Markets of this token accept native uscrt, None disables native deposits
+    SetNativeWrapper{
+        token: Option<HumanAddr>,
+    },
+    // Deposit of the uscrt sent with the message, wrapped before being bonded like the wrapper token.
+    // The fields are the ones of `ReceiveMsg::Deposit`
+    DepositNative{
+        market_id: u64,
+        max_price: Uint128,
+        depositor: Option<HumanAddr>,
+        referrer: Option<HumanAddr>,
+        permit: Option<Permit>,
+        min_payout: Option<Uint128>,
+        deadline_height: Option<u64>,
+        deadline_time: Option<u64>,
+    },
     Redeem{
         recipient:  HumanAddr,
         stake: bool,
+        // Notes to redeem, all the notes of the recipient if None
+        note_ids: Option<Vec<u64>>,
     },
     RecoverLostToken{
         token: Contract
     },
+    SetReferrer{
+        address: HumanAddr,
+        // Share of the fee paid to the front-end, in basis points. None removes it from the registry
+        share: Option<Uint128>,
+    },
+    ClaimReferralRewards{
+        padding: Option<String>,
+    },
+    // The recipient has to accept the owner of the note with SetBenefactor
+    TransferBond{
+        note_id: u64,
+        recipient: HumanAddr,
+        padding: Option<String>,
+    },
+    // Same acceptance by the recipient as TransferBond
+    TransferBondFrom{
+        owner: HumanAddr,
+        note_id: u64,
+        recipient: HumanAddr,
+        padding: Option<String>,
+    },
+    ApproveBond{
+        spender: HumanAddr,
+        note_id: u64,
+        expiration: Option<u64>,
+        padding: Option<String>,
+    },
+    RevokeBondApproval{
+        note_id: u64,
+        padding: Option<String>,
+    },
+    SetBenefactor{
+        benefactor: HumanAddr,
+        accepted: bool,
+        padding: Option<String>,
+    },
     ChangeAdmin {
         address: HumanAddr,
         padding: Option<String>,
     },
+    // The pause guardian can only stop the contract further
+    SetContractStatus {
+        level: ContractStatusLevel,
+        padding: Option<String>,
+    },
+    SetPauseGuardian {
+        guardian: Option<HumanAddr>,
+        padding: Option<String>,
+    },
     RevokePermit {
         permit_name: String,
         padding: Option<String>,
@@ -99,21 +232,60 @@ pub enum HandleMsg {
 #[serde(rename_all = "snake_case")]
 pub enum HandleAnswer {
 
+    AddMarket{
+        status: ResponseStatus,
+        market_id: u64,
+    },
+    CloseMarket{
+        status: ResponseStatus,
+    },
     InitializeBondTerms{
         status: ResponseStatus,
     },
     SetBondTerms{
         status: ResponseStatus,
+        activation: u64,
+    },
+    ApplyBondTerms{
+        status: ResponseStatus,
     },
     SetAdjustment{
         status: ResponseStatus,
     },
+    SetController{
+        status: ResponseStatus,
+    },
+    RemoveController{
+        status: ResponseStatus,
+    },
+    SetPurchaseLimits{
+        status: ResponseStatus,
+    },
+    SetTwap{
+        status: ResponseStatus,
+    },
+    SetAllowlist{
+        status: ResponseStatus,
+    },
+    SetAllowedDepositor{
+        status: ResponseStatus,
+    },
+    RemoveAllowedDepositor{
+        status: ResponseStatus,
+    },
+    UpdateTwap{
+        status: ResponseStatus,
+    },
     SetStaking{
         status: ResponseStatus,
     },
+    SetNativeWrapper{
+        status: ResponseStatus,
+    },
     Deposit{
         status: ResponseStatus,
-        payout: Uint128
+        payout: Uint128,
+        note_id: u64, // 0 when an inverse bond is paid instantly
     },
     Redeem{
         status: ResponseStatus,
@@ -125,6 +297,28 @@ pub enum HandleAnswer {
     RecoverLostToken{
         status: ResponseStatus,
     },
+    SetReferrer{
+        status: ResponseStatus,
+    },
+    ClaimReferralRewards{
+        status: ResponseStatus,
+        amount: Uint128,
+    },
+    TransferBond{
+        status: ResponseStatus,
+    },
+    TransferBondFrom{
+        status: ResponseStatus,
+    },
+    ApproveBond{
+        status: ResponseStatus,
+    },
+    RevokeBondApproval{
+        status: ResponseStatus,
+    },
+    SetBenefactor{
+        status: ResponseStatus,
+    },
 
 
 
@@ -151,6 +345,9 @@ pub enum HandleAnswer {
     SetContractStatus {
         status: ResponseStatus,
     },
+    SetPauseGuardian {
+        status: ResponseStatus,
+    },
 
     // Permit
     RevokePermit {
@@ -163,43 +360,111 @@ pub enum HandleAnswer {
 pub enum QueryMsg {
     TokenInfo {},
     ContractInfo {},
-    MaxPayout{},
+    ContractStatus {},
+    Market{
+        market_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    Markets{
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    MaxPayout{
+        market_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    RemainingCapacity{
+        market_id: u64,
+        address: HumanAddr,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
     PayoutFor{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
         value: Uint128
     },
     BondPrice{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     BondPriceInUsd{
+        market_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    Twap{
+        market_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    Controller{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     DebtRatio{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     StandardizedDebtRatio{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     CurrentDebt{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     DebtDecay{
+        market_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
     },
     BondInfo{
         address: HumanAddr,
+        block_height: u64,
+        block_time: Option<u64>,
         key: String,
     },
     PercentVestedFor{
         address: HumanAddr,
+        note_id: u64,
         block_height: u64,
+        block_time: Option<u64>,
         key: String,
     },
     PendingPayoutFor{
         address: HumanAddr,
         block_height: u64,
+        block_time: Option<u64>,
         key: String,
     }, 
+    TransferHistory{
+        address: HumanAddr,
+        key: String,
+        page: Option<u32>,
+        page_size: u32,
+    },
+    BondHistory{
+        address: HumanAddr,
+        key: String,
+        page: Option<u32>,
+        page_size: u32,
+    },
+    ReferralRewards{
+        address: HumanAddr,
+        key: String,
+    },
+    Referrer{
+        address: HumanAddr,
+    },
     WithPermit {
         permit: Permit,
         query: QueryWithPermit,
@@ -208,16 +473,40 @@ pub enum QueryMsg {
         address: HumanAddr,
         key: String,
     },
-    BondTerms{},
+    BondTerms{
+        market_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
+    },
+    QuoteDeposit{
+        market_id: u64,
+        amount: Uint128,
+        block_height: u64,
+        // Needed by time based markets, and by the treasury to value the principle or the reserves paid
+        block_time: Option<u64>,
+    },
+    // The changes due at `block_height` are already part of the terms
+    PendingTerms{
+        market_id: u64,
+        block_height: u64,
+    },
+    TermsHistory{
+        market_id: u64,
+        page: Option<u32>,
+        page_size: u32,
+    },
 }
 
 impl QueryMsg {
     pub fn get_validation_params(&self) -> (Vec<&HumanAddr>, ViewingKey) {
         match self {
             Self::Balance { address, key } => (vec![address], ViewingKey(key.clone())),
-            Self::BondInfo { address, key } => (vec![address], ViewingKey(key.clone())),
+            Self::BondInfo { address, key, .. } => (vec![address], ViewingKey(key.clone())),
             Self::PercentVestedFor { address, key, .. } => (vec![address], ViewingKey(key.clone())),
             Self::PendingPayoutFor { address, key, .. } => (vec![address], ViewingKey(key.clone())),
+            Self::TransferHistory { address, key, .. } => (vec![address], ViewingKey(key.clone())),
+            Self::BondHistory { address, key, .. } => (vec![address], ViewingKey(key.clone())),
+            Self::ReferralRewards { address, key } => (vec![address], ViewingKey(key.clone())),
             _ => panic!("This query type does not require authentication"),
         }
     }
@@ -226,13 +515,28 @@ impl QueryMsg {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryWithPermit { 
-    BondInfo{},
+    BondInfo{
+        block_height: u64,
+        block_time: Option<u64>,
+    },
     PercentVestedFor{
-        block_height: u64
+        note_id: u64,
+        block_height: u64,
+        block_time: Option<u64>,
     },
     PendingPayoutFor{
-        block_height: u64
+        block_height: u64,
+        block_time: Option<u64>,
     }, 
+    TransferHistory{
+        page: Option<u32>,
+        page_size: u32,
+    },
+    BondHistory{
+        page: Option<u32>,
+        page_size: u32,
+    },
+    ReferralRewards{},
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
@@ -246,23 +550,32 @@ pub enum QueryAnswer {
     },
     ContractInfo {
         ohm: Contract,
-        principle: Principle,
         treasury: Contract,
         dao: HumanAddr,
-        bond_calculator: Option<Contract>,
 
         staking: Option<Contract>,
 
-        terms: Option<Terms>,
-        adjustment: Option<Adjust>,
-
         admin: HumanAddr,
-        total_debt: Uint128,
-        last_decay: u64
+        market_count: u64,
+        terms_timelock: u64,
+    },
+    Market{
+        market: Market,
+    },
+    Markets{
+        markets: Vec<MarketInfo>,
     },
     MaxPayout{
         payout: Uint128
     },
+    // None when the corresponding limit is not set
+    RemainingCapacity{
+        address: Option<Uint128>,
+        window: Option<Uint128>,
+        market: Option<Uint128>,
+        // left to the address on a private market, None if it is not capped
+        allowlist: Option<Uint128>,
+    },
     PayoutFor{
         payout: Uint128
     },
@@ -272,6 +585,10 @@ pub enum QueryAnswer {
     BondPriceInUsd{
         price: Uint128
     },
+    Twap{
+        spot: Uint128,
+        twap: Uint128,
+    },
     DebtRatio{
         ratio: Uint128
     },
@@ -284,11 +601,8 @@ pub enum QueryAnswer {
     DebtDecay{
         decay: Uint128
     },
-    Bond{
-        payout: Uint128, 
-        vesting: u64, 
-        last_block: u64, 
-        price_paid: Uint128, 
+    BondInfo{
+        notes: Vec<Note>,
     },
     PercentVestedFor{
         percent: Uint128
@@ -296,6 +610,44 @@ pub enum QueryAnswer {
     PendingPayoutFor{
         payout: Uint128
     },
+    TransferHistory{
+        txs: Vec<BondTransfer>,
+        total: Option<u64>,
+    },
+    BondHistory{
+        txs: Vec<BondTx>,
+        total: Option<u64>,
+    },
+    ReferralRewards{
+        rewards: Uint128,
+    },
+    ContractStatus {
+        status: ContractStatusLevel,
+        pause_guardian: Option<HumanAddr>,
+    },
+    QuoteDeposit{
+        price: Uint128,
+        value: Uint128, // OHM value of the principle, or of the reserves paid by inverse bonds
+        payout: Uint128, // OHM, or reserves for inverse bonds
+        fee: Uint128, // OHM
+    },
+    PendingTerms{
+        changes: Vec<TermChange>,
+    },
+    TermsHistory{
+        changes: Vec<AppliedTermChange>,
+        total: Option<u64>,
+    },
+    Controller{
+        controller: Controller,
+        control_variable: Uint128,
+        standardized_debt_ratio: Uint128,
+        sold_since_adjustment: Uint128,
+        next_adjustment: u64, // on the market clock
+    },
+    Referrer{
+        share: Option<Uint128>,
+    },
     ViewingKeyError {
         msg: String,
     },
@@ -304,6 +656,35 @@ pub enum QueryAnswer {
     },
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct MarketInfo {
+    pub id: u64,
+    pub principle: Principle,
+    pub terms: Terms,
+    pub price: Uint128,
+    pub price_in_usd: Uint128,
+    pub current_debt: Uint128,
+    pub remaining_capacity: Option<Uint128>,
+    pub inverse: bool,
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Note {
+    pub id: u64,
+    pub market_id: u64,
+    pub time_based: bool,
+    pub payout: Uint128,
+    pub vesting: u64,
+    pub last_block: u64,
+    pub price_paid: Uint128,
+    pub percent_vested: Uint128,
+    pub pending_payout: Uint128,
+    pub payout_token: Option<Contract>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct CreateViewingKeyResponse {
     pub key: String,
@@ -335,7 +716,11 @@ pub enum TreasuryHandleMsg{
     },
     Deposit{
         profit: Uint128,
-    }
+    },
+    Withdraw{
+        token: HumanAddr,
+        amount: Uint128,
+    },
 }
 
 impl HandleCallback for TreasuryHandleMsg{
@@ -384,8 +769,10 @@ pub struct MarkdownResponse{
 pub enum TreasuryQueryMsg{
     ValueOf{
         token: HumanAddr,
-        amount: Uint128
+        amount: Uint128,
+        block_time: u64
     },
+    TotalReserves{},
 }
 
 impl Query for TreasuryQueryMsg {
@@ -401,6 +788,17 @@ pub struct ValueOfResponse{
     pub value_of: ValueOf,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TotalReserves{
+    pub total_reserves: Uint128,
+    pub excess_reserves: Uint128,
+    pub total_debt: Uint128,
+}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TotalReservesResponse{
+    pub total_reserves: TotalReserves,
+}
+
 
 pub fn status_level_to_u8(status_level: ContractStatusLevel) -> u8 {
     match status_level {
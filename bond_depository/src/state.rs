@@ -7,21 +7,36 @@ use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage, bucket, bucket_
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::msg::{status_level_to_u8, u8_to_status_level, BondParameter, ContractStatusLevel};
 use crate::viewing_key::ViewingKey;
 use serde::de::DeserializeOwned;
 
 pub static CONFIG_KEY: &[u8] = b"config";
 pub const PREFIX_TXS: &[u8] = b"transfers";
+pub const PREFIX_BOND_TXS: &[u8] = b"bond_txs";
+pub const PREFIX_TERMS_HISTORY: &[u8] = b"terms_history";
 
 pub const KEY_CONSTANTS: &[u8] = b"constants";
-pub const KEY_TOTAL_DEBT: &[u8] = b"total_debt";
-pub const KEY_LAST_DECAY: &[u8] = b"last_decay";
 pub const KEY_INFO: &[u8] = b"info";
 pub const KEY_ADJUSTMENTS: &[u8] = b"adjustments";
+pub const KEY_BOND_COUNT: &[u8] = b"bond_count";
+pub const KEY_TX_COUNT: &[u8] = b"tx_count";
+pub const KEY_MARKETS: &[u8] = b"markets";
+pub const KEY_MARKET_COUNT: &[u8] = b"market_count";
+pub const KEY_PURCHASES: &[u8] = b"purchases";
+pub const KEY_OBSERVATIONS: &[u8] = b"observations";
+pub const KEY_REFERRERS: &[u8] = b"referrers";
+pub const KEY_REFERRAL_REWARDS: &[u8] = b"referral_rewards";
+pub const KEY_ALLOWLISTS: &[u8] = b"allowlists";
+pub const KEY_BENEFACTORS: &[u8] = b"benefactors";
+pub const KEY_CONTRACT_STATUS: &[u8] = b"contract_status";
+pub const KEY_PAUSE_GUARDIAN: &[u8] = b"pause_guardian";
+pub const KEY_NATIVE_WRAPPER: &[u8] = b"native_wrapper";
 
 pub const PREFIX_CONFIG: &[u8] = b"config";
 pub const PREFIX_BONDS: &[u8] = b"bonds";
 pub const PREFIX_VIEW_KEY: &[u8] = b"viewingkey";
+pub const PREFIX_BOND_APPROVALS: &[u8] = b"bond_approvals";
 
 
 
@@ -32,30 +47,156 @@ pub struct Constants {
     pub symbol: String,
     pub ohm: Contract,
     pub ohm_decimals: u8,
-    pub principle: Principle,
-    pub principle_decimals: u8,
     pub treasury: Contract,
     pub dao: HumanAddr,
-    pub bond_calculator: Option<Contract>,
 
     pub staking: Option<Contract>,
 
+    pub admin: HumanAddr,
+    pub prng_seed: Vec<u8>,
+    pub contract_address: HumanAddr,
+
+    pub terms_timelock: u64, // blocks between a change of the bond terms and its activation
+}
+
+
+// A bond market, each market sells OHM against its own principle token
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Market {
+    pub id: u64,
+    pub principle: Principle,
+    pub principle_decimals: u8,
+    pub bond_calculator: Option<Contract>,
+
     pub terms: Option<Terms>,
+    pub pending_terms: Vec<TermChange>, // applied once their activation height is reached
     pub adjustment: Option<Adjust>,
+    pub controller: Option<Controller>, // replaces the admin set adjustment when set
 
-    pub admin: HumanAddr,
-    pub prng_seed: Vec<u8>,
-    pub contract_address: HumanAddr
+    pub capacity: Option<Uint128>, // max OHM payout sold by the market, unlimited if None
+    pub sold: Uint128, // OHM payout already sold by the market
+    pub total_debt: Uint128, // value of the bonds sold, decaying over the vesting term
+    pub last_decay: u64, // last debt decay, on the market clock
+    pub closed: bool,
+    // When set, vesting, debt decay, adjustment buffers and limit windows are in seconds instead of blocks
+    pub time_based: bool,
+
+    pub limits: Option<PurchaseLimits>,
+    pub window_usage: PurchaseUsage, // payout sold during the current `limits.window`
+
+    pub twap: Option<Twap>, // LP markets priced with a time weighted markdown when set
+
+    // Inverse markets buy OHM back and pay the principle out of the treasury excess reserves.
+    // Their capacity, debt and limits are then in OHM value of the reserves paid.
+    pub inverse: bool,
+
+    pub allowlist: Option<Allowlist>, // private markets only sell to their allowlist
+}
+
+/// Block time for time based markets and notes, block height otherwise
+fn clock(time_based: bool, block_height: u64, block_time: u64) -> u64 {
+    if time_based {
+        block_time
+    } else {
+        block_height
+    }
+}
+
+impl Market {
+    pub fn clock(&self, block_height: u64, block_time: u64) -> u64 {
+        clock(self.time_based, block_height, block_time)
+    }
+
+    pub fn terms(&self) -> StdResult<&Terms> {
+        self.terms.as_ref().ok_or_else(||{
+            StdError::generic_err(format!("The bond terms of market {} were not initialized", self.id))
+        })
+    }
+
+    pub fn remaining_capacity(&self) -> Option<u128> {
+        self.capacity.map(|capacity| capacity.u128().saturating_sub(self.sold.u128()))
+    }
+
+    // A market is live when its terms are initialized and it has capacity left
+    pub fn is_live(&self) -> bool {
+        !self.closed && self.terms.is_some() && self.remaining_capacity() != Some(0)
+    }
+}
+
+// Limits on the OHM payout sold by a market
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PurchaseLimits {
+    pub max_payout_per_address: Option<Uint128>, // per address, over a window of `vesting_term`
+    pub max_payout_per_window: Option<Uint128>, // for all addresses, over a window of `window`
+    pub window: u64, // on the market clock, windows start at multiples of this value
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct PurchaseUsage {
+    pub used: Uint128,
+    pub window_start: u64, // start of the window the usage was recorded in
+}
+
+impl PurchaseUsage {
+    pub fn used_in(&self, window_start: u64) -> u128 {
+        if self.window_start != window_start {
+            return 0;
+        }
+        self.used.u128()
+    }
+}
+
+/// Start of the window of `length` blocks containing `block_height`
+pub fn window_start(block_height: u64, length: u64) -> u64 {
+    if length == 0 {
+        return block_height;
+    }
+    block_height - block_height % length
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Twap {
+    pub window: u64, // on the market clock, length the markdown is averaged over
+    pub max_deviation: Uint128, // in hundreths of a %, max difference between the spot and TWAP markdowns
 }
 
+// Private market settings, the counterparties are added one by one by the admin
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Allowlist {
+    // Can also authorize counterparties with a permit named `bond_market_<market_id>_<depositor>`
+    pub issuer: Option<HumanAddr>,
+    pub permit_cap: Option<Uint128>, // payout cap of the counterparties authorized by a permit
+}
 
+// Allowlist entry of a counterparty of a private market
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct AllowedDepositor {
+    pub allowed: bool, // removed counterparties keep their entry so that a permit can't add them back
+    pub cap: Option<Uint128>, // max payout bought on the market, unlimited if None
+    pub bought: Uint128,
+}
+
+// Markdown of the pair seen by the depository, accumulated like a Uniswap V2 price
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Observation {
+    pub time: u64, // on the market clock
+    pub cumulative: Uint128, // sum of markdown * elapsed up to `time`
+    pub markdown: Uint128, // spot markdown observed at `time`
+}
 
 // Info for creating new bonds
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct Terms {
     pub control_variable: Uint128, // scaling variable for price
-    pub vesting_term: u64, // in blocks
+    pub vesting_term: u64, // in blocks, or seconds for time based markets
     pub minimum_price: Uint128, // vs principle value
     pub maximum_price: Uint128, // (to limit the token price)
     pub max_payout: Uint128 ,// in thousandths of a %. i.e. 500 = 0.5%
@@ -63,23 +204,46 @@ pub struct Terms {
     pub max_debt: Uint128, // 9 decimal debt ratio, max % total supply created as debt
 }
 
-// Info for bond holder
+// Change of the bond terms queued by the admin
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct TermChange {
+    pub parameter: BondParameter,
+    pub input: Uint128,
+    pub activation: u64, // block height
+}
+
+// Info for bond holder, one note per deposit
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct Bond{
+    pub id: u64, // Note id, unique across the contract
+    pub market_id: u64, // Market the bond was bought on
     pub payout: Uint128, // OHM remaining to be paid
-    pub vesting: u64, // Blocks left to vest
-    pub last_block: u64, // Last interaction
+    pub vesting: u64, // Blocks (or seconds) left to vest
+    pub last_block: u64, // Last interaction, block height (or block time)
     pub price_paid: Uint128, // In DAI, for front end viewing
+    pub time_based: bool, // Vests in seconds, like the market it was bought on
+    pub payout_token: Option<Contract>, // Reserve paid by an inverse bond, OHM if None
+}
+
+impl Bond {
+    pub fn clock(&self, block_height: u64, block_time: u64) -> u64 {
+        clock(self.time_based, block_height, block_time)
+    }
 }
 
 impl Default for Bond{
     fn default() -> Self{
         Bond{
+            id: 0,
+            market_id: 0,
             payout: Uint128(0),
             vesting: 0,
             last_block: 0,
-            price_paid: Uint128(0)
+            price_paid: Uint128(0),
+            time_based: false,
+            payout_token: None,
         }
     }
 }
@@ -90,8 +254,23 @@ pub struct Adjust {
     pub add: bool, // addition or subtraction
     pub rate: Uint128, // increment
     pub target: Uint128, // BCV when adjustment finished
-    pub buffer: u64, // minimum length (in blocks) between adjustments
-    pub last_block: u64, // block when last adjustment made
+    pub buffer: u64, // minimum length (in blocks, or seconds for time based markets) between adjustments
+    pub last_block: u64, // block (or time) when last adjustment made
+}
+
+// Moves the control variable every `buffer` toward the targets set by the admin,
+// by the relative distance to the targets up to `step`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Controller {
+    pub target_debt_ratio: Option<Uint128>, // standardized, as returned by the StandardizedDebtRatio query
+    pub target_sold: Option<Uint128>, // payout sold per `buffer`
+    pub step: Uint128, // max change of the control variable per adjustment, in hundreths of a %
+    pub min_control_variable: Uint128,
+    pub max_control_variable: Uint128,
+    pub buffer: u64, // minimum length (in blocks, or seconds for time based markets) between adjustments
+    pub last_adjustment: u64, // end of the last buffer (in blocks, or seconds) the control variable moved for
+    pub sold_at_last_adjustment: Uint128, // `Market::sold` when last adjustment made
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
@@ -136,12 +315,52 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfig<'a, S> {
         self.as_readonly().constants()
     }
 
-    pub fn total_debt(&self) -> u128 {
-        self.as_readonly().total_debt()
+    pub fn market(&self, id: u64) -> StdResult<Market> {
+        self.as_readonly().market(id)
+    }
+
+    pub fn markets(&self) -> StdResult<Vec<Market>> {
+        self.as_readonly().markets()
     }
 
-    pub fn last_decay(&self) -> u64 {
-        self.as_readonly().last_decay()
+    pub fn market_count(&self) -> u64 {
+        self.as_readonly().market_count()
+    }
+
+    pub fn contract_status(&self) -> ContractStatusLevel {
+        self.as_readonly().contract_status()
+    }
+
+    pub fn pause_guardian(&self) -> Option<HumanAddr> {
+        self.as_readonly().pause_guardian()
+    }
+
+    pub fn native_wrapper(&self) -> Option<HumanAddr> {
+        self.as_readonly().native_wrapper()
+    }
+
+    pub fn purchase_usage(&self, market_id: u64, account: &CanonicalAddr) -> PurchaseUsage {
+        self.as_readonly().purchase_usage(market_id, account)
+    }
+
+    pub fn observations(&self, market_id: u64) -> Vec<Observation> {
+        self.as_readonly().observations(market_id)
+    }
+
+    pub fn referral_share(&self, referrer: &CanonicalAddr) -> Option<Uint128> {
+        self.as_readonly().referral_share(referrer)
+    }
+
+    pub fn referral_rewards(&self, referrer: &CanonicalAddr) -> u128 {
+        self.as_readonly().referral_rewards(referrer)
+    }
+
+    pub fn allowed_depositor(&self, market_id: u64, account: &CanonicalAddr) -> Option<AllowedDepositor> {
+        self.as_readonly().allowed_depositor(market_id, account)
+    }
+
+    pub fn is_benefactor(&self, recipient: &CanonicalAddr, benefactor: &CanonicalAddr) -> bool {
+        self.as_readonly().is_benefactor(recipient, benefactor)
     }
 
     pub fn rate_info(&self) -> Vec<Info> {
@@ -155,6 +374,14 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfig<'a, S> {
     pub fn adjustment(&self, index: usize) -> Adjust {
         self.as_readonly().adjustment(index)
     }
+
+    pub fn bond_count(&self) -> u64 {
+        self.as_readonly().bond_count()
+    }
+
+    pub fn tx_count(&self) -> u64 {
+        self.as_readonly().tx_count()
+    }
 }
 
 fn ser_bin_data<T: Serialize>(obj: &T) -> StdResult<Vec<u8>> {
@@ -204,20 +431,111 @@ impl<'a, S: Storage> Config<'a, S> {
         set_bin_data(&mut self.storage, KEY_CONSTANTS, constants)
     }
 
-    pub fn total_debt(&self) -> u128 {
-        self.as_readonly().total_debt()
+    pub fn market(&self, id: u64) -> StdResult<Market> {
+        self.as_readonly().market(id)
+    }
+
+    pub fn markets(&self) -> StdResult<Vec<Market>> {
+        self.as_readonly().markets()
+    }
+
+    pub fn set_market(&mut self, market: &Market) -> StdResult<()> {
+        bucket(KEY_MARKETS, &mut self.storage).save(&market.id.to_be_bytes(), market)
+    }
+
+    pub fn market_count(&self) -> u64 {
+        self.as_readonly().market_count()
     }
 
-    pub fn set_total_debt(&mut self, debt: u128) {
-        self.storage.set(KEY_TOTAL_DEBT, &debt.to_be_bytes());
+    pub fn set_market_count(&mut self, count: u64) {
+        self.storage.set(KEY_MARKET_COUNT, &count.to_be_bytes());
     }
 
-    pub fn last_decay(&self) -> u64 {
-        self.as_readonly().last_decay()
+    pub fn contract_status(&self) -> ContractStatusLevel {
+        self.as_readonly().contract_status()
     }
 
-    pub fn set_last_decay(&mut self, decay: u64) {
-        self.storage.set(KEY_LAST_DECAY, &decay.to_be_bytes());
+    pub fn set_contract_status(&mut self, status: ContractStatusLevel) {
+        let status_u8 = status_level_to_u8(status);
+        self.storage
+            .set(KEY_CONTRACT_STATUS, &status_u8.to_be_bytes());
+    }
+
+    pub fn pause_guardian(&self) -> Option<HumanAddr> {
+        self.as_readonly().pause_guardian()
+    }
+
+    pub fn native_wrapper(&self) -> Option<HumanAddr> {
+        self.as_readonly().native_wrapper()
+    }
+
+    // Can stop the contract, but only the admin can restart it
+    pub fn set_pause_guardian(&mut self, guardian: &Option<HumanAddr>) -> StdResult<()> {
+        set_bin_data(&mut self.storage, KEY_PAUSE_GUARDIAN, guardian)
+    }
+
+    pub fn set_native_wrapper(&mut self, token: &Option<HumanAddr>) -> StdResult<()> {
+        set_bin_data(&mut self.storage, KEY_NATIVE_WRAPPER, token)
+    }
+
+    pub fn purchase_usage(&self, market_id: u64, account: &CanonicalAddr) -> PurchaseUsage {
+        self.as_readonly().purchase_usage(market_id, account)
+    }
+
+    pub fn observations(&self, market_id: u64) -> Vec<Observation> {
+        self.as_readonly().observations(market_id)
+    }
+
+    pub fn set_observations(&mut self, market_id: u64, observations: &[Observation]) -> StdResult<()> {
+        bucket(KEY_OBSERVATIONS, &mut self.storage).save(&market_id.to_be_bytes(), &observations.to_vec())
+    }
+
+    pub fn set_purchase_usage(&mut self, market_id: u64, account: &CanonicalAddr, usage: &PurchaseUsage) -> StdResult<()> {
+        bucket(KEY_PURCHASES, &mut self.storage).save(&purchase_key(market_id, account), usage)
+    }
+
+    pub fn referral_share(&self, referrer: &CanonicalAddr) -> Option<Uint128> {
+        self.as_readonly().referral_share(referrer)
+    }
+
+    // Removing a front-end from the registry keeps its accrued rewards claimable
+    pub fn set_referral_share(&mut self, referrer: &CanonicalAddr, share: Option<Uint128>) -> StdResult<()> {
+        let mut referrers = bucket(KEY_REFERRERS, &mut self.storage);
+        match share {
+            Some(share) => referrers.save(referrer.as_slice(), &share),
+            None => {
+                referrers.remove(referrer.as_slice());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn referral_rewards(&self, referrer: &CanonicalAddr) -> u128 {
+        self.as_readonly().referral_rewards(referrer)
+    }
+
+    pub fn set_referral_rewards(&mut self, referrer: &CanonicalAddr, rewards: u128) -> StdResult<()> {
+        bucket(KEY_REFERRAL_REWARDS, &mut self.storage).save(referrer.as_slice(), &Uint128(rewards))
+    }
+
+    pub fn allowed_depositor(&self, market_id: u64, account: &CanonicalAddr) -> Option<AllowedDepositor> {
+        self.as_readonly().allowed_depositor(market_id, account)
+    }
+
+    pub fn set_allowed_depositor(&mut self, market_id: u64, account: &CanonicalAddr, allowed: &AllowedDepositor) -> StdResult<()> {
+        bucket(KEY_ALLOWLISTS, &mut self.storage).save(&purchase_key(market_id, account), allowed)
+    }
+
+    // Addresses allowed to bond on behalf of `recipient`
+    pub fn set_benefactor(&mut self, recipient: &CanonicalAddr, benefactor: &CanonicalAddr, accepted: bool) -> StdResult<()> {
+        let mut benefactors = bucket(KEY_BENEFACTORS, &mut self.storage);
+        let key = [recipient.as_slice(), benefactor.as_slice()].concat();
+        if accepted {
+            benefactors.save(&key, &true)
+        } else {
+            benefactors.remove(&key);
+            Ok(())
+        }
     }
 
     pub fn rate_info(&self) -> Vec<Info> {
@@ -255,6 +573,22 @@ impl<'a, S: Storage> Config<'a, S> {
         self.as_readonly().adjustment(index)
     }
 
+    pub fn bond_count(&self) -> u64 {
+        self.as_readonly().bond_count()
+    }
+
+    pub fn set_bond_count(&mut self, count: u64) {
+        self.storage.set(KEY_BOND_COUNT, &count.to_be_bytes());
+    }
+
+    pub fn tx_count(&self) -> u64 {
+        self.as_readonly().tx_count()
+    }
+
+    pub fn set_tx_count(&mut self, count: u64) {
+        self.storage.set(KEY_TX_COUNT, &count.to_be_bytes());
+    }
+
 }
 
 /// This struct refactors out the readonly methods that we need for `Config` and `ReadonlyConfig`
@@ -274,22 +608,85 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
             .map_err(|e| StdError::serialize_err(type_name::<Constants>(), e))
     }
 
-    fn total_debt(&self) -> u128 {
-        let debt_bytes = self
-            .0
-            .get(KEY_TOTAL_DEBT)
-            .expect("no total debt stored in config");
-        // This unwrap is ok because we know we stored things correctly
-        slice_to_u128(&debt_bytes).unwrap()
+    fn market(&self, id: u64) -> StdResult<Market> {
+        bucket_read(KEY_MARKETS, self.0)
+            .may_load(&id.to_be_bytes())?
+            .ok_or_else(|| StdError::generic_err(format!("No bond market with id {}", id)))
     }
 
-    fn last_decay(&self) -> u64 {
-        let decay_bytes = self
-            .0
-            .get(KEY_LAST_DECAY)
-            .expect("no last decay stored in config");
-        // This unwrap is ok because we know we stored things correctly
-        slice_to_u64(&decay_bytes).unwrap()
+    fn markets(&self) -> StdResult<Vec<Market>> {
+        (0..self.market_count()).map(|id| self.market(id)).collect()
+    }
+
+    fn market_count(&self) -> u64 {
+        self.0
+            .get(KEY_MARKET_COUNT)
+            .map(|count_bytes| slice_to_u64(&count_bytes).unwrap())
+            .unwrap_or_default()
+    }
+
+    fn contract_status(&self) -> ContractStatusLevel {
+        // These unwraps are ok because we know we stored things correctly
+        self.0
+            .get(KEY_CONTRACT_STATUS)
+            .map(|status_bytes| u8_to_status_level(status_bytes[0]).unwrap())
+            .unwrap_or(ContractStatusLevel::NormalRun)
+    }
+
+    fn pause_guardian(&self) -> Option<HumanAddr> {
+        get_bin_data(self.0, KEY_PAUSE_GUARDIAN).ok().flatten()
+    }
+
+    // SNIP-20 native uscrt deposits are wrapped into (sSCRT), markets of this principle accept uscrt
+    fn native_wrapper(&self) -> Option<HumanAddr> {
+        get_bin_data(self.0, KEY_NATIVE_WRAPPER).ok().flatten()
+    }
+
+    fn observations(&self, market_id: u64) -> Vec<Observation> {
+        bucket_read(KEY_OBSERVATIONS, self.0)
+            .may_load(&market_id.to_be_bytes())
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn purchase_usage(&self, market_id: u64, account: &CanonicalAddr) -> PurchaseUsage {
+        bucket_read(KEY_PURCHASES, self.0)
+            .may_load(&purchase_key(market_id, account))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn referral_share(&self, referrer: &CanonicalAddr) -> Option<Uint128> {
+        bucket_read(KEY_REFERRERS, self.0)
+            .may_load(referrer.as_slice())
+            .ok()
+            .flatten()
+    }
+
+    fn allowed_depositor(&self, market_id: u64, account: &CanonicalAddr) -> Option<AllowedDepositor> {
+        bucket_read(KEY_ALLOWLISTS, self.0)
+            .may_load(&purchase_key(market_id, account))
+            .ok()
+            .flatten()
+    }
+
+    fn is_benefactor(&self, recipient: &CanonicalAddr, benefactor: &CanonicalAddr) -> bool {
+        bucket_read(KEY_BENEFACTORS, self.0)
+            .may_load(&[recipient.as_slice(), benefactor.as_slice()].concat())
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    }
+
+    fn referral_rewards(&self, referrer: &CanonicalAddr) -> u128 {
+        bucket_read(KEY_REFERRAL_REWARDS, self.0)
+            .may_load(referrer.as_slice())
+            .ok()
+            .flatten()
+            .unwrap_or(Uint128(0))
+            .u128()
     }
 
     fn rate_info(&self) -> Vec<Info> {
@@ -307,6 +704,20 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
     fn adjustment(&self, index: usize) -> Adjust {
         bucket_read(KEY_ADJUSTMENTS, self.0).load(&index.to_be_bytes()).unwrap()
     }
+
+    fn bond_count(&self) -> u64 {
+        self.0
+            .get(KEY_BOND_COUNT)
+            .map(|count_bytes| slice_to_u64(&count_bytes).unwrap())
+            .unwrap_or_default()
+    }
+
+    fn tx_count(&self) -> u64 {
+        self.0
+            .get(KEY_TX_COUNT)
+            .map(|count_bytes| slice_to_u64(&count_bytes).unwrap())
+            .unwrap_or_default()
+    }
 }
 
 // BondInfo
@@ -326,8 +737,12 @@ impl<'a, S: ReadonlyStorage> ReadonlyBondInfo<'a, S> {
         ReadonlyBondInfoImpl(&self.storage)
     }
 
-    pub fn bond(&self, account: &CanonicalAddr) -> Bond {
-        self.as_readonly().bond(account)
+    pub fn bonds(&self, account: &CanonicalAddr) -> Vec<Bond> {
+        self.as_readonly().bonds(account)
+    }
+
+    pub fn bond(&self, account: &CanonicalAddr, id: u64) -> Option<Bond> {
+        self.as_readonly().bond(account, id)
     }
 }
 
@@ -346,12 +761,44 @@ impl<'a, S: Storage> BondInfo<'a, S> {
         ReadonlyBondInfoImpl(&self.storage)
     }
 
-    pub fn bond(&self, account: &CanonicalAddr) -> Bond {
-        self.as_readonly().bond(account)
+    pub fn bonds(&self, account: &CanonicalAddr) -> Vec<Bond> {
+        self.as_readonly().bonds(account)
+    }
+
+    pub fn bond(&self, account: &CanonicalAddr, id: u64) -> Option<Bond> {
+        self.as_readonly().bond(account, id)
+    }
+
+    pub fn set_bonds(&mut self, account: &CanonicalAddr, bonds: Vec<Bond>) -> StdResult<()> {
+        if bonds.is_empty() {
+            self.storage.remove(account.as_slice());
+            return Ok(());
+        }
+        set_bin_data(&mut self.storage, account.as_slice(), &bonds)
+    }
+
+    pub fn add_bond(&mut self, account: &CanonicalAddr, bond: Bond) -> StdResult<()> {
+        let mut bonds = self.bonds(account);
+        bonds.push(bond);
+
+        self.set_bonds(account, bonds)
     }
 
     pub fn set_bond(&mut self, account: &CanonicalAddr, bond: Bond) -> StdResult<()> {
-        set_bin_data(&mut self.storage, account.as_slice(), &bond)
+        let mut bonds = self.bonds(account);
+        match bonds.iter_mut().find(|note| note.id == bond.id) {
+            Some(note) => *note = bond,
+            None => return Err(StdError::generic_err(format!("No bond note with id {}", bond.id))),
+        }
+
+        self.set_bonds(account, bonds)
+    }
+
+    pub fn remove_bond(&mut self, account: &CanonicalAddr, id: u64) -> StdResult<()> {
+        let mut bonds = self.bonds(account);
+        bonds.retain(|note| note.id != id);
+
+        self.set_bonds(account, bonds)
     }
 }
 
@@ -363,10 +810,56 @@ impl<'a, S: Storage> BondInfo<'a, S> {
 struct ReadonlyBondInfoImpl<'a, S: ReadonlyStorage>(&'a S);
 
 impl<'a, S: ReadonlyStorage> ReadonlyBondInfoImpl<'a, S> {
-    pub fn bond(&self, account: &CanonicalAddr) -> Bond {
+    pub fn bonds(&self, account: &CanonicalAddr) -> Vec<Bond> {
         get_bin_data(self.0, account.as_slice()).unwrap_or_default()
     }
+
+    pub fn bond(&self, account: &CanonicalAddr, id: u64) -> Option<Bond> {
+        self.bonds(account).into_iter().find(|note| note.id == id)
+    }
+}
+// Bond approvals
+
+// Allows `spender` to transfer a single note of the owner, until the `expiration` block if any
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BondApproval {
+    pub spender: CanonicalAddr,
+    pub expiration: Option<u64>,
+}
+
+impl BondApproval {
+    pub fn is_expired_at(&self, block_height: u64) -> bool {
+        match self.expiration {
+            Some(expiration) => block_height > expiration,
+            None => false,
+        }
+    }
 }
+
+pub fn write_bond_approval<S: Storage>(
+    store: &mut S,
+    owner: &CanonicalAddr,
+    note_id: u64,
+    approval: &BondApproval,
+) -> StdResult<()> {
+    let mut approval_store = PrefixedStorage::multilevel(&[PREFIX_BOND_APPROVALS, owner.as_slice()], store);
+    set_bin_data(&mut approval_store, &note_id.to_be_bytes(), approval)
+}
+
+pub fn read_bond_approval<S: ReadonlyStorage>(
+    store: &S,
+    owner: &CanonicalAddr,
+    note_id: u64,
+) -> Option<BondApproval> {
+    let approval_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_BOND_APPROVALS, owner.as_slice()], store);
+    get_bin_data(&approval_store, &note_id.to_be_bytes()).ok()
+}
+
+pub fn remove_bond_approval<S: Storage>(store: &mut S, owner: &CanonicalAddr, note_id: u64) {
+    let mut approval_store = PrefixedStorage::multilevel(&[PREFIX_BOND_APPROVALS, owner.as_slice()], store);
+    approval_store.remove(&note_id.to_be_bytes());
+}
+
 // Viewing Keys
 
 pub fn write_viewing_key<S: Storage>(store: &mut S, owner: &CanonicalAddr, key: &ViewingKey) {
@@ -381,15 +874,8 @@ pub fn read_viewing_key<S: Storage>(store: &S, owner: &CanonicalAddr) -> Option<
 
 // Helpers
 
-/// Converts 16 bytes value into u128
-/// Errors if data found that is not 16 bytes
-fn slice_to_u128(data: &[u8]) -> StdResult<u128> {
-    match <[u8; 16]>::try_from(data) {
-        Ok(bytes) => Ok(u128::from_be_bytes(bytes)),
-        Err(_) => Err(StdError::generic_err(
-            "Corrupted data found. 16 byte expected.",
-        )),
-    }
+fn purchase_key(market_id: u64, account: &CanonicalAddr) -> Vec<u8> {
+    [&market_id.to_be_bytes()[..], account.as_slice()].concat()
 }
 
 /// Converts 16 bytes value into u128
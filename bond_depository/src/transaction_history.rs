@@ -0,0 +1,283 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::{Api, CanonicalAddr, HumanAddr, ReadonlyStorage, StdResult, Storage, Uint128};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+
+use secret_toolkit::storage::{AppendStore, AppendStoreMut};
+
+use crate::msg::BondParameter;
+use crate::state::{Config, TermChange, PREFIX_BOND_TXS, PREFIX_TERMS_HISTORY, PREFIX_TXS};
+
+// Note that id is a globally incrementing counter.
+// Since it's 64 bits long, even at 50 tx/s it would take
+// over 11 billion years for it to rollback. I'm pretty sure
+// we'll have bigger issues by then.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct BondTransfer {
+    pub id: u64,
+    pub note_id: u64,
+    pub from: HumanAddr,
+    pub sender: HumanAddr,
+    pub receiver: HumanAddr,
+    // OHM remaining to be paid on the note when it was transferred
+    pub payout: Uint128,
+    pub block_time: u64,
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct ReservePayout {
+    pub token: HumanAddr,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BondAction {
+    Deposit {
+        market_id: u64,
+        note_id: u64, // 0 when an inverse bond is paid instantly
+        token: HumanAddr, // deposited, OHM for inverse bonds
+        amount: Uint128,
+        value: Uint128, // of the principle, in OHM
+        payout: Uint128, // OHM, or reserves for inverse bonds
+        price: Uint128,
+        fee: Uint128,
+    },
+    Redeem {
+        payout: Uint128, // OHM
+        stake: bool,
+        reserves: Vec<ReservePayout>, // paid by inverse bonds
+    },
+}
+
+// Same id counter as the transfers
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct BondTx {
+    pub id: u64,
+    pub action: BondAction,
+    pub block_time: u64,
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct AppliedTermChange {
+    pub parameter: BondParameter,
+    pub input: Uint128,
+    pub activation: u64,
+    pub block_time: u64, // when the change was applied
+    pub block_height: u64,
+}
+
+// Stored types:
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+struct StoredBondTransfer {
+    id: u64,
+    note_id: u64,
+    from: CanonicalAddr,
+    sender: CanonicalAddr,
+    receiver: CanonicalAddr,
+    payout: u128,
+    block_time: u64,
+    block_height: u64,
+}
+
+impl StoredBondTransfer {
+    fn into_humanized<A: Api>(self, api: &A) -> StdResult<BondTransfer> {
+        Ok(BondTransfer {
+            id: self.id,
+            note_id: self.note_id,
+            from: api.human_address(&self.from)?,
+            sender: api.human_address(&self.sender)?,
+            receiver: api.human_address(&self.receiver)?,
+            payout: Uint128(self.payout),
+            block_time: self.block_time,
+            block_height: self.block_height,
+        })
+    }
+}
+
+// Storage functions:
+
+fn increment_tx_count<S: Storage>(store: &mut S) -> StdResult<u64> {
+    let mut config = Config::from_storage(store);
+    let id = config.tx_count() + 1;
+    config.set_tx_count(id);
+    Ok(id)
+}
+
+pub fn store_bond_transfer<S: Storage>(
+    store: &mut S,
+    note_id: u64,
+    owner: &CanonicalAddr,
+    sender: &CanonicalAddr,
+    receiver: &CanonicalAddr,
+    payout: u128,
+    block: &cosmwasm_std::BlockInfo,
+) -> StdResult<()> {
+    let id = increment_tx_count(store)?;
+    let transfer = StoredBondTransfer {
+        id,
+        note_id,
+        from: owner.clone(),
+        sender: sender.clone(),
+        receiver: receiver.clone(),
+        payout,
+        block_time: block.time,
+        block_height: block.height,
+    };
+
+    // Write to the owners history if it's different from the other two addresses
+    if owner != sender && owner != receiver {
+        append_transfer(store, &transfer, owner)?;
+    }
+    // Write to the sender's history if it's different from the receiver
+    if sender != receiver {
+        append_transfer(store, &transfer, sender)?;
+    }
+    // Always write to the recipient's history
+    append_transfer(store, &transfer, receiver)
+}
+
+pub fn store_bond_tx<S: Storage>(
+    store: &mut S,
+    account: &CanonicalAddr,
+    action: BondAction,
+    block: &cosmwasm_std::BlockInfo,
+) -> StdResult<()> {
+    let id = increment_tx_count(store)?;
+    let tx = BondTx {
+        id,
+        action,
+        block_time: block.time,
+        block_height: block.height,
+    };
+
+    let mut store = PrefixedStorage::multilevel(&[PREFIX_BOND_TXS, account.as_slice()], store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(&tx)
+}
+
+pub fn store_term_change<S: Storage>(
+    store: &mut S,
+    market_id: u64,
+    change: TermChange,
+    block: &cosmwasm_std::BlockInfo,
+) -> StdResult<()> {
+    let applied = AppliedTermChange {
+        parameter: change.parameter,
+        input: change.input,
+        activation: change.activation,
+        block_time: block.time,
+        block_height: block.height,
+    };
+
+    let mut store = PrefixedStorage::multilevel(&[PREFIX_TERMS_HISTORY, &market_id.to_be_bytes()], store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(&applied)
+}
+
+fn append_transfer<S: Storage>(
+    store: &mut S,
+    tx: &StoredBondTransfer,
+    for_address: &CanonicalAddr,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::multilevel(&[PREFIX_TXS, for_address.as_slice()], store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(tx)
+}
+
+pub fn get_bond_transfers<A: Api, S: ReadonlyStorage>(
+    api: &A,
+    storage: &S,
+    for_address: &CanonicalAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<BondTransfer>, u64)> {
+    let store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_TXS, for_address.as_slice()], storage);
+
+    // Try to access the storage of transfers for the account.
+    // If it doesn't exist yet, return an empty list of transfers.
+    let store = AppendStore::<StoredBondTransfer, _, _>::attach(&store);
+    let store = if let Some(result) = store {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    // Take `page_size` txs starting from the latest tx, potentially skipping `page * page_size`
+    // txs from the start.
+    let transfer_iter = store
+        .iter()
+        .rev()
+        .skip(page.saturating_mul(page_size) as _)
+        .take(page_size as _);
+
+    // The `and_then` here flattens the `StdResult<StdResult<BondTransfer>>` to an `StdResult<BondTransfer>`
+    let transfers: StdResult<Vec<BondTransfer>> = transfer_iter
+        .map(|tx| tx.map(|tx| tx.into_humanized(api)).and_then(|x| x))
+        .collect();
+    transfers.map(|txs| (txs, store.len() as u64))
+}
+
+pub fn get_bond_txs<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &CanonicalAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<BondTx>, u64)> {
+    let store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_BOND_TXS, for_address.as_slice()], storage);
+
+    // Try to access the storage of deposits and redemptions for the account.
+    // If it doesn't exist yet, return an empty list.
+    let store = AppendStore::<BondTx, _, _>::attach(&store);
+    let store = if let Some(result) = store {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    // Take `page_size` txs starting from the latest tx, potentially skipping `page * page_size`
+    // txs from the start.
+    let txs: StdResult<Vec<BondTx>> = store
+        .iter()
+        .rev()
+        .skip(page.saturating_mul(page_size) as _)
+        .take(page_size as _)
+        .collect();
+    txs.map(|txs| (txs, store.len() as u64))
+}
+
+pub fn get_term_changes<S: ReadonlyStorage>(
+    storage: &S,
+    market_id: u64,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<AppliedTermChange>, u64)> {
+    let store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_TERMS_HISTORY, &market_id.to_be_bytes()], storage);
+
+    // The history doesn't exist until the first change of the market terms
+    let store = AppendStore::<AppliedTermChange, _, _>::attach(&store);
+    let store = if let Some(result) = store {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    // Take `page_size` changes starting from the latest one, potentially skipping `page * page_size`
+    // changes from the start.
+    let changes: StdResult<Vec<AppliedTermChange>> = store
+        .iter()
+        .rev()
+        .skip(page.saturating_mul(page_size) as _)
+        .take(page_size as _)
+        .collect();
+    changes.map(|changes| (changes, store.len() as u64))
+}
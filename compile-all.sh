@@ -29,3 +29,9 @@ cd ../
 cd treasury/
 ./compile.sh
 cd ../
+cd mock-oracle/
+./compile.sh
+cd ../
+cd mock-strategy/
+./compile.sh
+cd ../
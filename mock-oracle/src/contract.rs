@@ -0,0 +1,137 @@
+/// Mock price oracle, used to test the valuation of volatile reserves in the treasury.
+/// Prices are set by hand by the admin, it should never be used in production.
+use cosmwasm_std::{
+    to_binary, Api, Env, Extern, HandleResponse, HumanAddr, InitResponse, Querier, QueryResult,
+    StdError, StdResult, Storage, Uint128,
+};
+
+use crate::msg::{
+    HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg, ResponseStatus::Success,
+};
+use crate::state::{admin, price, set_admin, set_price, Price};
+
+pub fn init<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: InitMsg,
+) -> StdResult<InitResponse> {
+    set_admin(&mut deps.storage, &msg.admin.unwrap_or(env.message.sender))?;
+
+    Ok(InitResponse::default())
+}
+
+pub fn handle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: HandleMsg,
+) -> StdResult<HandleResponse> {
+    match msg {
+        HandleMsg::SetPrice {
+            token,
+            rate,
+            last_updated,
+        } => try_set_price(deps, env, token, rate, last_updated),
+    }
+}
+
+pub fn try_set_price<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr,
+    rate: Uint128,
+    last_updated: Option<u64>,
+) -> StdResult<HandleResponse> {
+    if admin(&deps.storage)? != env.message.sender {
+        return Err(StdError::generic_err(
+            "This is an admin command. Admin commands can only be run from admin address",
+        ));
+    }
+    set_price(
+        &mut deps.storage,
+        &token,
+        &Price {
+            rate,
+            last_updated: last_updated.unwrap_or(env.block.time),
+        },
+    )?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetPrice { status: Success })?),
+    })
+}
+
+pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
+    match msg {
+        QueryMsg::Price { token } => query_price(&deps.storage, token),
+    }
+}
+
+fn query_price<S: Storage>(storage: &S, token: HumanAddr) -> QueryResult {
+    let price = price(storage, &token)?
+        .ok_or_else(|| StdError::generic_err(format!("No price was set for {}", token)))?;
+
+    to_binary(&QueryAnswer::Price(price))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::from_binary;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+
+    #[test]
+    fn test_set_and_query_price() {
+        let mut deps = mock_dependencies(20, &[]);
+        init(&mut deps, mock_env("admin", &[]), InitMsg { admin: None }).unwrap();
+
+        let msg = HandleMsg::SetPrice {
+            token: HumanAddr("sscrt".to_string()),
+            rate: Uint128(2_500_000_000_000_000_000),
+            last_updated: None,
+        };
+        let env = mock_env("admin", &[]);
+        let block_time = env.block.time;
+        handle(&mut deps, env, msg).unwrap();
+
+        let answer: QueryAnswer = from_binary(
+            &query(
+                &deps,
+                QueryMsg::Price {
+                    token: HumanAddr("sscrt".to_string()),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        match answer {
+            QueryAnswer::Price(price) => {
+                assert_eq!(price.rate, Uint128(2_500_000_000_000_000_000));
+                assert_eq!(price.last_updated, block_time);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_price_not_admin() {
+        let mut deps = mock_dependencies(20, &[]);
+        init(&mut deps, mock_env("admin", &[]), InitMsg { admin: None }).unwrap();
+
+        let msg = HandleMsg::SetPrice {
+            token: HumanAddr("sscrt".to_string()),
+            rate: Uint128(1),
+            last_updated: None,
+        };
+        let handle_result = handle(&mut deps, mock_env("not-admin", &[]), msg);
+        assert!(handle_result.is_err());
+
+        let query_result = query(
+            &deps,
+            QueryMsg::Price {
+                token: HumanAddr("sscrt".to_string()),
+            },
+        );
+        assert!(query_result.is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use cosmwasm_std::{HumanAddr, ReadonlyStorage, StdResult, Storage, Uint128};
+use cosmwasm_storage::{bucket, bucket_read, singleton, singleton_read};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const KEY_ADMIN: &[u8] = b"admin";
+pub const PREFIX_PRICES: &[u8] = b"prices";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Price {
+    pub rate: Uint128,
+    pub last_updated: u64,
+}
+
+pub fn set_admin<S: Storage>(storage: &mut S, admin: &HumanAddr) -> StdResult<()> {
+    singleton(storage, KEY_ADMIN).save(admin)
+}
+
+pub fn admin<S: ReadonlyStorage>(storage: &S) -> StdResult<HumanAddr> {
+    singleton_read(storage, KEY_ADMIN).load()
+}
+
+pub fn set_price<S: Storage>(storage: &mut S, token: &HumanAddr, price: &Price) -> StdResult<()> {
+    bucket(PREFIX_PRICES, storage).save(token.as_str().as_bytes(), price)
+}
+
+pub fn price<S: ReadonlyStorage>(storage: &S, token: &HumanAddr) -> StdResult<Option<Price>> {
+    bucket_read(PREFIX_PRICES, storage).may_load(token.as_str().as_bytes())
+}
@@ -0,0 +1,2 @@
+RUSTFLAGS='-C link-arg=-s' cargo build --release --target wasm32-unknown-unknown
+wasm-opt -Oz ../build/wasm32-unknown-unknown/release/mock_strategy.wasm -o ./contract.wasm
@@ -0,0 +1,204 @@
+/// Mock yield strategy, used to test the treasury reserve allocator.
+/// It holds the reserves it receives and reports whatever value the admin sets,
+/// it should never be used in production.
+use cosmwasm_std::{
+    from_binary, to_binary, Api, Binary, Env, Extern, HandleResponse, HumanAddr, InitResponse,
+    Querier, QueryResult, StdError, StdResult, Storage, Uint128,
+};
+use secret_toolkit::snip20;
+
+use crate::msg::{
+    HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg, ReceiveMsg, ResponseStatus::Success,
+    RESPONSE_BLOCK_SIZE,
+};
+use crate::state::{constants, set_constants, set_value, value, Constants};
+
+pub fn init<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: InitMsg,
+) -> StdResult<InitResponse> {
+    set_constants(
+        &mut deps.storage,
+        &Constants {
+            admin: msg.admin.unwrap_or(env.message.sender),
+            treasury: msg.treasury,
+            token: msg.token.clone(),
+        },
+    )?;
+
+    Ok(InitResponse {
+        messages: vec![snip20::register_receive_msg(
+            env.contract_code_hash,
+            None,
+            RESPONSE_BLOCK_SIZE,
+            msg.token.code_hash,
+            msg.token.address,
+        )?],
+        log: vec![],
+    })
+}
+
+pub fn handle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: HandleMsg,
+) -> StdResult<HandleResponse> {
+    match msg {
+        HandleMsg::Receive {
+            from, amount, msg, ..
+        } => receive(deps, env, from, amount, msg),
+        HandleMsg::Withdraw { amount } => withdraw(deps, env, amount),
+        HandleMsg::SetValue { amount } => try_set_value(deps, env, amount),
+    }
+}
+
+fn receive<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    amount: Uint128,
+    msg: Binary,
+) -> StdResult<HandleResponse> {
+    let consts = constants(&deps.storage)?;
+    if env.message.sender != consts.token.address || from != consts.treasury {
+        return Err(StdError::generic_err(
+            "Only the treasury can deposit the strategy token",
+        ));
+    }
+    match from_binary(&msg)? {
+        ReceiveMsg::Deposit {} => {
+            let new_value = value(&deps.storage)?
+                .u128()
+                .checked_add(amount.u128())
+                .ok_or_else(|| StdError::generic_err("Too many tokens"))?;
+            set_value(&mut deps.storage, Uint128(new_value))?;
+        }
+    }
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Deposit { status: Success })?),
+    })
+}
+
+fn withdraw<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: Uint128,
+) -> StdResult<HandleResponse> {
+    let consts = constants(&deps.storage)?;
+    if env.message.sender != consts.treasury {
+        return Err(StdError::generic_err(
+            "Only the treasury can withdraw from the strategy",
+        ));
+    }
+    let new_value = value(&deps.storage)?
+        .u128()
+        .checked_sub(amount.u128())
+        .ok_or_else(|| StdError::generic_err("Not enough tokens in the strategy"))?;
+    set_value(&mut deps.storage, Uint128(new_value))?;
+
+    Ok(HandleResponse {
+        messages: vec![snip20::transfer_msg(
+            consts.treasury,
+            amount,
+            None,
+            RESPONSE_BLOCK_SIZE,
+            consts.token.code_hash,
+            consts.token.address,
+        )?],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Withdraw { status: Success })?),
+    })
+}
+
+fn try_set_value<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: Uint128,
+) -> StdResult<HandleResponse> {
+    if constants(&deps.storage)?.admin != env.message.sender {
+        return Err(StdError::generic_err(
+            "This is an admin command. Admin commands can only be run from admin address",
+        ));
+    }
+    set_value(&mut deps.storage, amount)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetValue { status: Success })?),
+    })
+}
+
+pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
+    match msg {
+        QueryMsg::StrategyValue {} => to_binary(&QueryAnswer::StrategyValue {
+            amount: value(&deps.storage)?,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Contract;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+
+    fn init_helper() -> Extern<
+        cosmwasm_std::testing::MockStorage,
+        cosmwasm_std::testing::MockApi,
+        cosmwasm_std::testing::MockQuerier,
+    > {
+        let mut deps = mock_dependencies(20, &[]);
+        let init_msg = InitMsg {
+            admin: None,
+            treasury: HumanAddr("treasury".to_string()),
+            token: Contract {
+                address: HumanAddr("sUST".to_string()),
+                code_hash: "Complicated_hash".to_string(),
+            },
+        };
+        init(&mut deps, mock_env("admin", &[]), init_msg).unwrap();
+        deps
+    }
+
+    fn strategy_value<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> Uint128 {
+        match from_binary(&query(deps, QueryMsg::StrategyValue {}).unwrap()).unwrap() {
+            QueryAnswer::StrategyValue { amount } => amount,
+        }
+    }
+
+    #[test]
+    fn test_deposit_report_withdraw() {
+        let mut deps = init_helper();
+
+        let deposit_msg = HandleMsg::Receive {
+            sender: HumanAddr("treasury".to_string()),
+            from: HumanAddr("treasury".to_string()),
+            amount: Uint128(1000),
+            msg: to_binary(&ReceiveMsg::Deposit {}).unwrap(),
+        };
+        // Only the strategy token sent by the treasury is accepted
+        assert!(handle(&mut deps, mock_env("sSCRT", &[]), deposit_msg.clone()).is_err());
+        handle(&mut deps, mock_env("sUST", &[]), deposit_msg).unwrap();
+        assert_eq!(strategy_value(&deps), Uint128(1000));
+
+        let set_value_msg = HandleMsg::SetValue {
+            amount: Uint128(1100),
+        };
+        assert!(handle(&mut deps, mock_env("treasury", &[]), set_value_msg.clone()).is_err());
+        handle(&mut deps, mock_env("admin", &[]), set_value_msg).unwrap();
+        assert_eq!(strategy_value(&deps), Uint128(1100));
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: Uint128(1100),
+        };
+        assert!(handle(&mut deps, mock_env("admin", &[]), withdraw_msg.clone()).is_err());
+        let handle_result = handle(&mut deps, mock_env("treasury", &[]), withdraw_msg).unwrap();
+        assert_eq!(handle_result.messages.len(), 1);
+        assert_eq!(strategy_value(&deps), Uint128(0));
+    }
+}
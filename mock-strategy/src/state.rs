@@ -0,0 +1,40 @@
+use cosmwasm_std::{HumanAddr, ReadonlyStorage, StdResult, Storage, Uint128};
+use cosmwasm_storage::{singleton, singleton_read};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const KEY_CONSTANTS: &[u8] = b"constants";
+pub const KEY_VALUE: &[u8] = b"value";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Contract {
+    pub address: HumanAddr,
+    pub code_hash: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Constants {
+    pub admin: HumanAddr,
+    pub treasury: HumanAddr,
+    pub token: Contract,
+}
+
+pub fn set_constants<S: Storage>(storage: &mut S, constants: &Constants) -> StdResult<()> {
+    singleton(storage, KEY_CONSTANTS).save(constants)
+}
+
+pub fn constants<S: ReadonlyStorage>(storage: &S) -> StdResult<Constants> {
+    singleton_read(storage, KEY_CONSTANTS).load()
+}
+
+// Amount of tokens the strategy reports to the treasury
+pub fn set_value<S: Storage>(storage: &mut S, value: Uint128) -> StdResult<()> {
+    singleton(storage, KEY_VALUE).save(&value)
+}
+
+pub fn value<S: ReadonlyStorage>(storage: &S) -> StdResult<Uint128> {
+    Ok(singleton_read(storage, KEY_VALUE)
+        .may_load()?
+        .unwrap_or_default())
+}
@@ -1,25 +1,40 @@
 /// This contract implements SNIP-20 standard:
 /// https://github.com/SecretFoundation/SNIPs/blob/master/SNIP-20.md
 use cosmwasm_std::{
-    to_binary, Api, Binary, CosmosMsg, Env, Extern,
+    to_binary, Api, Binary, CanonicalAddr, CosmosMsg, Env, Extern,
     HandleResponse, HumanAddr, InitResponse, Querier, QueryResult, ReadonlyStorage, StdError,
     StdResult, Storage, Uint128, from_binary
 };
 
 use crate::msg::{
     space_pad, ContractStatusLevel, HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg,
-    ResponseStatus::Success, ReceiveAnswer, ReceiveMsg
+    ResponseStatus::Success, ReceiveAnswer, ReceiveMsg, SOhmQueryMsg,
+    GonsForBalanceResponse, BalanceForGonsResponse,
+    StrategyHandleMsg, StrategyQueryMsg, StrategyReceiveMsg, StrategyValueResponse
+};
+use crate::msg::{
+    QueryWithPermit, QueueEntry, ReserveBalance, ExportedState, ExportedRole, ExportedDebtor,
+    ExportedDeposit, ExportedCalculator
 };
 use crate::rand::sha_256;
 use crate::state::{
      Config, Constants, Debtors, ReadonlyConfig, ReadonlyDebtors,
-    ManagingRole, Contract, Deposited, ReadonlyDeposited, RESPONSE_BLOCK_SIZE
+    ManagingRole, Contract, Deposited, ReadonlyDeposited, RESPONSE_BLOCK_SIZE,
+    Collateral, ReadonlyCollateral, DebtPosition, LiquidationParameters, ValuationSource, Budget, BudgetUsage,
+    Strategy, Migration, BORROW_RATE_DENOMINATOR
 };
 
+use crate::transaction_history::{get_ledger, get_txs, store_tx, TxCode};
+
+use secret_toolkit::permit::{validate, Permission, Permit, RevokedPermits};
 use secret_toolkit::snip20;
+use secret_toolkit::utils::{HandleCallback, Query};
+use primitive_types::U256;
 
 pub const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
 pub const COMMON_VIEWING_KEY : &str = "ALL_ORGANISATION_INFO_SHOULD_BE_PUBLIC";
+// About a day of blocks, users need time to exit before the reserves move to a new treasury
+pub const MIN_MIGRATION_DELAY : u64 = 14_400;
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
@@ -119,12 +134,74 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
         )?
     );
 
+    if let Some(state) = msg.import{
+        import_state(deps, state)?;
+        Config::from_storage(&mut deps.storage).set_import_open(true)?;
+    }
+
     Ok(InitResponse {
         messages,
         log: vec![],
     })
 }
 
+// Roles held by addresses, token roles are set through the init message
+fn managing_roles() -> Vec<ManagingRole> {
+    vec![
+        ManagingRole::ReserveDepositor,
+        ManagingRole::ReserveSpender,
+        ManagingRole::ReserveManager,
+        ManagingRole::LiquidityDepositor,
+        ManagingRole::LiquidityManager,
+        ManagingRole::Debtor,
+        ManagingRole::RewardManager,
+    ]
+}
+
+fn import_state<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    state: ExportedState,
+) -> StdResult<()> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    for exported_role in state.roles{
+        for address in exported_role.addresses{
+            let canonical_addr = deps.api.canonical_address(&address)?;
+            config.set_managing_position(&canonical_addr, exported_role.role.clone(), true)?;
+        }
+    }
+    for exported_calculator in state.bond_calculators{
+        config.set_bond_calculator(exported_calculator.token, exported_calculator.calculator)?;
+    }
+
+    for debtor in state.debtors{
+        let canonical_addr = deps.api.canonical_address(&debtor.address)?;
+        let gons = U256::from_dec_str(&debtor.collateral_gons).map_err(|_| {
+            StdError::generic_err("Invalid amount of collateral gons")
+        })?;
+        // Importing a page again replaces the positions, the total debt only moves by the difference
+        let previous_debt = principal_of(&ReadonlyDebtors::from_storage(&deps.storage).positions(&canonical_addr)?);
+        if debtor.positions.iter().any(|position| position.interest > position.amount) {
+            return Err(StdError::generic_err("The interest of a debt position can't be above its amount"));
+        }
+        let total_debt = Config::from_storage(&mut deps.storage).total_debt()
+            .saturating_sub(previous_debt)
+            .checked_add(principal_of(&debtor.positions))
+            .ok_or_else(|| StdError::generic_err("Not possible, the total debt is above the u128 capacity"))?;
+        Debtors::from_storage(&mut deps.storage).set_positions(&canonical_addr, &debtor.positions)?;
+        Collateral::from_storage(&mut deps.storage).set_gons(&canonical_addr, gons)?;
+        let mut config = Config::from_storage(&mut deps.storage);
+        config.set_total_debt(total_debt);
+        config.add_position_holder(&canonical_addr)?;
+    }
+
+    let mut deposited = Deposited::from_storage(&mut deps.storage);
+    for exported_deposit in state.deposited{
+        let canonical_token = deps.api.canonical_address(&exported_deposit.token)?;
+        deposited.set_deposited(&canonical_token, exported_deposit.amount.u128());
+    }
+    Ok(())
+}
+
 pub fn handle<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
@@ -154,17 +231,35 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
                 } => receive(deps, env, from, amount.u128(), msg),
 
         //Normal messages
+        HandleMsg::DepositNative { profit } => deposit_native(deps, env, profit.u128()),
         HandleMsg::IncurDebt { token, amount, .. } => incur_debt(deps, env, token, amount.u128()),
+        HandleMsg::WithdrawCollateral { amount, .. } => withdraw_collateral(deps, env, amount.u128()),
         HandleMsg::Manage { token, amount, .. } => manage(deps, env, token, amount.u128()),
         HandleMsg::MintRewards { recipient, amount, .. } => mint_rewards(deps, env, recipient, amount.u128()),
         HandleMsg::AuditReserves { .. } => audit_reserves(deps, env),
         HandleMsg::Queue { address, role } => queue(deps, env, address, role),
         HandleMsg::ToggleQueue { address, role } => toggle_queue(deps, env, address, role),
         HandleMsg::ToggleTokenQueue { token, role, calculator } => toggle_token_queue(deps, env, token, role, calculator),
+        HandleMsg::CancelQueue { address, role } => cancel_queue(deps, env, address, role),
+        HandleMsg::SetRevocationDelay { blocks } => set_revocation_delay(deps, env, blocks),
+        HandleMsg::SetBudget { address, role, budget } => set_budget(deps, env, address, role, budget),
+        HandleMsg::RegisterStrategy { address, code_hash, token } => register_strategy(deps, env, address, code_hash, token),
+        HandleMsg::RemoveStrategy { address } => remove_strategy(deps, env, address),
+        HandleMsg::AllocateToStrategy { strategy, amount } => allocate_to_strategy(deps, env, strategy, amount.u128()),
+        HandleMsg::WithdrawFromStrategy { strategy, amount } => withdraw_from_strategy(deps, env, strategy, amount.u128()),
+        HandleMsg::ReportStrategy { strategy } => report_strategy(deps, env, strategy),
+        HandleMsg::MigrateTo { new_treasury } => migrate_to(deps, env, new_treasury),
+        HandleMsg::CancelMigration { .. } => cancel_migration(deps, env),
+        HandleMsg::ImportState { state, last_page } => import_state_page(deps, env, state, last_page),
+        HandleMsg::SetNativeWrapper { token } => set_native_wrapper(deps, env, token),
+        HandleMsg::SetBorrowRate { token, rate } => set_borrow_rate(deps, env, token, rate.u128()),
+        HandleMsg::SetLiquidationParameters { ltv, bonus } => set_liquidation_parameters(deps, env, ltv.u128(), bonus.u128()),
+        HandleMsg::SetValuationSource { token, source } => set_valuation_source(deps, env, token, source),
 
         // Other
         HandleMsg::ChangeAdmin { address, .. } => change_admin(deps, env, address),
         HandleMsg::SetContractStatus { level, .. } => set_contract_status(deps, env, level),
+        HandleMsg::RevokePermit { permit_name, .. } => revoke_permit(deps, env, permit_name),
     };
 
     pad_response(response)
@@ -175,15 +270,30 @@ pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryM
         QueryMsg::ContractInfo {} => query_contract_info(deps),
         QueryMsg::Contracts {role} => query_tokens(&deps.storage, role),
         QueryMsg::ManagingAddresses {role} => query_managing_addresses(deps, role),
+        QueryMsg::PendingQueue {role} => query_pending_queue(deps, role),
+        QueryMsg::RemainingBudget {address, role, block_height} => query_remaining_budget(deps, address, role, block_height),
+        QueryMsg::Strategies {} => query_strategies(&deps.storage),
+        QueryMsg::PendingMigration {} => query_pending_migration(&deps.storage),
         QueryMsg::ContractStatus {} => query_contract_status(&deps.storage),
-        QueryMsg::ValueOf{ token, amount } => query_value_of(deps, token, amount),
+        QueryMsg::ValueOf{ token, amount, block_time } => query_value_of(deps, token, amount, block_time),
         QueryMsg::TotalBondDeposited{ token } => query_total_bond_deposited(deps, token),
+        QueryMsg::Collateral{ debtor } => query_collateral(deps, debtor),
+        QueryMsg::DebtorHealth{ debtor, block_height } => query_debtor_health(deps, debtor, block_height),
+        QueryMsg::BorrowRate{ token } => query_borrow_rate(&deps.storage, token),
+        QueryMsg::LiquidationParameters{} => query_liquidation_parameters(&deps.storage),
+        QueryMsg::ValuationSource{ token } => query_valuation_source(&deps.storage, token),
+        QueryMsg::WithPermit { permit, query } => permit_queries(deps, permit, query),
+        QueryMsg::TotalReserves{} => query_total_reserves(deps),
+        QueryMsg::ReserveBalances{ block_time } => query_reserve_balances(deps, block_time),
+        QueryMsg::RiskFreeValue{ block_time } => query_risk_free_value(deps, block_time),
+        QueryMsg::MarketValue{ block_time } => query_market_value(deps, block_time),
+        QueryMsg::Backing{ block_time } => query_backing(deps, block_time),
     }
 }
 
 pub fn deposit<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    _env: Env,
+    env: Env,
     from : HumanAddr,
     token : HumanAddr,
     amount: u128,
@@ -204,7 +314,7 @@ pub fn deposit<S: Storage, A: Api, Q: Querier>(
                 ));
     }
 
-    let value = config.value_of(&deps.querier,&token,amount)?;
+    let value = config.value_of_at(&deps.querier,&token,amount,env.block.time)?;
 
     let send = if let Some(send_) = value.checked_sub(profit) {
         send_
@@ -238,6 +348,8 @@ pub fn deposit<S: Storage, A: Api, Q: Querier>(
     //Save a bond deposit (to have statistics)
     deposited.add_new_bond(&token_address, amount)?;
 
+    store_tx(&mut deps.storage, TxCode::Deposit, Some(token), amount, value, &from_canonical, &env.block)?;
+
     Ok(HandleResponse {
         messages : messages,
         log: vec![],
@@ -245,15 +357,47 @@ pub fn deposit<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+pub fn deposit_native<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    profit: u128
+) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let native_wrapper = config.native_wrapper().ok_or_else(|| {
+        StdError::generic_err("Native deposits are not enabled")
+    })?;
+    let token_info = config.get_reserve_token_info(&native_wrapper)?;
+
+    let amount = match env.message.sent_funds.as_slice(){
+        [coin] if coin.denom == "uscrt" && coin.amount.u128() != 0 => coin.amount,
+        _ => return Err(StdError::generic_err("Only uscrt can be deposited")),
+    };
+    let wrap_msg = snip20::deposit_msg(
+        amount,
+        None,
+        RESPONSE_BLOCK_SIZE,
+        token_info.code_hash,
+        token_info.address,
+    )?;
+
+    // The wrapped tokens are accounted for exactly like a reserve deposit
+    let from = env.message.sender.clone();
+    let mut response = deposit(deps, env, from, native_wrapper, amount.u128(), profit)?;
+    response.messages.insert(0, wrap_msg);
+    response.data = Some(to_binary(&HandleAnswer::DepositNative { status: Success })?);
+    Ok(response)
+}
+
+/// Reserve depositors burn OHM for the reserves it is worth, the reserves are sent back to them
 pub fn withdraw<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    from: HumanAddr,
     sent_token: HumanAddr,
     sent_amount: u128,
     token_address : HumanAddr,
     withdraw_amount: u128
 ) -> StdResult<HandleResponse> {
-    let sender = env.message.sender.clone();
     let mut config = Config::from_storage(&mut deps.storage);
     if !config.is_reserve_token(&token_address){
         return Err(StdError::generic_err(
@@ -266,13 +410,13 @@ pub fn withdraw<S: Storage, A: Api, Q: Querier>(
                     "Can only withdraw by burning OHM",
                 ));
     }
-    let canonical_sender = deps.api.canonical_address(&sender)?;
-    if !config.has_managing_position(&canonical_sender,ManagingRole::ReserveDepositor){
+    let canonical_from = deps.api.canonical_address(&from)?;
+    if !config.has_managing_position(&canonical_from,ManagingRole::ReserveDepositor){
         return Err(StdError::generic_err(
                     "Not authorized",
                 ));
     }
-    let value = config.value_of(&deps.querier,&token_address,withdraw_amount)?;
+    let value = config.value_of_at(&deps.querier,&token_address,withdraw_amount,env.block.time)?;
     if value != sent_amount{
         return Err(StdError::generic_err(
             "Sent token amount and specified reserve amount don't match",
@@ -297,9 +441,12 @@ pub fn withdraw<S: Storage, A: Api, Q: Querier>(
         ));
     }
     let token = config.get_reserve_token_info(&token_address)?;
+
+    store_tx(&mut deps.storage, TxCode::Withdraw, Some(token_address), withdraw_amount, value, &canonical_from, &env.block)?;
+
     //We transfer the withdrawn amount
     messages.push(snip20::send_msg(
-        sender,
+        from,
         Uint128(withdraw_amount),
         None,
         None,
@@ -329,9 +476,13 @@ pub fn receive<S: Storage, A: Api, Q: Querier>(
     match msg {
         ReceiveMsg::Deposit {profit,..} => deposit(deps, env, from, sent_token, sent_amount, profit.u128()),
 
-        ReceiveMsg::Withdraw { token, amount,.. } => withdraw(deps, env, sent_token, sent_amount, token, amount.u128()),
+        ReceiveMsg::Withdraw { token, amount,.. } => withdraw(deps, env, from, sent_token, sent_amount, token, amount.u128()),
 
         ReceiveMsg::RepayDebt {..} => repay_debt(deps, env, from, sent_token, sent_amount),
+
+        ReceiveMsg::PostCollateral {..} => post_collateral(deps, env, from, sent_token, sent_amount),
+
+        ReceiveMsg::Liquidate { debtor } => liquidate(deps, env, from, sent_token, sent_amount, debtor),
     }
 }
 pub fn incur_debt<S: Storage, A: Api, Q: Querier>(
@@ -354,20 +505,15 @@ pub fn incur_debt<S: Storage, A: Api, Q: Querier>(
                     "Not authorized",
                 ));
     }
-    let value = config.value_of(&deps.querier,&token,amount)?;
-    let from_balance = snip20::balance_query(
-        &deps.querier,
-        sender.clone(),
-        COMMON_VIEWING_KEY.to_string(),
-        RESPONSE_BLOCK_SIZE,
-        config.constants()?.sohm.code_hash,
-        config.constants()?.sohm.address
-    )?;
-    let maximum_debt = from_balance.amount.u128();// Can only borrow against sOHM held
-
-    let debtors = ReadonlyDebtors::from_storage(& deps.storage);
-
-    let current_debt = debtors.debt(&canonical_sender);
+    let value = config.value_of_at(&deps.querier,&token,amount,env.block.time)?;
+    // Can only borrow against sOHM escrowed in the treasury
+    let collateral_gons = ReadonlyCollateral::from_storage(&deps.storage).gons(&canonical_sender);
+    let collateral = balance_for_gons(&deps.querier, &config.constants()?.sohm, collateral_gons)?;
+    let maximum_debt = maximum_debt_for(&config, collateral);
+
+    // Interests are accrued before the new debt is added
+    let mut positions = accrue_interest(deps, &canonical_sender, env.block.height)?;
+    let current_debt = total_debt_of(&positions);
     //Check if the debtor can occur this amount of debt
     if let Some(available_debt) = maximum_debt.checked_sub(current_debt) {
         if value > available_debt{
@@ -381,16 +527,22 @@ pub fn incur_debt<S: Storage, A: Api, Q: Querier>(
         ));
     }
     //Update the debtors debt
-    let mut debtors = Debtors::from_storage(&mut deps.storage);
-    if let Some(new_debt) = current_debt.checked_add(value){
-        debtors.set_account_debt(&canonical_sender,new_debt);
+    if let Some(position) = positions.iter_mut().find(|position| position.token == token){
+        position.amount = Uint128(position.amount.u128().checked_add(value).ok_or_else(|| {
+            StdError::generic_err("Not possible, the debt of this account is above the u128 capacity")
+        })?);
     } else {
-        return Err(StdError::generic_err(
-            "Not possible, the debt of this account is above the u128 capacity",
-        ));
-    };
+        positions.push(DebtPosition{
+            token : token.clone(),
+            amount : Uint128(value),
+            interest : Uint128(0),
+            last_block : env.block.height,
+        });
+    }
+    Debtors::from_storage(&mut deps.storage).set_positions(&canonical_sender,&positions)?;
     // Update the total debt 
     let mut config = Config::from_storage(&mut deps.storage);
+    config.add_position_holder(&canonical_sender)?;
     if let Some(new_total_debt) = config.total_debt().checked_add(value){
         config.set_total_debt(new_total_debt);
     } else {
@@ -407,7 +559,8 @@ pub fn incur_debt<S: Storage, A: Api, Q: Querier>(
         ));
     }
     let token_info = config.get_reserve_token_info(&token)?;
-    
+
+    store_tx(&mut deps.storage, TxCode::IncurDebt, Some(token), amount, value, &canonical_sender, &env.block)?;
 
     let messages = vec![
         snip20::send_msg(
@@ -429,7 +582,7 @@ pub fn incur_debt<S: Storage, A: Api, Q: Querier>(
 }
 pub fn repay_debt<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    _env: Env,
+    env: Env,
     from : HumanAddr,
     token : HumanAddr,
     amount: u128,
@@ -453,10 +606,16 @@ pub fn repay_debt<S: Storage, A: Api, Q: Querier>(
                 ));
     }
 
-    let value = config.value_of(&deps.querier,&token,amount)?;
+    let value = config.value_of_at(&deps.querier,&token,amount,env.block.time)?;
 
-    // Update the total debt 
-    if let Some(new_total_debt) = config.total_debt().checked_sub(value){
+    //Update the debtors debt, the position of the repaid token is repaid first
+    let mut positions = accrue_interest(deps, &canonical_from, env.block.height)?;
+    let principal = reduce_positions(&mut positions, &token, value)?;
+    Debtors::from_storage(&mut deps.storage).set_positions(&canonical_from,&positions)?;
+
+    let mut config = Config::from_storage(&mut deps.storage);
+    // Update the total debt, the interest repaid is a gain of reserves
+    if let Some(new_total_debt) = config.total_debt().checked_sub(principal){
         config.set_total_debt(new_total_debt);
     } else {
         return Err(StdError::generic_err(
@@ -485,16 +644,8 @@ pub fn repay_debt<S: Storage, A: Api, Q: Querier>(
         )?);
 
     }
-    
-    //Update the debtors debt
-    let mut debtors = Debtors::from_storage(&mut deps.storage);
-    if let Some(new_debt) = debtors.debt(&canonical_from).checked_sub(value){
-        debtors.set_account_debt(&canonical_from,new_debt);
-    } else {
-        return Err(StdError::generic_err(
-            "Not possible, the debt of this account would be negative",
-        ));
-    };
+
+    store_tx(&mut deps.storage, TxCode::RepayDebt, Some(token), amount, value, &canonical_from, &env.block)?;
 
     Ok(HandleResponse {
         messages : messages,
@@ -503,173 +654,602 @@ pub fn repay_debt<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-//Let me talk to management
-pub fn manage<S: Storage, A: Api, Q: Querier>(
+pub fn post_collateral<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    env: Env,
+    _env: Env,
+    from : HumanAddr,
     token : HumanAddr,
     amount: u128,
 ) -> StdResult<HandleResponse> {
 
-    let sender = env.message.sender.clone();
-    let mut config = Config::from_storage(&mut deps.storage);
-    let canonical_sender = deps.api.canonical_address(&sender)?;
-    if !((config.is_reserve_token(&token) & config.has_managing_position(&canonical_sender,ManagingRole::ReserveManager)) | 
-        (config.is_liquidity_token(&token) & config.has_managing_position(&canonical_sender,ManagingRole::LiquidityManager)))
-    {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let sohm = config.constants()?.sohm;
+    if token != sohm.address{
         return Err(StdError::generic_err(
-                    "Token not accepted",
+                    "Only sOHM can be posted as collateral",
                 ));
     }
 
-    let value = config.value_of(&deps.querier,&token,amount)?;
-    if value > config.excess_reserves(&deps.querier)?{
+    let canonical_from = deps.api.canonical_address(&from)?;
+    if !config.has_managing_position(&canonical_from,ManagingRole::Debtor){
         return Err(StdError::generic_err(
-                    "Insufficient reserves" ,
+                    "Not authorized",
                 ));
     }
-    if let Some(new_total_reserves) = config.total_reserves().checked_sub(value){
-        config.set_total_reserves(new_total_reserves);
+
+    // The collateral is stored in gons, so that it grows with the rebases
+    let gons = gons_for_balance(&deps.querier, &sohm, amount)?;
+    let mut collateral = Collateral::from_storage(&mut deps.storage);
+    if let Some(new_gons) = collateral.gons(&canonical_from).checked_add(gons){
+        collateral.set_gons(&canonical_from,new_gons)?;
     } else {
         return Err(StdError::generic_err(
-            "Insufficient reserves",
+            "Not possible, the collateral of this account is above the U256 capacity",
         ));
-    }
-    let token_info = config.get_reserve_token_info(&token)?;
-    let messages = vec![snip20::send_msg(
-         sender,
-         Uint128(amount),
-         None, //TODO Maybe change that, so that the recipient knows what to do with the funds
-         None,
-         RESPONSE_BLOCK_SIZE,
-         token_info.code_hash.clone(),
-         token_info.address.clone(),
-        )?];
+    };
+    Config::from_storage(&mut deps.storage).add_position_holder(&canonical_from)?;
 
     Ok(HandleResponse {
-        messages : messages,
+        messages : vec![],
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::Manage { status: Success })?),
+        data: Some(to_binary(&ReceiveAnswer::PostCollateral { status: Success })?),
     })
 }
 
-
-//Let me talk to management
-pub fn mint_rewards<S: Storage, A: Api, Q: Querier>(
+pub fn withdraw_collateral<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    recipient: HumanAddr,
-    mut amount: u128,
+    amount: u128,
 ) -> StdResult<HandleResponse> {
-
     let sender = env.message.sender.clone();
-    let config = Config::from_storage(&mut deps.storage);
     let canonical_sender = deps.api.canonical_address(&sender)?;
-    if !config.has_managing_position(&canonical_sender,ManagingRole::RewardManager)
-    {
+    let positions = accrue_interest(deps, &canonical_sender, env.block.height)?;
+    let debt = total_debt_of(&positions);
+
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let sohm = config.constants()?.sohm;
+    let collateral_gons = ReadonlyCollateral::from_storage(&deps.storage).gons(&canonical_sender);
+    let withdrawn_gons = gons_for_balance(&deps.querier, &sohm, amount)?;
+
+    let remaining_gons = collateral_gons.checked_sub(withdrawn_gons).ok_or_else(|| {
+        StdError::generic_err("Not enough collateral posted")
+    })?;
+    // The remaining collateral still has to cover the debt of the account
+    let remaining_collateral = balance_for_gons(&deps.querier, &sohm, remaining_gons)?;
+    if maximum_debt_for(&config, remaining_collateral) < debt{
         return Err(StdError::generic_err(
-                    "Address not approved",
-                ));
-    }
-    if amount > config.excess_reserves(&deps.querier)?
-    {
-        amount = config.excess_reserves(&deps.querier)?;
+            "Not possible, the remaining collateral would not cover the debt",
+        ));
     }
 
+    Collateral::from_storage(&mut deps.storage).set_gons(&canonical_sender,remaining_gons)?;
+
+    let messages = vec![
+        snip20::transfer_msg(
+        sender,
+        Uint128(amount),
+        None,
+        RESPONSE_BLOCK_SIZE,
+        sohm.code_hash,
+        sohm.address
+    )?];
+
     Ok(HandleResponse {
-        messages : vec![snip20::mint_msg(
-            recipient,
-            Uint128(amount),
-            None,
-            RESPONSE_BLOCK_SIZE,
-            config.constants()?.ohm.code_hash,
-            config.constants()?.ohm.address,
-            )?],
+        messages : messages,
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::MintRewards { status: Success })?),
+        data: Some(to_binary(&HandleAnswer::WithdrawCollateral { status: Success })?),
     })
 }
-pub fn audit_reserves<S: Storage, A: Api, Q: Querier>(
+
+/// The liquidator repays debt of an unhealthy debtor with a reserve token or OHM,
+/// and receives the repaid value in sOHM collateral plus the liquidation bonus
+pub fn liquidate<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    from: HumanAddr,
+    token: HumanAddr,
+    amount: u128,
+    debtor: HumanAddr,
 ) -> StdResult<HandleResponse> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let ohm = config.constants()?.ohm;
+    let token_is_ohm = token == ohm.address;
+    if !(config.is_reserve_token(&token) || token_is_ohm){
+        return Err(StdError::generic_err(
+                    "Token not accepted",
+                ));
+    }
+    let parameters = config.liquidation_parameters().ok_or_else(|| {
+        StdError::generic_err("Liquidations are not enabled")
+    })?;
+    let repaid = config.value_of_at(&deps.querier,&token,amount,env.block.time)?;
 
-    let sender = env.message.sender.clone();
-    let mut config = Config::from_storage(&mut deps.storage);
+    let canonical_debtor = deps.api.canonical_address(&debtor)?;
+    let mut positions = accrue_interest(deps, &canonical_debtor, env.block.height)?;
+    let debt = total_debt_of(&positions);
 
-    check_if_admin(&config, &sender)?;
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let sohm = config.constants()?.sohm;
+    let collateral_gons = ReadonlyCollateral::from_storage(&deps.storage).gons(&canonical_debtor);
+    let collateral = balance_for_gons(&deps.querier, &sohm, collateral_gons)?;
+    if debt == 0 || !is_liquidatable(&parameters, collateral, debt){
+        return Err(StdError::generic_err(
+            "This debtor is healthy, it can't be liquidated",
+        ));
+    }
+    if repaid == 0 || repaid > debt{
+        return Err(StdError::generic_err(
+            "The repaid value should be between 0 and the debt of the debtor",
+        ));
+    }
 
-    let contract_address = env.contract.address.clone();
-    let mut reserves :u128 = 0;
-    for token in config.reserve_tokens(){
+    // Only the repaid value is seized, with the bonus on top of it
+    let bonus = repaid.saturating_mul(parameters.bonus.u128()) / 10_000;
+    let seized = std::cmp::min(collateral, repaid.saturating_add(bonus));
+    let seized_gons = std::cmp::min(collateral_gons, gons_for_balance(&deps.querier, &sohm, seized)?);
 
-        let balance = snip20::balance_query(
-            &deps.querier,
-            contract_address.clone(),
-            COMMON_VIEWING_KEY.to_string(),
-            RESPONSE_BLOCK_SIZE,
-            token.code_hash.clone(),
-            token.address.clone()
-        )?.amount.u128();  
+    Collateral::from_storage(&mut deps.storage).set_gons(&canonical_debtor,collateral_gons - seized_gons)?;
+    let principal = reduce_positions(&mut positions, &token, repaid)?;
+    Debtors::from_storage(&mut deps.storage).set_positions(&canonical_debtor,&positions)?;
 
-        reserves = reserves.checked_add(config.value_of(&deps.querier,&token.address,balance)?)
-        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+    let mut config = Config::from_storage(&mut deps.storage);
+    if let Some(new_total_debt) = config.total_debt().checked_sub(principal){
+        config.set_total_debt(new_total_debt);
+    } else {
+        return Err(StdError::generic_err(
+            "Not possible, total debt would be negative",
+        ));
     }
-    for token in config.liquidity_tokens(){
-
-        let balance = snip20::balance_query(
-            &deps.querier,
-            contract_address.clone(),
-            COMMON_VIEWING_KEY.to_string(),
+    // The repayment is booked like a repayment of the debtor
+    let mut messages = vec![];
+    if !token_is_ohm{
+        if let Some(new_total_reserves) = config.total_reserves().checked_add(repaid){
+            config.set_total_reserves(new_total_reserves);
+        } else {
+            return Err(StdError::generic_err(
+                "The total reserves are through the roof",
+            ));
+        }
+    }else{
+        messages.push(snip20::burn_msg(
+            Uint128(amount),
+            None,
             RESPONSE_BLOCK_SIZE,
-            token.code_hash.clone(),
-            token.address.clone()
-        )?.amount.u128();   
-
-        reserves = reserves.checked_add(config.value_of(&deps.querier,&token.address,balance)?)
-        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+            ohm.code_hash,
+            ohm.address,
+        )?);
     }
-    config.set_total_reserves(reserves);
+    if seized != 0{
+        messages.push(snip20::transfer_msg(
+            from.clone(),
+            Uint128(seized),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            sohm.code_hash,
+            sohm.address
+        )?);
+    }
+
+    let canonical_from = deps.api.canonical_address(&from)?;
+    store_tx(&mut deps.storage, TxCode::Liquidate, Some(token), amount, repaid, &canonical_from, &env.block)?;
 
     Ok(HandleResponse {
-        messages : vec![],
+        messages : messages,
         log: vec![],
-        data: Some(to_binary(&HandleAnswer::AuditReserves { status: Success })?),
+        data: Some(to_binary(&HandleAnswer::Liquidate {
+            status: Success,
+            repaid: Uint128(repaid),
+            seized: Uint128(seized),
+        })?),
     })
 }
 
-pub fn require_different(a : HumanAddr,b: HumanAddr) -> StdResult<HandleResponse>{
-    if a == b
-    {
-        return Err(StdError::generic_err(format!(
-                    "{} is the same as {}", a, b,
-                )));
+pub fn set_borrow_rate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr,
+    rate: u128,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config, &env.message.sender)?;
+    if !config.is_reserve_token(&token){
+        return Err(StdError::generic_err(
+                    "Token not accepted",
+                ));
     }
-    Ok(HandleResponse::default())
+    config.set_borrow_rate(&token, rate)?;
 
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetBorrowRate { status: Success })?),
+    })
 }
 
-pub fn queue<S: Storage, A: Api, Q: Querier>(
+pub fn set_liquidation_parameters<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    address : HumanAddr,
-    new_role : ManagingRole
+    ltv: u128,
+    bonus: u128,
 ) -> StdResult<HandleResponse> {
-
-    let sender = env.message.sender.clone();
     let mut config = Config::from_storage(&mut deps.storage);
-    check_if_admin(&config,&sender)?;
-    require_different(address.clone(),HumanAddr::default())?;
-    let canonical_addr = deps.api.canonical_address(&address)?;
-    let mut blocks_needed_for_this_queue = config.constants()?.blocks_needed_for_queue;
-    match new_role{
-        ManagingRole::ReserveManager{} | ManagingRole::LiquidityManager{}
+    check_if_admin(&config, &env.message.sender)?;
+    if ltv == 0 || ltv > 10_000{
+        return Err(StdError::generic_err("The LTV should be between 0 and 100%"));
+    }
+    if bonus > 10_000{
+        return Err(StdError::generic_err("The liquidation bonus should not be higher than 100%"));
+    }
+    config.set_liquidation_parameters(&LiquidationParameters{
+        ltv: Uint128(ltv),
+        bonus: Uint128(bonus),
+    })?;
 
-            => blocks_needed_for_this_queue = blocks_needed_for_this_queue.checked_mul(2).ok_or_else(|| {
-                    StdError::generic_err("This is the end of the blockchain, no more blocks")
-                })?,
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetLiquidationParameters { status: Success })?),
+    })
+}
+
+pub fn set_valuation_source<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr,
+    source: ValuationSource,
+) -> StdResult<HandleResponse> {
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config, &env.message.sender)?;
+    if !config.is_reserve_token(&token){
+        return Err(StdError::generic_err(
+                    "Token not accepted",
+                ));
+    }
+    config.set_valuation_source(&token, &source)?;
+
+    // Make sure the new source is able to value the token right away
+    config.value_of_at(&deps.querier, &token, 0, env.block.time)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetValuationSource { status: Success })?),
+    })
+}
+
+/// Debt positions of the account with the interests accrued up to `block_height`
+fn accrued_positions<S: ReadonlyStorage>(
+    storage: &S,
+    account: &CanonicalAddr,
+    block_height: u64,
+) -> StdResult<Vec<DebtPosition>> {
+    let config = ReadonlyConfig::from_storage(storage);
+    let mut positions = ReadonlyDebtors::from_storage(storage).positions(account)?;
+    for position in positions.iter_mut(){
+        let blocks_since_last = block_height.checked_sub(position.last_block).ok_or_else(|| {
+            StdError::generic_err("You can't accrue interests for a past block !")
+        })?;
+        let rate = config.borrow_rate(&position.token);
+        let interest = position.amount.u128()
+            .checked_mul(rate)
+            .and_then(|interest| interest.checked_mul(blocks_since_last.into()))
+            .ok_or_else(|| StdError::generic_err("Too much interests, the position is above the u128 capacity"))?
+            / BORROW_RATE_DENOMINATOR;
+        // Interests that round down to zero keep accumulating from the last accrual
+        if interest != 0 || rate == 0{
+            position.last_block = block_height;
+        }
+        position.amount = Uint128(position.amount.u128().checked_add(interest).ok_or_else(|| {
+            StdError::generic_err("Not possible, the debt of this account is above the u128 capacity")
+        })?);
+        position.interest = Uint128(position.interest.u128() + interest);
+    }
+    Ok(positions)
+}
+
+/// Accrues the interests of the account. They stay out of the total debt, so that the
+/// excess reserves never count interests that weren't collected
+fn accrue_interest<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    account: &CanonicalAddr,
+    block_height: u64,
+) -> StdResult<Vec<DebtPosition>> {
+    let positions = accrued_positions(&deps.storage, account, block_height)?;
+    Debtors::from_storage(&mut deps.storage).set_positions(account,&positions)?;
+    Ok(positions)
+}
+
+/// Repays `value` across the positions, starting with the position of `token`.
+/// The interest of a position is repaid before its principal, returns the principal repaid
+fn reduce_positions(
+    positions: &mut Vec<DebtPosition>,
+    token: &HumanAddr,
+    value: u128,
+) -> StdResult<u128> {
+    if value > total_debt_of(positions){
+        return Err(StdError::generic_err(
+            "Not possible, the debt of this account would be negative",
+        ));
+    }
+    positions.sort_by_key(|position| position.token != *token);
+    let mut remaining = value;
+    let mut principal = 0;
+    for position in positions.iter_mut(){
+        let repaid = std::cmp::min(position.amount.u128(), remaining);
+        let interest_repaid = std::cmp::min(position.interest.u128(), repaid);
+        position.amount = Uint128(position.amount.u128() - repaid);
+        position.interest = Uint128(position.interest.u128() - interest_repaid);
+        principal += repaid - interest_repaid;
+        remaining -= repaid;
+    }
+    positions.retain(|position| position.amount.u128() != 0);
+    Ok(principal)
+}
+
+fn total_debt_of(positions: &[DebtPosition]) -> u128 {
+    positions.iter().map(|position| position.amount.u128()).sum()
+}
+
+/// Debt of the positions counted in the total debt, without their accrued interest
+fn principal_of(positions: &[DebtPosition]) -> u128 {
+    positions.iter().map(|position| position.amount.u128() - position.interest.u128()).sum()
+}
+
+/// When liquidations are enabled, debtors can only borrow up to the LTV of their collateral
+fn maximum_debt_for<S: ReadonlyStorage>(config: &ReadonlyConfig<S>, collateral: u128) -> u128 {
+    match config.liquidation_parameters(){
+        Some(parameters) => collateral.saturating_mul(parameters.ltv.u128()) / 10_000,
+        None => collateral,
+    }
+}
+
+fn is_liquidatable(parameters: &LiquidationParameters, collateral: u128, debt: u128) -> bool {
+    debt.saturating_mul(10_000) > collateral.saturating_mul(parameters.ltv.u128())
+}
+
+fn strategy_value<Q: Querier>(querier: &Q, strategy: &Strategy) -> StdResult<u128> {
+    let strategy_value_response: StrategyValueResponse = StrategyQueryMsg::StrategyValue {}.query(
+        querier,
+        strategy.code_hash.clone(),
+        strategy.address.clone(),
+    )?;
+    Ok(strategy_value_response.strategy_value.amount.u128())
+}
+
+fn gons_for_balance<Q: Querier>(
+    querier: &Q,
+    sohm: &Contract,
+    amount: u128,
+) -> StdResult<U256> {
+    let gons_for_balance_query_msg = SOhmQueryMsg::GonsForBalance {
+        amount: Uint128(amount),
+    };
+    let gons_for_balance_response: GonsForBalanceResponse = gons_for_balance_query_msg.query(
+        querier,
+        sohm.code_hash.clone(),
+        sohm.address.clone(),
+    )?;
+    U256::from_dec_str(&gons_for_balance_response.gons_for_balance.gons).map_err(|_| {
+        StdError::generic_err("sOHM returned an invalid amount of gons")
+    })
+}
+
+fn balance_for_gons<Q: Querier>(
+    querier: &Q,
+    sohm: &Contract,
+    gons: U256,
+) -> StdResult<u128> {
+    if gons.is_zero(){
+        return Ok(0);
+    }
+    let balance_for_gons_query_msg = SOhmQueryMsg::BalanceForGons {
+        gons: gons.to_string(),
+    };
+    let balance_for_gons_response: BalanceForGonsResponse = balance_for_gons_query_msg.query(
+        querier,
+        sohm.code_hash.clone(),
+        sohm.address.clone(),
+    )?;
+    Ok(balance_for_gons_response.balance_for_gons.amount.u128())
+}
+
+//Let me talk to management
+pub fn manage<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token : HumanAddr,
+    amount: u128,
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    if !((config.is_reserve_token(&token) & config.has_managing_position(&canonical_sender,ManagingRole::ReserveManager)) | 
+        (config.is_liquidity_token(&token) & config.has_managing_position(&canonical_sender,ManagingRole::LiquidityManager)))
+    {
+        return Err(StdError::generic_err(
+                    "Token not accepted",
+                ));
+    }
+
+    let value = config.value_of_at(&deps.querier,&token,amount,env.block.time)?;
+    if value > config.excess_reserves(&deps.querier)?{
+        return Err(StdError::generic_err(
+                    "Insufficient reserves" ,
+                ));
+    }
+    let manager_role = if config.is_reserve_token(&token){
+        ManagingRole::ReserveManager
+    }else{
+        ManagingRole::LiquidityManager
+    };
+    spend_budget(&mut config, &canonical_sender, manager_role, value, env.block.height)?;
+    if let Some(new_total_reserves) = config.total_reserves().checked_sub(value){
+        config.set_total_reserves(new_total_reserves);
+    } else {
+        return Err(StdError::generic_err(
+            "Insufficient reserves",
+        ));
+    }
+    let token_info = config.get_reserve_token_info(&token)?;
+
+    store_tx(&mut deps.storage, TxCode::Manage, Some(token), amount, value, &canonical_sender, &env.block)?;
+
+    let messages = vec![snip20::send_msg(
+         sender,
+         Uint128(amount),
+         None, //TODO Maybe change that, so that the recipient knows what to do with the funds
+         None,
+         RESPONSE_BLOCK_SIZE,
+         token_info.code_hash.clone(),
+         token_info.address.clone(),
+        )?];
+
+    Ok(HandleResponse {
+        messages : messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Manage { status: Success })?),
+    })
+}
+
+
+//Let me talk to management
+pub fn mint_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: HumanAddr,
+    mut amount: u128,
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    if !config.has_managing_position(&canonical_sender,ManagingRole::RewardManager)
+    {
+        return Err(StdError::generic_err(
+                    "Address not approved",
+                ));
+    }
+    if amount > config.excess_reserves(&deps.querier)?
+    {
+        amount = config.excess_reserves(&deps.querier)?;
+    }
+    spend_budget(&mut config, &canonical_sender, ManagingRole::RewardManager, amount, env.block.height)?;
+    let ohm = config.constants()?.ohm;
+
+    store_tx(&mut deps.storage, TxCode::MintRewards, Some(ohm.address.clone()), amount, amount, &canonical_sender, &env.block)?;
+
+    Ok(HandleResponse {
+        messages : vec![snip20::mint_msg(
+            recipient,
+            Uint128(amount),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            ohm.code_hash,
+            ohm.address,
+            )?],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::MintRewards { status: Success })?),
+    })
+}
+pub fn audit_reserves<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+
+    check_if_admin(&config, &sender)?;
+
+    let contract_address = env.contract.address.clone();
+    let mut reserves :u128 = 0;
+    for token in config.reserve_tokens(){
+
+        let balance = snip20::balance_query(
+            &deps.querier,
+            contract_address.clone(),
+            COMMON_VIEWING_KEY.to_string(),
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash.clone(),
+            token.address.clone()
+        )?.amount.u128();  
+
+        reserves = reserves.checked_add(config.value_of_at(&deps.querier,&token.address,balance,env.block.time)?)
+        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+    }
+    for token in config.liquidity_tokens(){
+
+        let balance = snip20::balance_query(
+            &deps.querier,
+            contract_address.clone(),
+            COMMON_VIEWING_KEY.to_string(),
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash.clone(),
+            token.address.clone()
+        )?.amount.u128();   
+
+        reserves = reserves.checked_add(config.value_of_at(&deps.querier,&token.address,balance,env.block.time)?)
+        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+    }
+    // Reserves allocated to strategies still back OHM
+    for strategy in config.strategies(){
+
+        let deployed = strategy_value(&deps.querier, &strategy)?;
+
+        reserves = reserves.checked_add(config.value_of_at(&deps.querier,&strategy.token,deployed,env.block.time)?)
+        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+    }
+    config.set_total_reserves(reserves);
+
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    store_tx(&mut deps.storage, TxCode::AuditReserves, None, reserves, reserves, &canonical_sender, &env.block)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::AuditReserves { status: Success })?),
+    })
+}
+
+pub fn require_different(a : HumanAddr,b: HumanAddr) -> StdResult<HandleResponse>{
+    if a == b
+    {
+        return Err(StdError::generic_err(format!(
+                    "{} is the same as {}", a, b,
+                )));
+    }
+    Ok(HandleResponse::default())
+
+}
+
+pub fn queue<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address : HumanAddr,
+    new_role : ManagingRole
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    require_different(address.clone(),HumanAddr::default())?;
+    let canonical_addr = deps.api.canonical_address(&address)?;
+    let revocation = holds_role(&ReadonlyConfig::from_storage(&deps.storage), &address, &canonical_addr, new_role.clone());
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    let mut blocks_needed_for_this_queue = config.constants()?.blocks_needed_for_queue;
+    if revocation && config.revocation_delay() != 0{
+        // Queuing an address that already holds the role schedules its revocation
+        blocks_needed_for_this_queue = config.revocation_delay();
+    }else{
+        match new_role{
+            ManagingRole::ReserveManager{} | ManagingRole::LiquidityManager{}
+
+                => blocks_needed_for_this_queue = blocks_needed_for_this_queue.checked_mul(2).ok_or_else(|| {
+                        StdError::generic_err("This is the end of the blockchain, no more blocks")
+                    })?,
 
-        _ => (),
+            _ => (),
+        }
     }
     config.set_managing_queue(
         &canonical_addr,
@@ -711,6 +1291,9 @@ pub fn toggle_queue<S: Storage, A: Api, Q: Querier>(
         config.set_managing_queue(&canonical_addr,role.clone(),0)?;
         config.set_managing_position(&canonical_addr,role.clone(),true)?;
         new_has_managing_position = true;
+    }else{
+        check_revocation_queue(&mut config, &canonical_addr, role.clone(), env.block.height)?;
+        config.clear_budget(&canonical_addr, role.clone())?;
     }
     config.set_managing_position(&canonical_addr,role.clone(),new_has_managing_position)?;
 
@@ -781,8 +1364,14 @@ pub fn toggle_token_queue<S: Storage, A: Api, Q: Querier>(
         }?;
     }else{
         match role.clone(){
-            ManagingRole::ReserveToken{} => config.remove_reserve_token(token),
-            ManagingRole::LiquidityToken{} => config.remove_liquidity_token(token),
+            ManagingRole::ReserveToken{} => {
+                check_revocation_queue(&mut config, &canonical_addr, role.clone(), env.block.height)?;
+                config.remove_reserve_token(token)
+            },
+            ManagingRole::LiquidityToken{} => {
+                check_revocation_queue(&mut config, &canonical_addr, role.clone(), env.block.height)?;
+                config.remove_liquidity_token(token)
+            },
             ManagingRole::SOHM{} => {
 
                 config.set_managing_queue(&canonical_addr,role,0)?;
@@ -801,23 +1390,489 @@ pub fn toggle_token_queue<S: Storage, A: Api, Q: Querier>(
         data: Some(to_binary(&HandleAnswer::ToggleQueue { status: Success })?),
     })
 }
-fn query_contract_info<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    ) -> QueryResult {
-    let config = ReadonlyConfig::from_storage(&deps.storage);
-    let constants = config.constants()?;
-    to_binary(&
-         QueryAnswer::ContractInfo{
-            name : constants.name.clone(),
-            admin : constants.admin.clone(),
-            prng_seed : constants.prng_seed.clone(),
-            ohm : constants.ohm.clone(),
-            sohm : constants.sohm.clone(),
-            blocks_needed_for_queue : constants.blocks_needed_for_queue.clone(),
-            total_reserves: Uint128(config.total_reserves()),
-            total_debt: Uint128(config.total_debt()),
-            excess_reserves: Uint128(config.excess_reserves(&deps.querier)?)
-        }
+pub fn cancel_queue<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address : HumanAddr,
+    role : ManagingRole
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    let canonical_addr = deps.api.canonical_address(&address)?;
+
+    if config.managing_queue(&canonical_addr,role.clone())? == 0{
+        return Err(StdError::generic_err("Nothing is queued for this address"));
+    }
+    config.set_managing_queue(&canonical_addr,role,0)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CancelQueue { status: Success })?),
+    })
+}
+
+pub fn set_revocation_delay<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    blocks : u64
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    config.set_revocation_delay(blocks)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetRevocationDelay { status: Success })?),
+    })
+}
+
+pub fn set_budget<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address : HumanAddr,
+    role : ManagingRole,
+    budget : Option<Budget>
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    match role{
+        ManagingRole::ReserveManager{} | ManagingRole::LiquidityManager{} | ManagingRole::RewardManager{} => (),
+        _ => return Err(StdError::generic_err("Budgets only apply to manager roles")),
+    }
+    if let Some(budget) = &budget{
+        if budget.period == 0{
+            return Err(StdError::generic_err("The budget period should be at least one block"));
+        }
+    }
+    let canonical_addr = deps.api.canonical_address(&address)?;
+    config.set_budget(&canonical_addr,role,budget)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetBudget { status: Success })?),
+    })
+}
+
+pub fn register_strategy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address : HumanAddr,
+    code_hash : String,
+    token : HumanAddr
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    if !config.is_reserve_token(&token){
+        return Err(StdError::generic_err(
+                    "Token not accepted",
+                ));
+    }
+    if config.strategy(&address).is_ok(){
+        return Err(StdError::generic_err("This strategy is already registered"));
+    }
+    config.save_strategy(Strategy{
+        address,
+        code_hash,
+        token,
+        principal: Uint128(0),
+        gains: Uint128(0),
+        losses: Uint128(0),
+    })?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RegisterStrategy { status: Success })?),
+    })
+}
+
+pub fn remove_strategy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address : HumanAddr
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    if config.strategy(&address)?.principal.u128() != 0{
+        return Err(StdError::generic_err("Withdraw the reserves from the strategy first"));
+    }
+    config.remove_strategy(&address)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RemoveStrategy { status: Success })?),
+    })
+}
+
+pub fn allocate_to_strategy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    strategy_address : HumanAddr,
+    amount : u128
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    let mut strategy = config.strategy(&strategy_address)?;
+    let token_info = config.get_reserve_token_info(&strategy.token)?;
+    let value = config.value_of_at(&deps.querier,&strategy.token,amount,env.block.time)?;
+
+    // The allocated reserves stay in the total reserves, they are only moved
+    strategy.principal = Uint128(strategy.principal.u128().checked_add(amount).ok_or_else(|| {
+        StdError::generic_err("Too much reserves")
+    })?);
+    let token = strategy.token.clone();
+    config.save_strategy(strategy)?;
+
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    store_tx(&mut deps.storage, TxCode::AllocateToStrategy, Some(token), amount, value, &canonical_sender, &env.block)?;
+
+    Ok(HandleResponse {
+        messages : vec![snip20::send_msg(
+            strategy_address,
+            Uint128(amount),
+            Some(to_binary(&StrategyReceiveMsg::Deposit {})?),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            token_info.code_hash,
+            token_info.address,
+            )?],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::AllocateToStrategy { status: Success })?),
+    })
+}
+
+pub fn withdraw_from_strategy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    strategy_address : HumanAddr,
+    amount : u128
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    let mut strategy = config.strategy(&strategy_address)?;
+    let value = config.value_of_at(&deps.querier,&strategy.token,amount,env.block.time)?;
+
+    strategy.principal = Uint128(strategy.principal.u128().checked_sub(amount).ok_or_else(|| {
+        StdError::generic_err("Not enough reserves in the strategy, report its gains first")
+    })?);
+    let token = strategy.token.clone();
+    let code_hash = strategy.code_hash.clone();
+    config.save_strategy(strategy)?;
+
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    store_tx(&mut deps.storage, TxCode::WithdrawFromStrategy, Some(token), amount, value, &canonical_sender, &env.block)?;
+
+    Ok(HandleResponse {
+        messages : vec![StrategyHandleMsg::Withdraw {
+            amount: Uint128(amount),
+        }.to_cosmos_msg(
+            code_hash,
+            strategy_address,
+            None,
+        )?],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::WithdrawFromStrategy { status: Success })?),
+    })
+}
+
+/// Compares the value reported by the strategy to its principal and books the difference in the reserves
+pub fn report_strategy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    strategy_address : HumanAddr
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    if sender != strategy_address{
+        check_if_admin(&config,&sender)?;
+    }
+    let mut strategy = config.strategy(&strategy_address)?;
+    let deployed = strategy_value(&deps.querier, &strategy)?;
+    let principal = strategy.principal.u128();
+
+    let gain = deployed.saturating_sub(principal);
+    let loss = principal.saturating_sub(deployed);
+    if gain != 0{
+        let gain_value = config.value_of_at(&deps.querier,&strategy.token,gain,env.block.time)?;
+        let total_reserves = config.total_reserves().checked_add(gain_value).ok_or_else(|| {
+            StdError::generic_err("Too much reserves")
+        })?;
+        config.set_total_reserves(total_reserves);
+        strategy.gains = Uint128(strategy.gains.u128().checked_add(gain).ok_or_else(|| {
+            StdError::generic_err("Too much gains")
+        })?);
+    }
+    if loss != 0{
+        let loss_value = config.value_of_at(&deps.querier,&strategy.token,loss,env.block.time)?;
+        config.set_total_reserves(config.total_reserves().saturating_sub(loss_value));
+        strategy.losses = Uint128(strategy.losses.u128().checked_add(loss).ok_or_else(|| {
+            StdError::generic_err("Too much losses")
+        })?);
+    }
+    strategy.principal = Uint128(deployed);
+    config.save_strategy(strategy)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ReportStrategy {
+            status: Success,
+            gain: Uint128(gain),
+            loss: Uint128(loss),
+        })?),
+    })
+}
+
+/// The first call queues the migration, the reserves are sent to the new treasury
+/// when called again once the queue is over. OHM minting rights have to be moved separately.
+pub fn migrate_to<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    new_treasury : HumanAddr
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    require_different(new_treasury.clone(),env.contract.address.clone())?;
+
+    let migration = match config.pending_migration(){
+        Some(migration) if migration.new_treasury == new_treasury => migration,
+        _ => {
+            // Migrating the treasury is as sensitive as adding a reserve manager, and is never instant
+            let blocks_needed = config.constants()?.blocks_needed_for_queue.checked_mul(2).ok_or_else(|| {
+                StdError::generic_err("This is the end of the blockchain, no more blocks")
+            })?.max(MIN_MIGRATION_DELAY);
+            config.set_pending_migration(Some(Migration{
+                new_treasury,
+                unlock_height: env.block.height.checked_add(blocks_needed).ok_or_else(|| {
+                    StdError::generic_err("This is the end of the blockchain, no more blocks")
+                })?,
+            }))?;
+            return Ok(HandleResponse {
+                messages : vec![],
+                log: vec![],
+                data: Some(to_binary(&HandleAnswer::MigrateTo { status: Success, executed: false })?),
+            });
+        }
+    };
+    if migration.unlock_height > env.block.height{
+        return Err(StdError::generic_err("Queue is not over yet, wait a bit more"));
+    }
+    if config.strategies().iter().any(|strategy| strategy.principal.u128() != 0){
+        return Err(StdError::generic_err("Withdraw the reserves from the strategies first"));
+    }
+
+    // Every reserve, liquidity token and the sOHM collateral is sent to the new treasury
+    let tokens = config.reserve_tokens().into_iter()
+        .chain(config.liquidity_tokens().into_iter())
+        .chain(std::iter::once(config.constants()?.sohm));
+    let mut messages = vec![];
+    for token in tokens{
+        let balance = snip20::balance_query(
+            &deps.querier,
+            env.contract.address.clone(),
+            COMMON_VIEWING_KEY.to_string(),
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash.clone(),
+            token.address.clone()
+        )?.amount;
+        if balance.u128() == 0{
+            continue;
+        }
+        messages.push(snip20::transfer_msg(
+            migration.new_treasury.clone(),
+            balance,
+            None,
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash,
+            token.address,
+        )?);
+    }
+    config.set_pending_migration(None)?;
+    config.set_total_reserves(0);
+    config.set_contract_status(ContractStatusLevel::StopAll);
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::MigrateTo { status: Success, executed: true })?),
+    })
+}
+
+pub fn set_native_wrapper<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token : Option<HumanAddr>
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    if let Some(token) = &token{
+        if !config.is_reserve_token(token){
+            return Err(StdError::generic_err(
+                        "Token not accepted",
+                    ));
+        }
+    }
+    config.set_native_wrapper(token)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetNativeWrapper { status: Success })?),
+    })
+}
+
+pub fn cancel_migration<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+
+    let sender = env.message.sender.clone();
+    let mut config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&sender)?;
+    if config.pending_migration().is_none(){
+        return Err(StdError::generic_err("No migration is queued"));
+    }
+    config.set_pending_migration(None)?;
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CancelMigration { status: Success })?),
+    })
+}
+
+/// Imports the pages of the exported state that didn't fit in the init message, the import is closed after the last page
+pub fn import_state_page<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    state: ExportedState,
+    last_page: bool,
+) -> StdResult<HandleResponse> {
+
+    let config = Config::from_storage(&mut deps.storage);
+    check_if_admin(&config,&env.message.sender)?;
+    if !config.import_open(){
+        return Err(StdError::generic_err("The state import is closed"));
+    }
+    import_state(deps, state)?;
+    if last_page{
+        Config::from_storage(&mut deps.storage).set_import_open(false)?;
+    }
+
+    Ok(HandleResponse {
+        messages : vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ImportState { status: Success })?),
+    })
+}
+
+/// Records `value` as used in the current period of the manager budget, if it has one
+fn spend_budget<S: Storage>(
+    config: &mut Config<S>,
+    canonical_addr: &CanonicalAddr,
+    role: ManagingRole,
+    value: u128,
+    block_height: u64
+) -> StdResult<()> {
+    let budget = if let Some(budget) = config.budget(canonical_addr,role.clone()){
+        budget
+    }else{
+        return Ok(());
+    };
+    let usage = config.budget_usage(canonical_addr,role.clone());
+    if value > budget.remaining(&usage, block_height){
+        return Err(StdError::generic_err("The manager budget for this period is exceeded"));
+    }
+    let period_start = budget.period_start(block_height);
+    let used = if usage.period_start == period_start { usage.used.u128() } else { 0 };
+    let used = used.checked_add(value).ok_or_else(|| {
+        StdError::generic_err("The manager budget for this period is exceeded")
+    })?;
+    config.set_budget_usage(canonical_addr, role, &BudgetUsage{
+        used: Uint128(used),
+        period_start,
+    })
+}
+
+/// Whether the address (or token) currently holds the role, i.e. whether toggling it would revoke it
+fn holds_role<S: ReadonlyStorage>(
+    config: &ReadonlyConfig<S>,
+    address: &HumanAddr,
+    canonical_addr: &CanonicalAddr,
+    role: ManagingRole
+) -> bool {
+    match role{
+        ManagingRole::ReserveToken{} => config.is_reserve_token(address),
+        ManagingRole::LiquidityToken{} => config.is_liquidity_token(address),
+        // The sOHM queue is used to replace the token, it is never revoked
+        ManagingRole::SOHM{} => false,
+        _ => config.has_managing_position(canonical_addr,role),
+    }
+}
+
+/// When revocations are delayed, the revocation needs to be queued and the queue to be over
+fn check_revocation_queue<S: Storage>(
+    config: &mut Config<S>,
+    canonical_addr: &CanonicalAddr,
+    role: ManagingRole,
+    block_height: u64
+) -> StdResult<()> {
+    let queue = config.managing_queue(canonical_addr,role.clone())?;
+    if config.revocation_delay() != 0{
+        if queue == 0{
+            return Err(StdError::generic_err("You need to queue the revocation first"));
+        }else if queue > block_height{
+            return Err(StdError::generic_err("Queue is not over yet, wait a bit more"));
+        }
+    }
+    if queue != 0{
+        config.set_managing_queue(canonical_addr,role,0)?;
+    }
+    Ok(())
+}
+
+fn query_contract_info<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let constants = config.constants()?;
+    to_binary(&
+         QueryAnswer::ContractInfo{
+            name : constants.name.clone(),
+            admin : constants.admin.clone(),
+            prng_seed : constants.prng_seed.clone(),
+            ohm : constants.ohm.clone(),
+            sohm : constants.sohm.clone(),
+            blocks_needed_for_queue : constants.blocks_needed_for_queue.clone(),
+            blocks_needed_for_revocation : config.revocation_delay(),
+            native_wrapper : config.native_wrapper(),
+            total_reserves: Uint128(config.total_reserves()),
+            total_debt: Uint128(config.total_debt()),
+            excess_reserves: Uint128(config.excess_reserves(&deps.querier)?)
+        }
     )
 }
 fn query_tokens<S: ReadonlyStorage>(storage: &S,role : ManagingRole) -> QueryResult {
@@ -844,6 +1899,70 @@ fn query_managing_addresses<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn query_pending_queue<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    role : ManagingRole
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+
+    let mut entries = vec![];
+    for canonical_addr in config.queued_addresses(role.clone()){
+        let address = deps.api.human_address(&canonical_addr)?;
+        entries.push(QueueEntry{
+            unlock_height: config.managing_queue(&canonical_addr, role.clone())?,
+            revocation: holds_role(&config, &address, &canonical_addr, role.clone()),
+            address,
+        });
+    }
+    to_binary(&QueryAnswer::PendingQueue{
+        entries,
+    })
+}
+
+fn query_remaining_budget<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address : HumanAddr,
+    role : ManagingRole,
+    block_height : u64
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let canonical_addr = deps.api.canonical_address(&address)?;
+
+    let budget = config.budget(&canonical_addr,role.clone());
+    let usage = config.budget_usage(&canonical_addr,role);
+    let (used, remaining, period_end) = match &budget{
+        Some(budget) => {
+            let period_start = budget.period_start(block_height);
+            let used = if usage.period_start == period_start { usage.used } else { Uint128(0) };
+            // The last period of a huge budget ends with the chain
+            (used, Some(Uint128(budget.remaining(&usage, block_height))), Some(period_start.saturating_add(budget.period)))
+        },
+        None => (Uint128(0), None, None),
+    };
+    to_binary(&QueryAnswer::RemainingBudget{
+        budget,
+        used,
+        remaining,
+        period_end,
+    })
+}
+
+fn query_strategies<S: ReadonlyStorage>(storage: &S) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::Strategies {
+        strategies: config.strategies(),
+    })
+}
+
+fn query_pending_migration<S: ReadonlyStorage>(storage: &S) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::PendingMigration {
+        migration: config.pending_migration(),
+    })
+}
+
 fn query_contract_status<S: ReadonlyStorage>(storage: &S) -> QueryResult {
     let config = ReadonlyConfig::from_storage(storage);
 
@@ -855,32 +1974,389 @@ fn query_contract_status<S: ReadonlyStorage>(storage: &S) -> QueryResult {
 fn query_value_of<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     token: HumanAddr,
-    amount: Uint128
+    amount: Uint128,
+    block_time: u64
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+
+    to_binary(&QueryAnswer::ValueOf {
+        value: Uint128(
+            config.value_of_at(
+                &deps.querier,
+                &token,
+                amount.u128(),
+                block_time
+            )?
+        ),
+    })
+}
+
+fn query_total_bond_deposited<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    token: HumanAddr
+    ) -> QueryResult {
+    
+    let deposited = ReadonlyDeposited::from_storage(&deps.storage);   
+    let token_address = deps.api.canonical_address(&token)?;
+
+    to_binary(&QueryAnswer::TotalBondDeposited {
+            amount: Uint128(deposited.deposited(&token_address)),
+        
+    })
+}
+
+fn query_total_reserves<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
     ) -> QueryResult {
     let config = ReadonlyConfig::from_storage(&deps.storage);
 
-    to_binary(&QueryAnswer::ValueOf {
-        value: Uint128(
-            config.value_of(
-                &deps.querier,
-                &token,
-                amount.u128()
-            )?
-        ),
+    to_binary(&QueryAnswer::TotalReserves {
+        total_reserves: Uint128(config.total_reserves()),
+        excess_reserves: Uint128(config.excess_reserves(&deps.querier)?),
+        total_debt: Uint128(config.total_debt()),
+    })
+}
+
+fn query_reserve_balances<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_time: u64
+    ) -> QueryResult {
+    to_binary(&QueryAnswer::ReserveBalances {
+        balances: reserve_balances(deps, block_time)?,
+    })
+}
+
+fn query_risk_free_value<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_time: u64
+    ) -> QueryResult {
+    let (risk_free_value, _) = treasury_value(&reserve_balances(deps, block_time)?)?;
+
+    to_binary(&QueryAnswer::RiskFreeValue {
+        value: Uint128(risk_free_value),
+    })
+}
+
+fn query_market_value<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_time: u64
+    ) -> QueryResult {
+    let (_, market_value) = treasury_value(&reserve_balances(deps, block_time)?)?;
+
+    to_binary(&QueryAnswer::MarketValue {
+        value: Uint128(market_value),
+    })
+}
+
+fn query_backing<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_time: u64
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let ohm = config.constants()?.ohm;
+    let ohm_info = snip20::token_info_query(
+        &deps.querier,
+        RESPONSE_BLOCK_SIZE,
+        ohm.code_hash,
+        ohm.address,
+    )?;
+    let total_supply = ohm_info.total_supply.map(|supply| supply.u128()).unwrap_or_default();
+    let (risk_free_value, market_value) = treasury_value(&reserve_balances(deps, block_time)?)?;
+
+    // Backing is expressed for one whole OHM, with the OHM decimals
+    let one_ohm = 10_u128.pow(ohm_info.decimals.into());
+    let per_ohm = |value: u128| -> StdResult<Uint128> {
+        if total_supply == 0 {
+            return Ok(Uint128(0));
+        }
+        value.checked_mul(one_ohm)
+            .map(|scaled| Uint128(scaled/total_supply))
+            .ok_or_else(|| StdError::generic_err("Too much reserves"))
+    };
+
+    to_binary(&QueryAnswer::Backing {
+        ohm_total_supply: Uint128(total_supply),
+        total_debt: Uint128(config.total_debt()),
+        risk_free_value: Uint128(risk_free_value),
+        market_value: Uint128(market_value),
+        risk_free_backing_per_ohm: per_ohm(risk_free_value)?,
+        backing_per_ohm: per_ohm(market_value)?,
+    })
+}
+
+// Live balances held by the treasury, for every reserve and liquidity token
+fn reserve_balances<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    block_time: u64
+    ) -> StdResult<Vec<ReserveBalance>> {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let contract_address = config.constants()?.contract_address;
+
+    let tokens = config.reserve_tokens().into_iter().map(|token| (token, ManagingRole::ReserveToken))
+        .chain(config.liquidity_tokens().into_iter().map(|token| (token, ManagingRole::LiquidityToken)));
+
+    let strategies = config.strategies();
+
+    let mut balances = vec![];
+    for (token, role) in tokens{
+        let balance = snip20::balance_query(
+            &deps.querier,
+            contract_address.clone(),
+            COMMON_VIEWING_KEY.to_string(),
+            RESPONSE_BLOCK_SIZE,
+            token.code_hash.clone(),
+            token.address.clone()
+        )?.amount.u128();
+        let deployed = strategies.iter()
+            .filter(|strategy| strategy.token == token.address)
+            .map(|strategy| strategy_value(&deps.querier, strategy))
+            .sum::<StdResult<u128>>()?;
+        let held = balance.checked_add(deployed).ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+
+        balances.push(ReserveBalance{
+            value: Uint128(config.value_of_at(&deps.querier, &token.address, held, block_time)?),
+            market_value: Uint128(config.market_value_of(&deps.querier, &token.address, held, block_time)?),
+            token: token.address,
+            role,
+            balance: Uint128(balance),
+            deployed: Uint128(deployed),
+        });
+    }
+    Ok(balances)
+}
+
+// Returns the (risk-free value, market value) of the given balances
+fn treasury_value(balances: &[ReserveBalance]) -> StdResult<(u128, u128)> {
+    let mut risk_free_value: u128 = 0;
+    let mut market_value: u128 = 0;
+    for balance in balances{
+        risk_free_value = risk_free_value.checked_add(balance.value.u128())
+            .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+        market_value = market_value.checked_add(balance.market_value.u128())
+            .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+    }
+    Ok((risk_free_value, market_value))
+}
+
+fn query_collateral<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    debtor: HumanAddr
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let canonical_debtor = deps.api.canonical_address(&debtor)?;
+    let gons = ReadonlyCollateral::from_storage(&deps.storage).gons(&canonical_debtor);
+
+    to_binary(&QueryAnswer::Collateral {
+        gons: gons.to_string(),
+        amount: Uint128(balance_for_gons(&deps.querier, &config.constants()?.sohm, gons)?),
+        debt: Uint128(ReadonlyDebtors::from_storage(&deps.storage).debt(&canonical_debtor)?),
+    })
+}
+
+fn query_debtor_health<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    debtor: HumanAddr,
+    block_height: u64
+    ) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let canonical_debtor = deps.api.canonical_address(&debtor)?;
+    let positions = accrued_positions(&deps.storage, &canonical_debtor, block_height)?;
+    let debt = total_debt_of(&positions);
+    let gons = ReadonlyCollateral::from_storage(&deps.storage).gons(&canonical_debtor);
+    let collateral = balance_for_gons(&deps.querier, &config.constants()?.sohm, gons)?;
+
+    // The health factor is in ten-thousandths, a debtor under 10_000 can be liquidated
+    let parameters = config.liquidation_parameters();
+    let health_factor = match &parameters{
+        Some(parameters) if debt != 0 => Some(Uint128(
+            collateral.saturating_mul(parameters.ltv.u128()) / debt
+        )),
+        _ => None,
+    };
+    let liquidatable = match &parameters{
+        Some(parameters) => debt != 0 && is_liquidatable(parameters, collateral, debt),
+        None => false,
+    };
+
+    to_binary(&QueryAnswer::DebtorHealth {
+        positions,
+        debt: Uint128(debt),
+        collateral: Uint128(collateral),
+        health_factor,
+        liquidatable,
+    })
+}
+
+fn query_borrow_rate<S: ReadonlyStorage>(storage: &S, token: HumanAddr) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::BorrowRate {
+        rate: Uint128(config.borrow_rate(&token)),
+    })
+}
+
+fn query_valuation_source<S: ReadonlyStorage>(storage: &S, token: HumanAddr) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::ValuationSource {
+        source: config.valuation_source(&token),
+    })
+}
+
+fn query_liquidation_parameters<S: ReadonlyStorage>(storage: &S) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(storage);
+
+    to_binary(&QueryAnswer::LiquidationParameters {
+        parameters: config.liquidation_parameters(),
+    })
+}
+
+fn permit_queries<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    permit: Permit,
+    query: QueryWithPermit,
+) -> Result<Binary, StdError> {
+    // Validate permit content
+    let consts = ReadonlyConfig::from_storage(&deps.storage).constants()?;
+
+    let account = validate(deps, PREFIX_REVOKED_PERMITS, &permit, consts.contract_address)?;
+
+    // Permit validated! We can now execute the query.
+    if !permit.check_permission(&Permission::History) {
+        return Err(StdError::generic_err(format!(
+            "No permission to query history, got permissions {:?}",
+            permit.params.permissions
+        )));
+    }
+    match query {
+        QueryWithPermit::Ledger { page, page_size } => {
+            if account != consts.admin {
+                return Err(StdError::generic_err(
+                    "The ledger can only be queried by the admin",
+                ));
+            }
+
+            query_ledger(deps, page.unwrap_or(0), page_size)
+        }
+        QueryWithPermit::TransactionHistory { address, page, page_size } => {
+            let address = address.unwrap_or_else(|| account.clone());
+            if address != account && account != consts.admin {
+                return Err(StdError::generic_err(format!(
+                    "Cannot query the history of {:?}, got permit for {:?}",
+                    address.as_str(), account.as_str()
+                )));
+            }
+
+            query_transactions(deps, &address, page.unwrap_or(0), page_size)
+        }
+        QueryWithPermit::ExportState { page, page_size } => {
+            if account != consts.admin {
+                return Err(StdError::generic_err(
+                    "The state can only be exported by the admin",
+                ));
+            }
+
+            query_export_state(deps, page.unwrap_or(0), page_size)
+        }
+    }
+}
+
+fn query_export_state<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    page: u32,
+    page_size: u32,
+) -> QueryResult {
+    let config = ReadonlyConfig::from_storage(&deps.storage);
+    let debtors = ReadonlyDebtors::from_storage(&deps.storage);
+    let collateral = ReadonlyCollateral::from_storage(&deps.storage);
+    let deposited = ReadonlyDeposited::from_storage(&deps.storage);
+
+    let mut roles = vec![];
+    let mut exported_deposits = vec![];
+    let mut bond_calculators = vec![];
+    if page == 0{
+        for role in managing_roles(){
+            let addresses = config.managing_addresses(role.clone()).iter()
+                .map(|x| deps.api.human_address(x))
+                .collect::<StdResult<Vec<HumanAddr>>>()?;
+            roles.push(ExportedRole{ role, addresses });
+        }
+
+        let tokens = config.reserve_tokens().into_iter().chain(config.liquidity_tokens().into_iter());
+        for token in tokens{
+            let canonical_token = deps.api.canonical_address(&token.address)?;
+            exported_deposits.push(ExportedDeposit{
+                amount: Uint128(deposited.deposited(&canonical_token)),
+                token: token.address,
+            });
+        }
+
+        // Liquidity tokens registered at init don't have a calculator
+        bond_calculators = config.liquidity_tokens().into_iter()
+            .filter_map(|token| config.bond_calculator(&token.address).ok().map(|calculator| ExportedCalculator{
+                calculator,
+                token: token.address,
+            }))
+            .collect();
+    }
+
+    // Revoked debtors can still hold debt or collateral, debtors from before the holders were tracked only hold the role
+    let mut accounts = config.position_holders();
+    for debtor in config.managing_addresses(ManagingRole::Debtor){
+        if !accounts.contains(&debtor){
+            accounts.push(debtor);
+        }
+    }
+    let mut exported_debtors = vec![];
+    for canonical_addr in accounts.iter().skip(page.saturating_mul(page_size) as _).take(page_size as _){
+        let positions = debtors.positions(canonical_addr)?;
+        let gons = collateral.gons(canonical_addr);
+        if total_debt_of(&positions) == 0 && gons.is_zero(){
+            continue;
+        }
+        exported_debtors.push(ExportedDebtor{
+            address: deps.api.human_address(canonical_addr)?,
+            positions,
+            collateral_gons: gons.to_string(),
+        });
+    }
+
+    to_binary(&QueryAnswer::ExportState {
+        state: ExportedState{
+            roles,
+            debtors: exported_debtors,
+            deposited: exported_deposits,
+            bond_calculators,
+        },
+        total_debtors: accounts.len() as u64,
     })
 }
 
-fn query_total_bond_deposited<S: Storage, A: Api, Q: Querier>(
+fn query_ledger<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
-    token: HumanAddr
-    ) -> QueryResult {
-    
-    let deposited = ReadonlyDeposited::from_storage(&deps.storage);   
-    let token_address = deps.api.canonical_address(&token)?;
+    page: u32,
+    page_size: u32,
+) -> QueryResult {
+    let (txs, total) = get_ledger(&deps.api, &deps.storage, page, page_size)?;
+
+    to_binary(&QueryAnswer::Ledger {
+        txs,
+        total: Some(total),
+    })
+}
 
-    to_binary(&QueryAnswer::TotalBondDeposited {
-            amount: Uint128(deposited.deposited(&token_address)),
-        
+fn query_transactions<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    account: &HumanAddr,
+    page: u32,
+    page_size: u32,
+) -> QueryResult {
+    let address = deps.api.canonical_address(account)?;
+    let (txs, total) = get_txs(&deps.api, &deps.storage, &address, page, page_size)?;
+
+    to_binary(&QueryAnswer::TransactionHistory {
+        txs,
+        total: Some(total),
     })
 }
 
@@ -923,6 +2399,25 @@ fn set_contract_status<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+fn revoke_permit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    permit_name: String,
+) -> StdResult<HandleResponse> {
+    RevokedPermits::revoke_permit(
+        &mut deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        &env.message.sender,
+        &permit_name,
+    );
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RevokePermit { status: Success })?),
+    })
+}
+
 fn is_admin<S: Storage>(config: &Config<S>, account: &HumanAddr) -> StdResult<bool> {
     let consts = config.constants()?;
     if &consts.admin != account {
@@ -960,40 +2455,253 @@ fn is_valid_name(name: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::msg::ResponseStatus;
+    use crate::msg::{ResponseStatus, OraclePrice};
+    use crate::state::PREFIX_DEBTORS;
+    use crate::transaction_history::TxAction;
+    use cosmwasm_storage::PrefixedStorage;
     use cosmwasm_std::testing::*;
-    use cosmwasm_std::{from_binary};
+    use cosmwasm_std::{from_binary, from_slice, Coin, Empty, QuerierResult, QueryRequest, WasmMsg, WasmQuery};
+    use serde::{Deserialize, Serialize};
     use std::any::Any;
+    use std::collections::HashMap;
+
+    // sOHM gons per token before any rebase
+    const GONS_PER_SOHM: u128 = 1_000_000;
+
+    // Answers the queries the treasury sends to the tokens, sOHM, price oracles, strategies and calculators
+    struct TestQuerier {
+        // Decimals and total supply of every token
+        tokens: HashMap<HumanAddr, (u8, u128)>,
+        // Balances held by the treasury
+        balances: HashMap<HumanAddr, u128>,
+        // Lowered to simulate a rebase
+        gons_per_sohm: u128,
+        prices: HashMap<HumanAddr, OraclePrice>,
+        strategy_values: HashMap<HumanAddr, u128>,
+        // Risk free value of one LP token, in OHM, and markdown of the pair
+        lp_valuation: u128,
+        lp_markdown: u128,
+    }
+
+    impl TestQuerier {
+        fn new() -> Self {
+            let tokens = vec![
+                (HumanAddr("ohm".to_string()), (9, 0)),
+                (HumanAddr("sohm".to_string()), (9, 0)),
+                (HumanAddr("sUST".to_string()), (6, 0)),
+                (HumanAddr("SSCRT".to_string()), (6, 0)),
+                (HumanAddr("sust-LP".to_string()), (18, 0)),
+            ];
+            TestQuerier {
+                tokens: tokens.into_iter().collect(),
+                balances: HashMap::new(),
+                gons_per_sohm: GONS_PER_SOHM,
+                prices: HashMap::new(),
+                strategy_values: HashMap::new(),
+                lp_valuation: 0,
+                lp_markdown: 0,
+            }
+        }
+
+        fn answer(&self, contract: &HumanAddr, query: TestQuery) -> StdResult<Binary> {
+            match query {
+                TestQuery::TokenInfo {} => {
+                    let (decimals, total_supply) = self.tokens.get(contract)
+                        .ok_or_else(|| StdError::generic_err("Unknown token"))?;
+                    to_binary(&TokenInfoAnswer {
+                        token_info: TokenInfoAnswerInner {
+                            name: contract.to_string(),
+                            symbol: "TKN".to_string(),
+                            decimals: *decimals,
+                            total_supply: Some(Uint128(*total_supply)),
+                        },
+                    })
+                }
+                TestQuery::Balance { .. } => to_binary(&BalanceAnswer {
+                    balance: BalanceAnswerInner {
+                        amount: Uint128(self.balances.get(contract).cloned().unwrap_or_default()),
+                    },
+                }),
+                TestQuery::GonsForBalance { amount } => to_binary(&GonsForBalanceResponse {
+                    gons_for_balance: crate::msg::GonsForBalance {
+                        gons: (amount.u128() * self.gons_per_sohm).to_string(),
+                    },
+                }),
+                TestQuery::BalanceForGons { gons } => to_binary(&BalanceForGonsResponse {
+                    balance_for_gons: crate::msg::BalanceForGons {
+                        amount: Uint128(gons.parse::<u128>().unwrap() / self.gons_per_sohm),
+                    },
+                }),
+                TestQuery::Price { token } => to_binary(&crate::msg::PriceResponse {
+                    price: self.prices.get(&token)
+                        .cloned()
+                        .ok_or_else(|| StdError::generic_err("No price for this token"))?,
+                }),
+                TestQuery::StrategyValue {} => to_binary(&StrategyValueResponse {
+                    strategy_value: crate::msg::StrategyValue {
+                        amount: Uint128(self.strategy_values.get(contract).cloned().unwrap_or_default()),
+                    },
+                }),
+                TestQuery::Valuation { amount, .. } => to_binary(&crate::msg::ValuationResponse {
+                    valuation: crate::msg::Valuation {
+                        value: Uint128(amount.u128() * self.lp_valuation / 10_u128.pow(18)),
+                    },
+                }),
+                TestQuery::Markdown { .. } => to_binary(&crate::msg::MarkdownResponse {
+                    markdown: crate::msg::Markdown {
+                        value: Uint128(self.lp_markdown),
+                    },
+                }),
+            }
+        }
+    }
+
+    impl Querier for TestQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            let request: QueryRequest<Empty> = from_slice(bin_request).unwrap();
+            let (contract, msg) = match request {
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg, .. }) => (contract_addr, msg),
+                _ => return Ok(Err(StdError::generic_err("Only smart queries are supported"))),
+            };
+            Ok(from_binary(&msg).and_then(|query| self.answer(&contract, query)))
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum TestQuery {
+        TokenInfo {},
+        Balance {},
+        GonsForBalance { amount: Uint128 },
+        BalanceForGons { gons: String },
+        Price { token: HumanAddr },
+        StrategyValue {},
+        Valuation { amount: Uint128 },
+        Markdown {},
+    }
+
+    #[derive(Serialize)]
+    struct TokenInfoAnswer {
+        token_info: TokenInfoAnswerInner,
+    }
+
+    #[derive(Serialize)]
+    struct TokenInfoAnswerInner {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        total_supply: Option<Uint128>,
+    }
+
+    #[derive(Serialize)]
+    struct BalanceAnswer {
+        balance: BalanceAnswerInner,
+    }
+
+    #[derive(Serialize)]
+    struct BalanceAnswerInner {
+        amount: Uint128,
+    }
 
     // Helper functions
 
+    fn token(address: &str) -> Contract {
+        Contract{address:HumanAddr(address.to_string()),code_hash:"Complicated_hash".to_string(),pair_address:None,pair_code_hash:None}
+    }
+
     fn init_helper(
         
     ) -> (
         StdResult<InitResponse>,
-        Extern<MockStorage, MockApi, MockQuerier>,
+        Extern<MockStorage, MockApi, TestQuerier>,
+    ) {
+        init_importing(None)
+    }
+
+    // Same as `init_helper`, with the first page of a previous treasury state
+    fn init_importing(
+        import: Option<ExportedState>
+    ) -> (
+        StdResult<InitResponse>,
+        Extern<MockStorage, MockApi, TestQuerier>,
     ) {
-        let mut deps = mock_dependencies(20, &[]);
+        let mut deps = Extern {
+            storage: MockStorage::default(),
+            api: MockApi::new(20),
+            querier: TestQuerier::new(),
+        };
         let env = mock_env("admin", &[]);
 
+        let mut liquidity_token = token("sust-LP");
+        liquidity_token.pair_address = Some(HumanAddr("sust-pair".to_string()));
+        liquidity_token.pair_code_hash = Some("Complicated_hash".to_string());
         let init_msg = InitMsg {
             name: "treasury".to_string(),
             admin: None,
             prng_seed: Binary::from("lolz fun yay".as_bytes()),
-            ohm : Contract{address:HumanAddr("ohm".to_string()),code_hash:"Complicated_hash".to_string()},
-            sohm : Contract{address:HumanAddr("sohm".to_string()),code_hash:"Complicated_hash".to_string()},
+            ohm : token("ohm"),
+            sohm : token("sohm"),
             reserve_tokens : Some(vec![
-                Contract{address:HumanAddr("sUST".to_string()),code_hash:"Complicated_hash".to_string()},
-                Contract{address:HumanAddr("SSCRT".to_string()),code_hash:"Complicated_hash".to_string()},
+                token("sUST"),
+                token("SSCRT"),
                 ]),
             liquidity_tokens : Some(vec![
-                Contract{address:HumanAddr("sust-LP".to_string()),code_hash:"Complicated_hash".to_string()},
+                liquidity_token,
                 ]),
             blocks_needed_for_queue : 0,
+            import,
         };
 
         (init(&mut deps, env, init_msg), deps)
     }
+
+    // Queues the role and toggles it right away, the queue is empty in `init_helper`
+    fn grant_role(deps: &mut Extern<MockStorage, MockApi, TestQuerier>, address: &str, role: ManagingRole) {
+        let handle_msg = HandleMsg::Queue {
+            address: HumanAddr(address.to_string()),
+            role: role.clone()
+        };
+        assert!(ensure_success(handle(deps, mock_env("admin", &[]), handle_msg).unwrap()));
+        let handle_msg = HandleMsg::ToggleQueue {
+            address: HumanAddr(address.to_string()),
+            role
+        };
+        assert!(ensure_success(handle(deps, mock_env("admin", &[]), handle_msg).unwrap()));
+    }
+
+    fn receive_helper(
+        deps: &mut Extern<MockStorage, MockApi, TestQuerier>,
+        token: &str,
+        from: &str,
+        amount: u128,
+        msg: ReceiveMsg,
+    ) -> StdResult<HandleResponse> {
+        receive_at(deps, mock_env(token, &[]), from, amount, msg)
+    }
+
+    // `env.message.sender` is the token that was sent
+    fn receive_at(
+        deps: &mut Extern<MockStorage, MockApi, TestQuerier>,
+        env: Env,
+        from: &str,
+        amount: u128,
+        msg: ReceiveMsg,
+    ) -> StdResult<HandleResponse> {
+        let handle_msg = HandleMsg::Receive {
+            sender: HumanAddr(from.to_string()),
+            from: HumanAddr(from.to_string()),
+            amount: Uint128(amount),
+            msg: to_binary(&msg).unwrap(),
+        };
+        handle(deps, env, handle_msg)
+    }
+
+    fn env_at(sender: &str, height: u64) -> Env {
+        let mut env = mock_env(sender, &[]);
+        env.block.height = height;
+        env
+    }
+
     /// Will return a ViewingKey only for the first account in `initial_balances`
     /*
     fn _auth_query_helper(
@@ -1038,18 +2746,49 @@ mod tests {
             HandleAnswer::Deposit { status }
             | HandleAnswer::Withdraw { status }
             | HandleAnswer::IncurDebt { status }
+            | HandleAnswer::WithdrawCollateral { status }
             | HandleAnswer::Manage { status }
             | HandleAnswer::MintRewards {status }
             | HandleAnswer::AuditReserves { status }
             | HandleAnswer::QueueAddress {status}
             | HandleAnswer::ToggleQueue {status}
+            | HandleAnswer::CancelQueue {status}
+            | HandleAnswer::SetRevocationDelay {status}
+            | HandleAnswer::SetBudget {status}
+            | HandleAnswer::RegisterStrategy {status}
+            | HandleAnswer::RemoveStrategy {status}
+            | HandleAnswer::AllocateToStrategy {status}
+            | HandleAnswer::WithdrawFromStrategy {status}
+            | HandleAnswer::ReportStrategy {status, ..}
+            | HandleAnswer::MigrateTo {status, ..}
+            | HandleAnswer::CancelMigration {status}
+            | HandleAnswer::ImportState {status}
+            | HandleAnswer::DepositNative {status}
+            | HandleAnswer::SetNativeWrapper {status}
+            | HandleAnswer::Liquidate { status, .. }
+            | HandleAnswer::SetBorrowRate { status }
+            | HandleAnswer::SetLiquidationParameters { status }
+            | HandleAnswer::SetValuationSource { status }
             | HandleAnswer::ChangeAdmin { status }
-            | HandleAnswer::SetContractStatus { status } => {
+            | HandleAnswer::SetContractStatus { status }
+            | HandleAnswer::RevokePermit { status } => {
                 matches!(status, ResponseStatus::Success { .. })
             }
         }
     }
 
+    fn ensure_receive_success(handle_result: HandleResponse) -> bool {
+        let handle_result: ReceiveAnswer = from_binary(&handle_result.data.unwrap()).unwrap();
+
+        match handle_result {
+            ReceiveAnswer::Deposit { status }
+            | ReceiveAnswer::RepayDebt { status }
+            | ReceiveAnswer::PostCollateral { status } => {
+                matches!(status, ResponseStatus::Success)
+            }
+        }
+    }
+
     fn ensure_failure(handle_result: HandleResponse) -> bool {
         let handle_result: HandleAnswer = from_binary(&handle_result.data.unwrap()).unwrap();
 
@@ -1057,13 +2796,32 @@ mod tests {
             HandleAnswer::Deposit { status }
             | HandleAnswer::Withdraw { status }
             | HandleAnswer::IncurDebt { status }
+            | HandleAnswer::WithdrawCollateral { status }
             | HandleAnswer::Manage { status }
             | HandleAnswer::MintRewards {status }
             | HandleAnswer::AuditReserves { status }
             | HandleAnswer::QueueAddress {status}
             | HandleAnswer::ToggleQueue {status}
+            | HandleAnswer::CancelQueue {status}
+            | HandleAnswer::SetRevocationDelay {status}
+            | HandleAnswer::SetBudget {status}
+            | HandleAnswer::RegisterStrategy {status}
+            | HandleAnswer::RemoveStrategy {status}
+            | HandleAnswer::AllocateToStrategy {status}
+            | HandleAnswer::WithdrawFromStrategy {status}
+            | HandleAnswer::ReportStrategy {status, ..}
+            | HandleAnswer::MigrateTo {status, ..}
+            | HandleAnswer::CancelMigration {status}
+            | HandleAnswer::ImportState {status}
+            | HandleAnswer::DepositNative {status}
+            | HandleAnswer::SetNativeWrapper {status}
+            | HandleAnswer::Liquidate { status, .. }
+            | HandleAnswer::SetBorrowRate { status }
+            | HandleAnswer::SetLiquidationParameters { status }
+            | HandleAnswer::SetValuationSource { status }
             | HandleAnswer::ChangeAdmin { status }
-            | HandleAnswer::SetContractStatus { status } => {
+            | HandleAnswer::SetContractStatus { status }
+            | HandleAnswer::RevokePermit { status } => {
                 matches!(status, ResponseStatus::Failure { .. })
             }
         }
@@ -1089,8 +2847,8 @@ mod tests {
     }
 
 
-    fn handle_queue_toggle_helper(init_result: StdResult<InitResponse>,
-        deps: &mut Extern<MockStorage, MockApi, MockQuerier>){
+    fn handle_queue_toggle_helper(_init_result: StdResult<InitResponse>,
+        deps: &mut Extern<MockStorage, MockApi, TestQuerier>){
 
         let handle_failure_msg = HandleMsg::Queue {
             address: HumanAddr("sUST".to_string()),
@@ -1131,6 +2889,95 @@ mod tests {
         
     }
 
+    #[test]
+    fn test_handle_cancel_queue(){
+        let (init_result, mut deps) = init_helper();
+        assert!(
+            init_result.is_ok(),
+            "Init failed: {}",
+            init_result.err().unwrap()
+        );
+
+        let handle_msg = HandleMsg::Queue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), handle_msg);
+        assert!(ensure_success(handle_result.unwrap()));
+
+        let query_msg = QueryMsg::PendingQueue { role: ManagingRole::Debtor };
+        let query_answer: QueryAnswer = from_binary(&query(&deps, query_msg.clone()).unwrap()).unwrap();
+        match query_answer {
+            QueryAnswer::PendingQueue { entries } => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].address, HumanAddr("bob".to_string()));
+                assert!(!entries[0].revocation);
+            }
+            _ => panic!("Unexpected query answer"),
+        }
+
+        // Only the admin can cancel a queue entry
+        let handle_msg = HandleMsg::CancelQueue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), handle_msg.clone());
+        assert!(handle_result.is_err());
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), handle_msg);
+        assert!(ensure_success(handle_result.unwrap()));
+
+        let query_answer: QueryAnswer = from_binary(&query(&deps, query_msg).unwrap()).unwrap();
+        match query_answer {
+            QueryAnswer::PendingQueue { entries } => assert!(entries.is_empty()),
+            _ => panic!("Unexpected query answer"),
+        }
+
+        // The cancelled entry can't be toggled anymore
+        let toggle_handle_msg = HandleMsg::ToggleQueue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), toggle_handle_msg);
+        assert!(handle_result.is_err());
+    }
+
+    #[test]
+    fn test_revocation_queue(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let canonical_bob = deps.api.canonical_address(&HumanAddr("bob".to_string())).unwrap();
+        let queue_msg = HandleMsg::Queue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+        let toggle_msg = HandleMsg::ToggleQueue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+
+        // Revocations are not delayed, queuing a role holder is still accepted
+        grant_role(&mut deps, "bob", ManagingRole::Debtor);
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), queue_msg.clone()).unwrap()));
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), toggle_msg.clone()).unwrap()));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert!(!config.has_managing_position(&canonical_bob, ManagingRole::Debtor));
+        assert_eq!(config.managing_queue(&canonical_bob, ManagingRole::Debtor).unwrap(), 0);
+
+        // Once delayed, the revocation has to be queued and wait for the delay
+        let handle_msg = HandleMsg::SetRevocationDelay { blocks: 10 };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), handle_msg).unwrap()));
+        grant_role(&mut deps, "bob", ManagingRole::Debtor);
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), toggle_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "You need to queue the revocation first");
+
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), queue_msg).unwrap()));
+        let handle_result = handle(&mut deps, env_at("admin", 12_354), toggle_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Queue is not over yet, wait a bit more");
+        assert!(ensure_success(handle(&mut deps, env_at("admin", 12_355), toggle_msg).unwrap()));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert!(!config.has_managing_position(&canonical_bob, ManagingRole::Debtor));
+    }
+
 
     #[test]
     fn test_deposit(){
@@ -1143,17 +2990,756 @@ mod tests {
         let deposit_msg = ReceiveMsg::Deposit{
             profit: Uint128(86945),
         };
-        let handle_receive_msg = HandleMsg::Receive {
-            sender : HumanAddr("sUST".to_string()),
-            from : HumanAddr("admin".to_string()),
-            amount: Uint128(7000000),
-            msg: to_binary(&deposit_msg).unwrap()
-        };
-        let handle_result = handle(&mut deps, mock_env("admin", &[]), handle_receive_msg);
+        // Only reserve depositors can deposit
+        let handle_result = receive_helper(&mut deps, "sUST", "admin", 7000000, deposit_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Depositor not approved");
+
+        grant_role(&mut deps, "admin", ManagingRole::ReserveDepositor);
+        let handle_result = receive_helper(&mut deps, "sUST", "admin", 7000000, deposit_msg);
         let result = handle_result.unwrap();
         assert!(ensure_success(result));
 
+        // 7 sUST are worth 7 OHM
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!(config.total_reserves(), 7_000_000_000);
+    }
+
+    #[test]
+    fn test_withdraw(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        grant_role(&mut deps, "bonds", ManagingRole::ReserveDepositor);
+        let deposit_msg = ReceiveMsg::Deposit{ profit: Uint128(0) };
+        assert!(ensure_success(receive_helper(&mut deps, "sUST", "bonds", 7_000_000, deposit_msg).unwrap()));
+        let withdraw_msg = |amount| ReceiveMsg::Withdraw{
+            token: HumanAddr("sUST".to_string()),
+            amount: Uint128(amount),
+        };
+
+        // The reserve depositor is the account that sent the OHM, not the OHM token
+        let handle_result = receive_helper(&mut deps, "ohm", "alice", 2_000_000_000, withdraw_msg(2_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not authorized");
+        let handle_result = receive_helper(&mut deps, "sUST", "bonds", 2_000_000_000, withdraw_msg(2_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Can only withdraw by burning OHM");
+        let handle_result = receive_helper(&mut deps, "ohm", "bonds", 2_000_000_000, withdraw_msg(3_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Sent token amount and specified reserve amount don't match");
+
+        let handle_result = receive_helper(&mut deps, "ohm", "bonds", 2_000_000_000, withdraw_msg(2_000_000)).unwrap();
+        match &handle_result.messages[1] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(*contract_addr, HumanAddr("sUST".to_string()));
+                assert!(String::from_utf8(msg.0.clone()).unwrap().contains("\"recipient\":\"bonds\""));
+            },
+            _ => panic!("Unexpected message"),
+        }
+        assert!(ensure_success(handle_result));
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 5_000_000_000);
+    }
+
+    // Collateral tests
+
+    #[test]
+    fn test_post_collateral(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        grant_role(&mut deps, "bob", ManagingRole::Debtor);
+
+        // Only sOHM is accepted, and only from debtors
+        let handle_result = receive_helper(&mut deps, "sUST", "bob", 100_000_000, ReceiveMsg::PostCollateral{});
+        assert_eq!(extract_error_msg(handle_result), "Only sOHM can be posted as collateral");
+        let handle_result = receive_helper(&mut deps, "sohm", "alice", 100_000_000_000, ReceiveMsg::PostCollateral{});
+        assert_eq!(extract_error_msg(handle_result), "Not authorized");
+
+        let handle_result = receive_helper(&mut deps, "sohm", "bob", 100_000_000_000, ReceiveMsg::PostCollateral{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+
+        let query_msg = QueryMsg::Collateral { debtor: HumanAddr("bob".to_string()) };
+        match from_binary(&query(&deps, query_msg.clone()).unwrap()).unwrap() {
+            QueryAnswer::Collateral { gons, amount, debt } => {
+                assert_eq!(gons, (100_000_000_000 * GONS_PER_SOHM).to_string());
+                assert_eq!(amount, Uint128(100_000_000_000));
+                assert_eq!(debt, Uint128(0));
+            }
+            _ => panic!("Unexpected query answer"),
+        }
+
+        // The collateral is kept in gons, so it grows with the rebases
+        deps.querier.gons_per_sohm = GONS_PER_SOHM / 2;
+        match from_binary(&query(&deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::Collateral { amount, .. } => assert_eq!(amount, Uint128(200_000_000_000)),
+            _ => panic!("Unexpected query answer"),
+        }
+    }
+
+    #[test]
+    fn test_debt_is_capped_by_collateral(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        grant_role(&mut deps, "bob", ManagingRole::Debtor);
+        Config::from_storage(&mut deps.storage).set_total_reserves(1_000_000_000_000);
+
+        // No debt without collateral
+        let incur_msg = |amount: u128| HandleMsg::IncurDebt {
+            token: HumanAddr("sUST".to_string()),
+            amount: Uint128(amount),
+        };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), incur_msg(1_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not possible, too much debt already");
+
+        let handle_result = receive_helper(&mut deps, "sohm", "bob", 100_000_000_000, ReceiveMsg::PostCollateral{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+
+        // 100 sOHM cover up to 100 OHM of debt
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), incur_msg(150_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not possible, too much debt already");
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), incur_msg(60_000_000));
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), incur_msg(50_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not possible, too much debt already");
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), incur_msg(10_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not authorized");
+
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!(config.total_debt(), 60_000_000_000);
+        assert_eq!(config.total_reserves(), 940_000_000_000);
+
+        // The collateral backing the debt can't be withdrawn
+        let withdraw_msg = |amount: u128| HandleMsg::WithdrawCollateral { amount: Uint128(amount) };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), withdraw_msg(50_000_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not possible, the remaining collateral would not cover the debt");
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), withdraw_msg(40_000_000_000));
+        assert!(ensure_success(handle_result.unwrap()));
+
+        // Once repaid, the rest of the collateral is free
+        let handle_result = receive_helper(&mut deps, "sUST", "bob", 60_000_000, ReceiveMsg::RepayDebt{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), withdraw_msg(60_000_000_000));
+        assert!(ensure_success(handle_result.unwrap()));
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), withdraw_msg(1));
+        assert_eq!(extract_error_msg(handle_result), "Not enough collateral posted");
+
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!(config.total_debt(), 0);
+        assert_eq!(config.total_reserves(), 1_000_000_000_000);
+    }
+
+    // Interest and liquidation tests
+
+    // Bob borrows `borrowed` sUST against 100 sOHM, at the height of `mock_env`
+    fn borrow_helper(deps: &mut Extern<MockStorage, MockApi, TestQuerier>, borrowed: u128) {
+        grant_role(deps, "bob", ManagingRole::Debtor);
+        Config::from_storage(&mut deps.storage).set_total_reserves(1_000_000_000_000);
+        let handle_result = receive_helper(deps, "sohm", "bob", 100_000_000_000, ReceiveMsg::PostCollateral{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        let handle_msg = HandleMsg::IncurDebt {
+            token: HumanAddr("sUST".to_string()),
+            amount: Uint128(borrowed),
+        };
+        assert!(ensure_success(handle(deps, mock_env("bob", &[]), handle_msg).unwrap()));
+    }
+
+    fn debtor_health(deps: &Extern<MockStorage, MockApi, TestQuerier>, block_height: u64) -> (u128, bool) {
+        let query_msg = QueryMsg::DebtorHealth { debtor: HumanAddr("bob".to_string()), block_height };
+        match from_binary(&query(deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::DebtorHealth { debt, liquidatable, .. } => (debt.u128(), liquidatable),
+            _ => panic!("Unexpected query answer"),
+        }
+    }
+
+    #[test]
+    fn test_interest_accrual(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+
+        // 0.1% per block
+        let rate_msg = HandleMsg::SetBorrowRate {
+            token: HumanAddr("sUST".to_string()),
+            rate: Uint128(1_000_000),
+        };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), rate_msg.clone());
+        assert!(handle_result.is_err());
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), rate_msg).unwrap()));
+
+        borrow_helper(&mut deps, 50_000_000);
+        let height = mock_env("bob", &[]).block.height;
+        assert_eq!(debtor_health(&deps, height), (50_000_000_000, false));
+        assert_eq!(debtor_health(&deps, height + 10), (50_500_000_000, false));
+
+        // The interests are repaid first, they add to the reserves but never to the total debt
+        let handle_result = receive_at(&mut deps, env_at("sUST", height + 10), "bob", 200_000, ReceiveMsg::RepayDebt{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!((config.total_debt(), config.total_reserves()), (50_000_000_000, 950_200_000_000));
+        let query_msg = QueryMsg::DebtorHealth { debtor: HumanAddr("bob".to_string()), block_height: height + 10 };
+        match from_binary(&query(&deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::DebtorHealth { positions, .. } => assert_eq!(
+                (positions[0].amount, positions[0].interest),
+                (Uint128(50_300_000_000), Uint128(300_000_000))
+            ),
+            _ => panic!("Unexpected query answer"),
+        }
+        let handle_result = receive_at(&mut deps, env_at("sUST", height + 10), "bob", 9_800_000, ReceiveMsg::RepayDebt{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!((config.total_debt(), config.total_reserves()), (40_500_000_000, 960_000_000_000));
+        assert_eq!(debtor_health(&deps, height + 10).0, 40_500_000_000);
+    }
+
+    #[test]
+    fn test_liquidate(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let handle_msg = HandleMsg::SetBorrowRate {
+            token: HumanAddr("sUST".to_string()),
+            rate: Uint128(1_000_000),
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), handle_msg).unwrap()));
+        let handle_msg = HandleMsg::SetLiquidationParameters {
+            ltv: Uint128(8_000),
+            bonus: Uint128(500),
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), handle_msg).unwrap()));
+
+        // Bob borrows the most his collateral allows, the interests make him liquidatable
+        borrow_helper(&mut deps, 80_000_000);
+        let height = mock_env("bob", &[]).block.height;
+        let liquidate_msg = ReceiveMsg::Liquidate { debtor: HumanAddr("bob".to_string()) };
+        let handle_result = receive_at(&mut deps, env_at("sUST", height), "carol", 40_000_000, liquidate_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "This debtor is healthy, it can't be liquidated");
+        assert_eq!(debtor_health(&deps, height + 10), (80_800_000_000, true));
+
+        // The debt has to be repaid with an accepted token, and not above the debt
+        let handle_result = receive_at(&mut deps, env_at("sohm", height + 10), "carol", 40_000_000_000, liquidate_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Token not accepted");
+        let handle_result = receive_at(&mut deps, env_at("sUST", height + 10), "carol", 90_000_000, liquidate_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "The repaid value should be between 0 and the debt of the debtor");
+
+        // Carol repays 40 OHM of debt and gets 42 sOHM, 5% of bonus included
+        let handle_result = receive_at(&mut deps, env_at("sUST", height + 10), "carol", 40_000_000, liquidate_msg);
+        let response = handle_result.unwrap();
+        assert_eq!(response.messages, vec![snip20::transfer_msg(
+            HumanAddr("carol".to_string()),
+            Uint128(42_000_000_000),
+            None,
+            RESPONSE_BLOCK_SIZE,
+            "Complicated_hash".to_string(),
+            HumanAddr("sohm".to_string()),
+        ).unwrap()]);
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Liquidate { repaid, seized, .. } => {
+                assert_eq!(repaid, Uint128(40_000_000_000));
+                assert_eq!(seized, Uint128(42_000_000_000));
+            }
+            _ => panic!("Unexpected handle answer"),
+        }
+
+        assert_eq!(debtor_health(&deps, height + 10), (40_800_000_000, false));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        assert_eq!(config.total_debt(), 40_800_000_000);
+        assert_eq!(config.total_reserves(), 960_000_000_000);
+        let query_msg = QueryMsg::Collateral { debtor: HumanAddr("bob".to_string()) };
+        match from_binary(&query(&deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::Collateral { amount, .. } => assert_eq!(amount, Uint128(58_000_000_000)),
+            _ => panic!("Unexpected query answer"),
+        }
+    }
+
+    #[test]
+    fn test_legacy_debt(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+
+        // Debts used to be stored as a bare u128, they didn't accrue interests
+        let canonical_bob = deps.api.canonical_address(&HumanAddr("bob".to_string())).unwrap();
+        PrefixedStorage::new(PREFIX_DEBTORS, &mut deps.storage).set(canonical_bob.as_slice(), &60_000_000_000_u128.to_be_bytes());
+        let mut config = Config::from_storage(&mut deps.storage);
+        config.set_total_debt(60_000_000_000);
+        config.add_position_holder(&canonical_bob).unwrap();
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), HandleMsg::SetBorrowRate {
+            token: HumanAddr("sUST".to_string()),
+            rate: Uint128(BORROW_RATE_DENOMINATOR / 1_000),
+        }).unwrap()));
+
+        let query_msg = QueryMsg::Collateral { debtor: HumanAddr("bob".to_string()) };
+        match from_binary(&query(&deps, query_msg).unwrap()).unwrap() {
+            QueryAnswer::Collateral { debt, .. } => assert_eq!(debt, Uint128(60_000_000_000)),
+            _ => panic!("Unexpected query answer"),
+        }
+        let (state, _) = export_page(&deps, 0, 10);
+        assert_eq!(state.debtors[0].positions, vec![DebtPosition {
+            token: HumanAddr::default(),
+            amount: Uint128(60_000_000_000),
+            interest: Uint128(0),
+            last_block: 0,
+        }]);
+
+        // The debtor can repay it, more than the legacy debt can't be repaid
+        let handle_result = receive_helper(&mut deps, "sUST", "bob", 1_000_000, ReceiveMsg::RepayDebt{});
+        assert_eq!(extract_error_msg(handle_result), "Not authorized");
+        grant_role(&mut deps, "bob", ManagingRole::Debtor);
+        let handle_result = receive_at(&mut deps, env_at("sUST", 1_000_000), "bob", 70_000_000, ReceiveMsg::RepayDebt{});
+        assert_eq!(extract_error_msg(handle_result), "Not possible, the debt of this account would be negative");
+        let handle_result = receive_at(&mut deps, env_at("sUST", 1_000_000), "bob", 20_000_000, ReceiveMsg::RepayDebt{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        let handle_result = receive_at(&mut deps, env_at("sUST", 2_000_000), "bob", 40_000_000, ReceiveMsg::RepayDebt{});
+        assert!(ensure_receive_success(handle_result.unwrap()));
+        assert_eq!(ReadonlyDebtors::from_storage(&deps.storage).positions(&canonical_bob).unwrap(), vec![]);
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_debt(), 0);
+    }
+
+    // Ledger tests
+
+    #[test]
+    fn test_ledger(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        grant_role(&mut deps, "alice", ManagingRole::ReserveDepositor);
+        grant_role(&mut deps, "bob", ManagingRole::ReserveDepositor);
+
+        for (depositor, amount) in [("alice", 1_000_000), ("bob", 2_000_000), ("alice", 3_000_000)] {
+            let handle_result = receive_helper(&mut deps, "sUST", depositor, amount, ReceiveMsg::Deposit{ profit: Uint128(0) });
+            assert!(ensure_success(handle_result.unwrap()));
+        }
+
+        // The latest transactions come first
+        let (txs, total) = match from_binary(&query_ledger(&deps, 0, 2).unwrap()).unwrap() {
+            QueryAnswer::Ledger { txs, total } => (txs, total),
+            _ => panic!("Unexpected query answer"),
+        };
+        assert_eq!(total, Some(3));
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].action, TxAction::Deposit {});
+        assert_eq!(txs[0].amount, Uint128(3_000_000));
+        assert_eq!(txs[0].value, Uint128(3_000_000_000));
+        assert_eq!(txs[0].caller, HumanAddr("alice".to_string()));
+        assert_eq!(txs[1].caller, HumanAddr("bob".to_string()));
+
+        let txs = match from_binary(&query_transactions(&deps, &HumanAddr("alice".to_string()), 1, 1).unwrap()).unwrap() {
+            QueryAnswer::TransactionHistory { txs, total } => {
+                assert_eq!(total, Some(2));
+                txs
+            },
+            _ => panic!("Unexpected query answer"),
+        };
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].amount, Uint128(1_000_000));
+
+        // Pages past the end are empty, even when page * page_size overflows
+        let txs = match from_binary(&query_ledger(&deps, u32::MAX, u32::MAX).unwrap()).unwrap() {
+            QueryAnswer::Ledger { txs, .. } => txs,
+            _ => panic!("Unexpected query answer"),
+        };
+        assert!(txs.is_empty());
+    }
+
+    // Backing tests
+
+    #[test]
+    fn test_backing(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let now = mock_env("admin", &[]).block.time;
+        deps.querier.tokens.insert(HumanAddr("ohm".to_string()), (9, 200_000_000_000));
+        deps.querier.balances.insert(HumanAddr("sUST".to_string()), 100_000_000);
+        deps.querier.balances.insert(HumanAddr("sust-LP".to_string()), 2_000_000_000_000_000_000);
+        // One LP token is worth 50 OHM risk free, the pool prices it 1.5 times higher
+        deps.querier.lp_valuation = 50_000_000_000;
+        deps.querier.lp_markdown = 1_500_000_000;
+
+        // The LP token was registered at init, without a calculator
+        assert_eq!(
+            extract_error_msg(query(&deps, QueryMsg::Backing{ block_time: now })),
+            "This liquidity token has no bond calculator"
+        );
+        Config::from_storage(&mut deps.storage)
+            .set_bond_calculator(HumanAddr("sust-LP".to_string()), token("calculator"))
+            .unwrap();
+
+        match from_binary(&query(&deps, QueryMsg::Backing{ block_time: now }).unwrap()).unwrap() {
+            QueryAnswer::Backing {
+                ohm_total_supply,
+                risk_free_value,
+                market_value,
+                risk_free_backing_per_ohm,
+                backing_per_ohm,
+                ..
+            } => {
+                assert_eq!(ohm_total_supply, Uint128(200_000_000_000));
+                assert_eq!(risk_free_value, Uint128(200_000_000_000));
+                assert_eq!(market_value, Uint128(250_000_000_000));
+                assert_eq!(risk_free_backing_per_ohm, Uint128(1_000_000_000));
+                assert_eq!(backing_per_ohm, Uint128(1_250_000_000));
+            },
+            _ => panic!("Unexpected query answer"),
+        }
+
+        let balances = match from_binary(&query(&deps, QueryMsg::ReserveBalances{ block_time: now }).unwrap()).unwrap() {
+            QueryAnswer::ReserveBalances { balances } => balances,
+            _ => panic!("Unexpected query answer"),
+        };
+        let lp = balances.iter().find(|balance| balance.token == HumanAddr("sust-LP".to_string())).unwrap();
+        assert_eq!(lp.value, Uint128(100_000_000_000));
+        assert_eq!(lp.market_value, Uint128(150_000_000_000));
+
+        // A markdown that overflows is an error, not a panic
+        deps.querier.lp_markdown = u128::MAX / 1_000;
+        assert_eq!(extract_error_msg(query(&deps, QueryMsg::MarketValue{ block_time: now })), "Market value overflow");
+    }
+
+    #[test]
+    fn test_excess_reserves(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        grant_role(&mut deps, "alice", ManagingRole::ReserveManager);
+        deps.querier.tokens.insert(HumanAddr("ohm".to_string()), (9, 200_000_000_000));
+        let excess_reserves = |deps: &Extern<MockStorage, MockApi, TestQuerier>| {
+            match from_binary(&query(deps, QueryMsg::TotalReserves{}).unwrap()).unwrap() {
+                QueryAnswer::TotalReserves { excess_reserves, .. } => excess_reserves.u128(),
+                _ => panic!("Unexpected query answer"),
+            }
+        };
+
+        // Under-backed, the treasury has no excess reserves to manage
+        Config::from_storage(&mut deps.storage).set_total_reserves(150_000_000_000);
+        assert_eq!(excess_reserves(&deps), 0);
+        assert!(query(&deps, QueryMsg::ContractInfo{}).is_ok());
+        let manage_msg = HandleMsg::Manage { token: HumanAddr("sUST".to_string()), amount: Uint128(1_000_000) };
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), manage_msg);
+        assert_eq!(extract_error_msg(handle_result), "Insufficient reserves");
+
+        // The debt counts as backing
+        Config::from_storage(&mut deps.storage).set_total_debt(100_000_000_000);
+        assert_eq!(excess_reserves(&deps), 50_000_000_000);
+        Config::from_storage(&mut deps.storage).set_total_debt(300_000_000_000);
+        assert_eq!(excess_reserves(&deps), 150_000_000_000);
+    }
+
+    // Oracle tests
+
+    #[test]
+    fn test_oracle_valuation(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let now = mock_env("admin", &[]).block.time;
+        let source = ValuationSource::Oracle{
+            address: HumanAddr("oracle".to_string()),
+            code_hash: "Complicated_hash".to_string(),
+            max_staleness: 600,
+        };
+        let handle_msg = HandleMsg::SetValuationSource{
+            token: HumanAddr("SSCRT".to_string()),
+            source,
+        };
+
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), handle_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "This is an admin command. Admin commands can only be run from admin address");
+        // The source has to price the token right away
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), handle_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "No price for this token");
+
+        // 1 SSCRT is worth 2.5 OHM
+        deps.querier.prices.insert(HumanAddr("SSCRT".to_string()), OraclePrice{
+            rate: Uint128(2_500_000_000_000_000_000),
+            last_updated: now - 100,
+        });
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), handle_msg).unwrap()));
+
+        let value_of = |deps: &Extern<MockStorage, MockApi, TestQuerier>, block_time: u64| {
+            query(deps, QueryMsg::ValueOf{
+                token: HumanAddr("SSCRT".to_string()),
+                amount: Uint128(10_000_000),
+                block_time,
+            }).and_then(|answer| match from_binary(&answer)? {
+                QueryAnswer::ValueOf { value } => Ok(value),
+                _ => panic!("Unexpected query answer"),
+            })
+        };
+        assert_eq!(value_of(&deps, now).unwrap(), Uint128(25_000_000_000));
+        assert_eq!(extract_error_msg(value_of(&deps, now + 600)), "The oracle price of SSCRT is stale");
+
+        // The reserve queries value the holdings with the same check
+        Config::from_storage(&mut deps.storage)
+            .set_bond_calculator(HumanAddr("sust-LP".to_string()), token("calculator"))
+            .unwrap();
+        deps.querier.balances.insert(HumanAddr("SSCRT".to_string()), 10_000_000);
+        let risk_free_value = |deps: &Extern<MockStorage, MockApi, TestQuerier>, block_time: u64| {
+            query(deps, QueryMsg::RiskFreeValue{ block_time }).and_then(|answer| match from_binary(&answer)? {
+                QueryAnswer::RiskFreeValue { value } => Ok(value),
+                _ => panic!("Unexpected query answer"),
+            })
+        };
+        assert_eq!(risk_free_value(&deps, now).unwrap(), Uint128(25_000_000_000));
+        assert_eq!(extract_error_msg(risk_free_value(&deps, now + 600)), "The oracle price of SSCRT is stale");
+        assert_eq!(
+            extract_error_msg(query(&deps, QueryMsg::ReserveBalances{ block_time: now + 600 })),
+            "The oracle price of SSCRT is stale"
+        );
+        deps.querier.balances.remove(&HumanAddr("SSCRT".to_string()));
+
+        // Deposits are valued with the price of their block
+        grant_role(&mut deps, "alice", ManagingRole::ReserveDepositor);
+        let mut env = mock_env("SSCRT", &[]);
+        env.block.time = now + 600;
+        let handle_result = receive_at(&mut deps, env, "alice", 10_000_000, ReceiveMsg::Deposit{ profit: Uint128(0) });
+        assert_eq!(extract_error_msg(handle_result), "The oracle price of SSCRT is stale");
+        let handle_result = receive_helper(&mut deps, "SSCRT", "alice", 10_000_000, ReceiveMsg::Deposit{ profit: Uint128(0) });
+        assert!(ensure_success(handle_result.unwrap()));
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 25_000_000_000);
+    }
+
+    // Budget tests
+
+    #[test]
+    fn test_manager_budget(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        Config::from_storage(&mut deps.storage).set_total_reserves(1_000_000_000_000);
+        grant_role(&mut deps, "alice", ManagingRole::ReserveManager);
+        let set_budget = |budget: Option<Budget>, role: ManagingRole| HandleMsg::SetBudget {
+            address: HumanAddr("alice".to_string()),
+            role,
+            budget,
+        };
+        let budget = Budget{ limit: Uint128(10_000_000_000), period: 100 };
+
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), set_budget(Some(budget.clone()), ManagingRole::Debtor));
+        assert_eq!(extract_error_msg(handle_result), "Budgets only apply to manager roles");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), set_budget(Some(Budget{ period: 0, ..budget.clone() }), ManagingRole::ReserveManager));
+        assert_eq!(extract_error_msg(handle_result), "The budget period should be at least one block");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), set_budget(Some(budget.clone()), ManagingRole::ReserveManager));
+        assert!(ensure_success(handle_result.unwrap()));
+
+        let manage = |amount: u128| HandleMsg::Manage {
+            token: HumanAddr("sUST".to_string()),
+            amount: Uint128(amount),
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), manage(6_000_000)).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("alice", &[]), manage(6_000_000));
+        assert_eq!(extract_error_msg(handle_result), "The manager budget for this period is exceeded");
+
+        let remaining_budget = |deps: &Extern<MockStorage, MockApi, TestQuerier>, block_height: u64| {
+            let query_msg = QueryMsg::RemainingBudget {
+                address: HumanAddr("alice".to_string()),
+                role: ManagingRole::ReserveManager,
+                block_height,
+            };
+            match from_binary(&query(deps, query_msg).unwrap()).unwrap() {
+                QueryAnswer::RemainingBudget { used, remaining, period_end, .. } => (used, remaining, period_end),
+                _ => panic!("Unexpected query answer"),
+            }
+        };
+        assert_eq!(remaining_budget(&deps, 12_345), (Uint128(6_000_000_000), Some(Uint128(4_000_000_000)), Some(12_400)));
+        // The next period starts with the whole budget
+        assert_eq!(remaining_budget(&deps, 12_400), (Uint128(0), Some(Uint128(10_000_000_000)), Some(12_500)));
+        assert!(ensure_success(handle(&mut deps, env_at("alice", 12_400), manage(6_000_000)).unwrap()));
+
+        // A period that would end past the last block is not a panic
+        let huge_budget = Budget{ limit: Uint128(10_000_000_000), period: u64::MAX };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), set_budget(Some(huge_budget), ManagingRole::ReserveManager)).unwrap()));
+        assert_eq!(remaining_budget(&deps, u64::MAX), (Uint128(0), Some(Uint128(10_000_000_000)), Some(u64::MAX)));
+        let handle_result = handle(&mut deps, env_at("alice", u64::MAX), manage(11_000_000));
+        assert_eq!(extract_error_msg(handle_result), "The manager budget for this period is exceeded");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), set_budget(Some(budget), ManagingRole::ReserveManager)).unwrap()));
+
+        // Revoking the role removes the budget and its usage
+        let toggle_msg = HandleMsg::ToggleQueue {
+            address: HumanAddr("alice".to_string()),
+            role: ManagingRole::ReserveManager
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), toggle_msg).unwrap()));
+        assert_eq!(remaining_budget(&deps, 12_400), (Uint128(0), None, None));
+        let config = ReadonlyConfig::from_storage(&deps.storage);
+        let canonical_alice = deps.api.canonical_address(&HumanAddr("alice".to_string())).unwrap();
+        assert_eq!(config.budget_usage(&canonical_alice, ManagingRole::ReserveManager), BudgetUsage::default());
+
+        grant_role(&mut deps, "alice", ManagingRole::ReserveManager);
+        assert!(ensure_success(handle(&mut deps, mock_env("alice", &[]), manage(20_000_000)).unwrap()));
+    }
+
+    // Strategy tests
+
+    #[test]
+    fn test_strategy_cycle(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        Config::from_storage(&mut deps.storage).set_total_reserves(1_000_000_000_000);
+        let register = |token: &str| HandleMsg::RegisterStrategy {
+            address: HumanAddr("vault".to_string()),
+            code_hash: "Complicated_hash".to_string(),
+            token: HumanAddr(token.to_string()),
+        };
+        let handle_result = handle(&mut deps, mock_env("bob", &[]), register("sUST"));
+        assert_eq!(extract_error_msg(handle_result), "This is an admin command. Admin commands can only be run from admin address");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), register("ohm"));
+        assert_eq!(extract_error_msg(handle_result), "Token not accepted");
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), register("sUST")).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), register("sUST"));
+        assert_eq!(extract_error_msg(handle_result), "This strategy is already registered");
+
+        let handle_msg = HandleMsg::AllocateToStrategy {
+            strategy: HumanAddr("vault".to_string()),
+            amount: Uint128(100_000_000),
+        };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), handle_msg).unwrap();
+        assert_eq!(handle_result.messages.len(), 1);
+        let strategy = |deps: &Extern<MockStorage, MockApi, TestQuerier>| {
+            ReadonlyConfig::from_storage(&deps.storage).strategy(&HumanAddr("vault".to_string())).unwrap()
+        };
+        assert_eq!(strategy(&deps).principal, Uint128(100_000_000));
+        // Allocated reserves are only moved
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 1_000_000_000_000);
+
+        // The strategy earned 10 sUST, only the strategy itself or the admin can report it
+        deps.querier.strategy_values.insert(HumanAddr("vault".to_string()), 110_000_000);
+        let report = HandleMsg::ReportStrategy { strategy: HumanAddr("vault".to_string()) };
+        let handle_result = handle(&mut deps, mock_env("carol", &[]), report.clone());
+        assert_eq!(extract_error_msg(handle_result), "This is an admin command. Admin commands can only be run from admin address");
+        let handle_result = handle(&mut deps, mock_env("vault", &[]), report.clone()).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::ReportStrategy { gain, loss, .. } => {
+                assert_eq!(gain, Uint128(10_000_000));
+                assert_eq!(loss, Uint128(0));
+            },
+            _ => panic!("Unexpected handle answer"),
+        }
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 1_010_000_000_000);
+
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::RemoveStrategy { address: HumanAddr("vault".to_string()) });
+        assert_eq!(extract_error_msg(handle_result), "Withdraw the reserves from the strategy first");
+        let withdraw = |amount: u128| HandleMsg::WithdrawFromStrategy {
+            strategy: HumanAddr("vault".to_string()),
+            amount: Uint128(amount),
+        };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), withdraw(120_000_000));
+        assert_eq!(extract_error_msg(handle_result), "Not enough reserves in the strategy, report its gains first");
+
+        // Then lost 5 sUST
+        deps.querier.strategy_values.insert(HumanAddr("vault".to_string()), 105_000_000);
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), report).unwrap()));
+        let vault = strategy(&deps);
+        assert_eq!((vault.principal, vault.gains, vault.losses), (Uint128(105_000_000), Uint128(10_000_000), Uint128(5_000_000)));
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 1_005_000_000_000);
+
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), withdraw(105_000_000)).unwrap()));
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::RemoveStrategy { address: HumanAddr("vault".to_string()) });
+        assert!(ensure_success(handle_result.unwrap()));
+        assert!(ReadonlyConfig::from_storage(&deps.storage).strategies().is_empty());
+    }
+
+    // Migration tests
+
+    fn export_page(deps: &Extern<MockStorage, MockApi, TestQuerier>, page: u32, page_size: u32) -> (ExportedState, u64) {
+        match from_binary(&query_export_state(deps, page, page_size).unwrap()).unwrap() {
+            QueryAnswer::ExportState { state, total_debtors } => (state, total_debtors),
+            _ => panic!("Unexpected query answer"),
+        }
+    }
+
+    #[test]
+    fn test_migration(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        borrow_helper(&mut deps, 50_000_000);
+        grant_role(&mut deps, "carol", ManagingRole::Debtor);
+        // Bob keeps his debt and collateral once his role is revoked
+        let toggle_msg = HandleMsg::ToggleQueue {
+            address: HumanAddr("bob".to_string()),
+            role: ManagingRole::Debtor
+        };
+        assert!(ensure_success(handle(&mut deps, mock_env("admin", &[]), toggle_msg).unwrap()));
+
+        let (first_page, total_debtors) = export_page(&deps, 0, 1);
+        assert_eq!(total_debtors, 2);
+        assert_eq!(first_page.debtors.len(), 1);
+        assert_eq!(first_page.debtors[0].address, HumanAddr("bob".to_string()));
+        assert_eq!(first_page.debtors[0].positions[0].amount, Uint128(50_000_000_000));
+        assert_eq!(first_page.debtors[0].collateral_gons, (100_000_000_000 * GONS_PER_SOHM).to_string());
+        let debtor_role = first_page.roles.iter().find(|role| role.role == ManagingRole::Debtor).unwrap();
+        assert_eq!(debtor_role.addresses, vec![HumanAddr("carol".to_string())]);
+        assert_eq!(first_page.deposited.len(), 3);
+        // Carol has nothing to export and the roles only come with the first page
+        let (second_page, _) = export_page(&deps, 1, 1);
+        assert!(second_page.debtors.is_empty());
+        assert!(second_page.roles.is_empty());
+
+        // A treasury initialized without a state can't import one
+        let (init_result, mut fresh_deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let import_msg = HandleMsg::ImportState { state: second_page.clone(), last_page: true };
+        let handle_result = handle(&mut fresh_deps, mock_env("admin", &[]), import_msg);
+        assert_eq!(extract_error_msg(handle_result), "The state import is closed");
+
+        // The next treasury imports the first page at init, then the next pages
+        let (init_result, mut next_deps) = init_importing(Some(first_page.clone()));
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let import_msg = HandleMsg::ImportState { state: first_page, last_page: false };
+        let handle_result = handle(&mut next_deps, mock_env("bob", &[]), import_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "This is an admin command. Admin commands can only be run from admin address");
+        // Importing a page twice doesn't count its debt twice
+        assert!(ensure_success(handle(&mut next_deps, mock_env("admin", &[]), import_msg.clone()).unwrap()));
+        let last_page_msg = HandleMsg::ImportState { state: second_page, last_page: true };
+        assert!(ensure_success(handle(&mut next_deps, mock_env("admin", &[]), last_page_msg.clone()).unwrap()));
+        // The import is closed after the last page
+        let handle_result = handle(&mut next_deps, mock_env("admin", &[]), import_msg);
+        assert_eq!(extract_error_msg(handle_result), "The state import is closed");
+        let handle_result = handle(&mut next_deps, mock_env("admin", &[]), last_page_msg);
+        assert_eq!(extract_error_msg(handle_result), "The state import is closed");
+        let config = ReadonlyConfig::from_storage(&next_deps.storage);
+        assert_eq!(config.total_debt(), 50_000_000_000);
+        let canonical_carol = next_deps.api.canonical_address(&HumanAddr("carol".to_string())).unwrap();
+        assert!(config.has_managing_position(&canonical_carol, ManagingRole::Debtor));
+        let (exported_again, _) = export_page(&next_deps, 0, 10);
+        assert_eq!(exported_again.debtors[0].address, HumanAddr("bob".to_string()));
+
+        // The migration waits at least MIN_MIGRATION_DELAY blocks, even without a queue delay
+        deps.querier.balances.insert(HumanAddr("sohm".to_string()), 100_000_000_000);
+        let migrate_msg = HandleMsg::MigrateTo { new_treasury: HumanAddr("next-treasury".to_string()) };
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), migrate_msg.clone()).unwrap();
+        match from_binary(&handle_result.data.unwrap()).unwrap() {
+            HandleAnswer::MigrateTo { executed, .. } => assert!(!executed),
+            _ => panic!("Unexpected handle answer"),
+        }
+        let handle_result = handle(&mut deps, env_at("admin", 12_345 + MIN_MIGRATION_DELAY - 1), migrate_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Queue is not over yet, wait a bit more");
+        let handle_result = handle(&mut deps, env_at("admin", 12_345 + MIN_MIGRATION_DELAY), migrate_msg).unwrap();
+        assert_eq!(handle_result.messages.len(), 1);
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).contract_status(), ContractStatusLevel::StopAll);
+    }
+
+    // Native deposit tests
 
+    #[test]
+    fn test_deposit_native(){
+        let (init_result, mut deps) = init_helper();
+        assert!(init_result.is_ok(), "Init failed: {}", init_result.err().unwrap());
+        let deposit_msg = HandleMsg::DepositNative { profit: Uint128(0) };
+        let uscrt = |amount: u128, denom: &str| vec![Coin{ denom: denom.to_string(), amount: Uint128(amount) }];
+
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(5_000_000, "uscrt")), deposit_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Native deposits are not enabled");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::SetNativeWrapper { token: Some(HumanAddr("ohm".to_string())) });
+        assert_eq!(extract_error_msg(handle_result), "Token not accepted");
+        let handle_result = handle(&mut deps, mock_env("admin", &[]), HandleMsg::SetNativeWrapper { token: Some(HumanAddr("SSCRT".to_string())) });
+        assert!(ensure_success(handle_result.unwrap()));
+
+        // Only reserve depositors can deposit, and only uscrt
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(5_000_000, "uscrt")), deposit_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Depositor not approved");
+        grant_role(&mut deps, "alice", ManagingRole::ReserveDepositor);
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(5_000_000, "uatom")), deposit_msg.clone());
+        assert_eq!(extract_error_msg(handle_result), "Only uscrt can be deposited");
+
+        let handle_result = handle(&mut deps, mock_env("alice", &uscrt(5_000_000, "uscrt")), deposit_msg).unwrap();
+        // The uscrt is wrapped first
+        match &handle_result.messages[0] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, send, .. }) => {
+                assert_eq!(*contract_addr, HumanAddr("SSCRT".to_string()));
+                assert_eq!(*send, uscrt(5_000_000, "uscrt"));
+            },
+            _ => panic!("Unexpected message"),
+        }
+        assert!(ensure_success(handle_result));
+        assert_eq!(ReadonlyConfig::from_storage(&deps.storage).total_reserves(), 5_000_000_000);
     }
 
 }
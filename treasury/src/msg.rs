@@ -4,8 +4,11 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use cosmwasm_std::{Binary, HumanAddr, StdError, StdResult, Uint128};
-use crate::state::{Contract, Pair, ManagingRole, RESPONSE_BLOCK_SIZE};
-use secret_toolkit::utils::Query;
+use crate::state::{Contract, Pair, ManagingRole, DebtPosition, LiquidationParameters, ValuationSource, Budget, Strategy,
+    Migration, RESPONSE_BLOCK_SIZE};
+use crate::transaction_history::RichTx;
+use secret_toolkit::permit::Permit;
+use secret_toolkit::utils::{HandleCallback, Query};
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct InitialBalance {
@@ -22,7 +25,44 @@ pub struct InitMsg {
     pub sohm : Contract,
     pub reserve_tokens : Option<Vec<Contract>>,
     pub liquidity_tokens : Option<Vec<Contract>>,
-    pub blocks_needed_for_queue : u64
+    pub blocks_needed_for_queue : u64,
+    // State exported from a previous treasury, see `QueryWithPermit::ExportState`
+    pub import : Option<ExportedState>
+}
+
+// Roles, deposits and calculators are only exported with the first page, the next pages only hold debtors.
+// The total debt is rebuilt from the imported debt positions
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExportedState {
+    pub roles : Vec<ExportedRole>,
+    pub debtors : Vec<ExportedDebtor>,
+    pub deposited : Vec<ExportedDeposit>,
+    pub bond_calculators : Vec<ExportedCalculator>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExportedRole {
+    pub role : ManagingRole,
+    pub addresses : Vec<HumanAddr>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExportedDebtor {
+    pub address : HumanAddr,
+    pub positions : Vec<DebtPosition>,
+    pub collateral_gons : String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExportedDeposit {
+    pub token : HumanAddr,
+    pub amount : Uint128,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ExportedCalculator {
+    pub token : HumanAddr,
+    pub calculator : Contract,
 }
 
 
@@ -39,6 +79,13 @@ pub enum ReceiveMsg{
     RepayDebt{
         
     },
+    PostCollateral{
+
+    },
+    // Repays the debt of an unhealthy debtor, the liquidator receives the repaid value in sOHM plus the bonus
+    Liquidate{
+        debtor : HumanAddr
+    },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
@@ -50,10 +97,18 @@ pub enum HandleMsg {
         amount: Uint128,
         msg: Binary,
     },
+    // Deposits the uscrt sent with the message, wrapped into the native wrapper reserve token.
+    // Only for reserve depositors, bond buyers send their uscrt to the bond depository
+    DepositNative{
+        profit : Uint128
+    },
     IncurDebt{
         token : HumanAddr,
         amount : Uint128
     },
+    WithdrawCollateral{
+        amount : Uint128
+    },
     Manage{
         token : HumanAddr,
         amount : Uint128
@@ -78,6 +133,66 @@ pub enum HandleMsg {
         role : ManagingRole,
         calculator: Option<Contract>
     },
+    CancelQueue{
+        address : HumanAddr,
+        role : ManagingRole
+    },
+    SetRevocationDelay{
+        blocks : u64
+    },
+    SetBudget{
+        address : HumanAddr,
+        role : ManagingRole,
+        // Removes the budget when empty
+        budget : Option<Budget>
+    },
+    RegisterStrategy{
+        address : HumanAddr,
+        code_hash : String,
+        token : HumanAddr
+    },
+    RemoveStrategy{
+        address : HumanAddr
+    },
+    AllocateToStrategy{
+        strategy : HumanAddr,
+        amount : Uint128
+    },
+    WithdrawFromStrategy{
+        strategy : HumanAddr,
+        amount : Uint128
+    },
+    ReportStrategy{
+        strategy : HumanAddr
+    },
+    // Queues the migration, then executes it once the queue is over
+    MigrateTo{
+        new_treasury : HumanAddr
+    },
+    CancelMigration{
+
+    },
+    // Imports a page of the state exported by the previous treasury, the first page has to be imported at init.
+    // No page can be imported once the last one is
+    ImportState{
+        state : ExportedState,
+        last_page : bool
+    },
+    SetNativeWrapper{
+        token : Option<HumanAddr>
+    },
+    SetBorrowRate{
+        token : HumanAddr,
+        rate : Uint128
+    },
+    SetLiquidationParameters{
+        ltv : Uint128,
+        bonus : Uint128
+    },
+    SetValuationSource{
+        token : HumanAddr,
+        source : ValuationSource
+    },
 
     // Admin
     ChangeAdmin {
@@ -88,6 +203,12 @@ pub enum HandleMsg {
         level: ContractStatusLevel,
         padding: Option<String>,
     },
+
+    // Permit
+    RevokePermit {
+        permit_name: String,
+        padding: Option<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
@@ -99,6 +220,9 @@ pub enum ReceiveAnswer {
     RepayDebt {
         status: ResponseStatus,
     },
+    PostCollateral {
+        status: ResponseStatus,
+    },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
@@ -117,6 +241,10 @@ pub enum HandleAnswer {
         status: ResponseStatus,
     },
 
+    WithdrawCollateral {
+        status: ResponseStatus,
+    },
+
     Manage {
         status: ResponseStatus,
     },
@@ -137,6 +265,79 @@ pub enum HandleAnswer {
         status: ResponseStatus,
     },
 
+    CancelQueue {
+        status: ResponseStatus,
+    },
+
+    SetRevocationDelay {
+        status: ResponseStatus,
+    },
+
+    SetBudget {
+        status: ResponseStatus,
+    },
+
+    RegisterStrategy {
+        status: ResponseStatus,
+    },
+
+    RemoveStrategy {
+        status: ResponseStatus,
+    },
+
+    AllocateToStrategy {
+        status: ResponseStatus,
+    },
+
+    WithdrawFromStrategy {
+        status: ResponseStatus,
+    },
+
+    ReportStrategy {
+        status: ResponseStatus,
+        gain: Uint128,
+        loss: Uint128,
+    },
+
+    MigrateTo {
+        status: ResponseStatus,
+        executed: bool,
+    },
+
+    CancelMigration {
+        status: ResponseStatus,
+    },
+
+    ImportState {
+        status: ResponseStatus,
+    },
+
+    DepositNative {
+        status: ResponseStatus,
+    },
+
+    SetNativeWrapper {
+        status: ResponseStatus,
+    },
+
+    Liquidate {
+        status: ResponseStatus,
+        repaid: Uint128,
+        seized: Uint128,
+    },
+
+    SetBorrowRate {
+        status: ResponseStatus,
+    },
+
+    SetLiquidationParameters {
+        status: ResponseStatus,
+    },
+
+    SetValuationSource {
+        status: ResponseStatus,
+    },
+
     // Other
     ChangeAdmin {
         status: ResponseStatus,
@@ -144,6 +345,11 @@ pub enum HandleAnswer {
     SetContractStatus {
         status: ResponseStatus,
     },
+
+    // Permit
+    RevokePermit {
+        status: ResponseStatus,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -156,14 +362,79 @@ pub enum QueryMsg {
     ManagingAddresses {
         role: ManagingRole
     },
+    PendingQueue {
+        role: ManagingRole
+    },
+    RemainingBudget {
+        address: HumanAddr,
+        role: ManagingRole,
+        block_height: u64
+    },
+    Strategies {},
+    PendingMigration {},
     ContractStatus {},
+    // Oracle prices older than their staleness bound at `block_time` are rejected
     ValueOf{
         token: HumanAddr,
-        amount: Uint128
-    },  
+        amount: Uint128,
+        block_time: u64
+    },
     TotalBondDeposited {
        token: HumanAddr
-    }
+    },
+    Collateral {
+        debtor: HumanAddr
+    },
+    DebtorHealth {
+        debtor: HumanAddr,
+        block_height: u64
+    },
+    BorrowRate {
+        token: HumanAddr
+    },
+    LiquidationParameters {},
+    ValuationSource {
+        token: HumanAddr
+    },
+    WithPermit {
+        permit: Permit,
+        query: QueryWithPermit,
+    },
+    TotalReserves {},
+    // The reserves are valued at `block_time`, stale oracle prices are rejected as in `ValueOf`
+    ReserveBalances {
+        block_time: u64
+    },
+    RiskFreeValue {
+        block_time: u64
+    },
+    MarketValue {
+        block_time: u64
+    },
+    Backing {
+        block_time: u64
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryWithPermit {
+    // Only available to the admin
+    Ledger {
+        page: Option<u32>,
+        page_size: u32,
+    },
+    // The admin can query the history of any address
+    TransactionHistory {
+        address: Option<HumanAddr>,
+        page: Option<u32>,
+        page_size: u32,
+    },
+    // Only available to the admin
+    ExportState {
+        page: Option<u32>,
+        page_size: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
@@ -176,6 +447,8 @@ pub enum QueryAnswer {
         ohm : Contract,
         sohm : Contract,
         blocks_needed_for_queue : u64,
+        blocks_needed_for_revocation : u64,
+        native_wrapper : Option<HumanAddr>,
         total_reserves: Uint128,
         total_debt: Uint128,
         excess_reserves: Uint128,
@@ -186,6 +459,28 @@ pub enum QueryAnswer {
     ManagersInfo{
         addresses : Vec<HumanAddr>
     },
+    PendingQueue{
+        entries : Vec<QueueEntry>
+    },
+    Strategies{
+        strategies : Vec<Strategy>
+    },
+    PendingMigration{
+        migration : Option<Migration>
+    },
+    ExportState{
+        state : ExportedState,
+        // Only the debtors are paginated, over every account that posted collateral or borrowed.
+        // Accounts without debt nor collateral are skipped, a page can hold less than `page_size` debtors
+        total_debtors : u64
+    },
+    RemainingBudget{
+        budget : Option<Budget>,
+        used : Uint128,
+        // Empty when the manager has no budget
+        remaining : Option<Uint128>,
+        period_end : Option<u64>
+    },
     ContractStatus {
         status: ContractStatusLevel,
     },
@@ -194,7 +489,80 @@ pub enum QueryAnswer {
     },
     TotalBondDeposited {
         amount:Uint128
-    }
+    },
+    Collateral {
+        gons: String,
+        amount: Uint128,
+        debt: Uint128,
+    },
+    DebtorHealth {
+        positions: Vec<DebtPosition>,
+        debt: Uint128,
+        collateral: Uint128,
+        health_factor: Option<Uint128>,
+        liquidatable: bool,
+    },
+    BorrowRate {
+        rate: Uint128
+    },
+    LiquidationParameters {
+        parameters: Option<LiquidationParameters>
+    },
+    ValuationSource {
+        source: ValuationSource
+    },
+    Ledger {
+        txs: Vec<RichTx>,
+        total: Option<u64>,
+    },
+    TransactionHistory {
+        txs: Vec<RichTx>,
+        total: Option<u64>,
+    },
+    TotalReserves {
+        total_reserves: Uint128,
+        excess_reserves: Uint128,
+        total_debt: Uint128,
+    },
+    ReserveBalances {
+        balances: Vec<ReserveBalance>,
+    },
+    RiskFreeValue {
+        value: Uint128,
+    },
+    MarketValue {
+        value: Uint128,
+    },
+    Backing {
+        ohm_total_supply: Uint128,
+        total_debt: Uint128,
+        risk_free_value: Uint128,
+        market_value: Uint128,
+        // Both are expressed with the OHM decimals
+        risk_free_backing_per_ohm: Uint128,
+        backing_per_ohm: Uint128,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct QueueEntry {
+    pub address: HumanAddr,
+    // The entry can be toggled from this height on
+    pub unlock_height: u64,
+    // True when the address already holds the role and toggling it will revoke it
+    pub revocation: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReserveBalance {
+    pub token: HumanAddr,
+    pub role: ManagingRole,
+    pub balance: Uint128,
+    // Tokens allocated to strategies, as reported by the strategies
+    pub deployed: Uint128,
+    // Risk free value of the balance and deployed tokens, as returned by `ValueOf`
+    pub value: Uint128,
+    pub market_value: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
@@ -241,6 +609,9 @@ pub enum CalculatorQueryMsg {
     Valuation { 
         pair: Pair,
         amount: Uint128,
+    },
+    Markdown {
+        pair: Pair,
     }
 }
 
@@ -248,6 +619,110 @@ impl Query for CalculatorQueryMsg {
     const BLOCK_SIZE: usize = RESPONSE_BLOCK_SIZE;
 }
 
+// Interface every strategy the reserves are allocated to should implement
+
+// Message attached to the reserves sent to a strategy
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyReceiveMsg {
+    Deposit {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyHandleMsg {
+    // The strategy should transfer `amount` tokens back to the treasury
+    Withdraw {
+        amount: Uint128,
+    },
+}
+
+impl HandleCallback for StrategyHandleMsg {
+    const BLOCK_SIZE: usize = RESPONSE_BLOCK_SIZE;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyQueryMsg {
+    StrategyValue {},
+}
+
+impl Query for StrategyQueryMsg {
+    const BLOCK_SIZE: usize = RESPONSE_BLOCK_SIZE;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StrategyValue {
+    // Amount of tokens the strategy could return to the treasury
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StrategyValueResponse {
+    pub strategy_value: StrategyValue,
+}
+
+// Interface every price oracle used to value reserve tokens should implement
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleQueryMsg {
+    Price {
+        token: HumanAddr,
+    }
+}
+
+impl Query for OracleQueryMsg {
+    const BLOCK_SIZE: usize = RESPONSE_BLOCK_SIZE;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OraclePrice {
+    // Value of one whole token in USD, with `ORACLE_PRICE_DECIMALS` decimals
+    pub rate: Uint128,
+    // Block time of the last price update, in seconds
+    pub last_updated: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceResponse {
+    pub price: OraclePrice,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SOhmQueryMsg {
+    GonsForBalance {
+        amount: Uint128
+    },
+    BalanceForGons {
+        gons: String
+    },
+}
+
+impl Query for SOhmQueryMsg {
+    const BLOCK_SIZE: usize = RESPONSE_BLOCK_SIZE;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct GonsForBalance {
+    pub gons: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct GonsForBalanceResponse {
+    pub gons_for_balance: GonsForBalance,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BalanceForGons {
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BalanceForGonsResponse {
+    pub balance_for_gons: BalanceForGons,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Valuation {
     pub value: Uint128,
@@ -258,6 +733,16 @@ pub struct ValuationResponse {
     pub valuation: Valuation,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Markdown {
+    pub value: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MarkdownResponse {
+    pub markdown: Markdown,
+}
+
 
 
 // Take a Vec<u8> and pad it up to a multiple of `block_size`, using spaces at the end.
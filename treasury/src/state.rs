@@ -9,9 +9,11 @@ use secret_toolkit::{snip20, utils::Query};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::msg::{status_level_to_u8, u8_to_status_level, ContractStatusLevel, CalculatorQueryMsg, ValuationResponse};
+use crate::msg::{status_level_to_u8, u8_to_status_level, ContractStatusLevel, CalculatorQueryMsg, ValuationResponse, MarkdownResponse,
+    OracleQueryMsg, PriceResponse};
 use crate::viewing_key::ViewingKey;
 use serde::de::DeserializeOwned;
+use primitive_types::U256;
 
 pub static CONFIG_KEY: &[u8] = b"config";
 pub const PREFIX_TXS: &[u8] = b"transfers";
@@ -26,10 +28,20 @@ pub const KEY_TX_COUNT: &[u8] = b"tx-count";
 pub const KEY_RESERVE_TOKENS: &[u8] = b"reserve_tokens";
 pub const KEY_LIQUIDITY_TOKENS: &[u8] = b"liquidity_tokens";
 pub const KEY_BOND_CALCULATOR: &[u8] = b"bond_calculator";
+pub const KEY_BORROW_RATES: &[u8] = b"borrow_rates";
+pub const KEY_LIQUIDATION: &[u8] = b"liquidation";
+pub const KEY_VALUATION_SOURCES: &[u8] = b"valuation_sources";
+pub const KEY_REVOCATION_DELAY: &[u8] = b"revocation_delay";
+pub const KEY_STRATEGIES: &[u8] = b"strategies";
+pub const KEY_MIGRATION: &[u8] = b"migration";
+pub const KEY_NATIVE_WRAPPER: &[u8] = b"native_wrapper";
+pub const KEY_POSITION_HOLDERS: &[u8] = b"position_holders";
+pub const KEY_IMPORT_OPEN: &[u8] = b"import_open";
 
 pub const PREFIX_CONFIG: &[u8] = b"config";
 pub const PREFIX_DEPOSITED: &[u8] = b"deposited";
 pub const PREFIX_DEBTORS: &[u8] = b"debtors";
+pub const PREFIX_COLLATERAL: &[u8] = b"collateral";
 pub const PREFIX_ALLOWANCES: &[u8] = b"allowances";
 pub const PREFIX_VIEW_KEY: &[u8] = b"viewingkey";
 pub const PREFIX_RECEIVERS: &[u8] = b"receivers";
@@ -37,8 +49,15 @@ pub const PREFIX_RECEIVERS: &[u8] = b"receivers";
 pub const QUEUE_POSTFIX : &str = "_queue";
 pub const POSITION_POSTFIX : &str = "_position";
 pub const MANAGING_ROLE_POSTFIX : &str = "_managing"; 
+pub const QUEUED_POSTFIX : &str = "_queued";
+pub const BUDGET_POSTFIX : &str = "_budget";
+pub const BUDGET_USAGE_POSTFIX : &str = "_budget_usage";
 
 pub const RESPONSE_BLOCK_SIZE: usize = 256;
+// Borrow rates are expressed in billionths of the debt per block
+pub const BORROW_RATE_DENOMINATOR: u128 = 1_000_000_000;
+// Oracle prices are the value of one whole token in USD, with this many decimals
+pub const ORACLE_PRICE_DECIMALS: u32 = 18;
 
 //Maybe try to not have duplicate methods BEG
 
@@ -85,6 +104,93 @@ pub enum ManagingRole{
     SOHM
 }
 
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct LiquidationParameters{
+    // Debt to collateral ratio above which a debtor can be liquidated, in ten-thousandths (8000 = 80%)
+    pub ltv : Uint128,
+    // Share of the repaid debt paid to the liquidator out of the seized collateral, in ten-thousandths
+    pub bonus : Uint128,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ValuationSource{
+    // The token is a stablecoin, only its decimals are converted
+    Stable{},
+    // The token is valued with the price returned by the oracle contract
+    Oracle{
+        address : HumanAddr,
+        code_hash : String,
+        // Maximum age of the price, in seconds
+        max_staleness : u64,
+    },
+}
+
+impl Default for ValuationSource{
+    fn default() -> Self {
+        ValuationSource::Stable{}
+    }
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Migration{
+    pub new_treasury : HumanAddr,
+    // The migration can be executed from this height on
+    pub unlock_height : u64,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Strategy{
+    pub address : HumanAddr,
+    pub code_hash : String,
+    // Reserve token the strategy is allocated
+    pub token : HumanAddr,
+    // Amount of tokens deployed in the strategy, as of the last report
+    pub principal : Uint128,
+    // Cumulated gains and losses reported by the strategy, in tokens
+    pub gains : Uint128,
+    pub losses : Uint128,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Budget{
+    // Maximum value in OHM the manager can use during a period
+    pub limit : Uint128,
+    // Length of a period in blocks, periods start at multiples of this value
+    pub period : u64,
+}
+
+impl Budget {
+    pub fn period_start(&self, block_height: u64) -> u64 {
+        block_height - block_height % self.period
+    }
+
+    pub fn remaining(&self, usage: &BudgetUsage, block_height: u64) -> u128 {
+        if usage.period_start != self.period_start(block_height){
+            return self.limit.u128();
+        }
+        self.limit.u128().saturating_sub(usage.used.u128())
+    }
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Default, JsonSchema)]
+pub struct BudgetUsage{
+    pub used : Uint128,
+    // Start of the period the usage was recorded in
+    pub period_start : u64,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct DebtPosition{
+    pub token : HumanAddr,
+    // Value of the debt in OHM, accrued interest included
+    pub amount : Uint128,
+    // Part of `amount` that is accrued interest. It isn't in the total debt, the reserves only get it once repaid
+    pub interest : Uint128,
+    // Last block the interest was accrued on this position
+    pub last_block : u64,
+}
+
 
 pub struct ReadonlyConfig<'a, S: ReadonlyStorage> {
     storage: ReadonlyPrefixedStorage<'a, S>,
@@ -122,6 +228,42 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfig<'a, S> {
         self.as_readonly().managing_queue(address,role)
     }
 
+    pub fn queued_addresses(&self, role : ManagingRole) -> Vec<CanonicalAddr> {
+        self.as_readonly().queued_addresses(role)
+    }
+
+    pub fn revocation_delay(&self) -> u64 {
+        self.as_readonly().revocation_delay()
+    }
+
+    pub fn budget(&self, address: &CanonicalAddr, role : ManagingRole) -> Option<Budget> {
+        self.as_readonly().budget(address,role)
+    }
+
+    pub fn budget_usage(&self, address: &CanonicalAddr, role : ManagingRole) -> BudgetUsage {
+        self.as_readonly().budget_usage(address,role)
+    }
+
+    pub fn strategies(&self) -> Vec<Strategy> {
+        self.as_readonly().strategies()
+    }
+
+    pub fn strategy(&self, address : &HumanAddr) -> StdResult<Strategy> {
+        self.as_readonly().strategy(address)
+    }
+
+    pub fn pending_migration(&self) -> Option<Migration> {
+        self.as_readonly().pending_migration()
+    }
+
+    pub fn position_holders(&self) -> Vec<CanonicalAddr> {
+        self.as_readonly().position_holders()
+    }
+
+    pub fn native_wrapper(&self) -> Option<HumanAddr> {
+        self.as_readonly().native_wrapper()
+    }
+
     pub fn total_reserves(&self) -> u128 {
         self.as_readonly().total_reserves()
     }
@@ -170,12 +312,28 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfig<'a, S> {
         self.as_readonly().is_liquidity_token(token)
     }
 
-    pub fn bond_calculator(&self, token : &HumanAddr) -> Contract{
+    pub fn bond_calculator(&self, token : &HumanAddr) -> StdResult<Contract>{
         self.as_readonly().bond_calculator(token)
     }
 
-    pub fn value_of<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128) -> StdResult<u128>{
-        self.as_readonly().value_of(querier,token,amount)
+    pub fn value_of_at<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128, time: u64) -> StdResult<u128>{
+        self.as_readonly().value_of_at(querier,token,amount,time)
+    }
+
+    pub fn market_value_of<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128, time: u64) -> StdResult<u128>{
+        self.as_readonly().market_value_of(querier,token,amount,time)
+    }
+
+    pub fn valuation_source(&self, token : &HumanAddr) -> ValuationSource{
+        self.as_readonly().valuation_source(token)
+    }
+
+    pub fn borrow_rate(&self, token : &HumanAddr) -> u128{
+        self.as_readonly().borrow_rate(token)
+    }
+
+    pub fn liquidation_parameters(&self) -> Option<LiquidationParameters>{
+        self.as_readonly().liquidation_parameters()
     }
 }
 
@@ -272,7 +430,144 @@ impl<'a, S: Storage> Config<'a, S> {
 
     pub fn set_managing_queue(&mut self, address: &CanonicalAddr, role : ManagingRole, value : u64) -> StdResult<()> {
         let role_string : String = role.to_string() + QUEUE_POSTFIX;
-        bucket(&role_string.into_bytes(), &mut self.storage).save(address.as_slice(),&value)
+        bucket(&role_string.into_bytes(), &mut self.storage).save(address.as_slice(),&value)?;
+
+        // Keep track of the pending entries, so that they can be listed
+        let mut queued = self.queued_addresses(role.clone());
+        queued.retain(|x| x != address);
+        if value != 0{
+            queued.push(address.clone());
+        }
+        let queued_string : String = role.to_string() + QUEUED_POSTFIX;
+        set_bin_data(&mut self.storage, &queued_string.into_bytes(), &queued)
+    }
+
+    pub fn queued_addresses(&self, role : ManagingRole) -> Vec<CanonicalAddr> {
+        self.as_readonly().queued_addresses(role)
+    }
+
+    pub fn revocation_delay(&self) -> u64 {
+        self.as_readonly().revocation_delay()
+    }
+
+    pub fn set_revocation_delay(&mut self, blocks : u64) -> StdResult<()> {
+        set_bin_data(&mut self.storage, KEY_REVOCATION_DELAY, &blocks)
+    }
+
+    pub fn budget(&self, address: &CanonicalAddr, role : ManagingRole) -> Option<Budget> {
+        self.as_readonly().budget(address,role)
+    }
+
+    pub fn set_budget(&mut self, address: &CanonicalAddr, role : ManagingRole, budget : Option<Budget>) -> StdResult<()> {
+        let role_string : String = role.to_string() + BUDGET_POSTFIX;
+        let mut budgets = bucket(&role_string.into_bytes(), &mut self.storage);
+        match budget{
+            Some(budget) => budgets.save(address.as_slice(),&budget),
+            None => {
+                budgets.remove(address.as_slice());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn budget_usage(&self, address: &CanonicalAddr, role : ManagingRole) -> BudgetUsage {
+        self.as_readonly().budget_usage(address,role)
+    }
+
+    pub fn set_budget_usage(&mut self, address: &CanonicalAddr, role : ManagingRole, usage : &BudgetUsage) -> StdResult<()> {
+        let role_string : String = role.to_string() + BUDGET_USAGE_POSTFIX;
+        bucket(&role_string.into_bytes(), &mut self.storage).save(address.as_slice(),usage)
+    }
+
+    // Removes the budget and its usage, a manager granted the role again starts from a clean slate
+    pub fn clear_budget(&mut self, address: &CanonicalAddr, role : ManagingRole) -> StdResult<()> {
+        self.set_budget(address, role.clone(), None)?;
+        let role_string : String = role.to_string() + BUDGET_USAGE_POSTFIX;
+        bucket::<_, BudgetUsage>(&role_string.into_bytes(), &mut self.storage).remove(address.as_slice());
+        Ok(())
+    }
+
+    pub fn strategies(&self) -> Vec<Strategy> {
+        self.as_readonly().strategies()
+    }
+
+    pub fn strategy(&self, address : &HumanAddr) -> StdResult<Strategy> {
+        self.as_readonly().strategy(address)
+    }
+
+    pub fn set_strategies(&mut self, strategies : Vec<Strategy>) -> StdResult<()> {
+        set_bin_data(&mut self.storage, KEY_STRATEGIES, &strategies)
+    }
+
+    // Adds the strategy or replaces the one registered at the same address
+    pub fn save_strategy(&mut self, strategy : Strategy) -> StdResult<()> {
+        let mut strategies = self.strategies();
+        if let Some(position) = strategies.iter().position(|x| x.address == strategy.address){
+            strategies[position] = strategy;
+        }else{
+            strategies.push(strategy);
+        }
+        self.set_strategies(strategies)
+    }
+
+    pub fn remove_strategy(&mut self, address : &HumanAddr) -> StdResult<()> {
+        let mut strategies = self.strategies();
+        strategies.retain(|x| x.address != *address);
+        self.set_strategies(strategies)
+    }
+
+    pub fn pending_migration(&self) -> Option<Migration> {
+        self.as_readonly().pending_migration()
+    }
+
+    pub fn position_holders(&self) -> Vec<CanonicalAddr> {
+        self.as_readonly().position_holders()
+    }
+
+    pub fn native_wrapper(&self) -> Option<HumanAddr> {
+        self.as_readonly().native_wrapper()
+    }
+
+    pub fn set_native_wrapper(&mut self, token : Option<HumanAddr>) -> StdResult<()> {
+        match token{
+            Some(token) => set_bin_data(&mut self.storage, KEY_NATIVE_WRAPPER, &token),
+            None => {
+                self.storage.remove(KEY_NATIVE_WRAPPER);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn import_open(&self) -> bool {
+        self.as_readonly().import_open()
+    }
+
+    pub fn set_import_open(&mut self, open : bool) -> StdResult<()> {
+        if open{
+            set_bin_data(&mut self.storage, KEY_IMPORT_OPEN, &open)
+        }else{
+            self.storage.remove(KEY_IMPORT_OPEN);
+            Ok(())
+        }
+    }
+
+    pub fn add_position_holder(&mut self, address : &CanonicalAddr) -> StdResult<()> {
+        let mut holders = self.position_holders();
+        if holders.contains(address){
+            return Ok(());
+        }
+        holders.push(address.clone());
+        set_bin_data(&mut self.storage, KEY_POSITION_HOLDERS, &holders)
+    }
+
+    pub fn set_pending_migration(&mut self, migration : Option<Migration>) -> StdResult<()> {
+        match migration{
+            Some(migration) => set_bin_data(&mut self.storage, KEY_MIGRATION, &migration),
+            None => {
+                self.storage.remove(KEY_MIGRATION);
+                Ok(())
+            }
+        }
     }
 
     pub fn set_constants(&mut self, constants: &Constants) -> StdResult<()> {
@@ -398,7 +693,7 @@ impl<'a, S: Storage> Config<'a, S> {
         self.set_liquidity_tokens(liquidity_tokens)
     }
 
-    pub fn bond_calculator(&self, token : &HumanAddr) -> Contract{
+    pub fn bond_calculator(&self, token : &HumanAddr) -> StdResult<Contract>{
         self.as_readonly().bond_calculator(token)
     }
 
@@ -406,8 +701,32 @@ impl<'a, S: Storage> Config<'a, S> {
         bucket(&KEY_BOND_CALCULATOR, &mut self.storage).save(token.as_str().as_bytes(),&calculator)
     }
 
-    pub fn value_of<Q: Querier> (&self, querier: &Q,token : &HumanAddr,amount:u128) -> StdResult<u128>{
-        self.as_readonly().value_of(querier,token,amount)
+    pub fn value_of_at<Q: Querier> (&self, querier: &Q,token : &HumanAddr,amount:u128, time: u64) -> StdResult<u128>{
+        self.as_readonly().value_of_at(querier,token,amount,time)
+    }
+
+    pub fn valuation_source(&self, token : &HumanAddr) -> ValuationSource{
+        self.as_readonly().valuation_source(token)
+    }
+
+    pub fn set_valuation_source(&mut self, token : &HumanAddr, source : &ValuationSource) -> StdResult<()>{
+        bucket(KEY_VALUATION_SOURCES, &mut self.storage).save(token.as_str().as_bytes(),source)
+    }
+
+    pub fn borrow_rate(&self, token : &HumanAddr) -> u128{
+        self.as_readonly().borrow_rate(token)
+    }
+
+    pub fn set_borrow_rate(&mut self, token : &HumanAddr, rate : u128) -> StdResult<()>{
+        bucket(KEY_BORROW_RATES, &mut self.storage).save(token.as_str().as_bytes(),&Uint128(rate))
+    }
+
+    pub fn liquidation_parameters(&self) -> Option<LiquidationParameters>{
+        self.as_readonly().liquidation_parameters()
+    }
+
+    pub fn set_liquidation_parameters(&mut self, parameters : &LiquidationParameters) -> StdResult<()>{
+        set_bin_data(&mut self.storage, KEY_LIQUIDATION, parameters)
     }
 }
 
@@ -453,6 +772,57 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
         managing_queue.map(Option::unwrap_or_default)
     }
 
+    pub fn queued_addresses(&self, role : ManagingRole) -> Vec<CanonicalAddr> {
+        let role_string : String = role.to_string() + QUEUED_POSTFIX;
+        get_bin_data(self.0,&role_string.into_bytes()).unwrap_or_default()
+    }
+
+    // Blocks to wait before a role can be revoked, revocations are instant when 0
+    pub fn revocation_delay(&self) -> u64 {
+        get_bin_data(self.0, KEY_REVOCATION_DELAY).unwrap_or_default()
+    }
+
+    // Managers without a budget are only limited by the excess reserves
+    pub fn budget(&self, address: &CanonicalAddr, role : ManagingRole) -> Option<Budget> {
+        let role_string : String = role.to_string() + BUDGET_POSTFIX;
+        bucket_read(&role_string.into_bytes(), self.0).may_load(address.as_slice()).ok().flatten()
+    }
+
+    pub fn budget_usage(&self, address: &CanonicalAddr, role : ManagingRole) -> BudgetUsage {
+        let role_string : String = role.to_string() + BUDGET_USAGE_POSTFIX;
+        bucket_read(&role_string.into_bytes(), self.0).may_load(address.as_slice()).ok().flatten().unwrap_or_default()
+    }
+
+    pub fn strategies(&self) -> Vec<Strategy> {
+        get_bin_data(self.0, KEY_STRATEGIES).unwrap_or_default()
+    }
+
+    pub fn strategy(&self, address : &HumanAddr) -> StdResult<Strategy> {
+        self.strategies()
+        .into_iter()
+        .find(|x| x.address == *address)
+        .ok_or_else(|| StdError::generic_err("This strategy is not registered"))
+    }
+
+    pub fn pending_migration(&self) -> Option<Migration> {
+        get_bin_data(self.0, KEY_MIGRATION).ok()
+    }
+
+    // Accounts that posted collateral or borrowed, debtors keep their positions when their role is revoked
+    pub fn position_holders(&self) -> Vec<CanonicalAddr> {
+        get_bin_data(self.0, KEY_POSITION_HOLDERS).unwrap_or_default()
+    }
+
+    // Reserve token native uscrt deposits are wrapped into (sSCRT)
+    pub fn native_wrapper(&self) -> Option<HumanAddr> {
+        get_bin_data(self.0, KEY_NATIVE_WRAPPER).ok()
+    }
+
+    // Only a treasury initialized with an imported state takes the next pages, until the last one
+    pub fn import_open(&self) -> bool {
+        get_bin_data(self.0, KEY_IMPORT_OPEN).unwrap_or_default()
+    }
+
     fn total_reserves(&self) -> u128 {
         let reserves_bytes = self
             .0
@@ -471,7 +841,8 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
             self.constants()?.ohm.address
         )?; 
         let total_ohm_supply = ohm_token_info.total_supply.unwrap_or_default().u128();
-        Ok(self.total_reserves() - (total_ohm_supply - self.total_debt()))
+        // An under-backed treasury has no excess reserves
+        Ok(self.total_reserves().saturating_sub(total_ohm_supply.saturating_sub(self.total_debt())))
     }
 
     fn total_debt(&self) -> u128 {
@@ -540,11 +911,13 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
         !liquidity_tokens_filtered.is_empty()
     }
 
-    pub fn bond_calculator(&self, token : &HumanAddr) -> Contract{
-        bucket_read(KEY_BOND_CALCULATOR, self.0).load(token.as_str().as_bytes()).unwrap()
+    pub fn bond_calculator(&self, token : &HumanAddr) -> StdResult<Contract>{
+        bucket_read(KEY_BOND_CALCULATOR, self.0).may_load(token.as_str().as_bytes())?
+            .ok_or_else(|| StdError::generic_err("This liquidity token has no bond calculator"))
     }
 
-    pub fn value_of<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128) -> StdResult<u128>{
+    // Oracle prices older than their staleness bound at `time` are rejected, every valuation has to say when it happens
+    pub fn value_of_at<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128, time: u64) -> StdResult<u128>{
         if self.is_reserve_token(token){
             let ohm_decimals = snip20::token_info_query(
                 querier,
@@ -560,7 +933,26 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
                 token_info.code_hash,
                 token_info.address,
             )?.decimals;
-            Ok(amount*10_u128.pow(ohm_decimals.into())/10_u128.pow(token_decimals.into()))
+            match self.valuation_source(token){
+                ValuationSource::Stable{} => Ok(amount*10_u128.pow(ohm_decimals.into())/10_u128.pow(token_decimals.into())),
+                ValuationSource::Oracle{ address, code_hash, max_staleness } => {
+                    let price = OracleQueryMsg::Price{
+                        token: token.clone(),
+                    }.query::<_, PriceResponse>(querier, code_hash, address)?.price;
+                    if time.saturating_sub(price.last_updated) > max_staleness{
+                        return Err(StdError::generic_err(format!(
+                            "The oracle price of {} is stale", token
+                        )));
+                    }
+                    let value = U256::from(amount)
+                        .checked_mul(U256::from(price.rate.u128()))
+                        .and_then(|value| value.checked_mul(U256::exp10(ohm_decimals.into())))
+                        .map(|value| value / U256::exp10(token_decimals as usize + ORACLE_PRICE_DECIMALS as usize))
+                        .filter(|value| *value <= U256::from(u128::MAX))
+                        .ok_or_else(|| StdError::generic_err("Too much reserves"))?;
+                    Ok(value.as_u128())
+                }
+            }
         }else if self.is_liquidity_token(&token){
             self.get_liquidity_valuation(token, amount, querier)
         }else if *token == self.constants()?.ohm.address{
@@ -570,6 +962,15 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
         }
     }
 
+    // Pair the liquidity token was registered with, the calculator values the token against it
+    fn get_liquidity_pair(&self, token : &HumanAddr) -> StdResult<Pair> {
+        let liquidity_info = self.get_liquidity_token_info(token)?;
+        match (liquidity_info.pair_address, liquidity_info.pair_code_hash) {
+            (Some(address), Some(code_hash)) => Ok(Pair{ address, code_hash }),
+            _ => Err(StdError::generic_err("This liquidity token was registered without its pair")),
+        }
+    }
+
     //Bond Valuation
     pub fn get_liquidity_valuation<Q: Querier>(
         &self,
@@ -578,23 +979,72 @@ impl<'a, S: ReadonlyStorage> ReadonlyConfigImpl<'a, S> {
         querier: &Q,
     ) -> StdResult<u128> {
 
-        let liquidity_info = self.get_liquidity_token_info(&token)?;
-
-        let pair = Pair{
-            address : liquidity_info.pair_address.unwrap(),
-            code_hash : liquidity_info.pair_code_hash.unwrap(),
-        };
+        let pair = self.get_liquidity_pair(token)?;
+        let calculator = self.bond_calculator(token)?;
         let valuation_msg = CalculatorQueryMsg::Valuation { 
             pair: pair,
             amount: Uint128(amount),
         };
         let valuation_response: ValuationResponse = valuation_msg.query(
             querier,
-            self.bond_calculator(&token).code_hash.clone(),
-            self.bond_calculator(&token).address.clone(),
+            calculator.code_hash,
+            calculator.address,
         )?;
         Ok(valuation_response.valuation.value.u128())
     }
+
+    // Reserve tokens are valued at par, liquidity tokens at their risk-free value marked down to the pool price
+    pub fn market_value_of<Q: Querier>(&self, querier: &Q,token : &HumanAddr,amount:u128, time: u64) -> StdResult<u128>{
+        if !self.is_liquidity_token(token){
+            return self.value_of_at(querier, token, amount, time);
+        }
+        let ohm_decimals = snip20::token_info_query(
+            querier,
+            RESPONSE_BLOCK_SIZE,
+            self.constants()?.ohm.code_hash,
+            self.constants()?.ohm.address,
+        )?.decimals;
+        let valuation = self.get_liquidity_valuation(token, amount, querier)?;
+        let markdown = self.get_liquidity_markdown(token, querier)?;
+
+        valuation
+            .checked_mul(markdown)
+            .map(|value| value / 10_u128.pow(ohm_decimals.into()))
+            .ok_or_else(|| StdError::generic_err("Market value overflow"))
+    }
+
+    pub fn get_liquidity_markdown<Q: Querier>(
+        &self,
+        token : &HumanAddr,
+        querier: &Q,
+    ) -> StdResult<u128> {
+
+        let pair = self.get_liquidity_pair(token)?;
+        let calculator = self.bond_calculator(token)?;
+        let markdown_msg = CalculatorQueryMsg::Markdown { 
+            pair: pair,
+        };
+        let markdown_response: MarkdownResponse = markdown_msg.query(
+            querier,
+            calculator.code_hash,
+            calculator.address,
+        )?;
+        Ok(markdown_response.markdown.value.u128())
+    }
+
+    pub fn borrow_rate(&self, token : &HumanAddr) -> u128{
+        bucket_read(KEY_BORROW_RATES, self.0).may_load(token.as_str().as_bytes()).ok().flatten()
+            .map(|rate: Uint128| rate.u128())
+            .unwrap_or_default()
+    }
+
+    pub fn liquidation_parameters(&self) -> Option<LiquidationParameters>{
+        get_bin_data(self.0, KEY_LIQUIDATION).ok()
+    }
+
+    pub fn valuation_source(&self, token : &HumanAddr) -> ValuationSource{
+        bucket_read(KEY_VALUATION_SOURCES, self.0).may_load(token.as_str().as_bytes()).ok().flatten().unwrap_or_default()
+    }
 }
 
 // Total Deposited
@@ -646,6 +1096,10 @@ impl<'a, S: Storage> Deposited<'a, S> {
         })?;
         Ok(self.storage.set(account.as_slice(), &bond_deposited.to_be_bytes()))
     }
+
+    pub fn set_deposited(&mut self, account: &CanonicalAddr, amount: u128) {
+        self.storage.set(account.as_slice(), &amount.to_be_bytes())
+    }
 }
 
 /// This struct refactors out the readonly methods that we need for `Deposited` and `ReadonlyDeposited`
@@ -683,9 +1137,13 @@ impl<'a, S: ReadonlyStorage> ReadonlyDebtors<'a, S> {
         ReadonlyDebtorsImpl(&self.storage)
     }
 
-    pub fn debt(&self, account: &CanonicalAddr) -> u128 {
+    pub fn debt(&self, account: &CanonicalAddr) -> StdResult<u128> {
         self.as_readonly().debt_amount(account)
     }
+
+    pub fn positions(&self, account: &CanonicalAddr) -> StdResult<Vec<DebtPosition>> {
+        self.as_readonly().positions(account)
+    }
 }
 
 pub struct Debtors<'a, S: Storage> {
@@ -703,12 +1161,16 @@ impl<'a, S: Storage> Debtors<'a, S> {
         ReadonlyDebtorsImpl(&self.storage)
     }
 
-    pub fn debt(&self, account: &CanonicalAddr) -> u128 {
+    pub fn debt(&self, account: &CanonicalAddr) -> StdResult<u128> {
         self.as_readonly().debt_amount(account)
     }
 
-    pub fn set_account_debt(&mut self, account: &CanonicalAddr, amount: u128) {
-        self.storage.set(account.as_slice(), &amount.to_be_bytes())
+    pub fn positions(&self, account: &CanonicalAddr) -> StdResult<Vec<DebtPosition>> {
+        self.as_readonly().positions(account)
+    }
+
+    pub fn set_positions(&mut self, account: &CanonicalAddr, positions: &[DebtPosition]) -> StdResult<()> {
+        set_bin_data(&mut self.storage, account.as_slice(), &positions)
     }
 }
 
@@ -720,13 +1182,87 @@ impl<'a, S: Storage> Debtors<'a, S> {
 struct ReadonlyDebtorsImpl<'a, S: ReadonlyStorage>(&'a S);
 
 impl<'a, S: ReadonlyStorage> ReadonlyDebtorsImpl<'a, S> {
-    pub fn debt_amount(&self, account: &CanonicalAddr) -> u128 {
-        let account_bytes = account.as_slice();
-        let result = self.0.get(account_bytes);
-        match result {
+    pub fn debt_amount(&self, account: &CanonicalAddr) -> StdResult<u128> {
+        Ok(self.positions(account)?.iter().map(|position| position.amount.u128()).sum())
+    }
+
+    pub fn positions(&self, account: &CanonicalAddr) -> StdResult<Vec<DebtPosition>> {
+        match self.0.get(account.as_slice()) {
+            None => Ok(vec![]),
+            // Debts used to be stored as a bare u128 without their token, a list of positions is never 16 bytes long.
+            // They are read as a single position without token, no borrow rate applies to it as before
+            Some(debt_bytes) if debt_bytes.len() == 16 => Ok(vec![DebtPosition{
+                token : HumanAddr::default(),
+                amount : Uint128(slice_to_u128(&debt_bytes)?),
+                interest : Uint128(0),
+                last_block : 0,
+            }]),
+            Some(debt_bytes) => deser_bin_data(&debt_bytes),
+        }
+    }
+}
+
+
+//Collateral
+pub struct ReadonlyCollateral<'a, S: ReadonlyStorage> {
+    storage: ReadonlyPrefixedStorage<'a, S>,
+}
+
+impl<'a, S: ReadonlyStorage> ReadonlyCollateral<'a, S> {
+    pub fn from_storage(storage: &'a S) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::new(PREFIX_COLLATERAL, storage),
+        }
+    }
+
+    fn as_readonly(&self) -> ReadonlyCollateralImpl<ReadonlyPrefixedStorage<S>> {
+        ReadonlyCollateralImpl(&self.storage)
+    }
+
+    pub fn gons(&self, account: &CanonicalAddr) -> U256 {
+        self.as_readonly().gons(account)
+    }
+}
+
+pub struct Collateral<'a, S: Storage> {
+    storage: PrefixedStorage<'a, S>,
+}
+
+impl<'a, S: Storage> Collateral<'a, S> {
+    pub fn from_storage(storage: &'a mut S) -> Self {
+        Self {
+            storage: PrefixedStorage::new(PREFIX_COLLATERAL, storage),
+        }
+    }
+
+    fn as_readonly(&self) -> ReadonlyCollateralImpl<PrefixedStorage<S>> {
+        ReadonlyCollateralImpl(&self.storage)
+    }
+
+    pub fn gons(&self, account: &CanonicalAddr) -> U256 {
+        self.as_readonly().gons(account)
+    }
+
+    // Collateral is stored in gons so that it follows the sOHM rebases
+    pub fn set_gons(&mut self, account: &CanonicalAddr, gons: U256) -> StdResult<()> {
+        set_bin_data(&mut self.storage, account.as_slice(), &gons.to_string())
+    }
+}
+
+/// This struct refactors out the readonly methods that we need for `Collateral` and `ReadonlyCollateral`
+/// in a way that is generic over their mutability.
+///
+/// This was the only way to prevent code duplication of these methods because of the way
+/// that `ReadonlyPrefixedStorage` and `PrefixedStorage` are implemented in `cosmwasm-std`
+struct ReadonlyCollateralImpl<'a, S: ReadonlyStorage>(&'a S);
+
+impl<'a, S: ReadonlyStorage> ReadonlyCollateralImpl<'a, S> {
+    pub fn gons(&self, account: &CanonicalAddr) -> U256 {
+        let gons: Option<String> = get_bin_data(self.0, account.as_slice()).ok();
+        match gons {
             // This unwrap is ok because we know we stored things correctly
-            Some(debtor_bytes) => slice_to_u128(&debtor_bytes).unwrap(),
-            None => 0,
+            Some(gons) => U256::from_dec_str(&gons).unwrap(),
+            None => U256::zero(),
         }
     }
 }
@@ -0,0 +1,236 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cosmwasm_std::{
+    Api, CanonicalAddr, HumanAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128,
+};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+
+use secret_toolkit::storage::{AppendStore, AppendStoreMut};
+
+use crate::state::Config;
+
+const PREFIX_LEDGER: &[u8] = b"ledger";
+const PREFIX_TXS: &[u8] = b"transactions";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxAction {
+    Deposit {},
+    Withdraw {},
+    IncurDebt {},
+    RepayDebt {},
+    Manage {},
+    MintRewards {},
+    AuditReserves {},
+    AllocateToStrategy {},
+    WithdrawFromStrategy {},
+    Liquidate {},
+}
+
+// Note that id is a globally incrementing counter.
+// Since it's 64 bits long, even at 50 tx/s it would take
+// over 11 billion years for it to rollback. I'm pretty sure
+// we'll have bigger issues by then.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct RichTx {
+    pub id: u64,
+    pub action: TxAction,
+    // Audits are done over all the reserves, they don't have a token
+    pub token: Option<HumanAddr>,
+    pub amount: Uint128,
+    // Value of the amount in OHM, as computed by the treasury at that block
+    pub value: Uint128,
+    pub caller: HumanAddr,
+    pub block_time: u64,
+    pub block_height: u64,
+}
+
+// Stored types:
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum TxCode {
+    Deposit = 0,
+    Withdraw = 1,
+    IncurDebt = 2,
+    RepayDebt = 3,
+    Manage = 4,
+    MintRewards = 5,
+    AuditReserves = 6,
+    AllocateToStrategy = 7,
+    WithdrawFromStrategy = 8,
+    Liquidate = 9,
+}
+
+impl TxCode {
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn from_u8(n: u8) -> StdResult<Self> {
+        use TxCode::*;
+        match n {
+            0 => Ok(Deposit),
+            1 => Ok(Withdraw),
+            2 => Ok(IncurDebt),
+            3 => Ok(RepayDebt),
+            4 => Ok(Manage),
+            5 => Ok(MintRewards),
+            6 => Ok(AuditReserves),
+            7 => Ok(AllocateToStrategy),
+            8 => Ok(WithdrawFromStrategy),
+            9 => Ok(Liquidate),
+            other => Err(StdError::generic_err(format!(
+                "Unexpected Tx code in transaction history: {} Storage is corrupted.",
+                other
+            ))),
+        }
+    }
+
+    fn into_action(self) -> TxAction {
+        match self {
+            TxCode::Deposit => TxAction::Deposit {},
+            TxCode::Withdraw => TxAction::Withdraw {},
+            TxCode::IncurDebt => TxAction::IncurDebt {},
+            TxCode::RepayDebt => TxAction::RepayDebt {},
+            TxCode::Manage => TxAction::Manage {},
+            TxCode::MintRewards => TxAction::MintRewards {},
+            TxCode::AuditReserves => TxAction::AuditReserves {},
+            TxCode::AllocateToStrategy => TxAction::AllocateToStrategy {},
+            TxCode::WithdrawFromStrategy => TxAction::WithdrawFromStrategy {},
+            TxCode::Liquidate => TxAction::Liquidate {},
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+struct StoredRichTx {
+    id: u64,
+    tx_type: u8,
+    token: Option<HumanAddr>,
+    amount: u128,
+    value: u128,
+    caller: CanonicalAddr,
+    block_time: u64,
+    block_height: u64,
+}
+
+impl StoredRichTx {
+    fn into_humanized<A: Api>(self, api: &A) -> StdResult<RichTx> {
+        Ok(RichTx {
+            id: self.id,
+            action: TxCode::from_u8(self.tx_type)?.into_action(),
+            token: self.token,
+            amount: Uint128(self.amount),
+            value: Uint128(self.value),
+            caller: api.human_address(&self.caller)?,
+            block_time: self.block_time,
+            block_height: self.block_height,
+        })
+    }
+}
+
+// Storage functions:
+
+fn increment_tx_count<S: Storage>(store: &mut S) -> StdResult<u64> {
+    let mut config = Config::from_storage(store);
+    let id = config.tx_count() + 1;
+    config.set_tx_count(id)?;
+    Ok(id)
+}
+
+/// Appends the action to the treasury ledger and to the history of the caller
+pub fn store_tx<S: Storage>(
+    store: &mut S,
+    code: TxCode,
+    token: Option<HumanAddr>,
+    amount: u128,
+    value: u128,
+    caller: &CanonicalAddr,
+    block: &cosmwasm_std::BlockInfo,
+) -> StdResult<()> {
+    let id = increment_tx_count(store)?;
+    let tx = StoredRichTx {
+        id,
+        tx_type: code.to_u8(),
+        token,
+        amount,
+        value,
+        caller: caller.clone(),
+        block_time: block.time,
+        block_height: block.height,
+    };
+
+    append_to_ledger(store, &tx)?;
+    append_tx(store, &tx, caller)
+}
+
+fn append_to_ledger<S: Storage>(store: &mut S, tx: &StoredRichTx) -> StdResult<()> {
+    let mut store = PrefixedStorage::new(PREFIX_LEDGER, store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(tx)
+}
+
+fn append_tx<S: Storage>(
+    store: &mut S,
+    tx: &StoredRichTx,
+    for_address: &CanonicalAddr,
+) -> StdResult<()> {
+    let mut store = PrefixedStorage::multilevel(&[PREFIX_TXS, for_address.as_slice()], store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(tx)
+}
+
+pub fn get_ledger<A: Api, S: ReadonlyStorage>(
+    api: &A,
+    storage: &S,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<RichTx>, u64)> {
+    let store = ReadonlyPrefixedStorage::new(PREFIX_LEDGER, storage);
+    get_page(api, &store, page, page_size)
+}
+
+pub fn get_txs<A: Api, S: ReadonlyStorage>(
+    api: &A,
+    storage: &S,
+    for_address: &CanonicalAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<RichTx>, u64)> {
+    let store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_TXS, for_address.as_slice()], storage);
+    get_page(api, &store, page, page_size)
+}
+
+fn get_page<A: Api, S: ReadonlyStorage>(
+    api: &A,
+    store: &S,
+    page: u32,
+    page_size: u32,
+) -> StdResult<(Vec<RichTx>, u64)> {
+    // Try to access the storage of txs.
+    // If it doesn't exist yet, return an empty list of transactions.
+    let store = AppendStore::<StoredRichTx, _, _>::attach(store);
+    let store = if let Some(result) = store {
+        result?
+    } else {
+        return Ok((vec![], 0));
+    };
+
+    // Take `page_size` txs starting from the latest tx, potentially skipping `page * page_size`
+    // txs from the start.
+    let tx_iter = store
+        .iter()
+        .rev()
+        .skip(page.saturating_mul(page_size) as _)
+        .take(page_size as _);
+
+    // The `and_then` here flattens the `StdResult<StdResult<RichTx>>` to an `StdResult<RichTx>`
+    let txs: StdResult<Vec<RichTx>> = tx_iter
+        .map(|tx| tx.map(|tx| tx.into_humanized(api)).and_then(|x| x))
+        .collect();
+    txs.map(|txs| (txs, store.len() as u64))
+}